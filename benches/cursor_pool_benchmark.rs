@@ -0,0 +1,78 @@
+use cosdata::models::buffered_io::{BufIoError, BufferManager};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use rand::Rng;
+use rayon::prelude::*;
+use std::io::SeekFrom;
+use std::sync::Arc;
+use tempfile::tempfile;
+
+const FILE_SIZE: u64 = 1024 * 1024;
+const READS: usize = 100_000;
+
+fn create_file() -> Arc<BufferManager> {
+    let bufman = Arc::new(BufferManager::new(tempfile().unwrap(), 1.0).unwrap());
+    let cursor = bufman.open_cursor().unwrap();
+    for i in 0..(FILE_SIZE / 4) {
+        bufman.write_u32_with_cursor(cursor, i as u32).unwrap();
+    }
+    bufman.close_cursor(cursor).unwrap();
+    bufman.flush().unwrap();
+    bufman
+}
+
+fn random_offsets() -> Vec<u64> {
+    let mut rng = rand::thread_rng();
+    (0..READS)
+        .map(|_| rng.gen_range(0..FILE_SIZE / 4) * 4)
+        .collect()
+}
+
+// one cursor opened and closed per read, as the embedding reads used to do
+fn read_open_close(bufman: &BufferManager, offsets: &[u64]) -> u64 {
+    offsets
+        .par_iter()
+        .map(|&offset| {
+            let cursor = bufman.open_cursor().unwrap();
+            bufman
+                .seek_with_cursor(cursor, SeekFrom::Start(offset))
+                .unwrap();
+            let value = bufman.read_u32_with_cursor(cursor).unwrap();
+            bufman.close_cursor(cursor).unwrap();
+            value as u64
+        })
+        .sum()
+}
+
+fn read_pooled(bufman: &BufferManager, offsets: &[u64]) -> u64 {
+    offsets
+        .par_iter()
+        .map(|&offset| {
+            bufman
+                .with_cursor(|cursor| -> Result<u32, BufIoError> {
+                    bufman.seek_with_cursor(cursor, SeekFrom::Start(offset))?;
+                    bufman.read_u32_with_cursor(cursor)
+                })
+                .unwrap() as u64
+        })
+        .sum()
+}
+
+fn cursor_pool_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Cursor Pool");
+    group.sample_size(20);
+
+    let bufman = create_file();
+    let offsets = random_offsets();
+
+    group.bench_with_input(BenchmarkId::new("OpenClose", READS), &READS, |b, _| {
+        b.iter(|| read_open_close(&bufman, &offsets))
+    });
+    group.bench_with_input(BenchmarkId::new("Pooled", READS), &READS, |b, _| {
+        b.iter(|| read_pooled(&bufman, &offsets))
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, cursor_pool_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,69 @@
+use cosdata::models::buffered_io::BufferManager;
+use cosdata::models::embedding_persist::{
+    read_embedding, read_embeddings_parallel, scan_embedding_offsets, write_embedding,
+};
+use cosdata::models::types::{RawVectorEmbedding, VectorId};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use rand::Rng;
+use std::sync::Arc;
+use tempfile::tempfile;
+
+const DIMENSION: usize = 768;
+
+// writes `count` random embeddings, returning the buffer and its length
+fn create_vec_raw_file(count: usize) -> (Arc<BufferManager>, u32) {
+    let mut rng = rand::thread_rng();
+    let bufman = Arc::new(BufferManager::new(tempfile().unwrap(), 1.0).unwrap());
+
+    let mut last = 0;
+    for id in 0..count {
+        let raw_vec: Vec<f32> = (0..DIMENSION).map(|_| rng.gen_range(-1.0..1.0)).collect();
+        let emb = RawVectorEmbedding {
+            raw_vec: Arc::new(raw_vec),
+            hash_vec: VectorId(id as u64),
+            metadata: None,
+        };
+        last = write_embedding(bufman.clone(), &emb).unwrap();
+    }
+    bufman.flush().unwrap();
+
+    let (_, end) = read_embedding(bufman.clone(), last).unwrap();
+    (bufman, end)
+}
+
+fn read_serial(bufman: Arc<BufferManager>, end: u32) -> Vec<RawVectorEmbedding> {
+    let mut embeddings = Vec::new();
+    let mut offset = 0;
+    while offset < end {
+        let (emb, next) = read_embedding(bufman.clone(), offset).unwrap();
+        embeddings.push(emb);
+        offset = next;
+    }
+    embeddings
+}
+
+fn read_parallel(bufman: Arc<BufferManager>, end: u32) -> Vec<RawVectorEmbedding> {
+    let offsets = scan_embedding_offsets(&bufman, 0, end).unwrap();
+    read_embeddings_parallel(bufman, &offsets).unwrap()
+}
+
+fn embedding_read_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Embedding Read");
+    group.sample_size(10);
+
+    for count in [10_000, 50_000] {
+        let (bufman, end) = create_vec_raw_file(count);
+
+        group.bench_with_input(BenchmarkId::new("Serial", count), &count, |b, _| {
+            b.iter(|| read_serial(bufman.clone(), end))
+        });
+        group.bench_with_input(BenchmarkId::new("Parallel", count), &count, |b, _| {
+            b.iter(|| read_parallel(bufman.clone(), end))
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, embedding_read_benchmark);
+criterion_main!(benches);
@@ -20,7 +20,7 @@ pub fn create_inverted_index_and_query_vector(
     num_vectors: usize,
 ) -> (InvertedIndexSparseAnnNewDS, SparseVector) {
     let nowx = Instant::now();
-    let inverted_index = InvertedIndexSparseAnnNewDS::new();
+    let inverted_index = InvertedIndexSparseAnnNewDS::new_default();
 
     let mut original_vectors: Vec<SparseVector> =
         bench_common::generate_random_sparse_vectors(num_vectors as usize, num_dimensions as usize);
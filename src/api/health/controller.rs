@@ -0,0 +1,10 @@
+use actix_web::{web, HttpResponse};
+
+use crate::app_context::AppContext;
+
+use super::service;
+
+pub(crate) async fn get_health(ctx: web::Data<AppContext>) -> HttpResponse {
+    let health = service::check_health(&ctx);
+    HttpResponse::build(health.status_code()).json(health)
+}
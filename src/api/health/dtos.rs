@@ -0,0 +1,61 @@
+use actix_web::http::StatusCode;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum HealthStatus {
+    Ok,
+    Failed,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct SubsystemHealthDto {
+    pub name: &'static str,
+    pub status: HealthStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl SubsystemHealthDto {
+    pub fn new(name: &'static str, check: Result<(), String>) -> Self {
+        match check {
+            Ok(()) => Self {
+                name,
+                status: HealthStatus::Ok,
+                error: None,
+            },
+            Err(error) => Self {
+                name,
+                status: HealthStatus::Failed,
+                error: Some(error),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct HealthResponseDto {
+    pub status: HealthStatus,
+    pub subsystems: Vec<SubsystemHealthDto>,
+}
+
+impl HealthResponseDto {
+    pub fn new(subsystems: Vec<SubsystemHealthDto>) -> Self {
+        let status = if subsystems
+            .iter()
+            .all(|subsystem| subsystem.status == HealthStatus::Ok)
+        {
+            HealthStatus::Ok
+        } else {
+            HealthStatus::Failed
+        };
+        Self { status, subsystems }
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        match self.status {
+            HealthStatus::Ok => StatusCode::OK,
+            HealthStatus::Failed => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+}
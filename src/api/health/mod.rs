@@ -0,0 +1,10 @@
+use actix_web::{web, Scope};
+mod controller;
+mod dtos;
+mod service;
+
+pub(crate) fn health_module() -> Scope {
+    let health_module = web::scope("/health").route("", web::get().to(controller::get_health));
+
+    health_module
+}
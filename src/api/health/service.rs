@@ -0,0 +1,126 @@
+use std::fs;
+use std::path::Path;
+
+use lmdb::{Database, Environment, Transaction};
+
+use crate::{app_context::AppContext, models::types::CollectionsMap};
+
+use super::dtos::{HealthResponseDto, SubsystemHealthDto};
+
+/// checks the subsystems the server depends on
+///
+/// the checks only touch metadata and in-memory state, so they are cheap
+/// enough to be polled every few seconds
+pub(crate) fn check_health(ctx: &AppContext) -> HealthResponseDto {
+    let collections_map = &ctx.ain_env.collections_map;
+
+    HealthResponseDto::new(vec![
+        SubsystemHealthDto::new(
+            "lmdb",
+            check_lmdb(&ctx.ain_env.persist, collections_map.lmdb_collections_db),
+        ),
+        SubsystemHealthDto::new(
+            "files",
+            check_files(collections_map, &ctx.config.collections_path),
+        ),
+        SubsystemHealthDto::new("collections_map", check_collections_map(collections_map)),
+    ])
+}
+
+/// opens a read transaction and reads from the collections db
+fn check_lmdb(env: &Environment, db: Database) -> Result<(), String> {
+    let txn = env.begin_ro_txn().map_err(|e| e.to_string())?;
+    match txn.get(db, &"health") {
+        Ok(_) | Err(lmdb::Error::NotFound) => Ok(()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// checks that the prop file and the directory holding the version files of
+/// every loaded collection are writable
+fn check_files(collections_map: &CollectionsMap, collections_path: &Path) -> Result<(), String> {
+    for entry in collections_map.iter() {
+        let (name, dense_index) = (entry.key(), entry.value());
+
+        let prop_file = dense_index
+            .prop_file
+            .read()
+            .map_err(|_| format!("prop file of `{}` is poisoned", name))?;
+        let metadata = prop_file
+            .metadata()
+            .map_err(|e| format!("prop file of `{}`: {}", name, e))?;
+        if metadata.permissions().readonly() {
+            return Err(format!("prop file of `{}` is read-only", name));
+        }
+        drop(prop_file);
+
+        if let Some(collection) = collections_map.get_collection(name) {
+            let metadata = fs::metadata(collection.get_path(collections_path))
+                .map_err(|e| format!("directory of `{}`: {}", name, e))?;
+            if metadata.permissions().readonly() {
+                return Err(format!("directory of `{}` is read-only", name));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// checks that every dense index in memory belongs to a loaded collection of
+/// the same name
+fn check_collections_map(collections_map: &CollectionsMap) -> Result<(), String> {
+    for entry in collections_map.iter() {
+        let (name, dense_index) = (entry.key(), entry.value());
+        if dense_index.database_name != *name {
+            return Err(format!(
+                "dense index `{}` is stored under `{}`",
+                dense_index.database_name, name
+            ));
+        }
+        if collections_map.get_collection(name).is_none() {
+            return Err(format!("dense index `{}` has no collection", name));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::check_lmdb;
+    use crate::api::health::dtos::{HealthResponseDto, HealthStatus, SubsystemHealthDto};
+    use actix_web::http::StatusCode;
+    use lmdb::{DatabaseFlags, Environment, Transaction};
+    use std::thread;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_lmdb_failure_reports_unavailable() {
+        let dir = tempdir().unwrap();
+        let env = Environment::new()
+            .set_max_dbs(1)
+            .set_max_readers(1)
+            .open(dir.as_ref())
+            .unwrap();
+        let db = env
+            .create_db(Some("collections"), DatabaseFlags::empty())
+            .unwrap();
+
+        let health =
+            HealthResponseDto::new(vec![SubsystemHealthDto::new("lmdb", check_lmdb(&env, db))]);
+        assert_eq!(health.status, HealthStatus::Ok);
+        assert_eq!(health.status_code(), StatusCode::OK);
+
+        // hold the only reader slot, so the check can't begin a read transaction
+        let txn = env.begin_ro_txn().unwrap();
+        let check = thread::scope(|s| s.spawn(|| check_lmdb(&env, db)).join().unwrap());
+        txn.abort();
+
+        let health = HealthResponseDto::new(vec![
+            SubsystemHealthDto::new("lmdb", check),
+            SubsystemHealthDto::new("files", Ok(())),
+        ]);
+        assert_eq!(health.status, HealthStatus::Failed);
+        assert_eq!(health.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(health.subsystems[0].error.is_some());
+        assert_eq!(health.subsystems[1].status, HealthStatus::Ok);
+    }
+}
@@ -3,7 +3,10 @@ use actix_web::{web, HttpResponse, Result};
 use crate::app_context::AppContext;
 
 use super::{
-    dtos::{CreateCollectionDto, GetCollectionsDto},
+    dtos::{
+        CreateCollectionDto, EvaluateRecallDto, GetOpLogDto, IndexCollectionDto, TrainQuantizerDto,
+        WarmCacheDto,
+    },
     service,
 };
 
@@ -17,11 +20,8 @@ pub(crate) async fn create_collection(
     Ok(HttpResponse::Ok().json(create_collection_response_dto))
 }
 
-pub(crate) async fn get_collections(
-    web::Query(get_collections_dto): web::Query<GetCollectionsDto>,
-    ctx: web::Data<AppContext>,
-) -> Result<HttpResponse> {
-    let collections = service::get_collections(ctx.into_inner(), get_collections_dto).await?;
+pub(crate) async fn list_collections(ctx: web::Data<AppContext>) -> Result<HttpResponse> {
+    let collections = service::list_collections(ctx.into_inner()).await?;
     Ok(HttpResponse::Ok().json(collections))
 }
 
@@ -40,3 +40,95 @@ pub(crate) async fn delete_collection_by_id(
     let collection = service::delete_collection_by_id(ctx.into_inner(), &collection_id).await?;
     Ok(HttpResponse::Ok().json(collection))
 }
+
+pub(crate) async fn get_quantization_by_id(
+    collection_id: web::Path<String>,
+    ctx: web::Data<AppContext>,
+) -> Result<HttpResponse> {
+    let quantization = service::get_quantization_by_id(ctx.into_inner(), &collection_id).await?;
+    Ok(HttpResponse::Ok().json(quantization))
+}
+
+pub(crate) async fn get_version_by_id(
+    collection_id: web::Path<String>,
+    ctx: web::Data<AppContext>,
+) -> Result<HttpResponse> {
+    let version = service::get_version_by_id(ctx.into_inner(), &collection_id).await?;
+    Ok(HttpResponse::Ok().json(version))
+}
+
+pub(crate) async fn reindex_collection_by_id(
+    collection_id: web::Path<String>,
+    ctx: web::Data<AppContext>,
+) -> Result<HttpResponse> {
+    service::reindex_collection_by_id(ctx.into_inner(), &collection_id).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub(crate) async fn index_collection_by_id(
+    collection_id: web::Path<String>,
+    web::Json(index_collection_dto): web::Json<IndexCollectionDto>,
+    ctx: web::Data<AppContext>,
+) -> Result<HttpResponse> {
+    let counts =
+        service::index_collection_by_id(ctx.into_inner(), &collection_id, index_collection_dto)
+            .await?;
+    Ok(HttpResponse::Ok().json(counts))
+}
+
+pub(crate) async fn warm_cache_by_id(
+    collection_id: web::Path<String>,
+    web::Json(warm_cache_dto): web::Json<WarmCacheDto>,
+    ctx: web::Data<AppContext>,
+) -> Result<HttpResponse> {
+    let warmed =
+        service::warm_cache_by_id(ctx.into_inner(), &collection_id, warm_cache_dto).await?;
+    Ok(HttpResponse::Ok().json(warmed))
+}
+
+pub(crate) async fn compact_prop_file_by_id(
+    collection_id: web::Path<String>,
+    ctx: web::Data<AppContext>,
+) -> Result<HttpResponse> {
+    let compacted = service::compact_prop_file_by_id(ctx.into_inner(), &collection_id).await?;
+    Ok(HttpResponse::Ok().json(compacted))
+}
+
+pub(crate) async fn train_quantizer_by_id(
+    collection_id: web::Path<String>,
+    web::Json(train_quantizer_dto): web::Json<TrainQuantizerDto>,
+    ctx: web::Data<AppContext>,
+) -> Result<HttpResponse> {
+    let trained =
+        service::train_quantizer_by_id(ctx.into_inner(), &collection_id, train_quantizer_dto)
+            .await?;
+    Ok(HttpResponse::Ok().json(trained))
+}
+
+pub(crate) async fn get_statistics_by_id(
+    collection_id: web::Path<String>,
+    ctx: web::Data<AppContext>,
+) -> Result<HttpResponse> {
+    let statistics = service::get_statistics_by_id(ctx.into_inner(), &collection_id).await?;
+    Ok(HttpResponse::Ok().json(statistics))
+}
+
+pub(crate) async fn evaluate_recall_by_id(
+    collection_id: web::Path<String>,
+    web::Json(evaluate_recall_dto): web::Json<EvaluateRecallDto>,
+    ctx: web::Data<AppContext>,
+) -> Result<HttpResponse> {
+    let evaluation =
+        service::evaluate_recall_by_id(ctx.into_inner(), &collection_id, evaluate_recall_dto)
+            .await?;
+    Ok(HttpResponse::Ok().json(evaluation))
+}
+
+pub(crate) async fn get_oplog_by_id(
+    collection_id: web::Path<String>,
+    web::Query(get_oplog_dto): web::Query<GetOpLogDto>,
+    ctx: web::Data<AppContext>,
+) -> Result<HttpResponse> {
+    let entries = service::get_oplog_by_id(ctx.into_inner(), &collection_id, get_oplog_dto).await?;
+    Ok(HttpResponse::Ok().json(entries))
+}
@@ -1,6 +1,17 @@
+use std::sync::atomic::Ordering;
+
+use lmdb::Transaction;
 use serde::{Deserialize, Serialize};
 
-use crate::models::collection::{CollectionConfig, DenseVectorOptions, SparseVectorOptions};
+use crate::{
+    models::{
+        collection::{CollectionConfig, DenseVectorOptions, SparseVectorOptions},
+        common::WaCustomError,
+        types::{DenseIndex, QuantizationMetric},
+        versioning::Hash,
+    },
+    quantization::StorageType,
+};
 
 #[derive(Deserialize)]
 pub(crate) struct CreateCollectionDto {
@@ -10,6 +21,27 @@ pub(crate) struct CreateCollectionDto {
     pub sparse_vector: SparseVectorOptions,
     pub metadata_schema: Option<String>, //object (optional)
     pub config: CollectionConfig,
+    // one of "cosine", "euclidean" or "dot", cosine if not set
+    #[serde(default)]
+    pub distance_metric: Option<String>,
+    // scalar if not set
+    #[serde(default)]
+    pub quantization: Option<QuantizationOptionsDto>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct QuantizationOptionsDto {
+    // one of "scalar", "product" or "binary"
+    #[serde(rename = "type")]
+    pub kind: String,
+    // the remaining fields only apply to product quantization
+    #[serde(default)]
+    pub subspace_count: Option<u16>,
+    #[serde(default)]
+    pub centroid_count: Option<u16>,
+    // vectors to collect before training the centroids
+    #[serde(default)]
+    pub training_sample_size: Option<usize>,
 }
 
 #[derive(Serialize)]
@@ -20,10 +52,262 @@ pub(crate) struct CreateCollectionDtoResponse {
 }
 
 #[derive(Deserialize)]
-pub(crate) struct GetCollectionsDto {}
+pub(crate) struct GetOpLogDto {
+    // only ops committed after this version number are returned
+    #[serde(default)]
+    pub from: u32,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct IndexCollectionDto {
+    pub batch_size: usize,
+}
+
+#[derive(Serialize)]
+pub(crate) struct IndexCollectionResponseDto {
+    pub count_indexed: u32,
+    pub count_unindexed: u32,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct WarmCacheDto {
+    // number of top HNSW levels to load, all of them if not set
+    #[serde(default)]
+    pub levels: Option<u8>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct WarmCacheResponseDto {
+    pub nodes_loaded: usize,
+}
+
+#[derive(Serialize)]
+pub(crate) struct CompactPropFileResponseDto {
+    pub bytes_reclaimed: u64,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct TrainQuantizerDto {
+    // most raw vectors to train on, `MAX_TRAINING_SAMPLE_SIZE` if not set
+    #[serde(default)]
+    pub sample_size: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct TrainQuantizerResponseDto {
+    pub sample_count: usize,
+    pub iterations: usize,
+    pub distortion: f32,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct EvaluateRecallDto {
+    pub queries: Vec<Vec<f32>>,
+    pub k: usize,
+    // overrides the collection's `ef_search` for the graph searches
+    #[serde(default)]
+    pub ef_search: Option<u32>,
+}
 
 #[derive(Serialize)]
-pub(crate) struct GetCollectionsResponseDto {
+pub(crate) struct EvaluateRecallResponseDto {
+    pub recall: f32,
+    // mean latency of the graph searches
+    pub mean_latency_ms: f64,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ListCollectionsResponseDto {
     pub name: String,
     pub description: Option<String>,
+    pub config: CollectionConfig,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "lowercase", tag = "type")]
+pub(crate) enum QuantizationSchemeDto {
+    Scalar,
+    Product { centroid_count: Option<u16> },
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct GetQuantizationResponseDto {
+    pub scheme: QuantizationSchemeDto,
+    pub storage_type: StorageType,
+    pub range_min: f32,
+    pub range_max: f32,
+    // false while the index is still collecting samples to pick the range
+    pub trained: bool,
+    pub sample_threshold: usize,
+    pub training_sample_size: usize,
+}
+
+impl GetQuantizationResponseDto {
+    pub fn new(
+        quantization_metric: &QuantizationMetric,
+        storage_type: StorageType,
+        (range_min, range_max): (f32, f32),
+        trained: bool,
+        sample_threshold: usize,
+        training_sample_size: usize,
+    ) -> Self {
+        let scheme = match quantization_metric {
+            QuantizationMetric::Scalar => QuantizationSchemeDto::Scalar,
+            QuantizationMetric::Product(product) => QuantizationSchemeDto::Product {
+                centroid_count: product
+                    .centroids
+                    .as_ref()
+                    .map(|centroid| centroid.number_of_centroids),
+            },
+        };
+        Self {
+            scheme,
+            storage_type,
+            range_min,
+            range_max,
+            trained,
+            sample_threshold,
+            training_sample_size,
+        }
+    }
+
+    pub fn from_dense_index(dense_index: &DenseIndex) -> Self {
+        Self::new(
+            &dense_index.quantization_metric.clone().get().clone(),
+            dense_index.storage_type.clone().get().clone(),
+            *dense_index.values_range.read().unwrap(),
+            dense_index.is_configured.load(Ordering::Acquire),
+            dense_index.sample_threshold,
+            dense_index
+                .vectors_collected
+                .load(Ordering::Relaxed)
+                .min(dense_index.sample_threshold),
+        )
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct GetVersionResponseDto {
+    pub hash: Hash,
+    pub version_number: u32,
+    pub branch: String,
+}
+
+impl GetVersionResponseDto {
+    /// the version `dense_index` currently serves, the last one committed
+    /// unless it was rolled back
+    pub fn from_dense_index(dense_index: &DenseIndex) -> Result<Self, WaCustomError> {
+        let hash = dense_index.get_current_version();
+        let txn = dense_index
+            .lmdb
+            .env
+            .begin_ro_txn()
+            .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
+        let version_hash = dense_index
+            .vcs
+            .get_version_hash(&hash, &txn)
+            .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?
+            .ok_or_else(|| WaCustomError::NotFound(format!("version {}", *hash)))?;
+        txn.abort();
+        let branch = dense_index.current_branch()?;
+
+        Ok(Self {
+            hash,
+            version_number: *version_hash.version,
+            branch: branch.get_branch_name().to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{GetQuantizationResponseDto, GetVersionResponseDto, QuantizationSchemeDto};
+    use crate::models::meta_persist::update_current_version;
+    use crate::models::types::{
+        DenseIndexTransaction, HNSWHyperParams, QuantizationMetric, RawVectorEmbedding, VectorId,
+    };
+    use crate::quantization::product::{Centroid, ProductQuantization};
+    use crate::quantization::StorageType;
+    use crate::vector_store::tests::{setup_dense_index, test_config};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_quantization_response_reflects_scheme() {
+        let untrained = GetQuantizationResponseDto::new(
+            &QuantizationMetric::Scalar,
+            StorageType::UnsignedByte,
+            (-1.0, 1.0),
+            false,
+            100,
+            40,
+        );
+        assert!(matches!(untrained.scheme, QuantizationSchemeDto::Scalar));
+        assert!(!untrained.trained);
+        assert_eq!(untrained.training_sample_size, 40);
+
+        // after the sampling run picks a range, the response reports it
+        let trained = GetQuantizationResponseDto::new(
+            &QuantizationMetric::Scalar,
+            StorageType::UnsignedByte,
+            (-0.3, 0.3),
+            true,
+            100,
+            100,
+        );
+        assert!(trained.trained);
+        assert_eq!((trained.range_min, trained.range_max), (-0.3, 0.3));
+
+        let product = GetQuantizationResponseDto::new(
+            &QuantizationMetric::Product(ProductQuantization {
+                centroids: Some(Centroid {
+                    number_of_centroids: 256,
+                    centroids: Vec::new(),
+                }),
+                subspace_count: 8,
+                retired_codebooks: Vec::new(),
+            }),
+            StorageType::UnsignedByte,
+            (-1.0, 1.0),
+            true,
+            0,
+            0,
+        );
+        assert!(matches!(
+            product.scheme,
+            QuantizationSchemeDto::Product {
+                centroid_count: Some(256)
+            }
+        ));
+    }
+
+    #[test]
+    fn test_version_response_follows_commits() {
+        let config = test_config();
+        let (dense_index, _dir) = setup_dense_index(HNSWHyperParams::default_from_config(&config));
+
+        let initial = GetVersionResponseDto::from_dense_index(&dense_index).unwrap();
+        assert_eq!(initial.hash, dense_index.get_current_version());
+        assert_eq!(initial.version_number, 0);
+        assert_eq!(initial.branch, "main");
+
+        // upload a vector in a transaction and commit it
+        let transaction =
+            DenseIndexTransaction::new(dense_index.clone(), config.commit_flush_batch_size)
+                .unwrap();
+        let transaction_id = transaction.id;
+        transaction.post_raw_embedding(RawVectorEmbedding {
+            raw_vec: Arc::new(vec![0.1, 0.2, -0.3, 0.4]),
+            hash_vec: VectorId(1),
+            metadata: None,
+        });
+        transaction.pre_commit().unwrap();
+        dense_index.set_current_version(transaction_id);
+        update_current_version(&dense_index.lmdb, transaction_id).unwrap();
+
+        let committed = GetVersionResponseDto::from_dense_index(&dense_index).unwrap();
+        assert_eq!(committed.hash, transaction_id);
+        assert_ne!(committed.hash, initial.hash);
+        assert_eq!(committed.version_number, initial.version_number + 1);
+        assert_eq!(committed.branch, "main");
+    }
 }
@@ -11,6 +11,10 @@ pub enum CollectionsError {
     NotFound,
     FailedToGetAppEnv,
     FailedToCreateCollection(String),
+    OngoingTransaction,
+    IndexingInProgress,
+    InvalidParams(String),
+    AlreadyExists(String),
     WaCustomError(WaCustomError),
 }
 
@@ -22,6 +26,16 @@ impl Display for CollectionsError {
             CollectionsError::FailedToCreateCollection(msg) => {
                 write!(f, "Failed to create collection due to {}", msg)
             }
+            CollectionsError::OngoingTransaction => {
+                write!(f, "There is an ongoing transaction on this collection!")
+            }
+            CollectionsError::IndexingInProgress => {
+                write!(f, "Indexing is already in progress on this collection!")
+            }
+            CollectionsError::InvalidParams(msg) => write!(f, "Invalid params: {}", msg),
+            CollectionsError::AlreadyExists(name) => {
+                write!(f, "Collection `{}` already exists!", name)
+            }
             CollectionsError::WaCustomError(e) => write!(f, "LMDB database error: {e:?}"),
         }
     }
@@ -35,9 +49,13 @@ impl ResponseError for CollectionsError {
     }
     fn status_code(&self) -> StatusCode {
         match self {
-            CollectionsError::NotFound => StatusCode::BAD_REQUEST,
+            CollectionsError::NotFound => StatusCode::NOT_FOUND,
             CollectionsError::FailedToGetAppEnv => StatusCode::INTERNAL_SERVER_ERROR,
             CollectionsError::FailedToCreateCollection(_) => StatusCode::BAD_REQUEST,
+            CollectionsError::OngoingTransaction => StatusCode::CONFLICT,
+            CollectionsError::IndexingInProgress => StatusCode::CONFLICT,
+            CollectionsError::InvalidParams(_) => StatusCode::BAD_REQUEST,
+            CollectionsError::AlreadyExists(_) => StatusCode::CONFLICT,
             CollectionsError::WaCustomError(_) => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
@@ -2,13 +2,13 @@ use actix_web::{web, Scope};
 mod controller;
 mod dtos;
 mod error;
-mod repo;
+pub(crate) mod repo;
 pub(crate) mod service;
 
 pub(crate) fn collections_module() -> Scope {
     let collections_module = web::scope("/collections")
         .route("", web::post().to(controller::create_collection))
-        .route("", web::get().to(controller::get_collections))
+        .route("", web::get().to(controller::list_collections))
         .route(
             "/{collection_id}",
             web::get().to(controller::get_collection_by_id),
@@ -16,6 +16,46 @@ pub(crate) fn collections_module() -> Scope {
         .route(
             "/{collection_id}",
             web::delete().to(controller::delete_collection_by_id),
+        )
+        .route(
+            "/{collection_id}/quantization",
+            web::get().to(controller::get_quantization_by_id),
+        )
+        .route(
+            "/{collection_id}/version",
+            web::get().to(controller::get_version_by_id),
+        )
+        .route(
+            "/{collection_id}/reindex",
+            web::post().to(controller::reindex_collection_by_id),
+        )
+        .route(
+            "/{collection_id}/index",
+            web::post().to(controller::index_collection_by_id),
+        )
+        .route(
+            "/{collection_id}/warm",
+            web::post().to(controller::warm_cache_by_id),
+        )
+        .route(
+            "/{collection_id}/compact",
+            web::post().to(controller::compact_prop_file_by_id),
+        )
+        .route(
+            "/{collection_id}/quantizer/train",
+            web::post().to(controller::train_quantizer_by_id),
+        )
+        .route(
+            "/{collection_id}/stats",
+            web::get().to(controller::get_statistics_by_id),
+        )
+        .route(
+            "/{collection_id}/evaluate",
+            web::post().to(controller::evaluate_recall_by_id),
+        )
+        .route(
+            "/{collection_id}/oplog",
+            web::get().to(controller::get_oplog_by_id),
         );
 
     collections_module
@@ -1,17 +1,41 @@
-use std::sync::Arc;
+use actix_web::web;
+use std::{
+    fs, io,
+    sync::{
+        atomic::{AtomicPtr, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use crate::{
-    api_service::init_inverted_index_for_collection,
+    api_service::{evaluate_recall, init_inverted_index_for_collection},
     app_context::AppContext,
     indexes::inverted_index::InvertedIndex,
-    models::{collection::Collection, types::DenseIndex},
+    models::{
+        collection::{Collection, QuantizationOptions},
+        common::WaCustomError,
+        file_persist::prop_file_path,
+        meta_persist::load_collections,
+        oplog::{oplog_path, read_oplog_since, OpLogEntry},
+        types::{DenseIndex, DenseIndexTransaction, DistanceMetric, QuantizationMetric},
+    },
+    quantization::{product::TrainingStats, StorageType},
+    storage::inverted_index_sparse_ann_new_ds::InvertedIndexSparseAnnNewDS,
+    vector_store::{
+        compact_prop_file, get_embedding_counts, index_pending_embeddings, reindex,
+        retrain_quantizer, warm_cache,
+    },
 };
 
 use super::{
-    dtos::{CreateCollectionDto, GetCollectionsDto, GetCollectionsResponseDto},
+    dtos::{CreateCollectionDto, ListCollectionsResponseDto, QuantizationOptionsDto},
     error::CollectionsError,
 };
 
+const DEFAULT_PQ_CENTROID_COUNT: u16 = 256;
+const DEFAULT_PQ_TRAINING_SAMPLE_SIZE: usize = 10_000;
+
 pub(crate) async fn create_collection(
     ctx: Arc<AppContext>,
     CreateCollectionDto {
@@ -21,8 +45,12 @@ pub(crate) async fn create_collection(
         dense_vector,
         metadata_schema,
         sparse_vector,
+        distance_metric,
+        quantization,
     }: CreateCollectionDto,
 ) -> Result<Collection, CollectionsError> {
+    let distance_metric = parse_distance_metric(distance_metric.as_deref())?;
+    let quantization = parse_quantization(quantization, dense_vector.dimension, &distance_metric)?;
     let env = &ctx.ain_env.persist;
     let collections_db = &ctx.ain_env.collections_map.lmdb_collections_db;
 
@@ -33,22 +61,99 @@ pub(crate) async fn create_collection(
         sparse_vector,
         metadata_schema,
         config,
+        distance_metric,
+        quantization,
+        &ctx.config.collections_path,
     )
     .map_err(|e| CollectionsError::WaCustomError(e))?;
 
+    // persisting collection after creation, this fails if another
+    // collection's name hashes to the same key
+    let created = collection
+        .persist_new(env, collections_db.clone())
+        .map_err(|e| CollectionsError::WaCustomError(e))?;
+    if !created {
+        return Err(CollectionsError::AlreadyExists(collection.name));
+    }
+
     // adding the created collection into the in-memory map
     ctx.ain_env
         .collections_map
         .insert_collection(Arc::new(collection.clone()))
         .map_err(|e| CollectionsError::WaCustomError(e))?;
 
-    // persisting collection after creation
-    let _ = collection
-        .persist(env, collections_db.clone())
-        .map_err(|e| CollectionsError::WaCustomError(e));
     Ok(collection)
 }
 
+/// parses the distance metric named in a create request, cosine if none is
+fn parse_distance_metric(name: Option<&str>) -> Result<DistanceMetric, CollectionsError> {
+    name.map_or(Ok(DistanceMetric::default()), |name| {
+        name.parse().map_err(CollectionsError::InvalidParams)
+    })
+}
+
+/// parses the quantization named in a create request, scalar if none is, and
+/// checks that product quantization can split `dimension` into its subspaces
+/// and that `distance_metric` can compare binary quantized vectors
+fn parse_quantization(
+    dto: Option<QuantizationOptionsDto>,
+    dimension: usize,
+    distance_metric: &DistanceMetric,
+) -> Result<QuantizationOptions, CollectionsError> {
+    let Some(dto) = dto else {
+        return Ok(QuantizationOptions::default());
+    };
+    match dto.kind.as_str() {
+        "scalar" => Ok(QuantizationOptions::Scalar),
+        "binary" if !distance_metric.supports_storage(StorageType::SubByte(1)) => {
+            Err(CollectionsError::InvalidParams(format!(
+                "binary quantization doesn't support the {:?} distance metric",
+                distance_metric
+            )))
+        }
+        "binary" => Ok(QuantizationOptions::Binary),
+        "product" => {
+            let subspace_count = dto.subspace_count.ok_or_else(|| {
+                CollectionsError::InvalidParams(
+                    "product quantization requires `subspace_count`".to_string(),
+                )
+            })?;
+            if subspace_count == 0 || dimension % subspace_count as usize != 0 {
+                return Err(CollectionsError::InvalidParams(format!(
+                    "`subspace_count` {} doesn't divide the dimension {}",
+                    subspace_count, dimension
+                )));
+            }
+            let centroid_count = dto.centroid_count.unwrap_or(DEFAULT_PQ_CENTROID_COUNT);
+            // codes are stored as bytes
+            if !(1..=256).contains(&centroid_count) {
+                return Err(CollectionsError::InvalidParams(format!(
+                    "`centroid_count` must be between 1 and 256, got {}",
+                    centroid_count
+                )));
+            }
+            let training_sample_size = dto
+                .training_sample_size
+                .unwrap_or(DEFAULT_PQ_TRAINING_SAMPLE_SIZE);
+            if training_sample_size < centroid_count as usize {
+                return Err(CollectionsError::InvalidParams(format!(
+                    "`training_sample_size` {} is smaller than `centroid_count` {}",
+                    training_sample_size, centroid_count
+                )));
+            }
+            Ok(QuantizationOptions::Product {
+                subspace_count,
+                centroid_count,
+                training_sample_size,
+            })
+        }
+        kind => Err(CollectionsError::InvalidParams(format!(
+            "unknown quantization `{}`, expected one of `scalar`, `product` or `binary`",
+            kind
+        ))),
+    }
+}
+
 /// creates a dense_index for a collection
 // pub(crate) async fn create_dense_index(
 //     ctx: Arc<AppContext>,
@@ -82,32 +187,41 @@ pub(crate) async fn create_inverted_index(
     result.map_err(|e| CollectionsError::FailedToCreateCollection(e.to_string()))
 }
 
-/// gets a list of collections
-/// TODO results should be filtered based on search params,
-/// if no params provided, it returns all collections
-pub(crate) async fn get_collections(
+/// gets a list of all collections persisted in lmdb, sorted by name
+pub(crate) async fn list_collections(
     ctx: Arc<AppContext>,
-    _get_collections_dto: GetCollectionsDto,
-) -> Result<Vec<GetCollectionsResponseDto>, CollectionsError> {
-    let collections = ctx
-        .ain_env
-        .collections_map
-        .iter_collections()
-        .map(|c| GetCollectionsResponseDto {
-            name: c.name.clone(),
-            description: c.description.clone(),
+) -> Result<Vec<ListCollectionsResponseDto>, CollectionsError> {
+    let env = &ctx.ain_env.persist;
+    let collections_db = ctx.ain_env.collections_map.lmdb_collections_db.clone();
+
+    let mut collections: Vec<_> = load_collections(env, collections_db)
+        .map_err(|e| CollectionsError::WaCustomError(WaCustomError::DatabaseError(e.to_string())))?
+        .into_iter()
+        .map(|c| ListCollectionsResponseDto {
+            name: c.name,
+            description: c.description,
+            config: c.config,
         })
         .collect();
+    collections.sort_by(|a, b| a.name.cmp(&b.name));
     Ok(collections)
 }
 
 /// gets a collection by its name
+///
+/// collections that aren't in memory yet (e.g. after a restart) are
+/// loaded from lmdb and registered along with their dense index
 pub(crate) async fn get_collection_by_name(
     ctx: Arc<AppContext>,
     name: &str,
 ) -> Result<Arc<Collection>, CollectionsError> {
-    let collection = match ctx.ain_env.collections_map.get_collection(name) {
-        Some(collection) => collection.clone(),
+    let collection = match ctx
+        .ain_env
+        .collections_map
+        .get_or_load_collection(name, &ctx.config)
+        .map_err(CollectionsError::WaCustomError)?
+    {
+        Some(collection) => collection,
         None => {
             // dense index not found, return an error response
             return Err(CollectionsError::NotFound);
@@ -122,8 +236,15 @@ pub(crate) async fn get_dense_index_by_name(
     name: &str,
 ) -> Result<Arc<DenseIndex>, CollectionsError> {
     // Try to get the dense_index from the environment
+    if let Some(index) = ctx.ain_env.collections_map.get(name) {
+        return Ok(index);
+    }
+
+    // loads the collection and its dense index if they aren't in memory yet
+    get_collection_by_name(ctx.clone(), name).await?;
+
     let dense_index = match ctx.ain_env.collections_map.get(name) {
-        Some(index) => index.clone(),
+        Some(index) => index,
         None => {
             // dense index not found, return an error response
             return Err(CollectionsError::NotFound);
@@ -132,6 +253,224 @@ pub(crate) async fn get_dense_index_by_name(
     Ok(dense_index)
 }
 
+/// gets the sparse index of a collection by name, `CollectionsError::NotFound`
+/// if the collection doesn't have sparse vectors enabled
+pub(crate) async fn get_sparse_index_by_name(
+    ctx: Arc<AppContext>,
+    name: &str,
+) -> Result<Arc<InvertedIndexSparseAnnNewDS>, CollectionsError> {
+    let collection = get_collection_by_name(ctx.clone(), name).await?;
+    if !collection.sparse_vector.enabled {
+        return Err(CollectionsError::NotFound);
+    }
+    ctx.ain_env
+        .collections_map
+        .get_or_create_sparse_index(&collection, &ctx.config.collections_path)
+        .map_err(CollectionsError::WaCustomError)
+}
+
+/// rebuilds the dense index of a collection and persists its new root
+pub(crate) async fn reindex_collection_by_name(
+    ctx: Arc<AppContext>,
+    name: &str,
+) -> Result<(), CollectionsError> {
+    let dense_index = get_dense_index_by_name(ctx.clone(), name).await?;
+    check_no_open_transaction(&dense_index.current_open_transaction)?;
+
+    let config = ctx.config.clone();
+    let index = dense_index.clone();
+    web::block(move || reindex(&config, index))
+        .await
+        .unwrap()
+        .map_err(CollectionsError::WaCustomError)?;
+
+    ctx.ain_env
+        .collections_map
+        .insert(name, dense_index)
+        .map_err(CollectionsError::WaCustomError)
+}
+
+/// indexes the unindexed embeddings of a collection, returning the counts of
+/// indexed and unindexed embeddings afterwards
+pub(crate) async fn index_collection_by_name(
+    ctx: Arc<AppContext>,
+    name: &str,
+    batch_size: usize,
+) -> Result<(u32, u32), CollectionsError> {
+    let dense_index = get_dense_index_by_name(ctx.clone(), name).await?;
+    check_no_open_transaction(&dense_index.current_open_transaction)?;
+
+    let config = ctx.config.clone();
+    let index = dense_index.clone();
+    web::block(move || index_pending_embeddings(&config, &index, batch_size))
+        .await
+        .unwrap()
+        .map_err(|e| match e {
+            // only raised while another indexing run holds the flag
+            WaCustomError::LockError(_) => CollectionsError::IndexingInProgress,
+            e => CollectionsError::WaCustomError(e),
+        })?;
+
+    get_embedding_counts(&dense_index).map_err(CollectionsError::WaCustomError)
+}
+
+/// loads the top `levels` HNSW levels of a collection's dense index into its
+/// cache, returning the number of nodes loaded
+pub(crate) async fn warm_cache_by_name(
+    ctx: Arc<AppContext>,
+    name: &str,
+    levels: Option<u8>,
+) -> Result<usize, CollectionsError> {
+    let dense_index = get_dense_index_by_name(ctx, name).await?;
+    web::block(move || warm_cache(&dense_index, levels))
+        .await
+        .unwrap()
+        .map_err(CollectionsError::WaCustomError)
+}
+
+/// rewrites the prop file of a collection's dense index without the props no
+/// node points at, returning the number of bytes reclaimed
+pub(crate) async fn compact_prop_file_by_name(
+    ctx: Arc<AppContext>,
+    name: &str,
+) -> Result<u64, CollectionsError> {
+    let collection = get_collection_by_name(ctx.clone(), name).await?;
+    let dense_index = get_dense_index_by_name(ctx.clone(), name).await?;
+    check_no_open_transaction(&dense_index.current_open_transaction)?;
+
+    let prop_file_path = prop_file_path(&collection.get_path(&ctx.config.collections_path));
+    web::block(move || compact_prop_file(&dense_index, &prop_file_path))
+        .await
+        .unwrap()
+        .map_err(|e| match e {
+            // only raised while an indexing run or a transaction holds the
+            // index
+            WaCustomError::LockError(_) => CollectionsError::IndexingInProgress,
+            e => CollectionsError::WaCustomError(e),
+        })
+}
+
+/// retrains a collection's product quantizer on at most `sample_size` of its
+/// raw vectors and persists the centroids, returning the number of vectors
+/// sampled and how training went
+pub(crate) async fn train_quantizer_by_name(
+    ctx: Arc<AppContext>,
+    name: &str,
+    sample_size: usize,
+) -> Result<(usize, TrainingStats), CollectionsError> {
+    let dense_index = get_dense_index_by_name(ctx.clone(), name).await?;
+    if !matches!(
+        dense_index.quantization_metric.clone().get(),
+        QuantizationMetric::Product(_)
+    ) {
+        return Err(CollectionsError::InvalidParams(
+            "the collection's quantizer doesn't need training".to_string(),
+        ));
+    }
+    check_no_open_transaction(&dense_index.current_open_transaction)?;
+
+    let index = dense_index.clone();
+    let trained = web::block(move || retrain_quantizer(&index, sample_size))
+        .await
+        .unwrap()
+        .map_err(CollectionsError::WaCustomError)?;
+    ctx.ain_env
+        .collections_map
+        .persist_dense_index(dense_index)
+        .map_err(CollectionsError::WaCustomError)?;
+    Ok(trained)
+}
+
+/// most queries a single recall evaluation runs, each one is also searched
+/// exactly, which scans the whole collection
+const MAX_EVALUATE_RECALL_QUERIES: usize = 100;
+
+/// largest `k` a recall evaluation takes, the exact search keeps the best `k`
+/// of every query in memory
+const MAX_EVALUATE_RECALL_K: usize = 1000;
+
+/// largest `ef_search` override a recall evaluation takes
+const MAX_EVALUATE_RECALL_EF_SEARCH: u32 = 10_000;
+
+/// checks that a recall evaluation stays within the limits above
+fn check_evaluate_recall_params(
+    queries: &[Vec<f32>],
+    k: usize,
+    ef_search: Option<u32>,
+) -> Result<(), CollectionsError> {
+    if queries.is_empty() || queries.len() > MAX_EVALUATE_RECALL_QUERIES {
+        return Err(CollectionsError::InvalidParams(format!(
+            "between 1 and {} queries are required",
+            MAX_EVALUATE_RECALL_QUERIES
+        )));
+    }
+    if k == 0 || k > MAX_EVALUATE_RECALL_K {
+        return Err(CollectionsError::InvalidParams(format!(
+            "k must be between 1 and {}",
+            MAX_EVALUATE_RECALL_K
+        )));
+    }
+    if ef_search.is_some_and(|ef| ef == 0 || ef > MAX_EVALUATE_RECALL_EF_SEARCH) {
+        return Err(CollectionsError::InvalidParams(format!(
+            "ef_search must be between 1 and {}",
+            MAX_EVALUATE_RECALL_EF_SEARCH
+        )));
+    }
+    Ok(())
+}
+
+/// runs `queries` through both the graph and the exact search of a
+/// collection's dense index, returning the recall@`k` of the graph search and
+/// its mean latency
+pub(crate) async fn evaluate_recall_by_name(
+    ctx: Arc<AppContext>,
+    name: &str,
+    queries: Vec<Vec<f32>>,
+    k: usize,
+    ef_search: Option<u32>,
+) -> Result<(f32, Duration), CollectionsError> {
+    check_evaluate_recall_params(&queries, k, ef_search)?;
+    let dense_index = get_dense_index_by_name(ctx.clone(), name).await?;
+    if let Some(query) = queries.iter().find(|query| query.len() != dense_index.dim) {
+        return Err(CollectionsError::InvalidParams(format!(
+            "query has {} dimensions, but the collection expects {}",
+            query.len(),
+            dense_index.dim
+        )));
+    }
+
+    web::block(move || evaluate_recall(&ctx.config, dense_index, &queries, k, ef_search))
+        .await
+        .unwrap()
+        .map_err(CollectionsError::WaCustomError)
+}
+
+/// gets the replication log entries of a collection committed after `from_version`
+pub(crate) async fn get_oplog_by_name(
+    ctx: Arc<AppContext>,
+    name: &str,
+    from_version: u32,
+) -> Result<Vec<OpLogEntry>, CollectionsError> {
+    let collection = get_collection_by_name(ctx.clone(), name).await?;
+    read_oplog_since(
+        &oplog_path(&collection.get_path(&ctx.config.collections_path)),
+        from_version,
+    )
+    .map_err(CollectionsError::WaCustomError)
+}
+
+/// fails with `CollectionsError::OngoingTransaction` if a transaction is open
+fn check_no_open_transaction(
+    current_open_transaction: &AtomicPtr<DenseIndexTransaction>,
+) -> Result<(), CollectionsError> {
+    if !current_open_transaction.load(Ordering::SeqCst).is_null() {
+        return Err(CollectionsError::OngoingTransaction);
+    }
+    Ok(())
+}
+
+/// deletes a collection along with its dense index and the files in the
+/// collection's directory (`.index`, `.vec_raw`, `prop.data`, ...)
 pub(crate) async fn delete_collection_by_name(
     ctx: Arc<AppContext>,
     name: &str,
@@ -141,11 +480,23 @@ pub(crate) async fn delete_collection_by_name(
 
     let collection = get_collection_by_name(ctx.clone(), name).await?;
 
+    if let Some(dense_index) = ctx.ain_env.collections_map.get(name) {
+        check_no_open_transaction(&dense_index.current_open_transaction)?;
+    }
+
     // deleting collection from disk
     collection
         .delete(env, collections_db.clone())
         .map_err(|e| CollectionsError::WaCustomError(e))?;
 
+    // deleting the dense index from lmdb and the in-memory map
+    ctx.ain_env
+        .collections_map
+        .remove(name)
+        .map_err(CollectionsError::WaCustomError)?;
+
+    ctx.ain_env.collections_map.remove_sparse_index(name);
+
     // deleting collection from in-memory map
     let collection = ctx
         .ain_env
@@ -153,6 +504,17 @@ pub(crate) async fn delete_collection_by_name(
         .remove_collection(name)
         .map_err(|e| CollectionsError::WaCustomError(e))?;
 
+    // deleting the index and raw vector files
+    match fs::remove_dir_all(collection.get_path(&ctx.config.collections_path)) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => {
+            return Err(CollectionsError::WaCustomError(WaCustomError::FsError(
+                e.to_string(),
+            )))
+        }
+    }
+
     Ok(collection)
 }
 
@@ -176,3 +538,156 @@ pub(crate) async fn delete_dense_index_by_name(
         }
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{
+        check_evaluate_recall_params, check_no_open_transaction, create_collection,
+        parse_distance_metric, parse_quantization, CollectionsError, CreateCollectionDto,
+        DenseIndexTransaction, DistanceMetric, QuantizationOptions, QuantizationOptionsDto,
+        MAX_EVALUATE_RECALL_EF_SEARCH, MAX_EVALUATE_RECALL_K, MAX_EVALUATE_RECALL_QUERIES,
+    };
+    use crate::app_context::tests::test_app_context;
+    use actix_web::{http::StatusCode, ResponseError};
+    use std::{
+        ptr::{self, NonNull},
+        sync::atomic::AtomicPtr,
+    };
+    use tempfile::tempdir;
+
+    /// The body of a request creating a collection of 4 dimensional dense
+    /// vectors.
+    pub(crate) fn create_collection_dto(
+        name: &str,
+        distance_metric: &str,
+        quantization: &str,
+    ) -> CreateCollectionDto {
+        serde_json::from_value(serde_json::json!({
+            "name": name,
+            "description": null,
+            "dense_vector": { "enabled": true, "auto_create_index": false, "dimension": 4 },
+            "sparse_vector": { "enabled": false, "auto_create_index": false },
+            "metadata_schema": null,
+            "config": { "max_vectors": null, "replication_factor": null },
+            "distance_metric": distance_metric,
+            "quantization": { "type": quantization },
+        }))
+        .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn test_binary_collection_needs_a_metric_for_bits() {
+        let dir = tempdir().unwrap();
+        let ctx = test_app_context(dir.as_ref());
+
+        let dto = create_collection_dto("binary_euclidean", "euclidean", "binary");
+        let err = create_collection(ctx.clone(), dto).await.unwrap_err();
+        assert!(matches!(err, CollectionsError::InvalidParams(_)));
+        assert_eq!(err.status_code(), StatusCode::BAD_REQUEST);
+        assert!(ctx
+            .ain_env
+            .collections_map
+            .get_collection("binary_euclidean")
+            .is_none());
+
+        for metric in ["cosine", "dot"] {
+            let name = format!("binary_{}", metric);
+            let collection =
+                create_collection(ctx.clone(), create_collection_dto(&name, metric, "binary"))
+                    .await
+                    .unwrap();
+            assert_eq!(collection.quantization, QuantizationOptions::Binary);
+        }
+    }
+
+    #[test]
+    fn test_delete_rejected_with_open_transaction() {
+        let no_transaction = AtomicPtr::<DenseIndexTransaction>::new(ptr::null_mut());
+        assert!(check_no_open_transaction(&no_transaction).is_ok());
+
+        // only the pointer's nullness is checked, it is never dereferenced
+        let open_transaction =
+            AtomicPtr::new(NonNull::<DenseIndexTransaction>::dangling().as_ptr());
+        assert!(matches!(
+            check_no_open_transaction(&open_transaction),
+            Err(CollectionsError::OngoingTransaction)
+        ));
+    }
+
+    #[test]
+    fn test_evaluate_recall_limits() {
+        let query = vec![0.1, 0.2, 0.3, 0.4];
+        let at_cap = vec![query.clone(); MAX_EVALUATE_RECALL_QUERIES];
+        assert!(check_evaluate_recall_params(&at_cap, MAX_EVALUATE_RECALL_K, Some(64)).is_ok());
+
+        let over_cap = vec![query.clone(); MAX_EVALUATE_RECALL_QUERIES + 1];
+        let one = vec![query];
+        for (queries, k, ef_search) in [
+            (&over_cap, 10, None),
+            (&Vec::new(), 10, None),
+            (&one, 0, None),
+            (&one, MAX_EVALUATE_RECALL_K + 1, None),
+            (&one, 10, Some(0)),
+            (&one, 10, Some(MAX_EVALUATE_RECALL_EF_SEARCH + 1)),
+        ] {
+            let err = check_evaluate_recall_params(queries, k, ef_search).unwrap_err();
+            assert!(matches!(err, CollectionsError::InvalidParams(_)));
+            assert_eq!(err.status_code(), StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[test]
+    fn test_parse_distance_metric() {
+        assert_eq!(parse_distance_metric(None).unwrap(), DistanceMetric::Cosine);
+        for (name, metric) in [
+            ("cosine", DistanceMetric::Cosine),
+            ("euclidean", DistanceMetric::Euclidean),
+            ("dot", DistanceMetric::DotProduct),
+        ] {
+            assert_eq!(parse_distance_metric(Some(name)).unwrap(), metric);
+        }
+
+        let err = parse_distance_metric(Some("manhattan")).unwrap_err();
+        assert!(matches!(err, CollectionsError::InvalidParams(_)));
+        assert_eq!(err.status_code(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_parse_quantization() {
+        let dto = |kind: &str, subspace_count| QuantizationOptionsDto {
+            kind: kind.to_string(),
+            subspace_count,
+            centroid_count: None,
+            training_sample_size: None,
+        };
+
+        assert_eq!(
+            parse_quantization(None, 8, &DistanceMetric::Cosine).unwrap(),
+            QuantizationOptions::Scalar
+        );
+        assert_eq!(
+            parse_quantization(Some(dto("binary", None)), 8, &DistanceMetric::Cosine).unwrap(),
+            QuantizationOptions::Binary
+        );
+        assert_eq!(
+            parse_quantization(Some(dto("product", Some(4))), 8, &DistanceMetric::Cosine).unwrap(),
+            QuantizationOptions::Product {
+                subspace_count: 4,
+                centroid_count: 256,
+                training_sample_size: 10_000,
+            }
+        );
+
+        for metric in [DistanceMetric::Euclidean, DistanceMetric::Hamming] {
+            let err = parse_quantization(Some(dto("binary", None)), 8, &metric).unwrap_err();
+            assert_eq!(err.status_code(), StatusCode::BAD_REQUEST);
+        }
+
+        for (kind, subspace_count) in [("lattice", None), ("product", None), ("product", Some(3))] {
+            let err =
+                parse_quantization(Some(dto(kind, subspace_count)), 8, &DistanceMetric::Cosine)
+                    .unwrap_err();
+            assert_eq!(err.status_code(), StatusCode::BAD_REQUEST);
+        }
+    }
+}
@@ -1,14 +1,20 @@
 use std::sync::Arc;
 
 use crate::{
+    api_service::calculate_statistics,
     app_context::AppContext,
-    models::{collection::Collection, types::DenseIndex},
+    models::{collection::Collection, oplog::OpLogEntry, types::DenseIndex, user::Statistics},
+    quantization::reservoir::MAX_TRAINING_SAMPLE_SIZE,
+    storage::inverted_index_sparse_ann_new_ds::InvertedIndexSparseAnnNewDS,
 };
 
 use super::{
     dtos::{
-        CreateCollectionDto, CreateCollectionDtoResponse, GetCollectionsDto,
-        GetCollectionsResponseDto,
+        CompactPropFileResponseDto, CreateCollectionDto, CreateCollectionDtoResponse,
+        EvaluateRecallDto, EvaluateRecallResponseDto, GetOpLogDto, GetQuantizationResponseDto,
+        GetVersionResponseDto, IndexCollectionDto, IndexCollectionResponseDto,
+        ListCollectionsResponseDto, TrainQuantizerDto, TrainQuantizerResponseDto, WarmCacheDto,
+        WarmCacheResponseDto,
     },
     error::CollectionsError,
     repo,
@@ -27,11 +33,11 @@ pub(crate) async fn create_collection(
     })
 }
 
-pub(crate) async fn get_collections(
+/// lists all persisted collections, an empty list if there are none
+pub(crate) async fn list_collections(
     ctx: Arc<AppContext>,
-    get_collections_dto: GetCollectionsDto,
-) -> Result<Vec<GetCollectionsResponseDto>, CollectionsError> {
-    let collections = repo::get_collections(ctx, get_collections_dto).await?;
+) -> Result<Vec<ListCollectionsResponseDto>, CollectionsError> {
+    let collections = repo::list_collections(ctx).await?;
     Ok(collections)
 }
 
@@ -57,6 +63,17 @@ pub(crate) async fn get_dense_index_by_id(
     Ok(index)
 }
 
+/// gets the sparse index by collection id
+///
+/// currently collection_id = collection.name
+pub(crate) async fn get_sparse_index_by_id(
+    ctx: Arc<AppContext>,
+    collection_id: &str,
+) -> Result<Arc<InvertedIndexSparseAnnNewDS>, CollectionsError> {
+    let index = repo::get_sparse_index_by_name(ctx, collection_id).await?;
+    Ok(index)
+}
+
 /// deletes a collection by its id
 ///
 /// currently collection_id = collection.name
@@ -67,3 +84,153 @@ pub(crate) async fn delete_collection_by_id(
     let collection = repo::delete_collection_by_name(ctx, collection_id).await?;
     Ok(collection)
 }
+
+/// gets the effective quantization parameters of a collection's dense index
+///
+/// currently collection_id = collection.name
+pub(crate) async fn get_quantization_by_id(
+    ctx: Arc<AppContext>,
+    collection_id: &str,
+) -> Result<GetQuantizationResponseDto, CollectionsError> {
+    let index = repo::get_dense_index_by_name(ctx, collection_id).await?;
+    Ok(GetQuantizationResponseDto::from_dense_index(&index))
+}
+
+/// gets the version a collection's dense index currently serves
+///
+/// currently collection_id = collection.name
+pub(crate) async fn get_version_by_id(
+    ctx: Arc<AppContext>,
+    collection_id: &str,
+) -> Result<GetVersionResponseDto, CollectionsError> {
+    let index = repo::get_dense_index_by_name(ctx, collection_id).await?;
+    GetVersionResponseDto::from_dense_index(&index).map_err(CollectionsError::WaCustomError)
+}
+
+/// rebuilds the HNSW graph of a collection's dense index from its raw vectors
+///
+/// currently collection_id = collection.name
+pub(crate) async fn reindex_collection_by_id(
+    ctx: Arc<AppContext>,
+    collection_id: &str,
+) -> Result<(), CollectionsError> {
+    repo::reindex_collection_by_name(ctx, collection_id).await
+}
+
+/// indexes the unindexed vectors of a collection in batches of `batch_size`
+///
+/// currently collection_id = collection.name
+pub(crate) async fn index_collection_by_id(
+    ctx: Arc<AppContext>,
+    collection_id: &str,
+    IndexCollectionDto { batch_size }: IndexCollectionDto,
+) -> Result<IndexCollectionResponseDto, CollectionsError> {
+    if batch_size == 0 {
+        return Err(CollectionsError::InvalidParams(
+            "batch_size must be greater than 0".to_string(),
+        ));
+    }
+    let (count_indexed, count_unindexed) =
+        repo::index_collection_by_name(ctx, collection_id, batch_size).await?;
+    Ok(IndexCollectionResponseDto {
+        count_indexed,
+        count_unindexed,
+    })
+}
+
+/// loads the top `levels` HNSW levels of a collection's dense index into its
+/// node cache
+///
+/// currently collection_id = collection.name
+pub(crate) async fn warm_cache_by_id(
+    ctx: Arc<AppContext>,
+    collection_id: &str,
+    WarmCacheDto { levels }: WarmCacheDto,
+) -> Result<WarmCacheResponseDto, CollectionsError> {
+    if levels == Some(0) {
+        return Err(CollectionsError::InvalidParams(
+            "levels must be greater than 0".to_string(),
+        ));
+    }
+    let nodes_loaded = repo::warm_cache_by_name(ctx, collection_id, levels).await?;
+    Ok(WarmCacheResponseDto { nodes_loaded })
+}
+
+/// drops the props no node of a collection's dense index points at from its
+/// prop file
+///
+/// currently collection_id = collection.name
+pub(crate) async fn compact_prop_file_by_id(
+    ctx: Arc<AppContext>,
+    collection_id: &str,
+) -> Result<CompactPropFileResponseDto, CollectionsError> {
+    let bytes_reclaimed = repo::compact_prop_file_by_name(ctx, collection_id).await?;
+    Ok(CompactPropFileResponseDto { bytes_reclaimed })
+}
+
+/// retrains the product quantizer of a collection's dense index on a sample
+/// of its raw vectors
+///
+/// currently collection_id = collection.name
+pub(crate) async fn train_quantizer_by_id(
+    ctx: Arc<AppContext>,
+    collection_id: &str,
+    TrainQuantizerDto { sample_size }: TrainQuantizerDto,
+) -> Result<TrainQuantizerResponseDto, CollectionsError> {
+    if sample_size == Some(0) {
+        return Err(CollectionsError::InvalidParams(
+            "sample_size must be greater than 0".to_string(),
+        ));
+    }
+    let sample_size = sample_size.unwrap_or(MAX_TRAINING_SAMPLE_SIZE);
+    let (sample_count, stats) =
+        repo::train_quantizer_by_name(ctx, collection_id, sample_size).await?;
+    Ok(TrainQuantizerResponseDto {
+        sample_count,
+        iterations: stats.iterations,
+        distortion: stats.distortion,
+    })
+}
+
+/// computes statistics over a collection's dense index
+///
+/// currently collection_id = collection.name
+pub(crate) async fn get_statistics_by_id(
+    ctx: Arc<AppContext>,
+    collection_id: &str,
+) -> Result<Statistics, CollectionsError> {
+    let index = repo::get_dense_index_by_name(ctx, collection_id).await?;
+    calculate_statistics(&index).map_err(CollectionsError::WaCustomError)
+}
+
+/// measures the recall@k of a collection's graph search against exact search
+///
+/// currently collection_id = collection.name
+pub(crate) async fn evaluate_recall_by_id(
+    ctx: Arc<AppContext>,
+    collection_id: &str,
+    EvaluateRecallDto {
+        queries,
+        k,
+        ef_search,
+    }: EvaluateRecallDto,
+) -> Result<EvaluateRecallResponseDto, CollectionsError> {
+    let (recall, mean_latency) =
+        repo::evaluate_recall_by_name(ctx, collection_id, queries, k, ef_search).await?;
+    Ok(EvaluateRecallResponseDto {
+        recall,
+        mean_latency_ms: mean_latency.as_secs_f64() * 1000.0,
+    })
+}
+
+/// gets the replication log entries of a collection committed after a version
+///
+/// currently collection_id = collection.name
+pub(crate) async fn get_oplog_by_id(
+    ctx: Arc<AppContext>,
+    collection_id: &str,
+    get_oplog_dto: GetOpLogDto,
+) -> Result<Vec<OpLogEntry>, CollectionsError> {
+    let entries = repo::get_oplog_by_name(ctx, collection_id, get_oplog_dto.from).await?;
+    Ok(entries)
+}
@@ -35,6 +35,7 @@ pub(crate) async fn fetch(
                     vector: Vector {
                         id: nvid,
                         values: vec![],
+                        metadata: None,
                     },
                 };
                 response_data
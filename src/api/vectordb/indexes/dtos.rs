@@ -2,7 +2,7 @@ use serde::{Deserialize, Serialize};
 
 use crate::{
     config_loader::Config,
-    models::types::{DistanceMetric, HNSWHyperParams},
+    models::types::{DistanceMetric, HNSWHyperParams, NeighborSelection},
     quantization::StorageType,
 };
 
@@ -42,6 +42,8 @@ pub struct HNSWHyperParamsDto {
     max_cache_size: Option<usize>, // Maximum number of elements in the cache
     level_0_neighbors_count: Option<usize>,
     neighbors_count: Option<usize>,
+    #[serde(default)]
+    neighbor_selection: Option<NeighborSelection>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -54,9 +56,25 @@ pub enum IndexParamsDto {
 pub(crate) struct CreateIndexDto {
     pub collection_name: String,
     pub name: String,
-    pub distance_metric_type: DistanceMetric,
+    /// must match the collection's distance metric if given
+    #[serde(default)]
+    pub distance_metric_type: Option<DistanceMetric>,
     pub quantization: QuantizationDto,
     pub index: IndexParamsDto,
+    /// seeds the random root vector, for reproducible indexes
+    #[serde(default)]
+    pub root_vector_seed: Option<u64>,
+    /// seeds the level each vector is inserted up to, for reproducible graphs
+    #[serde(default)]
+    pub level_seed: Option<u64>,
+    /// base of the level assignment distribution, a vector reaches level `n`
+    /// with probability `factor_levels^-n`, so higher values give flatter graphs
+    #[serde(default = "default_factor_levels")]
+    pub factor_levels: f64,
+}
+
+fn default_factor_levels() -> f64 {
+    10.0
 }
 
 impl HNSWHyperParamsDto {
@@ -87,6 +105,10 @@ impl HNSWHyperParamsDto {
             default.neighbors_count = neighbors_count;
         }
 
+        if let Some(neighbor_selection) = self.neighbor_selection {
+            default.neighbor_selection = neighbor_selection;
+        }
+
         default
     }
 }
@@ -11,6 +11,7 @@ pub enum IndexesError {
     FailedToGetAppEnv,
     CollectionNotFound,
     FailedToCreateIndex(String),
+    InvalidParams(String),
 }
 
 impl Display for IndexesError {
@@ -22,6 +23,7 @@ impl Display for IndexesError {
             Self::FailedToCreateIndex(msg) => {
                 write!(f, "Failed to create index due to {}", msg)
             }
+            Self::InvalidParams(msg) => write!(f, "Invalid params: {}", msg),
         }
     }
 }
@@ -39,6 +41,7 @@ impl ResponseError for IndexesError {
             Self::CollectionNotFound => StatusCode::BAD_REQUEST,
             Self::FailedToGetAppEnv => StatusCode::INTERNAL_SERVER_ERROR,
             Self::FailedToCreateIndex(_) => StatusCode::BAD_REQUEST,
+            Self::InvalidParams(_) => StatusCode::BAD_REQUEST,
         }
     }
 }
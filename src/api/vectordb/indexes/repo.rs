@@ -3,12 +3,15 @@ use std::sync::Arc;
 use crate::{
     api_service::init_dense_index_for_collection,
     app_context::AppContext,
-    models::types::{DistanceMetric, QuantizationMetric},
-    quantization::StorageType,
+    models::{
+        collection::QuantizationOptions,
+        types::{DistanceMetric, QuantizationMetric},
+    },
+    quantization::{product::ProductQuantization, StorageType},
 };
 
 use super::{
-    dtos::{IndexParamsDto, QuantizationDto},
+    dtos::{DataType, IndexParamsDto, QuantizationDto},
     error::IndexesError,
 };
 
@@ -16,32 +19,30 @@ pub(crate) async fn create_index(
     ctx: Arc<AppContext>,
     collection_name: String,
     _name: String,
-    distance_metric: DistanceMetric,
+    distance_metric: Option<DistanceMetric>,
     quantization: QuantizationDto,
     index_params: IndexParamsDto,
+    root_vector_seed: Option<u64>,
+    level_seed: Option<u64>,
+    factor_levels: f64,
 ) -> Result<(), IndexesError> {
     let collection = ctx
         .ain_env
         .collections_map
         .get_collection(&collection_name)
         .ok_or(IndexesError::CollectionNotFound)?;
+    // the index compares vectors the way the collection was created to
+    let distance_metric = match distance_metric {
+        Some(metric) if metric != collection.distance_metric => {
+            return Err(IndexesError::InvalidParams(format!(
+                "distance metric {:?} doesn't match the collection's {:?}",
+                metric, collection.distance_metric
+            )));
+        }
+        _ => collection.distance_metric.clone(),
+    };
     let (quantization_metric, storage_type, range, sample_threshold, is_configured) =
-        match quantization {
-            QuantizationDto::Auto { sample_threshold } => (
-                QuantizationMetric::Scalar,
-                StorageType::UnsignedByte,
-                None,
-                sample_threshold,
-                false,
-            ),
-            QuantizationDto::Scalar { data_type, range } => (
-                QuantizationMetric::Scalar,
-                data_type.into(),
-                Some((range.min, range.max)),
-                0,
-                true,
-            ),
-        };
+        index_quantization(&collection.quantization, quantization, &distance_metric)?;
     let IndexParamsDto::Hnsw(hnsw_params_dto) = index_params;
     let hnsw_params = hnsw_params_dto.into_params(&ctx.config);
     init_dense_index_for_collection(
@@ -54,9 +55,292 @@ pub(crate) async fn create_index(
         storage_type,
         sample_threshold,
         is_configured,
+        root_vector_seed,
+        level_seed,
+        factor_levels,
     )
     .await
     .map_err(|e| IndexesError::FailedToCreateIndex(e.to_string()))?;
 
     Ok(())
 }
+
+/// Picks the quantizer, storage type, values range, sample threshold and
+/// whether the index starts configured, from the quantization the collection
+/// was created with and the one requested for the index. The storage type has
+/// to be one `distance_metric` can compare.
+fn index_quantization(
+    options: &QuantizationOptions,
+    quantization: QuantizationDto,
+    distance_metric: &DistanceMetric,
+) -> Result<
+    (
+        QuantizationMetric,
+        StorageType,
+        Option<(f32, f32)>,
+        usize,
+        bool,
+    ),
+    IndexesError,
+> {
+    let picked = match (options, quantization) {
+        (
+            QuantizationOptions::Product {
+                subspace_count,
+                centroid_count,
+                training_sample_size,
+            },
+            QuantizationDto::Auto { .. },
+        ) => Ok((
+            QuantizationMetric::Product(ProductQuantization::new(*subspace_count, *centroid_count)),
+            StorageType::UnsignedByte,
+            None,
+            *training_sample_size,
+            false,
+        )),
+        (QuantizationOptions::Product { .. }, QuantizationDto::Scalar { .. }) => Err(
+            IndexesError::InvalidParams("the collection uses product quantization".to_string()),
+        ),
+        (QuantizationOptions::Binary, QuantizationDto::Scalar { data_type, .. })
+            if !matches!(data_type, DataType::Binary) =>
+        {
+            Err(IndexesError::InvalidParams(
+                "the collection uses binary quantization".to_string(),
+            ))
+        }
+        (options, QuantizationDto::Auto { sample_threshold }) => Ok((
+            QuantizationMetric::Scalar,
+            if *options == QuantizationOptions::Binary {
+                StorageType::SubByte(1)
+            } else {
+                StorageType::UnsignedByte
+            },
+            None,
+            sample_threshold,
+            false,
+        )),
+        (_, QuantizationDto::Scalar { data_type, range }) => Ok((
+            QuantizationMetric::Scalar,
+            data_type.into(),
+            Some((range.min, range.max)),
+            0,
+            true,
+        )),
+    }?;
+    if !distance_metric.supports_storage(picked.1) {
+        return Err(IndexesError::InvalidParams(format!(
+            "the {:?} distance metric can't compare vectors stored as {:?}",
+            distance_metric, picked.1
+        )));
+    }
+    Ok(picked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::index_quantization;
+    use crate::api::vectordb::collections::repo::create_collection;
+    use crate::api::vectordb::collections::repo::tests::create_collection_dto;
+    use crate::api::vectordb::indexes::dtos::CreateIndexDto;
+    use crate::api::vectordb::indexes::dtos::{DataType, QuantizationDto, ValuesRange};
+    use crate::api::vectordb::indexes::error::IndexesError;
+    use crate::api::vectordb::indexes::service::create_index;
+    use crate::api_service::run_upload;
+    use crate::app_context::tests::test_app_context;
+    use crate::models::buffered_io::BufferManagerFactory;
+    use crate::models::collection::QuantizationOptions;
+    use crate::models::file_persist::prop_file_path;
+    use crate::models::types::{
+        DistanceMetric, HNSWHyperParams, NeighborSelection, QuantizationMetric,
+    };
+    use crate::models::versioning::Hash;
+    use crate::quantization::StorageType;
+    use crate::storage::Storage;
+    use crate::vector_store::create_root_node;
+    use actix_web::{http::StatusCode, ResponseError};
+    use std::fs::{self, OpenOptions};
+    use std::sync::{Arc, RwLock};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_product_collection_index_carries_product_quantization() {
+        let options = QuantizationOptions::Product {
+            subspace_count: 2,
+            centroid_count: 16,
+            training_sample_size: 100,
+        };
+        let (quantization_metric, storage_type, _, sample_threshold, is_configured) =
+            index_quantization(
+                &options,
+                QuantizationDto::Auto {
+                    sample_threshold: 10,
+                },
+                &DistanceMetric::Cosine,
+            )
+            .unwrap();
+        let QuantizationMetric::Product(product) = &quantization_metric else {
+            panic!(
+                "expected product quantization, got {:?}",
+                quantization_metric
+            );
+        };
+        assert_eq!(product.subspace_count, 2);
+        assert_eq!(product.centroids.as_ref().unwrap().number_of_centroids, 16);
+        assert!(!product.is_trained());
+        // training waits for the collection's sample size
+        assert_eq!(sample_threshold, 100);
+        assert!(!is_configured);
+
+        // the untrained quantizer can still store the root vector
+        let dir = tempdir().unwrap();
+        let prop_file = Arc::new(RwLock::new(
+            OpenOptions::new()
+                .create(true)
+                .read(true)
+                .append(true)
+                .open(dir.as_ref().join("prop.data"))
+                .unwrap(),
+        ));
+        let index_manager = Arc::new(BufferManagerFactory::new(
+            dir.as_ref().into(),
+            |root, ver: &Hash| root.join(format!("{}.index", **ver)),
+            1.0,
+        ));
+        let hnsw_params = HNSWHyperParams {
+            num_layers: 1,
+            ef_construction: 64,
+            ef_search: 64,
+            max_cache_size: 1000,
+            level_0_neighbors_count: 8,
+            neighbors_count: 4,
+            neighbor_selection: NeighborSelection::Simple,
+        };
+        let root = create_root_node(
+            &quantization_metric,
+            storage_type,
+            4,
+            prop_file,
+            Hash::from(0),
+            index_manager,
+            (-1.0, 1.0),
+            &hnsw_params,
+            Some(7),
+        )
+        .unwrap();
+        let root = unsafe { &*root }.get_lazy_data().unwrap();
+        assert!(matches!(*root.prop.value, Storage::HalfPrecisionFP { .. }));
+
+        // product collections train their own quantizer
+        assert!(index_quantization(
+            &options,
+            QuantizationDto::Scalar {
+                data_type: DataType::U8,
+                range: ValuesRange {
+                    min: -1.0,
+                    max: 1.0
+                },
+            },
+            &DistanceMetric::Cosine,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_binary_collection_index_uses_single_bit_storage() {
+        let (quantization_metric, storage_type, ..) = index_quantization(
+            &QuantizationOptions::Binary,
+            QuantizationDto::Auto {
+                sample_threshold: 10,
+            },
+            &DistanceMetric::Cosine,
+        )
+        .unwrap();
+        assert!(matches!(quantization_metric, QuantizationMetric::Scalar));
+        assert!(matches!(storage_type, StorageType::SubByte(1)));
+
+        assert!(index_quantization(
+            &QuantizationOptions::Binary,
+            QuantizationDto::Scalar {
+                data_type: DataType::F16,
+                range: ValuesRange {
+                    min: -1.0,
+                    max: 1.0
+                },
+            },
+            &DistanceMetric::Cosine,
+        )
+        .is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_sub_byte_index_needs_a_metric_for_bits() {
+        let dir = tempdir().unwrap();
+        let ctx = test_app_context(dir.as_ref());
+        let dto = create_collection_dto("euclidean", "euclidean", "scalar");
+        create_collection(ctx.clone(), dto).await.unwrap();
+
+        for data_type in ["binary", "quaternay", "octal"] {
+            let dto: CreateIndexDto = serde_json::from_value(serde_json::json!({
+                "collection_name": "euclidean",
+                "name": "euclidean_index",
+                "quantization": {
+                    "type": "scalar",
+                    "properties": { "data_type": data_type, "range": { "min": -1.0, "max": 1.0 } },
+                },
+                "index": { "type": "hnsw", "properties": {} },
+            }))
+            .unwrap();
+            let err = create_index(dto, ctx.clone()).await.unwrap_err();
+            assert!(matches!(err, IndexesError::InvalidParams(_)));
+            assert_eq!(err.status_code(), StatusCode::BAD_REQUEST);
+        }
+        // no dense index was created
+        assert!(ctx.ain_env.collections_map.get("euclidean").is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_collections_get_their_own_prop_file() {
+        let dir = tempdir().unwrap();
+        let ctx = test_app_context(dir.as_ref());
+        for name in ["first", "second"] {
+            create_collection(ctx.clone(), create_collection_dto(name, "cosine", "scalar"))
+                .await
+                .unwrap();
+            let dto: CreateIndexDto = serde_json::from_value(serde_json::json!({
+                "collection_name": name,
+                "name": format!("{}_index", name),
+                "quantization": {
+                    "type": "scalar",
+                    "properties": { "data_type": "u8", "range": { "min": -1.0, "max": 1.0 } },
+                },
+                "index": { "type": "hnsw", "properties": {} },
+                // the same root vector in both, so their props take as many bytes
+                "root_vector_seed": 7,
+            }))
+            .unwrap();
+            create_index(dto, ctx.clone()).await.unwrap();
+        }
+
+        let prop_file_len = |name: &str| {
+            let collection = ctx.ain_env.collections_map.get_collection(name).unwrap();
+            let path = prop_file_path(&collection.get_path(&ctx.config.collections_path));
+            assert!(path.starts_with(&ctx.config.collections_path));
+            (path.clone(), fs::metadata(path).unwrap().len())
+        };
+        let (first_path, first_len) = prop_file_len("first");
+        let (second_path, second_len) = prop_file_len("second");
+        assert_ne!(first_path, second_path);
+        // each file starts out holding just its own root node's prop
+        assert!(first_len > 0);
+        assert_eq!(first_len, second_len);
+
+        // props of vectors indexed into one collection don't end up in the other's file
+        let first = ctx.ain_env.collections_map.get("first").unwrap();
+        let vecs = (0..ctx.config.upload_threshold as u64)
+            .map(|id| (id, vec![id as f32 / 100.0, 0.1, 0.2, 0.3], None))
+            .collect();
+        run_upload(ctx.clone(), first, vecs, None).unwrap();
+        assert!(prop_file_len("first").1 > first_len);
+        assert_eq!(prop_file_len("second").1, second_len);
+    }
+}
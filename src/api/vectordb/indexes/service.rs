@@ -8,6 +8,11 @@ pub(crate) async fn create_index(
     create_index_dto: CreateIndexDto,
     ctx: Arc<AppContext>,
 ) -> Result<(), IndexesError> {
+    if create_index_dto.factor_levels.is_nan() || create_index_dto.factor_levels <= 1.0 {
+        return Err(IndexesError::InvalidParams(
+            "factor_levels must be greater than 1.0".to_string(),
+        ));
+    }
     repo::create_index(
         ctx,
         create_index_dto.collection_name,
@@ -15,6 +20,9 @@ pub(crate) async fn create_index(
         create_index_dto.distance_metric_type,
         create_index_dto.quantization,
         create_index_dto.index,
+        create_index_dto.root_vector_seed,
+        create_index_dto.level_seed,
+        create_index_dto.factor_levels,
     )
     .await
 }
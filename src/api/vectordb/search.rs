@@ -6,6 +6,19 @@ use crate::{
     models::rpc::{BatchVectorANN, RPCResponseBody, VectorANN},
 };
 
+/// falls back to the collection's defaults for the params a query didn't set
+pub(crate) fn query_params(
+    ctx: &AppContext,
+    collection_name: &str,
+    k: Option<usize>,
+    ef_search: Option<u32>,
+) -> (Option<usize>, Option<u32>) {
+    match ctx.ain_env.collections_map.get_collection(collection_name) {
+        Some(collection) => collection.config.query_params(k, ef_search),
+        None => (k, ef_search),
+    }
+}
+
 // Route: `/vectordb/search`
 pub(crate) async fn search(
     web::Json(body): web::Json<VectorANN>,
@@ -20,11 +33,15 @@ pub(crate) async fn search(
         }
     };
 
+    let (k, ef_search) = query_params(&ctx, &body.vector_db_name, body.nn_count, body.ef_search);
     let result = match ann_vector_query(
         ctx.into_inner(),
         vec_store.clone(),
         body.vector,
-        body.nn_count,
+        k,
+        ef_search,
+        body.filter,
+        false,
     )
     .await
     {
@@ -52,11 +69,14 @@ pub(crate) async fn batch_search(
         }
     };
 
+    let (k, ef_search) = query_params(&ctx, &body.vector_db_name, body.nn_count, body.ef_search);
     let results = match batch_ann_vector_query(
         ctx.into_inner(),
         vec_store.clone(),
         body.vectors,
-        body.nn_count,
+        k,
+        ef_search,
+        body.filter,
     )
     .await
     {
@@ -5,7 +5,7 @@ use crate::{
     app_context::AppContext,
 };
 
-use super::{error::TransactionError, service};
+use super::{dtos::CommitTransactionQueryDto, error::TransactionError, service};
 
 pub(crate) async fn create_transaction(
     collection_id: web::Path<String>,
@@ -18,11 +18,17 @@ pub(crate) async fn create_transaction(
 
 pub(crate) async fn commit_transaction(
     params: web::Path<(String, u32)>,
+    web::Query(CommitTransactionQueryDto { sync }): web::Query<CommitTransactionQueryDto>,
     ctx: web::Data<AppContext>,
 ) -> Result<HttpResponse, TransactionError> {
     let (collection_id, transaction_id) = params.into_inner();
-    let _ = service::commit_transaction(ctx.into_inner(), &collection_id, transaction_id.into())
-        .await?;
+    let _ = service::commit_transaction(
+        ctx.into_inner(),
+        &collection_id,
+        transaction_id.into(),
+        sync,
+    )
+    .await?;
     Ok(HttpResponse::NoContent().finish())
 }
 
@@ -1,8 +1,15 @@
 use chrono::{DateTime, Utc};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 #[derive(Serialize)]
 pub(crate) struct CreateTransactionResponseDto {
     pub transaction_id: String,
     pub created_at: DateTime<Utc>,
 }
+
+#[derive(Deserialize)]
+pub(crate) struct CommitTransactionQueryDto {
+    /// when set, the commit only returns once its writes are fsynced
+    #[serde(default)]
+    pub sync: bool,
+}
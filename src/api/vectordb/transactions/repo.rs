@@ -6,13 +6,16 @@ use self::vectors::dtos::UpsertDto;
 
 use super::{dtos::CreateTransactionResponseDto, error::TransactionError};
 use crate::models::meta_persist::update_current_version;
+use crate::models::oplog::{append_to_oplog, oplog_path, OpLogEntry, OpLogOp};
 use crate::models::types::DenseIndexTransaction;
 use crate::models::versioning::Hash;
+use crate::vector_store::branch_from_rollback;
 use crate::{
     api::vectordb::vectors::{
         self,
         dtos::{CreateVectorDto, CreateVectorResponseDto},
     },
+    api_service::run_blocking,
     app_context::AppContext,
 };
 use chrono::Utc;
@@ -36,8 +39,16 @@ pub(crate) async fn create_transaction(
         return Err(TransactionError::OnGoingTransaction);
     }
 
-    let transaction = DenseIndexTransaction::new(vec_store.clone())
+    // a transaction on a rolled back collection starts off a new branch
+    let config = ctx.config.clone();
+    let index = vec_store.clone();
+    run_blocking(move || branch_from_rollback(&config, &index))
+        .await
         .map_err(|err| TransactionError::FailedToCreateTransaction(err.to_string()))?;
+
+    let transaction =
+        DenseIndexTransaction::new(vec_store.clone(), ctx.config.commit_flush_batch_size)
+            .map_err(|err| TransactionError::FailedToCreateTransaction(err.to_string()))?;
     let transaction_id = transaction.id;
 
     vec_store
@@ -50,11 +61,13 @@ pub(crate) async fn create_transaction(
     })
 }
 
-// commits a transaction for a specific collection (vector store)
+// commits a transaction for a specific collection (vector store), with `sync`
+// set it only returns once the committed data is fsynced
 pub(crate) async fn commit_transaction(
     ctx: Arc<AppContext>,
     collection_id: &str,
     transaction_id: Hash,
+    sync: bool,
 ) -> Result<(), TransactionError> {
     let vec_store = ctx
         .ain_env
@@ -77,19 +90,52 @@ pub(crate) async fn commit_transaction(
         return Err(TransactionError::NotFound);
     }
 
-    current_open_transaction
+    let version_number = current_open_transaction.version_number as u32;
+    let raw_embs = current_open_transaction
         .pre_commit()
         .map_err(|err| TransactionError::FailedToCommitTransaction(err.to_string()))?;
 
+    vec_store
+        .current_open_transaction
+        .store(ptr::null_mut(), Ordering::SeqCst);
+
+    // record the committed writes for standby replicas before the version
+    // they belong to is committed, under the version lock so the log follows
+    // the version order
+    let collection = ctx
+        .ain_env
+        .collections_map
+        .get_collection(collection_id)
+        .ok_or(TransactionError::CollectionNotFound)?;
+    let version_guard = vec_store.version_lock.lock().map_err(|_| {
+        TransactionError::FailedToCommitTransaction("Failed to lock the version".to_string())
+    })?;
+    append_to_oplog(
+        &oplog_path(&collection.get_path(&ctx.config.collections_path)),
+        raw_embs.into_iter().map(|raw_emb| OpLogEntry {
+            version_number,
+            version_hash: current_transaction_id,
+            op: OpLogOp::Upsert {
+                id: raw_emb.hash_vec.0,
+                values: (*raw_emb.raw_vec).clone(),
+            },
+        }),
+    )
+    .map_err(|err| TransactionError::FailedToCommitTransaction(err.to_string()))?;
+
     vec_store
         .current_version
         .clone()
         .update(current_transaction_id);
-    vec_store
-        .current_open_transaction
-        .store(ptr::null_mut(), Ordering::SeqCst);
     update_current_version(&vec_store.lmdb, current_transaction_id)
         .map_err(|err| TransactionError::FailedToCommitTransaction(err.to_string()))?;
+    drop(version_guard);
+
+    if sync {
+        vec_store
+            .sync()
+            .map_err(|err| TransactionError::FailedToCommitTransaction(err.to_string()))?;
+    }
 
     Ok(())
 }
@@ -160,7 +206,7 @@ pub(crate) async fn abort_transaction(
     }
 
     current_open_transaction
-        .pre_commit()
+        .abort()
         .map_err(|err| TransactionError::FailedToCommitTransaction(err.to_string()))?;
 
     vec_store
@@ -19,8 +19,9 @@ pub(crate) async fn commit_transaction(
     ctx: Arc<AppContext>,
     collection_id: &str,
     transaction_id: Hash,
+    sync: bool,
 ) -> Result<(), TransactionError> {
-    repo::commit_transaction(ctx, collection_id, transaction_id).await
+    repo::commit_transaction(ctx, collection_id, transaction_id, sync).await
 }
 
 pub(crate) async fn create_vector_in_transaction(
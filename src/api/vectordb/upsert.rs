@@ -3,8 +3,9 @@ use std::sync::atomic::Ordering;
 use actix_web::{web, HttpResponse};
 
 use crate::{
-    api_service::run_upload,
+    api_service::{run_blocking, run_upload},
     app_context::AppContext,
+    models::common::WaCustomError,
     models::rpc::{RPCResponseBody, UpsertVectors},
 };
 
@@ -32,22 +33,42 @@ pub(crate) async fn upsert(
             .body("Cannot upsert while there's an on-going transaction");
     }
 
+    if let Some((index, vec)) = body
+        .vectors
+        .iter()
+        .enumerate()
+        .find(|(_, vec)| vec.values.len() != collection.dim)
+    {
+        return HttpResponse::BadRequest().body(format!(
+            "vector at index {} (id {}) has {} dimensions, but the collection expects {}",
+            index,
+            vec.id,
+            vec.values.len(),
+            collection.dim
+        ));
+    }
+
     // Call run_upload with the extracted parameters
-    let res = web::block(move || {
+    let res = run_blocking(move || {
         run_upload(
             ctx.into_inner(),
             collection,
             body.vectors
                 .into_iter()
-                .map(|vec| (vec.id, vec.values))
+                .map(|vec| (vec.id, vec.values, vec.metadata))
                 .collect(),
+            None,
         )
     })
-    .await
-    .unwrap();
+    .await;
 
     match res {
         Ok(_) => HttpResponse::Ok().json(RPCResponseBody::RespUpsertVectors { insert_stats: None }),
+        Err(
+            err @ (WaCustomError::InvalidParams
+            | WaCustomError::DimensionMismatch(..)
+            | WaCustomError::DuplicateVectorId(..)),
+        ) => HttpResponse::BadRequest().body(format!("error upserting vectors: {}", err)),
         Err(err) => {
             HttpResponse::InternalServerError().body(format!("error upserting vectors: {}", err))
         }
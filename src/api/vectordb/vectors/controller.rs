@@ -1,7 +1,10 @@
 use actix_web::{web, HttpResponse, Result};
 
 use super::{
-    dtos::{CreateVectorDto, FindSimilarVectorsDto, UpdateVectorDto},
+    dtos::{
+        CreateSparseVectorDto, CreateSparseVectorsBatchDto, CreateVectorDto, FindSimilarVectorsDto,
+        FindSimilarVectorsQuery, ListVectorsQuery, UpdateVectorDto,
+    },
     service,
 };
 use crate::{app_context::AppContext, models::types::VectorId};
@@ -16,16 +19,76 @@ pub(crate) async fn create_vector(
     Ok(HttpResponse::Ok().json(vector))
 }
 
+pub(crate) async fn create_sparse_vector(
+    collection_id: web::Path<String>,
+    web::Json(create_sparse_vector_dto): web::Json<CreateSparseVectorDto>,
+    ctx: web::Data<AppContext>,
+) -> Result<HttpResponse> {
+    let vector =
+        service::create_sparse_vector(ctx.into_inner(), &collection_id, create_sparse_vector_dto)
+            .await?;
+    Ok(HttpResponse::Ok().json(vector))
+}
+
+pub(crate) async fn create_sparse_vectors_batch(
+    collection_id: web::Path<String>,
+    web::Json(batch_dto): web::Json<CreateSparseVectorsBatchDto>,
+    ctx: web::Data<AppContext>,
+) -> Result<HttpResponse> {
+    let results =
+        service::create_sparse_vectors_batch(ctx.into_inner(), &collection_id, batch_dto).await?;
+    Ok(HttpResponse::Ok().json(results))
+}
+
 pub(crate) async fn get_vector_by_id(
     path: web::Path<(String, u64)>,
     ctx: web::Data<AppContext>,
 ) -> Result<HttpResponse> {
     let (collection_id, vector_id) = path.into_inner();
-    let vector =
-        service::get_vector_by_id(ctx.into_inner(), &collection_id, VectorId(vector_id)).await?;
+    let ctx = ctx.into_inner();
+    if service::is_sparse_collection(ctx.clone(), &collection_id).await? {
+        let vector =
+            service::get_sparse_vector_by_id(ctx, &collection_id, VectorId(vector_id)).await?;
+        return Ok(HttpResponse::Ok().json(vector));
+    }
+    let vector = service::get_vector_by_id(ctx, &collection_id, VectorId(vector_id)).await?;
     Ok(HttpResponse::Ok().json(vector))
 }
 
+pub(crate) async fn get_vector_neighbors(
+    path: web::Path<(String, u64)>,
+    ctx: web::Data<AppContext>,
+) -> Result<HttpResponse> {
+    let (collection_id, vector_id) = path.into_inner();
+    let neighbors =
+        service::get_vector_neighbors(ctx.into_inner(), &collection_id, VectorId(vector_id))
+            .await?;
+    Ok(HttpResponse::Ok().json(neighbors))
+}
+
+pub(crate) async fn vector_exists(
+    path: web::Path<(String, u64)>,
+    ctx: web::Data<AppContext>,
+) -> Result<HttpResponse> {
+    let (collection_id, vector_id) = path.into_inner();
+    let exists =
+        service::vector_exists(ctx.into_inner(), &collection_id, VectorId(vector_id)).await?;
+    if exists {
+        Ok(HttpResponse::Ok().finish())
+    } else {
+        Ok(HttpResponse::NotFound().finish())
+    }
+}
+
+pub(crate) async fn list_vectors(
+    collection_id: web::Path<String>,
+    web::Query(query): web::Query<ListVectorsQuery>,
+    ctx: web::Data<AppContext>,
+) -> Result<HttpResponse> {
+    let vectors = service::list_vectors(ctx.into_inner(), &collection_id, query).await?;
+    Ok(HttpResponse::Ok().json(vectors))
+}
+
 pub(crate) async fn update_vector_by_id(
     path: web::Path<(String, u64)>,
     web::Json(update_vector_dto): web::Json<UpdateVectorDto>,
@@ -43,9 +106,19 @@ pub(crate) async fn update_vector_by_id(
 }
 
 pub(crate) async fn find_similar_vectors(
+    path: web::Path<String>,
     web::Json(find_similar_vectors): web::Json<FindSimilarVectorsDto>,
+    web::Query(query): web::Query<FindSimilarVectorsQuery>,
+    ctx: web::Data<AppContext>,
 ) -> Result<HttpResponse> {
-    let similar_vectors = service::find_similar_vectors(find_similar_vectors).await?;
+    let collection_id = path.into_inner();
+    let similar_vectors = service::find_similar_vectors(
+        ctx.into_inner(),
+        &collection_id,
+        find_similar_vectors,
+        query.exact,
+    )
+    .await?;
     Ok(HttpResponse::Ok().json(similar_vectors))
 }
 
@@ -1,23 +1,66 @@
 use serde::{Deserialize, Serialize};
 
-use crate::models::rpc::Vector;
+use crate::models::{rpc::Vector, versioning::Hash};
 
 #[derive(Deserialize)]
 pub(crate) struct CreateVectorDto {
     pub id: u64,
     pub values: Vec<f32>,
+    #[serde(default)]
+    pub metadata: Option<serde_json::Value>,
+    /// only write if this is still the collection's current version
+    #[serde(default)]
+    pub if_version: Option<Hash>,
 }
 
-#[derive(Serialize)]
+#[derive(Debug, Serialize)]
 pub(crate) struct CreateVectorResponseDto {
     pub id: u64,
     pub values: Vec<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Value>,
     // pub created_at: String
 }
 
+#[derive(Deserialize)]
+pub(crate) struct CreateSparseVectorDto {
+    pub id: u64,
+    // (dimension, value) pairs, values are expected in [0, 1]
+    pub entries: Vec<(u32, f32)>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct CreateSparseVectorsBatchDto {
+    pub vectors: Vec<CreateSparseVectorDto>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct SparseVectorBatchResult {
+    pub id: u64,
+    pub success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct CreateSparseVectorsBatchResponseDto {
+    // one result per vector, in the order they were sent
+    pub results: Vec<SparseVectorBatchResult>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct SparseVectorResponseDto {
+    pub id: u64,
+    // (dimension, value) pairs sorted by dimension
+    pub entries: Vec<(u32, f32)>,
+}
+
 #[derive(Deserialize)]
 pub(crate) struct UpdateVectorDto {
     pub values: Vec<f32>,
+    /// only write if this is still the collection's current version
+    #[serde(default)]
+    pub if_version: Option<Hash>,
 }
 
 #[derive(Serialize)]
@@ -33,9 +76,19 @@ pub(crate) struct FindSimilarVectorsDto {
     pub k: u64,
 }
 
+#[derive(Deserialize)]
+pub(crate) struct FindSimilarVectorsQuery {
+    // score every vector rather than searching the graph, slow but exact
+    #[serde(default)]
+    pub exact: bool,
+}
+
 #[derive(Serialize)]
 pub(crate) struct SimilarVector {
     pub id: u64,
+    /// raw distance in the collection's metric, lower is closer
+    pub distance: f32,
+    /// similarity normalized to `[0, 1]`, higher is closer
     pub score: f32,
 }
 
@@ -44,6 +97,39 @@ pub(crate) struct FindSimilarVectorsResponseDto {
     pub results: Vec<SimilarVector>,
 }
 
+#[derive(Debug, Serialize)]
+pub(crate) struct NeighborDto {
+    pub id: u64,
+    /// similarity normalized to `[0, 1]`, higher is closer
+    pub score: f32,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct LevelNeighborsDto {
+    pub level: u8,
+    pub neighbors: Vec<NeighborDto>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct VectorNeighborsResponseDto {
+    pub id: u64,
+    // top level first, empty while the vector is still pending indexing
+    pub levels: Vec<LevelNeighborsDto>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ListVectorsQuery {
+    pub offset: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ListVectorsResponseDto {
+    pub vectors: Vec<u64>,
+    // pass as `offset` to fetch the next page, absent on the last page
+    pub next_offset: Option<u64>,
+}
+
 #[derive(Deserialize)]
 pub(crate) struct UpsertDto {
     pub vectors: Vec<Vector>,
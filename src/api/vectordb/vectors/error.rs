@@ -4,7 +4,7 @@ use actix_web::{
 };
 use std::fmt::Display;
 
-use crate::WaCustomError;
+use crate::{models::versioning::Hash, storage::InvertedIndexError, WaCustomError};
 
 #[allow(dead_code)]
 #[derive(Debug)]
@@ -18,6 +18,11 @@ pub(crate) enum VectorsError {
     NotImplemented,
     DatabaseError(String),
     InternalServerError,
+    /// the write expected another version than the current one
+    VersionConflict {
+        expected: Hash,
+        current: Hash,
+    },
     WaCustom(WaCustomError),
 }
 
@@ -45,6 +50,11 @@ impl Display for VectorsError {
             Self::FailedToDeleteVector(msg) => {
                 write!(f, "Failed to delete vector due to: {}", msg)
             }
+            Self::VersionConflict { expected, current } => write!(
+                f,
+                "Expected version {} but the collection is at version {}",
+                **expected, **current
+            ),
             Self::WaCustom(e) => {
                 write!(f, "Vector operation failed due to internal error: {e:?}")
             }
@@ -60,7 +70,7 @@ impl ResponseError for VectorsError {
     }
     fn status_code(&self) -> StatusCode {
         match self {
-            Self::NotFound => StatusCode::BAD_REQUEST,
+            Self::NotFound => StatusCode::NOT_FOUND,
             Self::FailedToGetAppEnv => StatusCode::INTERNAL_SERVER_ERROR,
             Self::FailedToCreateVector(_) => StatusCode::BAD_REQUEST,
             Self::NotImplemented => StatusCode::BAD_REQUEST,
@@ -69,7 +79,29 @@ impl ResponseError for VectorsError {
             Self::FailedToUpdateVector(_) => StatusCode::BAD_REQUEST,
             Self::FailedToFindSimilarVectors(_) => StatusCode::BAD_REQUEST,
             Self::FailedToDeleteVector(_) => StatusCode::BAD_REQUEST,
+            Self::VersionConflict { .. } => StatusCode::CONFLICT,
+            Self::WaCustom(
+                WaCustomError::InvalidParams
+                | WaCustomError::DimensionMismatch(..)
+                | WaCustomError::DuplicateVectorId(..),
+            ) => StatusCode::BAD_REQUEST,
             Self::WaCustom(_) => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 }
+
+impl From<InvertedIndexError> for VectorsError {
+    fn from(error: InvertedIndexError) -> Self {
+        match error {
+            InvertedIndexError::VectorNotFound(_) => Self::NotFound,
+            InvertedIndexError::DuplicateVector(_)
+            | InvertedIndexError::DimensionOutOfRange(_)
+            | InvertedIndexError::DimensionAlreadyExplicit(_) => {
+                Self::FailedToCreateVector(error.to_string())
+            }
+            InvertedIndexError::Locked | InvertedIndexError::LogWrite(_) => {
+                Self::WaCustom(error.into())
+            }
+        }
+    }
+}
@@ -9,8 +9,19 @@ mod service;
 pub(crate) fn vectors_module() -> Scope {
     let vectors_module = web::scope("/collections/{collection_id}/vectors")
         .route("", web::post().to(controller::create_vector))
+        .route("", web::get().to(controller::list_vectors))
+        .route("/sparse", web::post().to(controller::create_sparse_vector))
+        .route(
+            "/sparse/batch",
+            web::post().to(controller::create_sparse_vectors_batch),
+        )
         .route("/search", web::post().to(controller::find_similar_vectors))
         .route("/{vector_id}", web::get().to(controller::get_vector_by_id))
+        .route("/{vector_id}", web::head().to(controller::vector_exists))
+        .route(
+            "/{vector_id}/neighbors",
+            web::get().to(controller::get_vector_neighbors),
+        )
         .route(
             "/{vector_id}",
             web::put().to(controller::update_vector_by_id),
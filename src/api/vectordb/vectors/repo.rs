@@ -1,21 +1,54 @@
+use rayon::prelude::*;
 use std::sync::{atomic::Ordering, Arc};
 
 use crate::{
-    api::vectordb::collections,
-    api_service::{run_upload, run_upload_in_transaction},
+    api::vectordb::{collections, search::query_params},
+    api_service::{ann_vector_query, run_blocking, run_upload, run_upload_in_transaction},
     app_context::AppContext,
-    models::types::{DenseIndexTransaction, VectorId},
-    vector_store::get_embedding_by_id,
+    models::{
+        common::WaCustomError,
+        types::{DenseIndex, DenseIndexTransaction, MetricResult, SparseVector, VectorId},
+    },
+    storage::inverted_index_sparse_ann_new_ds::InvertedIndexSparseAnnNewDS,
+    vector_store::{self, get_embedding_by_id, list_vector_ids},
 };
 
 use super::{
     dtos::{
-        CreateVectorDto, CreateVectorResponseDto, FindSimilarVectorsDto, SimilarVector,
-        UpdateVectorDto, UpdateVectorResponseDto, UpsertDto,
+        CreateSparseVectorDto, CreateSparseVectorsBatchDto, CreateSparseVectorsBatchResponseDto,
+        CreateVectorDto, CreateVectorResponseDto, FindSimilarVectorsDto, LevelNeighborsDto,
+        ListVectorsQuery, ListVectorsResponseDto, NeighborDto, SimilarVector,
+        SparseVectorBatchResult, SparseVectorResponseDto, UpdateVectorDto, UpdateVectorResponseDto,
+        UpsertDto, VectorNeighborsResponseDto,
     },
     error::VectorsError,
 };
 
+/// Checks that `values` has the dimension the collection was created with,
+/// mismatched vectors would otherwise fail deep inside quantization.
+fn check_dimension(id: u64, values: &[f32], dimension: usize) -> Result<(), String> {
+    if values.len() != dimension {
+        return Err(format!(
+            "vector {} has {} dimensions, but the collection expects {}",
+            id,
+            values.len(),
+            dimension
+        ));
+    }
+    Ok(())
+}
+
+/// Maps an upload failure, a version conflict being the client's to resolve.
+fn upload_error(error: WaCustomError) -> VectorsError {
+    match error {
+        WaCustomError::VersionConflict(expected, current) => {
+            VectorsError::VersionConflict { expected, current }
+        }
+        error => VectorsError::WaCustom(error),
+    }
+}
+
+/// Stores the vector, replacing the one already stored under its id if any.
 pub(crate) async fn create_vector(
     ctx: Arc<AppContext>,
     collection_id: &str,
@@ -35,22 +68,100 @@ pub(crate) async fn create_vector(
         ));
     }
 
-    // TODO: handle the error
-    run_upload(
-        ctx,
-        dense_index,
-        vec![(
-            create_vector_dto.id.clone(),
-            create_vector_dto.values.clone(),
-        )],
+    check_dimension(
+        create_vector_dto.id,
+        &create_vector_dto.values,
+        dense_index.dim,
     )
-    .map_err(VectorsError::WaCustom)?;
+    .map_err(VectorsError::FailedToCreateVector)?;
+
+    let vecs = vec![(
+        create_vector_dto.id.clone(),
+        create_vector_dto.values.clone(),
+        create_vector_dto.metadata.clone(),
+    )];
+    let if_version = create_vector_dto.if_version;
+    run_blocking(move || run_upload(ctx, dense_index, vecs, if_version))
+        .await
+        .map_err(upload_error)?;
     Ok(CreateVectorResponseDto {
         id: create_vector_dto.id,
         values: create_vector_dto.values,
+        metadata: create_vector_dto.metadata,
     })
 }
 
+pub(crate) async fn create_sparse_vector(
+    ctx: Arc<AppContext>,
+    collection_id: &str,
+    create_sparse_vector_dto: CreateSparseVectorDto,
+) -> Result<SparseVectorResponseDto, VectorsError> {
+    let sparse_index = collections::service::get_sparse_index_by_id(ctx, collection_id)
+        .await
+        .map_err(|e| VectorsError::FailedToCreateVector(e.to_string()))?;
+
+    insert_sparse_vector(&sparse_index, create_sparse_vector_dto)
+}
+
+/// adds the vector to a sparse index, echoing it back on success
+fn insert_sparse_vector(
+    sparse_index: &InvertedIndexSparseAnnNewDS,
+    CreateSparseVectorDto { id, entries }: CreateSparseVectorDto,
+) -> Result<SparseVectorResponseDto, VectorsError> {
+    let vector_id = u32::try_from(id).map_err(|_| {
+        VectorsError::FailedToCreateVector(format!(
+            "sparse vector id {} doesn't fit in 32 bits",
+            id
+        ))
+    })?;
+    sparse_index.add_sparse_vector(SparseVector::new(vector_id, entries.clone()))?;
+
+    Ok(SparseVectorResponseDto { id, entries })
+}
+
+/// Adds a batch of vectors to a collection's sparse index in parallel. A
+/// vector that fails to insert doesn't stop the rest of the batch, its error
+/// is reported in its result instead.
+pub(crate) async fn create_sparse_vectors_batch(
+    ctx: Arc<AppContext>,
+    collection_id: &str,
+    batch_dto: CreateSparseVectorsBatchDto,
+) -> Result<CreateSparseVectorsBatchResponseDto, VectorsError> {
+    let sparse_index = collections::service::get_sparse_index_by_id(ctx.clone(), collection_id)
+        .await
+        .map_err(|e| VectorsError::FailedToCreateVector(e.to_string()))?;
+
+    Ok(ctx
+        .threadpool
+        .install(|| insert_sparse_vectors(&sparse_index, batch_dto.vectors)))
+}
+
+fn insert_sparse_vectors(
+    sparse_index: &InvertedIndexSparseAnnNewDS,
+    vectors: Vec<CreateSparseVectorDto>,
+) -> CreateSparseVectorsBatchResponseDto {
+    let results = vectors
+        .into_par_iter()
+        .map(|vector| {
+            let id = vector.id;
+            match insert_sparse_vector(sparse_index, vector) {
+                Ok(_) => SparseVectorBatchResult {
+                    id,
+                    success: true,
+                    error: None,
+                },
+                Err(e) => SparseVectorBatchResult {
+                    id,
+                    success: false,
+                    error: Some(e.to_string()),
+                },
+            }
+        })
+        .collect();
+
+    CreateSparseVectorsBatchResponseDto { results }
+}
+
 pub(crate) async fn create_vector_in_transaction(
     ctx: Arc<AppContext>,
     collection_id: &str,
@@ -61,6 +172,13 @@ pub(crate) async fn create_vector_in_transaction(
         .await
         .map_err(|e| VectorsError::FailedToCreateVector(e.to_string()))?;
 
+    check_dimension(
+        create_vector_dto.id,
+        &create_vector_dto.values,
+        dense_index.dim,
+    )
+    .map_err(VectorsError::FailedToCreateVector)?;
+
     run_upload_in_transaction(
         ctx.clone(),
         dense_index,
@@ -68,6 +186,7 @@ pub(crate) async fn create_vector_in_transaction(
         vec![(
             create_vector_dto.id.clone(),
             create_vector_dto.values.clone(),
+            create_vector_dto.metadata.clone(),
         )],
     )
     .map_err(VectorsError::WaCustom)?;
@@ -75,6 +194,7 @@ pub(crate) async fn create_vector_in_transaction(
     Ok(CreateVectorResponseDto {
         id: create_vector_dto.id,
         values: create_vector_dto.values,
+        metadata: create_vector_dto.metadata,
     })
 }
 
@@ -87,14 +207,151 @@ pub(crate) async fn get_vector_by_id(
         .await
         .map_err(|_| VectorsError::NotFound)?;
 
-    let embedding = get_embedding_by_id(vec_store, &vector_id)
-        .map_err(|e| VectorsError::DatabaseError(e.to_string()))?;
+    read_dense_vector(vec_store, &vector_id)
+}
+
+/// reads a vector back from a dense index, a missing one is `NotFound` and
+/// any other failure a `DatabaseError`
+fn read_dense_vector(
+    dense_index: Arc<DenseIndex>,
+    vector_id: &VectorId,
+) -> Result<CreateVectorResponseDto, VectorsError> {
+    let embedding = get_embedding_by_id(dense_index, vector_id)
+        .map_err(|e| VectorsError::DatabaseError(e.to_string()))?
+        .ok_or(VectorsError::NotFound)?;
 
     let id = embedding.hash_vec.0;
 
     Ok(CreateVectorResponseDto {
         id,
         values: (*embedding.raw_vec).clone(),
+        metadata: embedding.metadata,
+    })
+}
+
+pub(crate) async fn get_vector_neighbors(
+    ctx: Arc<AppContext>,
+    collection_id: &str,
+    vector_id: VectorId,
+) -> Result<VectorNeighborsResponseDto, VectorsError> {
+    let dense_index = collections::service::get_dense_index_by_id(ctx.clone(), collection_id)
+        .await
+        .map_err(|_| VectorsError::NotFound)?;
+
+    read_vector_neighbors(dense_index, vector_id)
+}
+
+/// reads the neighbors of a vector on each HNSW level it's on, a vector that
+/// was never inserted is `NotFound` while one still pending indexing has no
+/// levels
+fn read_vector_neighbors(
+    dense_index: Arc<DenseIndex>,
+    vector_id: VectorId,
+) -> Result<VectorNeighborsResponseDto, VectorsError> {
+    if !vector_store::vector_exists(&dense_index, &vector_id)
+        .map_err(|e| VectorsError::DatabaseError(e.to_string()))?
+    {
+        return Err(VectorsError::NotFound);
+    }
+
+    let levels = vector_store::vector_fetch(dense_index, vector_id.clone())
+        .map_err(|e| VectorsError::DatabaseError(e.to_string()))?
+        .into_iter()
+        .enumerate()
+        .rev()
+        .filter_map(|(level, neighbors)| {
+            neighbors.map(|(_, neighbors)| LevelNeighborsDto {
+                level: level as u8,
+                neighbors: neighbors
+                    .into_iter()
+                    .map(|(id, dist)| NeighborDto {
+                        id: id.0,
+                        score: dist.score(),
+                    })
+                    .collect(),
+            })
+        })
+        .collect();
+
+    Ok(VectorNeighborsResponseDto {
+        id: vector_id.0,
+        levels,
+    })
+}
+
+/// whether the collection only holds sparse vectors, those are read back from
+/// its sparse index rather than the dense one
+pub(crate) async fn is_sparse_collection(
+    ctx: Arc<AppContext>,
+    collection_id: &str,
+) -> Result<bool, VectorsError> {
+    let collection = collections::service::get_collection_by_id(ctx, collection_id)
+        .await
+        .map_err(|_| VectorsError::NotFound)?;
+    Ok(collection.sparse_vector.enabled && !collection.dense_vector.enabled)
+}
+
+pub(crate) async fn get_sparse_vector_by_id(
+    ctx: Arc<AppContext>,
+    collection_id: &str,
+    vector_id: VectorId,
+) -> Result<SparseVectorResponseDto, VectorsError> {
+    let sparse_index = collections::service::get_sparse_index_by_id(ctx, collection_id)
+        .await
+        .map_err(|_| VectorsError::NotFound)?;
+
+    reconstruct_sparse_vector(&sparse_index, vector_id)
+}
+
+/// reads a vector back from a sparse index, which only stores `u32` ids
+fn reconstruct_sparse_vector(
+    sparse_index: &InvertedIndexSparseAnnNewDS,
+    vector_id: VectorId,
+) -> Result<SparseVectorResponseDto, VectorsError> {
+    let id = u32::try_from(vector_id.0).map_err(|_| VectorsError::NotFound)?;
+    let vector = sparse_index.reconstruct(id).ok_or(VectorsError::NotFound)?;
+
+    Ok(SparseVectorResponseDto {
+        id: vector_id.0,
+        entries: vector.entries,
+    })
+}
+
+pub(crate) async fn vector_exists(
+    ctx: Arc<AppContext>,
+    collection_id: &str,
+    vector_id: VectorId,
+) -> Result<bool, VectorsError> {
+    let vec_store = collections::service::get_dense_index_by_id(ctx.clone(), collection_id)
+        .await
+        .map_err(|_| VectorsError::NotFound)?;
+
+    vector_store::vector_exists(&vec_store, &vector_id)
+        .map_err(|e| VectorsError::DatabaseError(e.to_string()))
+}
+
+const DEFAULT_LIST_VECTORS_LIMIT: usize = 100;
+const MAX_LIST_VECTORS_LIMIT: usize = 1000;
+
+pub(crate) async fn list_vectors(
+    ctx: Arc<AppContext>,
+    collection_id: &str,
+    query: ListVectorsQuery,
+) -> Result<ListVectorsResponseDto, VectorsError> {
+    let dense_index = collections::service::get_dense_index_by_id(ctx.clone(), collection_id)
+        .await
+        .map_err(|_| VectorsError::NotFound)?;
+
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_LIST_VECTORS_LIMIT)
+        .min(MAX_LIST_VECTORS_LIMIT);
+    let (ids, next) = list_vector_ids(&dense_index, query.offset.map(VectorId), limit)
+        .map_err(|e| VectorsError::DatabaseError(e.to_string()))?;
+
+    Ok(ListVectorsResponseDto {
+        vectors: ids.into_iter().map(|id| id.0).collect(),
+        next_offset: next.map(|id| id.0),
     })
 }
 
@@ -118,12 +375,14 @@ pub(crate) async fn update_vector(
         ));
     }
 
-    run_upload(
-        ctx,
-        dense_index,
-        vec![(vector_id.clone(), update_vector_dto.values.clone())],
-    )
-    .map_err(VectorsError::WaCustom)?;
+    check_dimension(vector_id, &update_vector_dto.values, dense_index.dim)
+        .map_err(VectorsError::FailedToUpdateVector)?;
+
+    let vecs = vec![(vector_id.clone(), update_vector_dto.values.clone(), None)];
+    let if_version = update_vector_dto.if_version;
+    run_blocking(move || run_upload(ctx, dense_index, vecs, if_version))
+        .await
+        .map_err(upload_error)?;
 
     Ok(UpdateVectorResponseDto {
         id: vector_id,
@@ -132,17 +391,51 @@ pub(crate) async fn update_vector(
 }
 
 pub(crate) async fn find_similar_vectors(
+    ctx: Arc<AppContext>,
+    collection_id: &str,
     find_similar_vectors: FindSimilarVectorsDto,
+    exact: bool,
 ) -> Result<Vec<SimilarVector>, VectorsError> {
     if find_similar_vectors.vector.len() == 0 {
         return Err(VectorsError::FailedToFindSimilarVectors(
             "Vector shouldn't be empty".to_string(),
         ));
     }
-    Ok(vec![SimilarVector {
-        id: find_similar_vectors.k,
-        score: find_similar_vectors.vector[0],
-    }])
+    let dense_index = collections::service::get_dense_index_by_id(ctx.clone(), collection_id)
+        .await
+        .map_err(|e| VectorsError::FailedToFindSimilarVectors(e.to_string()))?;
+    // the request always sets `k`, `ef_search` comes from the collection
+    let (k, ef_search) = query_params(
+        &ctx,
+        collection_id,
+        Some(find_similar_vectors.k as usize),
+        None,
+    );
+
+    let results = ann_vector_query(
+        ctx,
+        dense_index,
+        find_similar_vectors.vector,
+        k,
+        ef_search,
+        None,
+        exact,
+    )
+    .await
+    .map_err(|e| VectorsError::FailedToFindSimilarVectors(e.to_string()))?;
+
+    Ok(results
+        .into_iter()
+        .map(|(id, dist)| similar_vector(id, dist))
+        .collect())
+}
+
+fn similar_vector(id: VectorId, dist: MetricResult) -> SimilarVector {
+    SimilarVector {
+        id: id.0,
+        distance: dist.distance(),
+        score: dist.score(),
+    }
 }
 
 pub(crate) async fn delete_vector_by_id(
@@ -171,6 +464,11 @@ pub(crate) async fn upsert_in_transaction(
         .await
         .map_err(|e| VectorsError::FailedToCreateVector(e.to_string()))?;
 
+    for vec in &upsert_dto.vectors {
+        check_dimension(vec.id, &vec.values, dense_index.dim)
+            .map_err(VectorsError::FailedToCreateVector)?;
+    }
+
     run_upload_in_transaction(
         ctx.clone(),
         dense_index,
@@ -178,10 +476,245 @@ pub(crate) async fn upsert_in_transaction(
         upsert_dto
             .vectors
             .into_iter()
-            .map(|vec| (vec.id, vec.values))
+            .map(|vec| (vec.id, vec.values, vec.metadata))
             .collect(),
     )
     .map_err(VectorsError::WaCustom)?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        check_dimension, insert_sparse_vector, insert_sparse_vectors, read_dense_vector,
+        read_vector_neighbors, reconstruct_sparse_vector, similar_vector, upload_error,
+        CreateSparseVectorDto, VectorsError, WaCustomError,
+    };
+    use crate::distance::DistanceFunction;
+    use crate::macros::key;
+    use crate::models::types::DistanceMetric;
+    use crate::models::types::{HNSWHyperParams, RawVectorEmbedding};
+    use crate::models::types::{SparseVector, VectorId};
+    use crate::models::versioning::{BranchId, Hash};
+    use crate::quantization::{scalar::ScalarQuantization, Quantization, StorageType};
+    use crate::storage::inverted_index_sparse_ann_new_ds::InvertedIndexSparseAnnNewDS;
+    use crate::vector_store::insert_embedding;
+    use crate::vector_store::tests::{setup_dense_index, test_config};
+    use actix_web::{http::StatusCode, ResponseError};
+    use lmdb::{Transaction, WriteFlags};
+    use std::sync::Arc;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_vector_neighbors_of_pending_and_missing_vectors() {
+        let config = test_config();
+        let (dense_index, _dir) = setup_dense_index(HNSWHyperParams::default_from_config(&config));
+        let version = dense_index.get_current_version();
+        let bufman = dense_index.vec_raw_manager.get(version).unwrap();
+        let emb = RawVectorEmbedding {
+            raw_vec: Arc::new(vec![0.1, 0.2, 0.3, 0.4]),
+            hash_vec: VectorId(3),
+            metadata: None,
+        };
+        insert_embedding(bufman.clone(), dense_index.clone(), &emb, version).unwrap();
+        bufman.flush().unwrap();
+
+        // stored but not indexed yet, so not on any level
+        let pending = read_vector_neighbors(dense_index.clone(), VectorId(3)).unwrap();
+        assert_eq!(pending.id, 3);
+        assert!(pending.levels.is_empty());
+
+        let missing = read_vector_neighbors(dense_index.clone(), VectorId(4)).unwrap_err();
+        assert!(matches!(missing, VectorsError::NotFound));
+        assert_eq!(missing.status_code(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_get_dense_vector_by_id() {
+        let config = test_config();
+        let (dense_index, _dir) = setup_dense_index(HNSWHyperParams::default_from_config(&config));
+        let version = dense_index.get_current_version();
+        let bufman = dense_index.vec_raw_manager.get(version).unwrap();
+        let emb = RawVectorEmbedding {
+            raw_vec: Arc::new(vec![0.1, 0.2, 0.3, 0.4]),
+            hash_vec: VectorId(3),
+            metadata: None,
+        };
+        insert_embedding(bufman.clone(), dense_index.clone(), &emb, version).unwrap();
+        bufman.flush().unwrap();
+
+        let vector = read_dense_vector(dense_index.clone(), &VectorId(3)).unwrap();
+        assert_eq!(vector.id, 3);
+        assert_eq!(vector.values, vec![0.1, 0.2, 0.3, 0.4]);
+
+        let missing = read_dense_vector(dense_index.clone(), &VectorId(4)).unwrap_err();
+        assert!(matches!(missing, VectorsError::NotFound));
+        assert_eq!(missing.status_code(), StatusCode::NOT_FOUND);
+
+        // an offset record that can't be read is a database failure
+        let mut txn = dense_index.lmdb.env.begin_rw_txn().unwrap();
+        txn.put(
+            *dense_index.lmdb.db,
+            &key!(e:BranchId::new("main"), VectorId(5)),
+            &[0xff; 3],
+            WriteFlags::empty(),
+        )
+        .unwrap();
+        txn.commit().unwrap();
+        let broken = read_dense_vector(dense_index, &VectorId(5)).unwrap_err();
+        assert!(matches!(broken, VectorsError::DatabaseError(_)));
+        assert_eq!(broken.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn test_version_conflict_is_a_conflict() {
+        let (expected, current) = (Hash::from(1), Hash::from(2));
+        let conflict = upload_error(WaCustomError::VersionConflict(expected, current));
+        assert!(matches!(
+            conflict,
+            VectorsError::VersionConflict { expected: e, current: c }
+                if e == expected && c == current
+        ));
+        assert_eq!(conflict.status_code(), StatusCode::CONFLICT);
+
+        let failure = upload_error(WaCustomError::FsError("disk full".to_string()));
+        assert_eq!(failure.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        for invalid in [
+            WaCustomError::DimensionMismatch(0, 3, 4),
+            WaCustomError::DuplicateVectorId(1, 7),
+        ] {
+            assert_eq!(upload_error(invalid).status_code(), StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[test]
+    fn test_check_dimension() {
+        assert!(check_dimension(1, &[0.1, 0.2, 0.3, 0.4], 4).is_ok());
+
+        let too_short = check_dimension(1, &[0.1, 0.2, 0.3], 4).unwrap_err();
+        assert_eq!(
+            too_short,
+            "vector 1 has 3 dimensions, but the collection expects 4"
+        );
+
+        let too_long = check_dimension(2, &[0.1, 0.2, 0.3, 0.4, 0.5], 4).unwrap_err();
+        assert_eq!(
+            too_long,
+            "vector 2 has 5 dimensions, but the collection expects 4"
+        );
+    }
+
+    #[test]
+    fn test_get_sparse_vector_by_id() {
+        let dir = tempdir().unwrap();
+        let sparse_index = InvertedIndexSparseAnnNewDS::new(dir.as_ref(), 100);
+        sparse_index
+            .add_sparse_vector(SparseVector::new(3, vec![(40, 0.5), (7, 1.0)]))
+            .unwrap();
+
+        let vector = reconstruct_sparse_vector(&sparse_index, VectorId(3)).unwrap();
+        assert_eq!(vector.id, 3);
+        let dims: Vec<_> = vector.entries.iter().map(|&(dim, _)| dim).collect();
+        assert_eq!(dims, vec![7, 40]);
+        assert!((vector.entries[0].1 - 1.0).abs() <= 1.0 / 63.0);
+        assert!((vector.entries[1].1 - 0.5).abs() <= 1.0 / 63.0);
+
+        for missing_id in [4, u32::MAX as u64 + 3] {
+            assert!(matches!(
+                reconstruct_sparse_vector(&sparse_index, VectorId(missing_id)),
+                Err(VectorsError::NotFound)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_create_sparse_vector_echoes_vector() {
+        let dir = tempdir().unwrap();
+        let sparse_index = InvertedIndexSparseAnnNewDS::new(dir.as_ref(), 100);
+        let create = || CreateSparseVectorDto {
+            id: 5,
+            entries: vec![(9, 0.25), (1, 0.5)],
+        };
+
+        let vector = insert_sparse_vector(&sparse_index, create()).unwrap();
+        assert_eq!(
+            serde_json::to_value(&vector).unwrap(),
+            serde_json::json!({ "id": 5, "entries": [[9, 0.25], [1, 0.5]] })
+        );
+        assert_eq!(sparse_index.get(9, 5), Some(15));
+
+        assert!(matches!(
+            insert_sparse_vector(&sparse_index, create()),
+            Err(VectorsError::FailedToCreateVector(_))
+        ));
+        let too_large = CreateSparseVectorDto {
+            id: u32::MAX as u64 + 1,
+            entries: vec![(1, 0.5)],
+        };
+        assert!(matches!(
+            insert_sparse_vector(&sparse_index, too_large),
+            Err(VectorsError::FailedToCreateVector(_))
+        ));
+    }
+
+    #[test]
+    fn test_create_sparse_vectors_batch_reports_failures() {
+        let dir = tempdir().unwrap();
+        let sparse_index = InvertedIndexSparseAnnNewDS::new(dir.as_ref(), 100).with_max_dim(1000);
+        let vectors = vec![
+            CreateSparseVectorDto {
+                id: 1,
+                entries: vec![(3, 0.5), (10, 0.25)],
+            },
+            // beyond the index's max_dim
+            CreateSparseVectorDto {
+                id: 2,
+                entries: vec![(3, 0.5), (5000, 0.25)],
+            },
+            CreateSparseVectorDto {
+                id: 3,
+                entries: vec![(10, 1.0)],
+            },
+        ];
+
+        let response = insert_sparse_vectors(&sparse_index, vectors);
+        let outcomes: Vec<_> = response
+            .results
+            .iter()
+            .map(|result| (result.id, result.success))
+            .collect();
+        assert_eq!(outcomes, vec![(1, true), (2, false), (3, true)]);
+        assert!(response.results[1].error.as_ref().unwrap().contains("5000"));
+        assert!(response.results[0].error.is_none());
+
+        assert!(sparse_index.reconstruct(1).is_some());
+        assert!(sparse_index.reconstruct(2).is_none());
+        assert!(sparse_index.reconstruct(3).is_some());
+    }
+
+    #[test]
+    fn test_similar_vector_carries_distance_and_score() {
+        let quantize = |v: &[f32]| {
+            ScalarQuantization
+                .quantize(v, StorageType::UnsignedByte, (-1.0, 1.0))
+                .unwrap()
+        };
+        let query = quantize(&[0.9, 0.1, -0.3, 0.5]);
+        let stored = quantize(&[0.8, 0.2, -0.1, 0.6]);
+        let dist = DistanceMetric::Cosine.calculate(&query, &stored).unwrap();
+
+        let similar = similar_vector(VectorId(7), dist);
+        assert_eq!(similar.id, 7);
+        assert_eq!(similar.distance, dist.distance());
+        assert_eq!(similar.score, dist.score());
+        // close vectors: a small cosine distance and a score near 1
+        assert!(similar.distance > 0.0 && similar.distance < 0.2);
+        assert!(similar.score > 0.9 && similar.score <= 1.0);
+
+        let json = serde_json::to_value(&similar).unwrap();
+        assert!(json["distance"].is_number());
+        assert!(json["score"].is_number());
+    }
+}
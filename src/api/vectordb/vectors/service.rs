@@ -4,8 +4,11 @@ use crate::{app_context::AppContext, models::types::VectorId};
 
 use super::{
     dtos::{
+        CreateSparseVectorDto, CreateSparseVectorsBatchDto, CreateSparseVectorsBatchResponseDto,
         CreateVectorDto, CreateVectorResponseDto, FindSimilarVectorsDto,
-        FindSimilarVectorsResponseDto, UpdateVectorDto, UpdateVectorResponseDto,
+        FindSimilarVectorsResponseDto, ListVectorsQuery, ListVectorsResponseDto,
+        SparseVectorResponseDto, UpdateVectorDto, UpdateVectorResponseDto,
+        VectorNeighborsResponseDto,
     },
     error::VectorsError,
     repo,
@@ -19,6 +22,22 @@ pub(crate) async fn create_vector(
     repo::create_vector(ctx, collection_id, create_vector_dto).await
 }
 
+pub(crate) async fn create_sparse_vector(
+    ctx: Arc<AppContext>,
+    collection_id: &str,
+    create_sparse_vector_dto: CreateSparseVectorDto,
+) -> Result<SparseVectorResponseDto, VectorsError> {
+    repo::create_sparse_vector(ctx, collection_id, create_sparse_vector_dto).await
+}
+
+pub(crate) async fn create_sparse_vectors_batch(
+    ctx: Arc<AppContext>,
+    collection_id: &str,
+    batch_dto: CreateSparseVectorsBatchDto,
+) -> Result<CreateSparseVectorsBatchResponseDto, VectorsError> {
+    repo::create_sparse_vectors_batch(ctx, collection_id, batch_dto).await
+}
+
 pub(crate) async fn get_vector_by_id(
     ctx: Arc<AppContext>,
     collection_id: &str,
@@ -27,6 +46,45 @@ pub(crate) async fn get_vector_by_id(
     repo::get_vector_by_id(ctx, collection_id, vector_id).await
 }
 
+pub(crate) async fn get_vector_neighbors(
+    ctx: Arc<AppContext>,
+    collection_id: &str,
+    vector_id: VectorId,
+) -> Result<VectorNeighborsResponseDto, VectorsError> {
+    repo::get_vector_neighbors(ctx, collection_id, vector_id).await
+}
+
+pub(crate) async fn is_sparse_collection(
+    ctx: Arc<AppContext>,
+    collection_id: &str,
+) -> Result<bool, VectorsError> {
+    repo::is_sparse_collection(ctx, collection_id).await
+}
+
+pub(crate) async fn get_sparse_vector_by_id(
+    ctx: Arc<AppContext>,
+    collection_id: &str,
+    vector_id: VectorId,
+) -> Result<SparseVectorResponseDto, VectorsError> {
+    repo::get_sparse_vector_by_id(ctx, collection_id, vector_id).await
+}
+
+pub(crate) async fn vector_exists(
+    ctx: Arc<AppContext>,
+    collection_id: &str,
+    vector_id: VectorId,
+) -> Result<bool, VectorsError> {
+    repo::vector_exists(ctx, collection_id, vector_id).await
+}
+
+pub(crate) async fn list_vectors(
+    ctx: Arc<AppContext>,
+    collection_id: &str,
+    query: ListVectorsQuery,
+) -> Result<ListVectorsResponseDto, VectorsError> {
+    repo::list_vectors(ctx, collection_id, query).await
+}
+
 pub(crate) async fn update_vector_by_id(
     ctx: Arc<AppContext>,
     collection_id: &str,
@@ -37,9 +95,13 @@ pub(crate) async fn update_vector_by_id(
 }
 
 pub(crate) async fn find_similar_vectors(
+    ctx: Arc<AppContext>,
+    collection_id: &str,
     find_similar_vectors: FindSimilarVectorsDto,
+    exact: bool,
 ) -> Result<FindSimilarVectorsResponseDto, VectorsError> {
-    let similar_vectors = repo::find_similar_vectors(find_similar_vectors).await?;
+    let similar_vectors =
+        repo::find_similar_vectors(ctx, collection_id, find_similar_vectors, exact).await?;
 
     Ok(FindSimilarVectorsResponseDto {
         results: similar_vectors,
@@ -1,27 +1,34 @@
 use crate::app_context::AppContext;
+use crate::config_loader::Config;
 use crate::indexes::inverted_index::InvertedIndex;
 use crate::models::buffered_io::BufferManagerFactory;
-use crate::models::cache_loader::ProbCache;
+use crate::models::cache_loader::{ProbCache, DEFAULT_PROB_CACHE_CAPACITY};
 use crate::models::collection::Collection;
 use crate::models::common::*;
 use crate::models::embedding_persist::EmbeddingOffset;
-use crate::models::file_persist::write_node_to_file;
-use crate::models::meta_persist::update_current_version;
+use crate::models::file_persist::{prop_file_path, write_node_to_file};
+use crate::models::meta_persist::{retrieve_current_version, update_current_version};
+use crate::models::oplog::{append_to_oplog, oplog_path, OpLogEntry, OpLogOp};
+use crate::models::rpc::Filter;
 use crate::models::types::*;
 use crate::models::user::Statistics;
 use crate::models::versioning::{Hash, VersionControl};
 use crate::quantization::{Quantization, StorageType};
+use crate::storage::Storage;
 use crate::vector_store::*;
+use actix_web::web;
 use arcshift::ArcShift;
 use lmdb::Transaction;
 use lmdb::WriteFlags;
 use rayon::iter::{IndexedParallelIterator, IntoParallelIterator, ParallelIterator};
 use std::array::TryFromSliceError;
+use std::collections::HashSet;
 use std::fs;
 use std::io::SeekFrom;
 use std::path::Path;
 use std::sync::atomic::Ordering;
 use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
 /// creates a dense index for a collection
 #[allow(unused_variables)]
@@ -35,9 +42,12 @@ pub async fn init_dense_index_for_collection(
     storage_type: StorageType,
     sample_threshold: usize,
     is_configured: bool,
+    root_vector_seed: Option<u64>,
+    level_seed: Option<u64>,
+    factor_levels: f64,
 ) -> Result<Arc<DenseIndex>, WaCustomError> {
     let collection_name = &collection.name;
-    let collection_path: Arc<Path> = collection.get_path();
+    let collection_path: Arc<Path> = collection.get_path(&ctx.config.collections_path);
 
     let values_range = values_range.unwrap_or((-1.0, 1.0));
 
@@ -58,7 +68,7 @@ pub async fn init_dense_index_for_collection(
             .create(true)
             .read(true)
             .append(true)
-            .open(collection_path.join("prop.data"))
+            .open(prop_file_path(&collection_path))
             .map_err(|e| WaCustomError::FsError(e.to_string()))?,
     ));
 
@@ -67,14 +77,19 @@ pub async fn init_dense_index_for_collection(
         |root, ver: &Hash| root.join(format!("{}.index", **ver)),
         ctx.config.flush_eagerness_factor,
     ));
-    let vec_raw_manager = Arc::new(BufferManagerFactory::new(
-        collection_path.clone(),
-        |root, ver: &Hash| root.join(format!("{}.vec_raw", **ver)),
+    let vec_raw_manager =
+        vec_raw_manager(collection_path.clone(), ctx.config.flush_eagerness_factor);
+    let vec_raw_replica_managers = vec_raw_replica_managers(
+        &collection_path,
+        collection.config.replica_count(),
         ctx.config.flush_eagerness_factor,
-    ));
-    // TODO: May be the value can be taken from config
-    let cache = Arc::new(ProbCache::new(
+    )?;
+    let cache = Arc::new(ProbCache::with_capacity(
         1000,
+        collection
+            .config
+            .cache_capacity
+            .unwrap_or(DEFAULT_PROB_CACHE_CAPACITY),
         index_manager.clone(),
         prop_file.clone(),
     ));
@@ -88,13 +103,10 @@ pub async fn init_dense_index_for_collection(
         index_manager.clone(),
         values_range,
         &hnsw_params,
+        root_vector_seed,
     )?;
 
     index_manager.flush_all()?;
-    // ---------------------------
-    // -- TODO level entry ratio
-    // ---------------------------
-    let factor_levels = 10.0;
     let lp = Arc::new(generate_tuples(factor_levels, hnsw_params.num_layers));
 
     let dense_index = Arc::new(DenseIndex::new(
@@ -113,9 +125,11 @@ pub async fn init_dense_index_for_collection(
         cache,
         index_manager,
         vec_raw_manager,
+        vec_raw_replica_managers,
         values_range,
         sample_threshold,
         is_configured,
+        level_seed,
     ));
 
     ctx.ain_env
@@ -131,7 +145,7 @@ pub async fn init_inverted_index_for_collection(
     collection: &Collection,
 ) -> Result<Arc<InvertedIndex>, WaCustomError> {
     let collection_name = &collection.name;
-    let collection_path: Arc<Path> = collection.get_path();
+    let collection_path: Arc<Path> = collection.get_path(&ctx.config.collections_path);
 
     let env = ctx.ain_env.persist.clone();
 
@@ -150,7 +164,7 @@ pub async fn init_inverted_index_for_collection(
             .create(true)
             .read(true)
             .append(true)
-            .open(collection_path.join("prop.data"))
+            .open(prop_file_path(&collection_path))
             .map_err(|e| WaCustomError::FsError(e.to_string()))?,
     );
 
@@ -165,7 +179,7 @@ pub async fn init_inverted_index_for_collection(
         lmdb,
         ArcShift::new(hash),
         Arc::new(QuantizationMetric::Scalar),
-        Arc::new(DistanceMetric::DotProduct),
+        Arc::new(collection.distance_metric.clone()),
         StorageType::UnsignedByte,
         vcs,
     );
@@ -178,7 +192,7 @@ pub fn run_upload_in_transaction(
     ctx: Arc<AppContext>,
     dense_index: Arc<DenseIndex>,
     transaction: &DenseIndexTransaction,
-    mut sample_points: Vec<(u64, Vec<f32>)>,
+    mut sample_points: Vec<(u64, Vec<f32>, Option<serde_json::Value>)>,
 ) -> Result<(), WaCustomError> {
     let version = transaction.id;
     let version_number = transaction.version_number;
@@ -191,7 +205,11 @@ pub fn run_upload_in_transaction(
             .fetch_add(sample_points.len(), Ordering::SeqCst);
 
         if collected_count < dense_index.sample_threshold {
-            for (_, values) in &sample_points {
+            sample_for_training(
+                &dense_index,
+                sample_points.iter().map(|(_, values, _)| &values[..]),
+            );
+            for (_, values, _) in &sample_points {
                 for value in values {
                     let value = *value;
 
@@ -308,19 +326,19 @@ pub fn run_upload_in_transaction(
                 (dense_index.sampling_data.below_01.load(Ordering::Relaxed) as f32 / values_count)
                     * 100.0;
 
-            println!("Above percentages:");
-            println!("> 0.5: {:.2}%", above_05_pecent);
-            println!("> 0.4: {:.2}%", above_04_pecent);
-            println!("> 0.3: {:.2}%", above_03_pecent);
-            println!("> 0.2: {:.2}%", above_02_pecent);
-            println!("> 0.1: {:.2}%", above_01_pecent);
+            log::debug!("Above percentages:");
+            log::debug!("> 0.5: {:.2}%", above_05_pecent);
+            log::debug!("> 0.4: {:.2}%", above_04_pecent);
+            log::debug!("> 0.3: {:.2}%", above_03_pecent);
+            log::debug!("> 0.2: {:.2}%", above_02_pecent);
+            log::debug!("> 0.1: {:.2}%", above_01_pecent);
 
-            println!("Below percentages:");
-            println!("< -0.5: {:.2}%", below_05_pecent);
-            println!("< -0.4: {:.2}%", below_04_pecent);
-            println!("< -0.3: {:.2}%", below_03_pecent);
-            println!("< -0.2: {:.2}%", below_02_pecent);
-            println!("< -0.1: {:.2}%", below_01_pecent);
+            log::debug!("Below percentages:");
+            log::debug!("< -0.5: {:.2}%", below_05_pecent);
+            log::debug!("< -0.4: {:.2}%", below_04_pecent);
+            log::debug!("< -0.3: {:.2}%", below_03_pecent);
+            log::debug!("< -0.2: {:.2}%", below_02_pecent);
+            log::debug!("< -0.1: {:.2}%", below_01_pecent);
 
             let range_start = if below_01_pecent <= ctx.config.indexing.clamp_margin_percent {
                 -0.1
@@ -351,8 +369,15 @@ pub fn run_upload_in_transaction(
             };
 
             let range = (range_start, range_end);
-            println!("Range: {:?}", range);
+            log::info!("Range: {:?}", range);
             *dense_index.values_range.write().unwrap() = range;
+            // the vectors collected so far are quantized only now, so they
+            // get encoded with the trained quantizer
+            if train_quantizer(&dense_index)? {
+                ctx.ain_env
+                    .collections_map
+                    .persist_dense_index(dense_index.clone())?;
+            }
             dense_index.is_configured.store(true, Ordering::Release);
             sample_points = std::mem::replace(&mut *vectors, Vec::new());
             is_first_batch = true;
@@ -395,12 +420,48 @@ pub fn run_upload_in_transaction(
     Ok(())
 }
 
-/// uploads a vector embedding
+/// Runs `f` on actix's blocking thread pool and awaits its result, so that
+/// file and LMDB I/O, like `run_upload` and the indexing it triggers, doesn't
+/// stall the worker running the request handler. A task that never completes
+/// is reported as `WaCustomError::BlockingTaskFailed` rather than a panic.
+pub async fn run_blocking<F, R>(f: F) -> Result<R, WaCustomError>
+where
+    F: FnOnce() -> Result<R, WaCustomError> + Send + 'static,
+    R: Send + 'static,
+{
+    web::block(f)
+        .await
+        .map_err(|e| WaCustomError::BlockingTaskFailed(e.to_string()))?
+}
+
+/// Fails with `WaCustomError::VersionConflict` unless `if_version`, if given,
+/// is the version the collection is currently at.
+fn check_if_version(
+    dense_index: &DenseIndex,
+    if_version: Option<Hash>,
+) -> Result<(), WaCustomError> {
+    let Some(expected) = if_version else {
+        return Ok(());
+    };
+    let current = retrieve_current_version(&dense_index.lmdb)?;
+    if current != expected {
+        return Err(WaCustomError::VersionConflict(expected, current));
+    }
+    Ok(())
+}
+
+/// Stores `vecs` as a new version of the collection. With `if_version` set
+/// nothing is stored unless the collection is still at that version, checked
+/// under `version_lock` together with the move to the new version so that of
+/// two writes made against the same version only one is stored.
 pub fn run_upload(
     ctx: Arc<AppContext>,
     dense_index: Arc<DenseIndex>,
-    vecs: Vec<(u64, Vec<f32>)>,
+    vecs: Vec<(u64, Vec<f32>, Option<serde_json::Value>)>,
+    if_version: Option<Hash>,
 ) -> Result<(), WaCustomError> {
+    check_upload_batch(&vecs, dense_index.dim)?;
+    branch_from_rollback(&ctx.config, &dense_index)?;
     let env = dense_index.lmdb.env.clone();
     let db = dense_index.lmdb.db.clone();
     let txn = env
@@ -447,10 +508,38 @@ pub fn run_upload(
     }
 
     // Add next version
-    let (current_version, _) = dense_index
+    let version_guard = dense_index
+        .version_lock
+        .lock()
+        .map_err(|_| WaCustomError::LockError("Failed to lock the version".to_string()))?;
+    check_if_version(&dense_index, if_version)?;
+    let branch = dense_index.current_branch()?;
+    let (current_version, version_number) = dense_index
         .vcs
-        .add_next_version("main")
+        .add_next_version(branch.get_branch_name())
         .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
+
+    // record the vectors for standby replicas before the version is made
+    // current, still under the version lock so the log follows the version
+    // order
+    if let Some(collection) = ctx
+        .ain_env
+        .collections_map
+        .get_collection(&dense_index.database_name)
+    {
+        append_to_oplog(
+            &oplog_path(&collection.get_path(&ctx.config.collections_path)),
+            vecs.iter().map(|(id, values, _)| OpLogEntry {
+                version_number: *version_number,
+                version_hash: current_version,
+                op: OpLogOp::Upsert {
+                    id: *id,
+                    values: values.clone(),
+                },
+            }),
+        )?;
+    }
+
     dense_index.set_current_version(current_version);
     update_current_version(&dense_index.lmdb, current_version)?;
 
@@ -474,27 +563,23 @@ pub fn run_upload(
 
     txn.commit()
         .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
+    drop(version_guard);
 
     // Insert vectors
     let bufman = dense_index.vec_raw_manager.get(current_version)?;
 
-    vecs.into_par_iter()
-        .map(|(id, vec)| {
-            let hash_vec = VectorId(id);
-            let vec_emb = RawVectorEmbedding {
-                raw_vec: Arc::new(vec),
-                hash_vec,
-            };
-
-            insert_embedding(
-                bufman.clone(),
-                dense_index.clone(),
-                &vec_emb,
-                current_version,
-            )
-        })
-        .collect::<Result<Vec<_>, _>>()?;
+    insert_embeddings_batch(vecs, ctx.config.upload_concurrency, |vec_emb| {
+        insert_embedding(
+            bufman.clone(),
+            dense_index.clone(),
+            &vec_emb,
+            current_version,
+        )
+    })?;
     bufman.flush()?;
+    for replica in dense_index.vec_raw_replica_bufmans(current_version)? {
+        replica.flush()?;
+    }
 
     let env = dense_index.lmdb.env.clone();
     let db = dense_index.lmdb.db.clone();
@@ -531,22 +616,114 @@ pub fn run_upload(
         write_node_to_file(node, &dense_index.index_manager)?;
     }
 
-    dense_index.vec_raw_manager.flush_all()?;
+    dense_index.flush_vec_raw()?;
     dense_index.index_manager.flush_all()?;
+    dense_index.record_node_locations()?;
 
     Ok(())
 }
 
+/// Rejects a batch before anything of it is stored if one of its vectors
+/// doesn't have `dim` dimensions, or has the id of an earlier one, which
+/// concurrent inserts would store in no particular order.
+fn check_upload_batch(
+    vecs: &[(u64, Vec<f32>, Option<serde_json::Value>)],
+    dim: usize,
+) -> Result<(), WaCustomError> {
+    let mut ids = HashSet::with_capacity(vecs.len());
+    for (index, (id, values, _)) in vecs.iter().enumerate() {
+        if values.len() != dim {
+            return Err(WaCustomError::DimensionMismatch(index, values.len(), dim));
+        }
+        if !ids.insert(*id) {
+            return Err(WaCustomError::DuplicateVectorId(index, *id));
+        }
+    }
+    Ok(())
+}
+
+/// Inserts every vector of a batch with `insert`, in parallel with at most
+/// `concurrency` inserts running at once. A failure is reported as
+/// `WaCustomError::InsertFailed` carrying the position of the vector in
+/// `vecs`, vectors inserted before it are kept.
+fn insert_embeddings_batch<F>(
+    vecs: Vec<(u64, Vec<f32>, Option<serde_json::Value>)>,
+    concurrency: usize,
+    insert: F,
+) -> Result<(), WaCustomError>
+where
+    F: Fn(RawVectorEmbedding) -> Result<(), WaCustomError> + Sync,
+{
+    // the batch is split into `concurrency` tasks, each inserting its share
+    // in order
+    let chunk_size = vecs.len().div_ceil(concurrency.max(1)).max(1);
+    let mut vecs = vecs.into_iter().enumerate().peekable();
+    let mut chunks = Vec::new();
+    while vecs.peek().is_some() {
+        chunks.push(vecs.by_ref().take(chunk_size).collect::<Vec<_>>());
+    }
+
+    chunks.into_par_iter().try_for_each(|chunk| {
+        chunk
+            .into_iter()
+            .try_for_each(|(index, (id, vec, metadata))| {
+                let vec_emb = RawVectorEmbedding {
+                    raw_vec: Arc::new(vec),
+                    hash_vec: VectorId(id),
+                    metadata,
+                };
+
+                insert(vec_emb).map_err(|e| WaCustomError::InsertFailed(index, e.to_string()))
+            })
+    })
+}
+
+/// `ef_search` overrides the one the dense index was created with.
+///
+/// With `exact` set, or below the configured exact search threshold, every
+/// vector is scored instead of searching the graph.
 pub async fn ann_vector_query(
     ctx: Arc<AppContext>,
     dense_index: Arc<DenseIndex>,
     query: Vec<f32>,
     k: Option<usize>,
+    ef_search: Option<u32>,
+    filter: Option<Filter>,
+    exact: bool,
+) -> Result<Vec<(VectorId, MetricResult)>, WaCustomError> {
+    if exact || below_exact_search_threshold(&ctx.config, &dense_index)? {
+        return exact_search(
+            &dense_index,
+            &query,
+            k.unwrap_or(usize::MAX),
+            filter.as_ref(),
+            false,
+        );
+    }
+
+    search_graph(
+        &ctx.config,
+        dense_index,
+        &query,
+        k,
+        ef_search,
+        filter.as_ref(),
+    )
+}
+
+/// Searches the HNSW graph of `dense_index` for the `k` vectors closest to
+/// `query`.
+fn search_graph(
+    config: &Config,
+    dense_index: Arc<DenseIndex>,
+    query: &[f32],
+    k: Option<usize>,
+    ef_search: Option<u32>,
+    filter: Option<&Filter>,
 ) -> Result<Vec<(VectorId, MetricResult)>, WaCustomError> {
-    let dense_index = dense_index.clone();
     let vec_hash = VectorId(u64::MAX - 1);
     let vector_list = dense_index.quantization_metric.quantize(
-        &query,
+        query,
         *dense_index.storage_type.clone().get(),
         *dense_index.values_range.read().unwrap(),
     )?;
@@ -556,27 +733,95 @@ pub async fn ann_vector_query(
         hash_vec: vec_hash.clone(),
     };
 
-    let hnsw_params = dense_index.hnsw_params.clone();
-    let hnsw_params_guard = hnsw_params.read().unwrap();
+    let hnsw_params = query_hnsw_params(&dense_index, ef_search);
+    let deadline = config.search.deadline();
 
     let results = ann_search(
-        &ctx.config,
+        config,
         dense_index.clone(),
         vec_emb,
         dense_index.get_root_vec(),
-        HNSWLevel(hnsw_params_guard.num_layers),
-        &*hnsw_params_guard,
+        HNSWLevel(hnsw_params.num_layers),
+        &hnsw_params,
+        filter,
+        deadline,
     )?;
-    let output = finalize_ann_results(dense_index, results, &query, k)?;
+    let output = finalize_ann_results(dense_index, results, query, k, filter)?;
     Ok(output)
 }
 
+/// Runs each of `queries` through both the graph search and the exact search,
+/// returning the share of the exact top `k` the graph search found and how
+/// long it took on average.
+pub fn evaluate_recall(
+    config: &Config,
+    dense_index: Arc<DenseIndex>,
+    queries: &[Vec<f32>],
+    k: usize,
+    ef_search: Option<u32>,
+) -> Result<(f32, Duration), WaCustomError> {
+    let mut found = 0;
+    let mut expected = 0;
+    let mut elapsed = Duration::ZERO;
+    for query in queries {
+        let start = Instant::now();
+        let approx = search_graph(config, dense_index.clone(), query, Some(k), ef_search, None)?;
+        elapsed += start.elapsed();
+
+        let approx: HashSet<_> = approx.into_iter().map(|(id, _)| id).collect();
+        // the graph search can't find what isn't indexed yet
+        let exact = exact_search(&dense_index, query, k, None, true)?;
+        found += exact.iter().filter(|(id, _)| approx.contains(id)).count();
+        expected += exact.len();
+    }
+
+    // an empty collection has nothing to miss
+    let recall = if expected == 0 {
+        1.0
+    } else {
+        found as f32 / expected as f32
+    };
+    Ok((recall, elapsed / queries.len().max(1) as u32))
+}
+
+/// Whether `dense_index` has too few indexed vectors for the graph search to
+/// be worth it.
+fn below_exact_search_threshold(
+    config: &Config,
+    dense_index: &DenseIndex,
+) -> Result<bool, WaCustomError> {
+    let Some(threshold) = config.search.exact_search_threshold else {
+        return Ok(false);
+    };
+    let (count_indexed, _) = get_embedding_counts(dense_index)?;
+    Ok(count_indexed < threshold)
+}
+
 pub async fn batch_ann_vector_query(
     ctx: Arc<AppContext>,
     dense_index: Arc<DenseIndex>,
     queries: Vec<Vec<f32>>,
     k: Option<usize>,
+    ef_search: Option<u32>,
+    filter: Option<Filter>,
 ) -> Result<Vec<Vec<(VectorId, MetricResult)>>, WaCustomError> {
+    if below_exact_search_threshold(&ctx.config, &dense_index)? {
+        return queries
+            .iter()
+            .map(|query| {
+                exact_search(
+                    &dense_index,
+                    query,
+                    k.unwrap_or(usize::MAX),
+                    filter.as_ref(),
+                    false,
+                )
+            })
+            .collect();
+    }
+
+    let hnsw_params = query_hnsw_params(&dense_index, ef_search);
+    let deadline = ctx.config.search.deadline();
     queries
         .into_par_iter()
         .map(|query| {
@@ -592,7 +837,6 @@ pub async fn batch_ann_vector_query(
                 hash_vec: vec_hash.clone(),
             };
 
-            let hnsw_params = dense_index.hnsw_params.read().unwrap();
             let results = ann_search(
                 &ctx.config,
                 dense_index.clone(),
@@ -600,13 +844,25 @@ pub async fn batch_ann_vector_query(
                 dense_index.get_root_vec(),
                 HNSWLevel(hnsw_params.num_layers),
                 &hnsw_params,
+                filter.as_ref(),
+                deadline,
             )?;
-            let output = finalize_ann_results(dense_index.clone(), results, &query, k)?;
+            let output =
+                finalize_ann_results(dense_index.clone(), results, &query, k, filter.as_ref())?;
             Ok::<_, WaCustomError>(output)
         })
         .collect()
 }
 
+/// The dense index's HNSW params, with `ef_search` replaced if given.
+fn query_hnsw_params(dense_index: &DenseIndex, ef_search: Option<u32>) -> HNSWHyperParams {
+    let mut hnsw_params = dense_index.hnsw_params.read().unwrap().clone();
+    if let Some(ef_search) = ef_search {
+        hnsw_params.ef_search = ef_search;
+    }
+    hnsw_params
+}
+
 pub async fn fetch_vector_neighbors(
     dense_index: Arc<DenseIndex>,
     vector_id: VectorId,
@@ -615,10 +871,104 @@ pub async fn fetch_vector_neighbors(
     return results.expect("Failed fetching vector neighbors");
 }
 
-#[allow(dead_code)]
-fn calculate_statistics(_: &[i32]) -> Option<Statistics> {
-    // Placeholder for calculating statistics
-    None
+/// Computes statistics over the current state of a dense index, loading
+/// nodes through the cache.
+///
+/// Nodes are found by the ids of the embeddings stored on the current
+/// branch, so nodes no other node links to anymore are counted too. A node
+/// built from an embedding that was overwritten since isn't.
+pub fn calculate_statistics(dense_index: &DenseIndex) -> Result<Statistics, WaCustomError> {
+    let num_layers = dense_index.hnsw_params.read().unwrap().num_layers;
+    let mut level_node_counts = vec![0; num_layers as usize + 1];
+    let mut neighbors_total = 0u64;
+    let mut nodes_without_neighbors = 0u64;
+    let mut values_count = 0u64;
+    let mut values_sum = 0.0;
+    let mut values_sqr_sum = 0.0;
+
+    let branch = dense_index.branch_of(dense_index.get_current_version())?;
+    for (id, offset) in embedding_offsets(dense_index, branch)? {
+        for level in 0..=num_layers {
+            let Some(item) = dense_index.find_node(&id, HNSWLevel(level))? else {
+                continue;
+            };
+            let visible = dense_index.get_visible_version(item)?;
+            let node = unsafe { &*visible }.try_get_data(&dense_index.cache)?;
+            // nodes that don't know their embedding predate overwrites
+            let is_current = node.prop.source.map_or(true, |source| {
+                source.version == offset.version
+                    && source.offset.map_or(true, |o| o == offset.offset)
+            });
+            if !is_current {
+                continue;
+            }
+
+            level_node_counts[level as usize] += 1;
+            if level == 0 {
+                let neighbors = node.get_neighbors();
+                neighbors_total += neighbors.len() as u64;
+                if neighbors.is_empty() {
+                    nodes_without_neighbors += 1;
+                }
+                for value in quantized_values(&node.prop.value, dense_index.dim) {
+                    values_count += 1;
+                    values_sum += value;
+                    values_sqr_sum += value * value;
+                }
+            }
+        }
+    }
+
+    let count = level_node_counts[0];
+    let avg_neighbor_degree = if count == 0 {
+        0.0
+    } else {
+        neighbors_total as f64 / count as f64
+    };
+    let (quantized_mean, quantized_variance) = if values_count == 0 {
+        (0.0, 0.0)
+    } else {
+        let mean = values_sum / values_count as f64;
+        (mean, values_sqr_sum / values_count as f64 - mean * mean)
+    };
+
+    Ok(Statistics {
+        count,
+        level_node_counts,
+        avg_neighbor_degree,
+        nodes_without_neighbors,
+        quantized_mean,
+        quantized_variance,
+    })
+}
+
+/// Unpacks the quantized values of a vector with `dim` dimensions.
+fn quantized_values(storage: &Storage, dim: usize) -> Vec<f64> {
+    match storage {
+        Storage::UnsignedByte { quant_vec, .. } => quant_vec.iter().map(|&v| v as f64).collect(),
+        Storage::SubByte { quant_vec, .. } => (0..dim)
+            .map(|i| {
+                // each entry of `quant_vec` holds one bit of every value
+                quant_vec
+                    .iter()
+                    .enumerate()
+                    .map(|(bit, plane)| (((plane[i / 8] >> (i % 8)) & 1) as u32) << bit)
+                    .sum::<u32>() as f64
+            })
+            .collect(),
+        Storage::HalfPrecisionFP { quant_vec, .. } => {
+            quant_vec.iter().map(|v| v.to_f64()).collect()
+        }
+        Storage::Sparse { indices, values } => {
+            let mut dense = vec![0.0; dim];
+            for (&i, &v) in indices.iter().zip(values) {
+                if let Some(slot) = dense.get_mut(i as usize) {
+                    *slot = v as f64;
+                }
+            }
+            dense
+        }
+    }
 }
 
 #[allow(dead_code)]
@@ -626,3 +976,289 @@ fn vector_knn(_vs: &Vec<f32>, _vecs: &Vec<f32>) -> Vec<(i8, i8, String, f64)> {
     // Placeholder for vector KNN
     vec![]
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app_context::tests::test_app_context;
+    use crate::vector_store::tests::{
+        index_vectors, setup_dense_index, test_config, unlink_vector,
+    };
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::{mpsc, Barrier};
+    use std::thread;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_higher_factor_levels_favors_lower_levels() {
+        let num_layers = 5;
+        let mean_level = |factor_levels: f64| {
+            let lp = Arc::new(generate_tuples(factor_levels, num_layers));
+            let samples = 10_000;
+            let total: i32 = (0..samples)
+                .map(|i| get_max_insert_level(i as f64 / samples as f64, lp.clone()))
+                .sum();
+            total as f64 / samples as f64
+        };
+
+        let low = mean_level(2.0);
+        let default = mean_level(10.0);
+        let high = mean_level(50.0);
+        assert!(low > default);
+        assert!(default > high);
+    }
+
+    #[test]
+    fn test_evaluate_recall_on_a_tiny_collection() {
+        let config = test_config();
+        let (dense_index, _dir) = setup_dense_index(HNSWHyperParams::default_from_config(&config));
+        let version = dense_index.get_current_version();
+        let bufman = dense_index.vec_raw_manager.get(version).unwrap();
+        for id in 0..6u64 {
+            let angle = id as f32 * 0.5;
+            let emb = RawVectorEmbedding {
+                raw_vec: Arc::new(vec![angle.cos(), angle.sin(), 0.2, -0.1]),
+                hash_vec: VectorId(id),
+                metadata: None,
+            };
+            insert_embedding(bufman.clone(), dense_index.clone(), &emb, version).unwrap();
+        }
+        bufman.flush().unwrap();
+        index_pending_embeddings(&config, &dense_index, 16).unwrap();
+
+        // with `ef_search` above the number of vectors the graph search sees
+        // every vector, so it finds the exact top k
+        let queries = vec![vec![1.0, 0.1, 0.2, -0.1], vec![-0.5, 0.8, 0.2, -0.1]];
+        let (recall, _) = evaluate_recall(&config, dense_index.clone(), &queries, 3, None).unwrap();
+        assert_eq!(recall, 1.0);
+
+        // asking for more than there is, the exact search returns all 6
+        let (recall, _) = evaluate_recall(&config, dense_index, &queries, 10, None).unwrap();
+        assert_eq!(recall, 1.0);
+    }
+
+    #[test]
+    fn test_evaluate_recall_counts_what_the_graph_search_misses() {
+        let config = test_config();
+        let (dense_index, _dir) = setup_dense_index(HNSWHyperParams::default_from_config(&config));
+        let vecs: Vec<_> = (0..6u64)
+            .map(|id| {
+                let angle = id as f32 * 0.5;
+                (id, vec![angle.cos(), angle.sin(), 0.2, -0.1])
+            })
+            .collect();
+        index_vectors(&config, &dense_index, &vecs);
+        // the graph search can no longer reach 0, the nearest to the first query
+        unlink_vector(&dense_index, VectorId(0));
+
+        let queries = vec![vec![1.0, 0.1, 0.2, -0.1], vec![-0.5, 0.8, 0.2, -0.1]];
+        let (recall, _) =
+            evaluate_recall(&config, dense_index.clone(), &queries, 1, Some(16)).unwrap();
+        assert_eq!(recall, 0.5);
+    }
+
+    #[actix_web::test]
+    async fn test_blocking_work_does_not_stall_the_executor() {
+        let executor_thread = thread::current().id();
+        let (release, released) = mpsc::channel::<()>();
+        let task = actix_web::rt::spawn(run_blocking(move || {
+            released
+                .recv_timeout(Duration::from_secs(5))
+                .map_err(|e| WaCustomError::LockError(e.to_string()))?;
+            Ok(thread::current().id())
+        }));
+
+        // the timer only fires if the executor isn't blocked by the task,
+        // which is still waiting to be released
+        actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+        assert!(!task.is_finished());
+
+        release.send(()).unwrap();
+        let worker_thread = task.await.unwrap().unwrap();
+        assert_ne!(worker_thread, executor_thread);
+
+        // errors of the blocking work reach the caller
+        let res = run_blocking(|| Err::<(), _>(WaCustomError::InvalidParams)).await;
+        assert!(matches!(res, Err(WaCustomError::InvalidParams)));
+    }
+
+    #[test]
+    fn test_failing_insert_reports_batch_index() {
+        let vecs = (0..8)
+            .map(|id| (id, vec![id as f32; 4], None))
+            .collect::<Vec<_>>();
+        let inserted = AtomicUsize::new(0);
+
+        let res = insert_embeddings_batch(vecs, 10, |emb| {
+            if emb.hash_vec == VectorId(5) {
+                return Err(WaCustomError::FsError("disk full".to_string()));
+            }
+            inserted.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        });
+
+        match res {
+            Err(WaCustomError::InsertFailed(index, msg)) => {
+                assert_eq!(index, 5);
+                assert!(msg.contains("disk full"));
+            }
+            _ => panic!("expected the failing insert to be reported"),
+        }
+        assert!(inserted.load(Ordering::SeqCst) <= 7);
+    }
+
+    #[test]
+    fn test_invalid_upload_batch_is_a_client_error() {
+        let vec = |id| (id, vec![0.1, 0.2, 0.3, 0.4], None);
+        assert!(check_upload_batch(&[vec(1), vec(2)], 4).is_ok());
+
+        let short = (3, vec![0.1, 0.2], None);
+        assert!(matches!(
+            check_upload_batch(&[vec(1), vec(2), short], 4),
+            Err(WaCustomError::DimensionMismatch(2, 2, 4))
+        ));
+        assert!(matches!(
+            check_upload_batch(&[vec(1), vec(2), vec(1)], 4),
+            Err(WaCustomError::DuplicateVectorId(2, 1))
+        ));
+
+        // nothing of a rejected batch is stored
+        let dir = tempdir().unwrap();
+        let ctx = test_app_context(dir.as_ref());
+        let (dense_index, _dir) =
+            setup_dense_index(HNSWHyperParams::default_from_config(&ctx.config));
+        let version = dense_index.get_current_version();
+        let res = run_upload(ctx, dense_index.clone(), vec![vec(1), vec(1)], None);
+        assert!(matches!(res, Err(WaCustomError::DuplicateVectorId(1, 1))));
+        assert_eq!(dense_index.get_current_version(), version);
+        assert!(get_embedding_by_id(dense_index, &VectorId(1))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_upload_concurrency_does_not_change_the_result() {
+        let vecs: Vec<_> = (0..100u64)
+            .map(|id| {
+                let x = id as f32 / 100.0;
+                (id, vec![x, 1.0 - x, 0.5 - x / 2.0, 0.3], None)
+            })
+            .collect();
+        let queries: Vec<_> = [3, 42, 77].iter().map(|&i| vecs[i].1.clone()).collect();
+
+        let upload = |concurrency: usize| {
+            let dir = tempdir().unwrap();
+            let mut ctx = test_app_context(dir.as_ref());
+            let config = &mut Arc::get_mut(&mut ctx).unwrap().config;
+            config.upload_concurrency = concurrency;
+            // every upload is indexed before the next one
+            config.upload_threshold = 1;
+            let (dense_index, _index_dir) =
+                setup_dense_index(HNSWHyperParams::default_from_config(&ctx.config));
+
+            for batch in vecs.chunks(25) {
+                run_upload(ctx.clone(), dense_index.clone(), batch.to_vec(), None).unwrap();
+            }
+            assert_eq!(get_embedding_counts(&dense_index).unwrap(), (100, 0));
+
+            let embeddings: Vec<_> = (0..100u64)
+                .map(|id| {
+                    get_embedding_by_id(dense_index.clone(), &VectorId(id))
+                        .unwrap()
+                        .unwrap()
+                        .raw_vec
+                        .to_vec()
+                })
+                .collect();
+            let results: Vec<Vec<_>> = queries
+                .iter()
+                .map(|query| {
+                    search_graph(&ctx.config, dense_index.clone(), query, Some(5), None, None)
+                        .unwrap()
+                        .into_iter()
+                        .map(|(id, dist)| (id, dist.score()))
+                        .collect()
+                })
+                .collect();
+            (embeddings, results)
+        };
+
+        let (embeddings, results) = upload(1);
+        for (id, embedding) in embeddings.iter().enumerate() {
+            assert_eq!(*embedding, vecs[id].1);
+        }
+        for (query, results) in [3u64, 42, 77].iter().zip(&results) {
+            assert_eq!(results[0].0, VectorId(*query));
+        }
+        assert_eq!(upload(8), (embeddings, results));
+    }
+
+    #[test]
+    fn test_if_version_must_match_current_version() {
+        let config = test_config();
+        let (dense_index, _dir) = setup_dense_index(HNSWHyperParams::default_from_config(&config));
+        let first = dense_index.get_current_version();
+        update_current_version(&dense_index.lmdb, first).unwrap();
+
+        assert!(check_if_version(&dense_index, None).is_ok());
+        assert!(check_if_version(&dense_index, Some(first)).is_ok());
+
+        // another client's write moves the collection to the next version
+        let (second, _) = dense_index.vcs.add_next_version("main").unwrap();
+        update_current_version(&dense_index.lmdb, second).unwrap();
+
+        let stale = check_if_version(&dense_index, Some(first)).unwrap_err();
+        assert!(matches!(
+            stale,
+            WaCustomError::VersionConflict(expected, current)
+                if expected == first && current == second
+        ));
+        assert!(check_if_version(&dense_index, Some(second)).is_ok());
+        assert!(check_if_version(&dense_index, None).is_ok());
+    }
+
+    #[test]
+    fn test_only_one_of_concurrent_writes_against_a_version_is_stored() {
+        let dir = tempdir().unwrap();
+        let ctx = test_app_context(dir.as_ref());
+        let (dense_index, _dir) =
+            setup_dense_index(HNSWHyperParams::default_from_config(&ctx.config));
+        let first = dense_index.get_current_version();
+        update_current_version(&dense_index.lmdb, first).unwrap();
+
+        // every writer read the collection at `first`, and writes at once
+        let writers = 8;
+        let barrier = Barrier::new(writers);
+        let results: Vec<_> = thread::scope(|s| {
+            let handles: Vec<_> = (0..writers as u64)
+                .map(|id| {
+                    let (ctx, dense_index, barrier) = (ctx.clone(), dense_index.clone(), &barrier);
+                    s.spawn(move || {
+                        barrier.wait();
+                        let vecs = vec![(id, vec![0.1, 0.2, 0.3, 0.4], None)];
+                        run_upload(ctx, dense_index, vecs, Some(first))
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+
+        let current = retrieve_current_version(&dense_index.lmdb).unwrap();
+        assert_ne!(current, first);
+        assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 1);
+        for (id, result) in results.iter().enumerate() {
+            let exists = vector_exists(&dense_index, &VectorId(id as u64)).unwrap();
+            match result {
+                Ok(()) => assert!(exists),
+                Err(err) => {
+                    assert!(matches!(
+                        err,
+                        WaCustomError::VersionConflict(expected, at)
+                            if *expected == first && *at == current
+                    ));
+                    assert!(!exists);
+                }
+            }
+        }
+    }
+}
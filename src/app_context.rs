@@ -27,3 +27,30 @@ impl AppContext {
         })
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::AppContext;
+    use crate::models::types::open_app_env;
+    use crate::vector_store::tests::test_config;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    /// An `AppContext` as the server sets one up, with its database and
+    /// collections stored in `dir`.
+    pub(crate) fn test_app_context(dir: &Path) -> Arc<AppContext> {
+        let mut config = test_config();
+        config.collections_path = dir.join("collections");
+        config.thread_pool.pool_size = 2;
+        let ain_env = open_app_env(&dir.join("_mdb"), &config).unwrap();
+        let threadpool = rayon::ThreadPoolBuilder::new()
+            .num_threads(config.thread_pool.pool_size)
+            .build()
+            .unwrap();
+        Arc::new(AppContext {
+            config,
+            threadpool,
+            ain_env,
+        })
+    }
+}
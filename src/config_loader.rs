@@ -1,5 +1,6 @@
 use serde::{Deserialize, Deserializer};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::time::{Duration, Instant};
 use std::{fs, path::PathBuf};
 use std::{io, vec};
 
@@ -13,7 +14,32 @@ pub struct Config {
     pub search: Search,
     pub upload_threshold: u32,
     pub upload_process_batch_size: usize,
+    /// Number of vectors of an upload inserted at once.
+    #[serde(default = "default_upload_concurrency")]
+    pub upload_concurrency: usize,
     pub flush_eagerness_factor: f32,
+    /// Number of graph nodes of a transaction written to the index files
+    /// between flushes, they are taken off the queue once flushed.
+    #[serde(default = "default_commit_flush_batch_size")]
+    pub commit_flush_batch_size: usize,
+    #[serde(default)]
+    pub prop_file: PropFile,
+    /// Directory holding one directory per collection, with its index, raw
+    /// vector and prop files.
+    #[serde(default = "default_collections_path")]
+    pub collections_path: PathBuf,
+}
+
+fn default_upload_concurrency() -> usize {
+    10
+}
+
+fn default_commit_flush_batch_size() -> usize {
+    1000
+}
+
+fn default_collections_path() -> PathBuf {
+    PathBuf::from("./collections")
 }
 
 #[derive(Deserialize, Clone)]
@@ -172,6 +198,38 @@ pub struct Indexing {
 #[derive(Deserialize, Clone)]
 pub struct Search {
     pub shortlist_size: usize,
+    /// Time in milliseconds a search may traverse the graph for before it
+    /// returns the best results found so far. Unbounded if not set.
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+    /// Collections with fewer indexed vectors than this are searched by
+    /// scoring every vector rather than through the graph. Never if not set.
+    #[serde(default)]
+    pub exact_search_threshold: Option<u32>,
+}
+
+impl Search {
+    /// Deadline of a search starting now, `None` if searches are unbounded.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.timeout_ms
+            .map(|timeout_ms| Instant::now() + Duration::from_millis(timeout_ms))
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct PropFile {
+    /// Size in bytes past which writes to `prop.data` start reporting that
+    /// the file is due for compaction. Writes are never rejected.
+    pub soft_size_limit: u64,
+}
+
+impl Default for PropFile {
+    fn default() -> Self {
+        // prop offsets are stored as `u32`, so warn well before hitting 4 GiB
+        Self {
+            soft_size_limit: 3 * 1024 * 1024 * 1024,
+        }
+    }
 }
 
 pub fn load_config() -> Config {
@@ -1,8 +1,8 @@
 use nom::{
     branch::alt,
-    bytes::complete::{tag, take_while1},
-    character::complete::{alpha1, alphanumeric1, char, multispace0},
-    combinator::recognize,
+    bytes::complete::{tag, take_until, take_while1},
+    character::complete::{alpha1, alphanumeric1, char, multispace0, multispace1, not_line_ending},
+    combinator::{recognize, value},
     multi::many0,
     sequence::{delimited, pair, preceded},
     IResult,
@@ -19,6 +19,18 @@ where
     delimited(multispace0, inner, multispace0)
 }
 
+/// Skips whitespace along with `//` and `/* */` comments.
+pub fn ws_comments0(input: &str) -> IResult<&str, ()> {
+    value(
+        (),
+        many0(alt((
+            multispace1,
+            preceded(tag("//"), not_line_ending),
+            delimited(tag("/*"), take_until("*/"), tag("*/")),
+        ))),
+    )(input)
+}
+
 pub fn parse_identifier(input: &str) -> IResult<&str, &str> {
     recognize(pair(
         alt((alpha1, tag("_"))),
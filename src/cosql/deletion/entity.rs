@@ -0,0 +1,86 @@
+use nom::{
+    character::complete::char,
+    combinator::{map, opt},
+    sequence::{preceded, tuple},
+    IResult,
+};
+
+use super::Deletion;
+use crate::cosql::common::{parse_identifier, parse_variable, ws, ws_tag};
+
+pub fn parse_entity_deletion(input: &str) -> IResult<&str, Deletion> {
+    map(
+        tuple((
+            ws_tag("delete"),
+            ws(parse_variable),
+            opt(preceded(ws_tag("has"), ws(parse_identifier))),
+            ws(char(';')),
+        )),
+        |(_, variable, attribute, _)| match attribute {
+            Some(attribute) => Deletion::Attribute {
+                variable: variable.to_owned(),
+                attribute: attribute.to_owned(),
+            },
+            None => Deletion::Entity {
+                variable: variable.to_owned(),
+            },
+        },
+    )(input)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_entity_deletion() {
+        let test_cases = [
+            (
+                "delete $developer;",
+                Deletion::Entity {
+                    variable: "developer".to_owned(),
+                },
+            ),
+            (
+                "delete
+                    $project ;",
+                Deletion::Entity {
+                    variable: "project".to_owned(),
+                },
+            ),
+        ];
+
+        for (input, expected) in test_cases {
+            let (_, result) = parse_entity_deletion(input).unwrap();
+            assert_eq!(result, expected);
+        }
+    }
+
+    #[test]
+    fn test_parse_attribute_deletion() {
+        let test_cases = [
+            (
+                "delete $developer has name;",
+                Deletion::Attribute {
+                    variable: "developer".to_owned(),
+                    attribute: "name".to_owned(),
+                },
+            ),
+            (
+                "delete $project has end_date;",
+                Deletion::Attribute {
+                    variable: "project".to_owned(),
+                    attribute: "end_date".to_owned(),
+                },
+            ),
+        ];
+
+        for (input, expected) in test_cases {
+            let (_, result) = parse_entity_deletion(input).unwrap();
+            assert_eq!(result, expected);
+        }
+
+        // the attribute to delete has to be named
+        assert!(parse_entity_deletion("delete $developer has;").is_err());
+    }
+}
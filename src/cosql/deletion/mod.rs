@@ -0,0 +1,104 @@
+pub mod entity;
+
+use nom::{
+    character::complete::char,
+    combinator::{map, opt},
+    multi::many1,
+    sequence::tuple,
+    IResult,
+};
+
+use super::{common::ws, pattern::parse_patterns0, Patterns};
+
+pub use entity::parse_entity_deletion;
+
+pub type Deletions = Vec<Deletion>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Deletion {
+    /// `delete $x;`, removes the entity bound to `$x`
+    Entity { variable: String },
+    /// `delete $x has name;`, removes a single attribute of the entity
+    Attribute { variable: String, attribute: String },
+}
+
+/// Deletes whatever the patterns match, like a `Query` that ends in
+/// `delete` statements instead of `get`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeleteQuery {
+    pub patterns: Patterns,
+    pub deletions: Deletions,
+}
+
+pub fn parse_delete_query(input: &str) -> IResult<&str, DeleteQuery> {
+    map(
+        tuple((
+            parse_patterns0,
+            opt(ws(char(';'))),
+            many1(parse_entity_deletion),
+        )),
+        |(patterns, _, deletions)| DeleteQuery {
+            patterns,
+            deletions,
+        },
+    )(input)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cosql::{insertion::Attribute, pattern::entity::EntityPattern, Pattern, Value};
+
+    #[test]
+    fn test_delete_query_parser() {
+        // the `parse_delete_query` function assumes the `match` part is already consumed
+        let values = [
+            (
+                "$x isa person; delete $x;",
+                DeleteQuery {
+                    patterns: vec![Pattern::EntityPattern(EntityPattern {
+                        variable: "x".to_string(),
+                        entity_type: "person".to_string(),
+                        attributes: vec![],
+                    })],
+                    deletions: vec![Deletion::Entity {
+                        variable: "x".to_string(),
+                    }],
+                },
+            ),
+            (
+                r#"$x isa person (
+                        name: "The Rust Dev"
+                    )
+                delete $x has name;
+                delete $x has age;"#,
+                DeleteQuery {
+                    patterns: vec![Pattern::EntityPattern(EntityPattern {
+                        variable: "x".to_string(),
+                        entity_type: "person".to_string(),
+                        attributes: vec![Attribute {
+                            name: "name".to_string(),
+                            value: Value::String("The Rust Dev".to_string()),
+                        }],
+                    })],
+                    deletions: vec![
+                        Deletion::Attribute {
+                            variable: "x".to_string(),
+                            attribute: "name".to_string(),
+                        },
+                        Deletion::Attribute {
+                            variable: "x".to_string(),
+                            attribute: "age".to_string(),
+                        },
+                    ],
+                },
+            ),
+        ];
+
+        for (source, expected) in values {
+            let (_, parsed) = parse_delete_query(source).unwrap();
+
+            assert_eq!(parsed, expected);
+        }
+    }
+}
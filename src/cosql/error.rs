@@ -0,0 +1,60 @@
+use std::fmt;
+
+/// A statement that failed to parse, located by the byte offset of the first
+/// token that couldn't be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CosQLError {
+    pub offset: usize,
+    /// the input left unparsed, starting at `offset`
+    pub remaining: String,
+    pub message: String,
+    /// index of the statement that failed, when parsing a whole program
+    pub statement: Option<usize>,
+}
+
+impl CosQLError {
+    /// `remaining` must be a suffix of `input`, leading whitespace is skipped
+    /// to point at the offending token.
+    pub fn new(input: &str, remaining: &str, message: impl Into<String>) -> Self {
+        let remaining = remaining.trim_start();
+        Self {
+            offset: input.len() - remaining.len(),
+            remaining: remaining.to_string(),
+            message: message.into(),
+            statement: None,
+        }
+    }
+
+    pub fn in_statement(self, index: usize) -> Self {
+        Self {
+            statement: Some(index),
+            ..self
+        }
+    }
+
+    pub(crate) fn from_nom(input: &str, error: nom::Err<nom::error::Error<&str>>) -> Self {
+        match error {
+            nom::Err::Incomplete(_) => Self::new(input, "", "unexpected end of input"),
+            nom::Err::Error(e) | nom::Err::Failure(e) => {
+                let remaining = e.input.trim_start();
+                let message = match remaining.split_whitespace().next() {
+                    Some(token) => format!("unexpected `{}`", token),
+                    None => "unexpected end of input".to_string(),
+                };
+                Self::new(input, remaining, message)
+            }
+        }
+    }
+}
+
+impl fmt::Display for CosQLError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at offset {}", self.message, self.offset)?;
+        if let Some(index) = self.statement {
+            write!(f, " in statement {}", index)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for CosQLError {}
@@ -14,6 +14,8 @@ use super::{
     value::parse_value,
     Value,
 };
+use std::collections::HashSet;
+
 pub use entity::EntityInsertion;
 pub use relationship::RelationshipInsertion;
 
@@ -25,6 +27,18 @@ pub struct Attribute {
     pub value: Value,
 }
 
+/// Rejects attribute lists that name the same attribute more than once, the
+/// value to insert would be ambiguous.
+pub fn validate_attributes(attributes: &[Attribute]) -> Result<(), String> {
+    let mut names = HashSet::new();
+    for attribute in attributes {
+        if !names.insert(attribute.name.as_str()) {
+            return Err(format!("duplicate attribute `{}`", attribute.name));
+        }
+    }
+    Ok(())
+}
+
 pub fn parse_attributes0(input: &str) -> IResult<&str, Attributes> {
     delimited(
         ws(char('(')),
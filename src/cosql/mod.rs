@@ -3,6 +3,8 @@ pub mod compute_clause;
 pub mod condition;
 pub mod data_type;
 pub mod definition;
+pub mod deletion;
+mod error;
 pub mod expression;
 pub mod inference;
 pub mod insertion;
@@ -12,22 +14,29 @@ pub mod query;
 pub mod rule;
 pub mod value;
 
-use common::ws_tag;
-use nom::{branch::alt, combinator::map, multi::many0, sequence::preceded, IResult};
+use common::{ws_comments0, ws_tag};
+use nom::{
+    combinator::map,
+    multi::many0,
+    sequence::{pair, preceded},
+    IResult,
+};
 
 use definition::{
     entity::parse_entity_definition, relationship::parse_relationship_definition, EntityDefinition,
     RelationshipDefinition,
 };
+use deletion::{parse_delete_query, DeleteQuery};
 use insertion::{
-    entity::parse_entity_insertion, relationship::parse_relationship_insertion, EntityInsertion,
-    RelationshipInsertion,
+    entity::parse_entity_insertion, relationship::parse_relationship_insertion,
+    validate_attributes, EntityInsertion, RelationshipInsertion,
 };
 use query::{parse_query, Query};
 use rule::{parse_rule, Rule};
 
 pub use compute_clause::{ComputeClause, ComputeClauses};
 pub use data_type::DataType;
+pub use error::CosQLError;
 pub use expression::Expression;
 pub use inference::{Inference, Inferences};
 pub use pattern::{Pattern, Patterns};
@@ -43,49 +52,129 @@ pub enum CosQLStatement {
     EntityInsertion(EntityInsertion),
     RelationshipInsertion(RelationshipInsertion),
     Query(Query),
+    Deletion(DeleteQuery),
     Rule(Rule),
 }
 
+impl CosQLStatement {
+    /// Checks what the grammar alone can't, like insertions naming an
+    /// attribute twice.
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            Self::EntityInsertion(insertion) => validate_attributes(&insertion.attributes),
+            Self::RelationshipInsertion(insertion) => validate_attributes(&insertion.attributes),
+            _ => Ok(()),
+        }
+    }
+}
+
 pub fn parse_cosql_statements(input: &str) -> IResult<&str, CosQLStatements> {
     many0(parse_cosql_statement)(input)
 }
 
+/// Parses a single statement, the whole of `input` must be consumed apart
+/// from trailing whitespace.
+pub fn parse_cosql(input: &str) -> Result<CosQLStatement, CosQLError> {
+    let (remaining, statement) =
+        parse_cosql_statement(input).map_err(|e| CosQLError::from_nom(input, e))?;
+    if !remaining.trim().is_empty() {
+        return Err(CosQLError::new(
+            input,
+            remaining,
+            "unexpected input after the statement",
+        ));
+    }
+    statement
+        .validate()
+        .map_err(|message| CosQLError::new(input, input, message))?;
+    Ok(statement)
+}
+
+/// Parses a script of statements, each terminated by `;`, separated by
+/// whitespace and comments. Errors carry the index of the failed statement.
+pub fn parse_program(input: &str) -> Result<CosQLStatements, CosQLError> {
+    let mut statements = Vec::new();
+    let (mut remaining, _) = ws_comments0(input).map_err(|e| CosQLError::from_nom(input, e))?;
+    while !remaining.is_empty() {
+        let (rest, statement) = parse_cosql_statement(remaining)
+            .map_err(|e| CosQLError::from_nom(input, e).in_statement(statements.len()))?;
+        statement.validate().map_err(|message| {
+            CosQLError::new(input, remaining, message).in_statement(statements.len())
+        })?;
+        statements.push(statement);
+        (remaining, _) = ws_comments0(rest).map_err(|e| CosQLError::from_nom(input, e))?;
+    }
+    Ok(statements)
+}
+
+type StatementParser = fn(&str) -> IResult<&str, CosQLStatement>;
+
 pub fn parse_cosql_statement(input: &str) -> IResult<&str, CosQLStatement> {
-    alt((
-        preceded(
-            ws_tag("define"),
-            alt((
-                preceded(
-                    ws_tag("entity"),
-                    map(parse_entity_definition, |ed| {
-                        CosQLStatement::EntityDefinition(ed)
-                    }),
+    let parsers: [StatementParser; 7] = [
+        |input| {
+            preceded(
+                pair(ws_tag("define"), ws_tag("entity")),
+                map(parse_entity_definition, CosQLStatement::EntityDefinition),
+            )(input)
+        },
+        |input| {
+            preceded(
+                pair(ws_tag("define"), ws_tag("relationship")),
+                map(
+                    parse_relationship_definition,
+                    CosQLStatement::RelationshipDefinition,
                 ),
-                preceded(
-                    ws_tag("relationship"),
-                    map(parse_relationship_definition, |rd| {
-                        CosQLStatement::RelationshipDefinition(rd)
-                    }),
+            )(input)
+        },
+        |input| {
+            preceded(
+                pair(ws_tag("define"), ws_tag("rule")),
+                map(parse_rule, CosQLStatement::Rule),
+            )(input)
+        },
+        |input| {
+            preceded(
+                ws_tag("insert"),
+                map(parse_entity_insertion, CosQLStatement::EntityInsertion),
+            )(input)
+        },
+        |input| {
+            preceded(
+                ws_tag("insert"),
+                map(
+                    parse_relationship_insertion,
+                    CosQLStatement::RelationshipInsertion,
                 ),
-                preceded(ws_tag("rule"), map(parse_rule, |r| CosQLStatement::Rule(r))),
-            )),
-        ),
-        preceded(
-            ws_tag("insert"),
-            alt((
-                map(parse_entity_insertion, |ei| {
-                    CosQLStatement::EntityInsertion(ei)
-                }),
-                map(parse_relationship_insertion, |ri| {
-                    CosQLStatement::RelationshipInsertion(ri)
-                }),
-            )),
-        ),
-        preceded(
-            ws_tag("match"),
-            map(parse_query, |q| CosQLStatement::Query(q)),
-        ),
-    ))(input)
+            )(input)
+        },
+        |input| preceded(ws_tag("match"), map(parse_query, CosQLStatement::Query))(input),
+        |input| {
+            preceded(
+                ws_tag("match"),
+                map(parse_delete_query, CosQLStatement::Deletion),
+            )(input)
+        },
+    ];
+
+    // like `alt`, but fails with the error of the parser that got furthest
+    // into `input`, the one closest to the actual mistake
+    let mut furthest: Option<nom::error::Error<&str>> = None;
+    for parser in parsers {
+        match parser(input) {
+            Err(nom::Err::Error(e)) => {
+                if furthest
+                    .as_ref()
+                    .map_or(true, |f| e.input.len() < f.input.len())
+                {
+                    furthest = Some(e);
+                }
+            }
+            result => return result,
+        }
+    }
+    Err(nom::Err::Error(
+        furthest.expect("there is a parser for every statement"),
+    ))
 }
 
 #[cfg(test)]
@@ -101,6 +190,124 @@ mod tests {
         *,
     };
 
+    #[test]
+    fn test_parse_cosql_errors() {
+        // the attribute is missing its colon
+        let input = r#"insert $rust_dev isa person (
+                    name "The Rust Dev"
+                );"#;
+        let err = parse_cosql(input).unwrap_err();
+        assert_eq!(err.offset, input.find(r#""The Rust Dev""#).unwrap());
+        assert!(err.remaining.starts_with(r#""The Rust Dev""#));
+        assert_eq!(err.message, r#"unexpected `"The`"#);
+
+        let input = "insert $rust_dev isa person (age: 54); extra";
+        let err = parse_cosql(input).unwrap_err();
+        assert_eq!(err.offset, input.find("extra").unwrap());
+        assert_eq!(err.remaining, "extra");
+
+        let input = "insert $rust_dev isa";
+        let err = parse_cosql(input).unwrap_err();
+        assert_eq!(err.offset, input.len());
+        assert_eq!(err.message, "unexpected end of input");
+
+        assert!(matches!(
+            parse_cosql("insert $rust_dev isa person (age: 54);\n"),
+            Ok(CosQLStatement::EntityInsertion(_))
+        ));
+    }
+
+    #[test]
+    fn test_duplicate_attributes_are_rejected() {
+        let input = r#"insert $rust_dev isa person (name: "a", age: 54, name: "b");"#;
+        let err = parse_cosql(input).unwrap_err();
+        assert_eq!(err.message, "duplicate attribute `name`");
+        assert_eq!(err.offset, 0);
+
+        let script = r#"
+            insert $rust_dev isa person (name: "a", age: 54);
+            insert $relation1 (
+                project: $rust_project,
+                assignee: $rust_dev
+            ) forms works_in (salary: 1, salary: 2);
+        "#;
+        let err = parse_program(script).unwrap_err();
+        assert_eq!(err.statement, Some(1));
+        assert_eq!(err.message, "duplicate attribute `salary`");
+        assert_eq!(err.offset, script.find("insert $relation1").unwrap());
+
+        assert!(parse_cosql(r#"insert $rust_dev isa person (name: "a", age: 54);"#).is_ok());
+    }
+
+    #[test]
+    fn test_parse_program() {
+        let script = r#"
+            // people first
+            insert $rust_dev isa person (name: "The Rust Dev", age: 54);
+            insert $rust_project isa project (name: "A Rust Project");
+            /* then who works on what */
+            insert $relation1 (
+                project: $rust_project,
+                assignee: $rust_dev
+            ) forms assigned_to;
+        "#;
+        let statements = parse_program(script).unwrap();
+        assert_eq!(statements.len(), 3);
+        assert!(matches!(
+            &statements[0],
+            CosQLStatement::EntityInsertion(ei) if ei.variable == "rust_dev"
+        ));
+        assert!(matches!(
+            &statements[1],
+            CosQLStatement::EntityInsertion(ei) if ei.entity_type == "project"
+        ));
+        assert!(matches!(
+            &statements[2],
+            CosQLStatement::RelationshipInsertion(ri) if ri.relationship_type == "assigned_to"
+        ));
+        assert_eq!(parse_program("  // nothing\n").unwrap(), vec![]);
+
+        let script = r#"
+            insert $rust_dev isa person (name: "The Rust Dev");
+            insert $rust_project isa project (name: );
+            insert $other isa person (name: "Other");
+        "#;
+        let err = parse_program(script).unwrap_err();
+        assert_eq!(err.statement, Some(1));
+        // at the missing value
+        assert_eq!(err.offset, script.find(": );").unwrap() + 2);
+        assert!(err.remaining.starts_with(");"));
+    }
+
+    #[test]
+    fn test_parse_delete_statement() {
+        let statements = parse_program(
+            r#"
+            match $x isa person (name: "The Rust Dev"); delete $x has age;
+            match $x isa person; delete $x;
+        "#,
+        )
+        .unwrap();
+        assert_eq!(statements.len(), 2);
+        assert!(matches!(
+            &statements[0],
+            CosQLStatement::Deletion(dq) if dq.deletions == vec![deletion::Deletion::Attribute {
+                variable: "x".to_string(),
+                attribute: "age".to_string(),
+            }]
+        ));
+        assert!(matches!(
+            &statements[1],
+            CosQLStatement::Deletion(dq) if dq.patterns.len() == 1
+        ));
+
+        // a query still ends in `get`
+        assert!(matches!(
+            parse_cosql("match $x isa person get $x;"),
+            Ok(CosQLStatement::Query(_))
+        ));
+    }
+
     #[test]
     fn test_cosql_statement_parser() {
         let values = [
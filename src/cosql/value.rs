@@ -3,6 +3,7 @@ use nom::{
     bytes::complete::tag,
     character::complete::{char, digit1},
     combinator::{map, map_res, opt, recognize},
+    error::{Error, ErrorKind},
     sequence::{pair, tuple},
     IResult,
 };
@@ -25,8 +26,24 @@ pub enum Value {
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Date(pub u8, pub u8, pub u16);
 
+impl Date {
+    /// whether the day exists in the month, leap years included
+    pub fn is_valid(&self) -> bool {
+        let &Date(day, month, year) = self;
+        let is_leap_year = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+        let days_in_month = match month {
+            1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+            4 | 6 | 9 | 11 => 30,
+            2 if is_leap_year => 29,
+            2 => 28,
+            _ => return false,
+        };
+        (1..=days_in_month).contains(&day)
+    }
+}
+
 pub fn parse_date(input: &str) -> IResult<&str, Date> {
-    let (input, (d, _, m, _, y)) = tuple((
+    let (rest, (d, _, m, _, y)) = tuple((
         map_res(digit1::<&str, _>, str::parse),
         char('-'),
         map_res(digit1::<&str, _>, str::parse),
@@ -34,7 +51,13 @@ pub fn parse_date(input: &str) -> IResult<&str, Date> {
         map_res(digit1::<&str, _>, str::parse),
     ))(input)?;
 
-    Ok((input, Date(d, m, y)))
+    let date = Date(d, m, y);
+    // it can only have been meant as a date, so it's not parsed as anything else
+    if !date.is_valid() {
+        return Err(nom::Err::Failure(Error::new(input, ErrorKind::Verify)));
+    }
+
+    Ok((rest, date))
 }
 
 pub fn parse_value(input: &str) -> IResult<&str, Value> {
@@ -77,6 +100,33 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_impossible_dates_are_rejected() {
+        assert_eq!(parse_date("29-02-2020").unwrap().1, Date(29, 2, 2020));
+        assert_eq!(parse_date("29-02-2000").unwrap().1, Date(29, 2, 2000));
+
+        for source in [
+            "29-02-2021",
+            "29-02-1900",
+            "31-04-2000",
+            "32-01-2000",
+            "00-01-2000",
+            "01-13-2000",
+        ] {
+            assert!(
+                matches!(parse_date(source), Err(nom::Err::Failure(_))),
+                "{} should be rejected",
+                source
+            );
+        }
+
+        // and not read back as an integer instead
+        assert!(matches!(
+            parse_value("31-04-2000"),
+            Err(nom::Err::Failure(_))
+        ));
+    }
+
     #[test]
     fn test_value_parser() {
         let values = [
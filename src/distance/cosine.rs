@@ -1,6 +1,9 @@
 use serde::{Deserialize, Serialize};
 
-use super::{DistanceError, DistanceFunction};
+use super::{
+    sparse::{dot_product_sorted, magnitude},
+    DistanceError, DistanceFunction,
+};
 use crate::{
     models::dot_product::{
         dot_product_binary, dot_product_f16, dot_product_octal, dot_product_quaternary,
@@ -80,6 +83,23 @@ impl DistanceFunction for CosineSimilarity {
                 let dot_product = dot_product_f16(x_vec, y_vec);
                 cosine_similarity_from_dot_product(dot_product, *x_mag, *y_mag)
             }
+            (
+                Storage::Sparse {
+                    indices: x_indices,
+                    values: x_values,
+                },
+                Storage::Sparse {
+                    indices: y_indices,
+                    values: y_values,
+                },
+            ) => {
+                let dot_product = dot_product_sorted(x_indices, x_values, y_indices, y_values);
+                cosine_similarity_from_dot_product(
+                    dot_product,
+                    magnitude(x_values),
+                    magnitude(y_values),
+                )
+            }
             _ => Err(DistanceError::StorageMismatch),
         }
     }
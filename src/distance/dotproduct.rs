@@ -1,4 +1,4 @@
-use super::{DistanceError, DistanceFunction};
+use super::{sparse::dot_product_sorted, DistanceError, DistanceFunction};
 use crate::models::dot_product::{
     dot_product_binary, dot_product_f16, dot_product_octal, dot_product_quaternary, dot_product_u8,
 };
@@ -53,6 +53,18 @@ impl DistanceFunction for DotProductDistance {
                 };
                 Ok(DotProductDistance(dot_product))
             }
+            (
+                Storage::Sparse {
+                    indices: x_indices,
+                    values: x_values,
+                },
+                Storage::Sparse {
+                    indices: y_indices,
+                    values: y_values,
+                },
+            ) => Ok(DotProductDistance(dot_product_sorted(
+                x_indices, x_values, y_indices, y_values,
+            ))),
             _ => Err(DistanceError::StorageMismatch),
         }
     }
@@ -39,7 +39,8 @@ pub fn euclidean_distance_u8(x: &[u8], y: &[u8]) -> EuclideanDistance {
         x.iter()
             .zip(y.iter())
             .map(|(&a, &b)| {
-                let diff = (a as i16) - (b as i16);
+                // squares of differences up to 255 don't fit an i16
+                let diff = (a as i32) - (b as i32);
                 (diff * diff) as f32
             })
             .sum::<f32>()
@@ -59,3 +60,14 @@ pub fn euclidean_distance_f16(x: &[f16], y: &[f16]) -> EuclideanDistance {
             .sqrt(),
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::euclidean_distance_u8;
+
+    #[test]
+    fn test_euclidean_distance_u8_handles_the_full_range() {
+        assert_eq!(euclidean_distance_u8(&[0, 255], &[255, 0]).0, 255.0 * 2f32.sqrt());
+        assert_eq!(euclidean_distance_u8(&[255; 4], &[0; 4]).0, 510.0);
+    }
+}
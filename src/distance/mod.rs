@@ -2,6 +2,7 @@ pub mod cosine;
 pub mod dotproduct;
 pub mod euclidean;
 pub mod hamming;
+pub mod sparse;
 
 use crate::storage::Storage;
 
@@ -0,0 +1,137 @@
+use std::borrow::Cow;
+use std::cmp::Ordering;
+
+use super::{cosine::CosineSimilarity, DistanceError};
+use crate::models::types::SparseVector;
+
+/// Dot product of two sparse vectors given as dimension indices, sorted in
+/// ascending order, and their values. Dimensions present in only one of them
+/// contribute nothing.
+pub fn dot_product_sorted<T: Copy + Into<f32>>(
+    x_indices: &[u32],
+    x_values: &[T],
+    y_indices: &[u32],
+    y_values: &[T],
+) -> f32 {
+    let (mut i, mut j) = (0, 0);
+    let mut dot_product = 0.0;
+    while i < x_indices.len() && j < y_indices.len() {
+        match x_indices[i].cmp(&y_indices[j]) {
+            Ordering::Less => i += 1,
+            Ordering::Greater => j += 1,
+            Ordering::Equal => {
+                dot_product += x_values[i].into() * y_values[j].into();
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    dot_product
+}
+
+/// Magnitude of a sparse vector from its values.
+pub fn magnitude<T: Copy + Into<f32>>(values: &[T]) -> f32 {
+    values
+        .iter()
+        .map(|&v| {
+            let v: f32 = v.into();
+            v * v
+        })
+        .sum::<f32>()
+        .sqrt()
+}
+
+/// Splits the entries of `vector` into indices and values, sorted by index.
+fn sorted_entries(vector: &SparseVector) -> (Vec<u32>, Vec<f32>) {
+    let mut entries = Cow::Borrowed(&vector.entries[..]);
+    if !entries.windows(2).all(|w| w[0].0 <= w[1].0) {
+        entries
+            .to_mut()
+            .sort_unstable_by_key(|&(dim_index, _)| dim_index);
+    }
+    entries.iter().copied().unzip()
+}
+
+/// Dot product of two sparse vectors, their entries don't need to be sorted.
+pub fn sparse_dot(x: &SparseVector, y: &SparseVector) -> f32 {
+    let (x_indices, x_values) = sorted_entries(x);
+    let (y_indices, y_values) = sorted_entries(y);
+    dot_product_sorted(&x_indices, &x_values, &y_indices, &y_values)
+}
+
+/// Cosine similarity of two sparse vectors, their entries don't need to be
+/// sorted. Fails for vectors without any non zero value.
+pub fn sparse_cosine(
+    x: &SparseVector,
+    y: &SparseVector,
+) -> Result<CosineSimilarity, DistanceError> {
+    let (x_indices, x_values) = sorted_entries(x);
+    let (y_indices, y_values) = sorted_entries(y);
+    let dot_product = dot_product_sorted(&x_indices, &x_values, &y_indices, &y_values);
+    let denominator = magnitude(&x_values) * magnitude(&y_values);
+    if denominator == 0.0 {
+        return Err(DistanceError::CalculationError);
+    }
+    Ok(CosineSimilarity(dot_product / denominator))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sparse_cosine, sparse_dot};
+    use crate::distance::cosine::CosineSimilarity;
+    use crate::models::types::SparseVector;
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 1e-6,
+            "expected {}, got {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn test_disjoint_vectors() {
+        let x = SparseVector::new(0, vec![(1, 0.5), (4, 0.2)]);
+        let y = SparseVector::new(1, vec![(2, 0.7), (9, 0.1)]);
+        assert_close(sparse_dot(&x, &y), 0.0);
+        let CosineSimilarity(cs) = sparse_cosine(&x, &y).unwrap();
+        assert_close(cs, 0.0);
+    }
+
+    #[test]
+    fn test_identical_vectors() {
+        let x = SparseVector::new(0, vec![(3, 0.6), (7, 0.8), (100, 0.5)]);
+        assert_close(sparse_dot(&x, &x), 0.36 + 0.64 + 0.25);
+        let CosineSimilarity(cs) = sparse_cosine(&x, &x).unwrap();
+        assert_close(cs, 1.0);
+    }
+
+    #[test]
+    fn test_partially_overlapping_unsorted_vectors() {
+        let x = SparseVector::new(0, vec![(10, 1.0), (2, 2.0), (5, 3.0)]);
+        let y = SparseVector::new(1, vec![(5, 4.0), (11, 1.0), (2, 0.5)]);
+        // dims 2 and 5 overlap
+        let expected_dot = 2.0 * 0.5 + 3.0 * 4.0;
+        assert_close(sparse_dot(&x, &y), expected_dot);
+        assert_close(sparse_dot(&y, &x), expected_dot);
+
+        let CosineSimilarity(cs) = sparse_cosine(&x, &y).unwrap();
+        let x_mag = (1.0f32 + 4.0 + 9.0).sqrt();
+        let y_mag = (16.0f32 + 1.0 + 0.25).sqrt();
+        assert_close(cs, expected_dot / (x_mag * y_mag));
+
+        // the same vector with its entries sorted scores the same
+        let mut sorted = x.entries.clone();
+        sorted.sort_by_key(|&(dim_index, _)| dim_index);
+        assert_close(sparse_dot(&SparseVector::new(0, sorted), &y), expected_dot);
+    }
+
+    #[test]
+    fn test_cosine_of_empty_vector_fails() {
+        let x = SparseVector::new(0, vec![]);
+        let y = SparseVector::new(1, vec![(1, 1.0)]);
+        assert_close(sparse_dot(&x, &y), 0.0);
+        assert!(sparse_cosine(&x, &y).is_err());
+    }
+}
@@ -1,7 +1,4 @@
-use std::{
-    fs::File,
-    sync::{Arc, Mutex},
-};
+use std::{fs::File, sync::Arc};
 
 use arcshift::ArcShift;
 
@@ -11,6 +8,7 @@ use crate::{
         versioning::{Hash, VersionControl},
     },
     quantization::StorageType,
+    storage::InvertedIndexError,
 };
 
 use super::inverted_index_item::InvertedIndexItem;
@@ -23,7 +21,7 @@ pub(crate) struct InvertedIndex {
     pub metadata_schema: Option<String>, //object (optional)
     pub max_vectors: Option<i32>,
     pub replication_factor: Option<i32>,
-    pub root: Arc<Mutex<InvertedIndexItem>>,
+    pub root: Arc<InvertedIndexItem>,
     pub prop_file: Arc<File>,
     pub lmdb: MetaDb,
     pub current_version: ArcShift<Hash>,
@@ -58,7 +56,7 @@ impl InvertedIndex {
             max_vectors,
             metadata_schema,
             replication_factor,
-            root: Arc::new(Mutex::new(InvertedIndexItem::new(0, false))),
+            root: Arc::new(InvertedIndexItem::new(0, false)),
             prop_file,
             lmdb,
             current_version,
@@ -70,14 +68,20 @@ impl InvertedIndex {
         }
     }
 
-    pub fn add_dim_index(&self, dim_index: u32, value: f32, vector_id: u32) -> Result<(), String> {
-        self.root
-            .lock()
-            .unwrap()
-            .insert_dim_index(dim_index, value, vector_id)
+    pub fn add_dim_index(
+        &self,
+        dim_index: u32,
+        value: f32,
+        vector_id: u32,
+    ) -> Result<(), InvertedIndexError> {
+        self.root.insert_dim_index(dim_index, value, vector_id)
     }
 
     pub fn print_tree(&self) {
-        self.root.lock().unwrap().print_tree(0);
+        self.root.print_tree(0);
+    }
+
+    pub fn format_tree(&self) -> String {
+        self.root.format_tree()
     }
 }
@@ -1,105 +1,228 @@
-use std::sync::{Arc, Mutex};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
 
 use super::helpers::generate_power_of_4_list;
+use crate::storage::InvertedIndexError;
 
+/// Node of the inverted index tree. Children are created at most once and
+/// never replaced, so only the data of a single node is ever locked and
+/// inserts into different dimensions don't block each other.
 #[derive(Debug)]
 pub(crate) struct InvertedIndexItem {
     dim_index: u32,
-    implicit: bool,
-    data: Vec<(f32, u32)>,
-    pointers: Vec<Option<Arc<Mutex<InvertedIndexItem>>>>,
+    implicit: AtomicBool,
+    data: Mutex<Vec<(f32, u32)>>,
+    pointers: Vec<OnceLock<Arc<InvertedIndexItem>>>,
 }
 
 impl InvertedIndexItem {
     pub fn new(dim_index: u32, implicit: bool) -> Self {
         InvertedIndexItem {
             dim_index,
-            implicit,
-            data: vec![],
-            pointers: vec![None; 8], // Space for exponents 0 to 7 (1 to 16384)
+            implicit: AtomicBool::new(implicit),
+            data: Mutex::new(vec![]),
+            // Space for exponents 0 to 7 (1 to 16384)
+            pointers: (0..8).map(|_| OnceLock::new()).collect(),
         }
     }
 
     pub fn insert_dim_index(
-        &mut self,
+        &self,
         target_dim_index: u32,
         value: f32,
         vector_id: u32,
-    ) -> Result<(), String> {
-        let path = generate_power_of_4_list(target_dim_index - self.dim_index);
+    ) -> Result<(), InvertedIndexError> {
+        let out_of_range = InvertedIndexError::DimensionOutOfRange(target_dim_index);
+        let offset = target_dim_index
+            .checked_sub(self.dim_index)
+            .ok_or_else(|| out_of_range.clone())?;
+        let path = generate_power_of_4_list(offset);
+        if path
+            .iter()
+            .any(|&(_, exponent)| exponent as usize >= self.pointers.len())
+        {
+            return Err(out_of_range);
+        }
         self.insert_recursive(target_dim_index, &path, 0, value, vector_id)
     }
 
     fn insert_recursive(
-        &mut self,
+        &self,
         target_dim_index: u32,
         path: &[(u32, u32)],
         path_index: usize,
         value: f32,
         vector_id: u32,
-    ) -> Result<(), String> {
+    ) -> Result<(), InvertedIndexError> {
         if path_index == path.len() {
-            // We've reached the target dimension index
-            if !self.implicit {
-                return Err(format!(
-                    "Dimension-Index {} is already explicit",
-                    self.dim_index
-                ));
+            // We've reached the target dimension index, implicit nodes are
+            // created with it already, only the flag has to be cleared
+            debug_assert_eq!(self.dim_index, target_dim_index);
+            if self
+                .implicit
+                .compare_exchange(true, false, Ordering::AcqRel, Ordering::Acquire)
+                .is_err()
+            {
+                return Err(InvertedIndexError::DimensionAlreadyExplicit(self.dim_index));
             }
-            self.dim_index = target_dim_index;
-            self.implicit = false;
-            self.insert_data(value, vector_id);
-            return Ok(());
+            return self.insert_data(value, vector_id);
         }
 
         let (_, exponent) = path[path_index];
         let next_dim_index = self.dim_index + 4u32.pow(exponent);
 
-        if self.pointers[exponent as usize].is_none() {
-            let new_item = Arc::new(Mutex::new(InvertedIndexItem::new(next_dim_index, true)));
-            self.pointers[exponent as usize] = Some(new_item);
-        }
-
-        if let Some(next_item) = &self.pointers[exponent as usize] {
-            next_item.lock().unwrap().insert_recursive(
-                target_dim_index,
-                path,
-                path_index + 1,
-                value,
-                vector_id,
-            )
-        } else {
-            Err("Failed to create or access the next item".to_string())
-        }
+        self.pointers[exponent as usize]
+            .get_or_init(|| Arc::new(InvertedIndexItem::new(next_dim_index, true)))
+            .insert_recursive(target_dim_index, path, path_index + 1, value, vector_id)
     }
 
-    fn insert_data(&mut self, value: f32, vector_id: u32) {
-        let is_repeated = self.data.iter().find(|i| i.1 == vector_id);
+    fn insert_data(&self, value: f32, vector_id: u32) -> Result<(), InvertedIndexError> {
+        let mut data = self.data.lock().map_err(|_| InvertedIndexError::Locked)?;
+        let is_repeated = data.iter().find(|i| i.1 == vector_id);
 
         // TODO should this return error if the vector id is already registered ?
         //  or should it skip inserting and return nothing ?
         if is_repeated.is_none() {
-            self.data.push((value, vector_id))
+            data.push((value, vector_id))
         }
+        Ok(())
     }
 
     pub fn print_tree(&self, depth: usize) {
+        let mut tree = String::new();
+        self.write_tree(depth, &mut tree);
+        print!("{}", tree);
+    }
+
+    /// Renders the subtree rooted at this item, as printed by `print_tree`.
+    pub fn format_tree(&self) -> String {
+        let mut tree = String::new();
+        self.write_tree(0, &mut tree);
+        tree
+    }
+
+    fn write_tree(&self, depth: usize, tree: &mut String) {
         let indent = "  ".repeat(depth);
-        println!(
+        // writing to a `String` can't fail
+        writeln!(
+            tree,
             "{}Dimension-Index {}: {}",
             indent,
             self.dim_index,
-            if self.implicit {
+            if self.implicit.load(Ordering::Acquire) {
                 "Implicit"
             } else {
                 "Explicit"
             }
-        );
+        )
+        .unwrap();
         for (i, pointer) in self.pointers.iter().enumerate() {
-            if let Some(item) = pointer {
-                println!("{}-> 4^{} to:", indent, i);
-                item.lock().unwrap().print_tree(depth + 1);
+            if let Some(item) = pointer.get() {
+                writeln!(tree, "{}-> 4^{} to:", indent, i).unwrap();
+                item.write_tree(depth + 1, tree);
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::InvertedIndexItem;
+    use crate::indexes::helpers::generate_power_of_4_list;
+    use crate::storage::InvertedIndexError;
+    use std::sync::atomic::Ordering;
+    use std::thread;
+
+    fn data_at(root: &InvertedIndexItem, dim_index: u32) -> Vec<(f32, u32)> {
+        let mut item = root;
+        for (_, exponent) in generate_power_of_4_list(dim_index - root.dim_index) {
+            item = item.pointers[exponent as usize].get().unwrap().as_ref();
+        }
+        assert_eq!(item.dim_index, dim_index);
+        assert!(!item.implicit.load(Ordering::Acquire));
+        item.data.lock().unwrap().clone()
+    }
+
+    #[test]
+    fn test_insert_dim_index_out_of_range() {
+        let root = InvertedIndexItem::new(0, false);
+        root.insert_dim_index(5, 0.5, 1).unwrap();
+        // 4^7 is the largest step a node has a pointer for
+        root.insert_dim_index(16384, 0.5, 1).unwrap();
+
+        assert_eq!(
+            root.insert_dim_index(65536, 0.5, 1),
+            Err(InvertedIndexError::DimensionOutOfRange(65536))
+        );
+
+        // a subtree can't hold dimensions below its own
+        let item = InvertedIndexItem::new(10, true);
+        assert_eq!(
+            item.insert_dim_index(5, 0.5, 1),
+            Err(InvertedIndexError::DimensionOutOfRange(5))
+        );
+
+        assert_eq!(
+            root.insert_dim_index(5, 0.7, 2),
+            Err(InvertedIndexError::DimensionAlreadyExplicit(5))
+        );
+    }
+
+    #[test]
+    fn test_format_tree() {
+        let root = InvertedIndexItem::new(0, false);
+        for dim_index in [1, 5, 20] {
+            root.insert_dim_index(dim_index, 0.5, 1).unwrap();
+        }
+
+        // 5 = 4 + 1 and 20 = 16 + 4 go through implicit nodes
+        let expected = "\
+Dimension-Index 0: Explicit
+-> 4^0 to:
+  Dimension-Index 1: Explicit
+-> 4^1 to:
+  Dimension-Index 4: Implicit
+  -> 4^0 to:
+    Dimension-Index 5: Explicit
+-> 4^2 to:
+  Dimension-Index 16: Implicit
+  -> 4^1 to:
+    Dimension-Index 20: Explicit
+";
+        assert_eq!(root.format_tree(), expected);
+
+        // making an implicit node explicit keeps its subtree
+        root.insert_dim_index(4, 0.5, 2).unwrap();
+        assert!(root
+            .format_tree()
+            .contains("  Dimension-Index 4: Explicit\n  -> 4^0 to:\n    Dimension-Index 5"));
+    }
+
+    #[test]
+    fn test_concurrent_insert_dim_index() {
+        let root = InvertedIndexItem::new(0, false);
+        let threads: u32 = 8;
+        let max_dim_index = 2000;
+
+        // the threads share most of the implicit nodes on their paths
+        thread::scope(|scope| {
+            for first_dim_index in 1..=threads {
+                let root = &root;
+                scope.spawn(move || {
+                    for dim_index in (first_dim_index..=max_dim_index).step_by(threads as usize) {
+                        root.insert_dim_index(dim_index, dim_index as f32, dim_index * 10)
+                            .unwrap();
+                    }
+                });
+            }
+        });
+
+        for dim_index in 1..=max_dim_index {
+            assert_eq!(
+                data_at(&root, dim_index),
+                vec![(dim_index as f32, dim_index * 10)]
+            );
+        }
+    }
+}
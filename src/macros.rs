@@ -6,9 +6,11 @@ macro_rules! key {
         key.extend_from_slice(&$version_id.to_le_bytes());
         key
     }};
-    (e:$embedding_id:expr) => {{
-        let mut prefixed_key = Vec::with_capacity(5); // prefix = 1 bytes, id = 4 byte
+    (e:$branch_id:expr, $embedding_id:expr) => {{
+        // prefix = 1 byte, BranchId = 8 bytes, id = 8 bytes
+        let mut prefixed_key = Vec::with_capacity(17);
         prefixed_key.push(1);
+        prefixed_key.extend_from_slice(&$branch_id.to_le_bytes());
         prefixed_key.extend_from_slice(&$embedding_id.0.to_le_bytes());
         prefixed_key
     }};
@@ -18,6 +20,43 @@ macro_rules! key {
         key.extend_from_slice(&$branch_id.to_le_bytes());
         key
     }};
+    (m:$embedding_id:expr) => {{
+        let mut prefixed_key = Vec::with_capacity(9); // prefix = 1 byte, id = 8 bytes
+        prefixed_key.push(3);
+        prefixed_key.extend_from_slice(&$embedding_id.0.to_le_bytes());
+        prefixed_key
+    }};
+    (r:$branch_id:expr) => {{
+        let mut key = Vec::with_capacity(9); // prefix = 1 byte, BranchId = 8 bytes
+        key.push(4);
+        key.extend_from_slice(&$branch_id.to_le_bytes());
+        key
+    }};
+    (t:$embedding_id:expr) => {{
+        let mut prefixed_key = Vec::with_capacity(9); // prefix = 1 byte, id = 8 bytes
+        prefixed_key.push(5);
+        prefixed_key.extend_from_slice(&$embedding_id.0.to_le_bytes());
+        prefixed_key
+    }};
+    (n:$vector_id:expr, $level:expr, $version_id:expr, $offset:expr) => {{
+        // prefix = 1 byte, id = 8 bytes, level = 1 byte, version = 4 bytes,
+        // offset = 4 bytes
+        let mut key = Vec::with_capacity(18);
+        key.push(6);
+        key.extend_from_slice(&$vector_id.0.to_le_bytes());
+        key.push($level.0);
+        key.extend_from_slice(&$version_id.to_le_bytes());
+        key.extend_from_slice(&$offset.to_le_bytes());
+        key
+    }};
+    (n:$vector_id:expr, $level:expr) => {{
+        // the part of the key above shared by the nodes of a vector on a level
+        let mut key = Vec::with_capacity(10);
+        key.push(6);
+        key.extend_from_slice(&$vector_id.0.to_le_bytes());
+        key.push($level.0);
+        key
+    }};
 }
 
 pub(crate) use key;
@@ -7,13 +7,16 @@ use std::hash::Hash;
 use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 
 use super::lru_cache::LRUCache;
 
 const BUFFER_SIZE: usize = 8192;
 const FLUSH_THRESHOLD: usize = (BUFFER_SIZE as f32 * 0.7) as usize; // 70% of buffer size
 
+// max idle cursors kept around by `BufferManager::with_cursor`
+const CURSOR_POOL_SIZE: usize = 16;
+
 #[derive(Debug)]
 pub enum BufIoError {
     Io(io::Error),
@@ -110,6 +113,11 @@ impl<K: Hash + Eq> BufferManagerFactory<K> {
         }
     }
 
+    /// directory the files of this factory are kept in
+    pub fn root_path(&self) -> &Path {
+        &self.root_path
+    }
+
     pub fn get(&self, key: K) -> Result<Arc<BufferManager>, BufIoError> {
         if let Some(bufman) = self.bufmans.get(&key) {
             return Ok(bufman.clone());
@@ -135,12 +143,22 @@ impl<K: Hash + Eq> BufferManagerFactory<K> {
         }
         Ok(())
     }
+
+    /// Like `flush_all`, but also waits for the files to reach the disk.
+    pub fn sync_all(&self) -> Result<(), BufIoError> {
+        for bufman in self.bufmans.iter() {
+            bufman.sync()?;
+        }
+        Ok(())
+    }
 }
 
 pub struct BufferManager {
     file: Arc<RwLock<File>>,
     regions: LRUCache<u64, Arc<BufferRegion>>,
     cursors: RwLock<HashMap<u64, Cursor>>,
+    // idle cursors handed out by `with_cursor`, still present in `cursors`
+    free_cursors: Mutex<Vec<u64>>,
     next_cursor_id: AtomicU64,
     file_size: RwLock<u64>,
     flush_eagerness: f32,
@@ -155,6 +173,7 @@ impl BufferManager {
             file: Arc::new(RwLock::new(file)),
             regions,
             cursors: RwLock::new(HashMap::new()),
+            free_cursors: Mutex::new(Vec::with_capacity(CURSOR_POOL_SIZE)),
             next_cursor_id: AtomicU64::new(0),
             file_size: RwLock::new(file_size),
             flush_eagerness,
@@ -181,6 +200,39 @@ impl BufferManager {
         Ok(())
     }
 
+    /// Runs `f` with a cursor taken from a small pool, opening a new one if
+    /// the pool is empty. This avoids taking the `cursors` write lock twice
+    /// per operation on hot paths.
+    ///
+    /// A pooled cursor keeps the position its previous user left it at, so
+    /// `f` must seek before reading or writing.
+    pub fn with_cursor<T, E>(&self, f: impl FnOnce(u64) -> Result<T, E>) -> Result<T, E>
+    where
+        E: From<BufIoError>,
+    {
+        let pooled = self
+            .free_cursors
+            .lock()
+            .map_err(|_| BufIoError::Locking)?
+            .pop();
+        let cursor = match pooled {
+            Some(cursor) => cursor,
+            None => self.open_cursor()?,
+        };
+
+        let result = f(cursor);
+
+        let mut free_cursors = self.free_cursors.lock().map_err(|_| BufIoError::Locking)?;
+        if free_cursors.len() < CURSOR_POOL_SIZE {
+            free_cursors.push(cursor);
+        } else {
+            drop(free_cursors);
+            self.close_cursor(cursor)?;
+        }
+
+        result
+    }
+
     fn get_or_create_region(&self, position: u64) -> Result<Arc<BufferRegion>, BufIoError> {
         let start = position - (position % BUFFER_SIZE as u64);
         let cached_region = self.regions.get_or_insert::<BufIoError>(start, || {
@@ -245,6 +297,11 @@ impl BufferManager {
             .ok_or_else(|| BufIoError::InvalidCursor(cursor_id))
     }
 
+    /// Current length of the file, including writes not flushed yet.
+    pub fn file_size(&self) -> Result<u64, BufIoError> {
+        Ok(*self.file_size.read().map_err(|_| BufIoError::Locking)?)
+    }
+
     pub fn read_with_cursor(&self, cursor_id: u64, buf: &mut [u8]) -> Result<usize, BufIoError> {
         let mut curr_pos = {
             let cursors = self.cursors.read().map_err(|_| BufIoError::Locking)?;
@@ -461,6 +518,53 @@ impl BufferManager {
             .flush()
             .map_err(BufIoError::Io)
     }
+
+    /// Cuts the file back to `len` bytes, discarding everything written past
+    /// it, whether it was flushed already or not. Cursors past the new end
+    /// are moved back to it.
+    pub fn truncate(&self, len: u64) -> Result<(), BufIoError> {
+        let mut file_size = self.file_size.write().map_err(|_| BufIoError::Locking)?;
+        if len >= *file_size {
+            return Ok(());
+        }
+
+        for region in self.regions.values() {
+            if region.start >= len {
+                region.end.store(0, Ordering::SeqCst);
+                region.dirty.store(false, Ordering::SeqCst);
+            } else {
+                let kept = (len - region.start) as usize;
+                if region.end.load(Ordering::SeqCst) > kept {
+                    region.end.store(kept, Ordering::SeqCst);
+                }
+            }
+        }
+        self.file
+            .write()
+            .map_err(|_| BufIoError::Locking)?
+            .set_len(len)
+            .map_err(BufIoError::Io)?;
+        *file_size = len;
+
+        let mut cursors = self.cursors.write().map_err(|_| BufIoError::Locking)?;
+        for cursor in cursors.values_mut() {
+            if cursor.position >= len {
+                cursor.position = len;
+                cursor.is_eof = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// Flushes the dirty regions and fsyncs the file.
+    pub fn sync(&self) -> Result<(), BufIoError> {
+        self.flush()?;
+        self.file
+            .read()
+            .map_err(|_| BufIoError::Locking)?
+            .sync_all()
+            .map_err(BufIoError::Io)
+    }
 }
 
 #[cfg(test)]
@@ -469,6 +573,7 @@ mod tests {
     use super::*;
     use quickcheck_macros::quickcheck;
     use rand::Rng;
+    use std::collections::HashSet;
     use std::thread;
     use tempfile::tempfile;
 
@@ -1389,4 +1494,64 @@ mod tests {
     fn prop_seek_with_cursor_from_current(filesize: u16, pos: i16) -> bool {
         check_seek_with_cursor_doesnt_crash(filesize, SeekFrom::Current(pos as i64))
     }
+
+    #[test]
+    fn test_with_cursor_reuses_pooled_cursors() {
+        let bufman = BufferManager::new(tempfile().unwrap(), 1.0).unwrap();
+
+        let first = bufman.with_cursor(Ok::<_, BufIoError>).unwrap();
+        let second = bufman.with_cursor(Ok::<_, BufIoError>).unwrap();
+        assert_eq!(first, second);
+
+        // nested users get distinct cursors, the pool never grows past its bound
+        fn nest(bufman: &BufferManager, depth: usize, seen: &mut Vec<u64>) {
+            if depth == 0 {
+                return;
+            }
+            bufman
+                .with_cursor(|cursor| {
+                    seen.push(cursor);
+                    nest(bufman, depth - 1, seen);
+                    Ok::<_, BufIoError>(())
+                })
+                .unwrap();
+        }
+        let mut seen = Vec::new();
+        nest(&bufman, CURSOR_POOL_SIZE + 4, &mut seen);
+        let unique: HashSet<_> = seen.iter().collect();
+        assert_eq!(unique.len(), seen.len());
+        assert_eq!(bufman.free_cursors.lock().unwrap().len(), CURSOR_POOL_SIZE);
+        assert_eq!(bufman.cursors.read().unwrap().len(), CURSOR_POOL_SIZE);
+    }
+
+    #[test]
+    fn test_truncate_discards_flushed_and_buffered_writes() {
+        let file = tempfile().unwrap();
+        let on_disk = file.try_clone().unwrap();
+        let bufman = BufferManager::new(file, 1.0).unwrap();
+        let cursor = bufman.open_cursor().unwrap();
+
+        // spans two regions, the first flushed and the second still buffered
+        let data: Vec<u8> = (0..BUFFER_SIZE + 100).map(|i| i as u8).collect();
+        bufman.write_with_cursor(cursor, &data).unwrap();
+        bufman.flush().unwrap();
+        bufman.write_with_cursor(cursor, &[0xff; 50]).unwrap();
+
+        bufman.truncate(10).unwrap();
+        assert_eq!(bufman.file_size().unwrap(), 10);
+        assert_eq!(bufman.cursor_position(cursor).unwrap(), 10);
+
+        // appends continue from the new end
+        bufman.seek_with_cursor(cursor, SeekFrom::End(0)).unwrap();
+        bufman.write_with_cursor(cursor, &[7, 7]).unwrap();
+        bufman.flush().unwrap();
+        assert_eq!(on_disk.metadata().unwrap().len(), 12);
+
+        let mut buf = vec![0; 20];
+        bufman.seek_with_cursor(cursor, SeekFrom::Start(0)).unwrap();
+        let read = bufman.read_with_cursor(cursor, &mut buf).unwrap();
+        assert_eq!(read, 12);
+        assert_eq!(&buf[..10], &data[..10]);
+        assert_eq!(&buf[10..12], &[7, 7]);
+    }
 }
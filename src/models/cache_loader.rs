@@ -2,7 +2,7 @@ use super::buffered_io::{BufIoError, BufferManagerFactory};
 use super::common::TSHashTable;
 use super::file_persist::read_prop_from_file;
 use super::lazy_load::{FileIndex, LazyItem, LazyItemVec, VectorData};
-use super::lru_cache::LRUCache;
+use super::lru_cache::{CacheStats, LRUCache};
 use super::prob_lazy_load::lazy_item::{ProbLazyItem, ProbLazyItemState, ReadyState};
 use super::prob_node::{ProbNode, SharedNode};
 use super::serializer::prob::ProbSerialize;
@@ -28,7 +28,10 @@ use std::collections::HashSet;
 use std::fs::File;
 use std::io;
 use std::sync::TryLockError;
-use std::sync::{atomic::AtomicBool, Arc, Mutex, RwLock, Weak};
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc, Mutex, RwLock, Weak,
+};
 
 macro_rules! define_cache_items {
     ($($variant:ident = $type:ty),+ $(,)?) => {
@@ -77,16 +80,41 @@ define_cache_items! {
     VectorData = STM<VectorData>,
 }
 
+/// Key a node is cached under. The same offset means different nodes in
+/// different version files, so the version is part of the key.
+fn cache_key(FileOffset(offset): FileOffset, version_id: Hash) -> u64 {
+    ((offset as u64) << 32) | (*version_id as u64)
+}
+
 pub struct NodeRegistry {
     cuckoo_filter: RwLock<CuckooFilter<u64>>,
     registry: LRUCache<u64, CacheItem>,
     bufmans: Arc<BufferManagerFactory<Hash>>,
 }
 
+/// Number of nodes a `NodeRegistry` keeps unless told otherwise.
+pub const DEFAULT_NODE_REGISTRY_CAPACITY: usize = 1000;
+
+/// Number of nodes a `ProbCache` keeps unless told otherwise.
+pub const DEFAULT_PROB_CACHE_CAPACITY: usize = 1_000_000;
+
 impl NodeRegistry {
     pub fn new(cuckoo_filter_capacity: usize, bufmans: Arc<BufferManagerFactory<Hash>>) -> Self {
+        Self::with_capacity(
+            cuckoo_filter_capacity,
+            DEFAULT_NODE_REGISTRY_CAPACITY,
+            bufmans,
+        )
+    }
+
+    /// Like `new`, keeping up to `capacity` nodes in memory.
+    pub fn with_capacity(
+        cuckoo_filter_capacity: usize,
+        capacity: usize,
+        bufmans: Arc<BufferManagerFactory<Hash>>,
+    ) -> Self {
         let cuckoo_filter = CuckooFilter::new(cuckoo_filter_capacity);
-        let registry = LRUCache::with_prob_eviction(1000, 0.03125);
+        let registry = LRUCache::with_prob_eviction(capacity, 0.03125);
         NodeRegistry {
             cuckoo_filter: RwLock::new(cuckoo_filter),
             registry,
@@ -98,6 +126,11 @@ impl NodeRegistry {
         self.bufmans.clone()
     }
 
+    /// Number of nodes kept in memory before older ones are evicted.
+    pub fn capacity(&self) -> usize {
+        self.registry.capacity()
+    }
+
     pub fn get_object<T: Cacheable, F>(
         self: Arc<Self>,
         file_index: FileIndex,
@@ -114,33 +147,34 @@ impl NodeRegistry {
             &mut HashSet<u64>,
         ) -> Result<LazyItem<T>, BufIoError>,
     {
-        println!(
+        log::trace!(
             "get_object called with file_index: {:?}, max_loads: {}",
-            file_index, max_loads
+            file_index,
+            max_loads
         );
 
         let combined_index = Self::combine_index(&file_index);
 
         {
             let cuckoo_filter = self.cuckoo_filter.read().unwrap();
-            println!("Acquired read lock on cuckoo_filter");
+            log::trace!("Acquired read lock on cuckoo_filter");
 
             // Initial check with Cuckoo filter
             if cuckoo_filter.contains(&combined_index) {
-                println!("FileIndex found in cuckoo_filter");
+                log::trace!("FileIndex found in cuckoo_filter");
                 if let Some(obj) = self.registry.get(&combined_index) {
                     if let Some(item) = T::from_cache_item(obj) {
-                        println!("Object found in registry, returning");
+                        log::trace!("Object found in registry, returning");
                         return Ok(item);
                     }
                 } else {
-                    println!("Object not found in registry despite being in cuckoo_filter");
+                    log::trace!("Object not found in registry despite being in cuckoo_filter");
                 }
             } else {
-                println!("FileIndex not found in cuckoo_filter");
+                log::trace!("FileIndex not found in cuckoo_filter");
             }
         }
-        println!("Released read lock on cuckoo_filter");
+        log::trace!("Released read lock on cuckoo_filter");
 
         let (version_id, version_number) = if let FileIndex::Valid {
             version_id,
@@ -154,7 +188,7 @@ impl NodeRegistry {
         };
 
         if max_loads == 0 || !skipm.insert(combined_index) {
-            println!("Either max_loads hit 0 or loop detected, returning LazyItem with no data");
+            log::trace!("Either max_loads hit 0 or loop detected, returning LazyItem with no data");
             return Ok(LazyItem::Valid {
                 data: ArcShift::new(None),
                 file_index: ArcShift::new(Some(file_index)),
@@ -167,7 +201,7 @@ impl NodeRegistry {
             });
         }
 
-        println!("Calling load_function");
+        log::trace!("Calling load_function");
         let item = load_function(
             self.bufmans.clone(),
             file_index.clone(),
@@ -175,23 +209,23 @@ impl NodeRegistry {
             max_loads - 1,
             skipm,
         )?;
-        println!("load_function returned successfully");
+        log::trace!("load_function returned successfully");
 
-        println!("Trying to get or insert item into registry");
+        log::trace!("Trying to get or insert item into registry");
         let cached_item = self
             .registry
             .get_or_insert::<BufIoError>(combined_index.clone(), || Ok(T::into_cache_item(item)))?;
 
         match cached_item {
             CachedValue::Hit(item) => {
-                println!("Object found in registry after load, returning");
+                log::trace!("Object found in registry after load, returning");
                 Ok(T::from_cache_item(item).unwrap())
             }
             CachedValue::Miss(item) => {
-                println!("Inserting key into cuckoo_filter");
+                log::trace!("Inserting key into cuckoo_filter");
                 self.cuckoo_filter.write().unwrap().insert(&combined_index);
 
-                println!("Returning newly created LazyItem");
+                log::trace!("Returning newly created LazyItem");
                 Ok(T::from_cache_item(item).unwrap())
             }
         }
@@ -224,7 +258,7 @@ impl NodeRegistry {
         match file_index {
             FileIndex::Valid {
                 offset, version_id, ..
-            } => ((offset.0 as u64) << 32) | (**version_id as u64),
+            } => cache_key(*offset, *version_id),
             FileIndex::Invalid => u64::MAX, // Use max u64 value for Invalid
         }
     }
@@ -247,6 +281,9 @@ pub struct ProbCache {
     props_registry: DashMap<u64, Weak<NodeProp>>,
     bufmans: Arc<BufferManagerFactory<Hash>>,
     prop_file: Arc<RwLock<File>>,
+    // bumped whenever compaction moves props, locations read before that may
+    // point at another prop
+    prop_file_generation: AtomicU64,
     loading_items: TSHashTable<u64, Arc<Mutex<bool>>>,
     // A global lock to prevent deadlocks during batch loading of cache entries when `max_loads > 1`.
     //
@@ -264,9 +301,24 @@ impl ProbCache {
         cuckoo_filter_capacity: usize,
         bufmans: Arc<BufferManagerFactory<Hash>>,
         prop_file: Arc<RwLock<File>>,
+    ) -> Self {
+        Self::with_capacity(
+            cuckoo_filter_capacity,
+            DEFAULT_PROB_CACHE_CAPACITY,
+            bufmans,
+            prop_file,
+        )
+    }
+
+    /// Like `new`, keeping up to `capacity` nodes in memory.
+    pub fn with_capacity(
+        cuckoo_filter_capacity: usize,
+        capacity: usize,
+        bufmans: Arc<BufferManagerFactory<Hash>>,
+        prop_file: Arc<RwLock<File>>,
     ) -> Self {
         let cuckoo_filter = CuckooFilter::new(cuckoo_filter_capacity);
-        let registry = LRUCache::with_prob_eviction(1_000_000, 0.03125);
+        let registry = LRUCache::with_prob_eviction(capacity, 0.03125);
         let props_registry = DashMap::new();
 
         Self {
@@ -275,25 +327,54 @@ impl ProbCache {
             props_registry,
             bufmans,
             prop_file,
+            prop_file_generation: AtomicU64::new(0),
             loading_items: TSHashTable::new(16),
             batch_load_lock: Mutex::new(()),
         }
     }
 
+    /// Number of nodes kept in memory before older ones are evicted.
+    pub fn capacity(&self) -> usize {
+        self.registry.capacity()
+    }
+
+    /// Number of nodes currently cached.
+    pub fn len(&self) -> usize {
+        self.registry.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.registry.is_empty()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.registry.stats()
+    }
+
+    pub fn prop_file_generation(&self) -> u64 {
+        self.prop_file_generation.load(Ordering::Acquire)
+    }
+
+    /// Reads the prop at `offset`, `None` if the prop file was compacted
+    /// since `generation` was taken, the location may be stale then.
     pub fn get_prop(
         &self,
         offset: FileOffset,
         length: BytesToRead,
-    ) -> Result<Arc<NodeProp>, BufIoError> {
+        generation: u64,
+    ) -> Result<Option<Arc<NodeProp>>, BufIoError> {
         let key = Self::get_prop_key(offset, length);
         if let Some(prop) = self
             .props_registry
             .get(&key)
             .and_then(|prop| prop.upgrade())
         {
-            return Ok(prop);
+            return Ok((self.prop_file_generation() == generation).then_some(prop));
         }
         let mut prop_file_guard = self.prop_file.write().unwrap();
+        if self.prop_file_generation() != generation {
+            return Ok(None);
+        }
         let prop = Arc::new(read_prop_from_file(
             (offset, length),
             &mut *prop_file_guard,
@@ -301,15 +382,35 @@ impl ProbCache {
         drop(prop_file_guard);
         let weak = Arc::downgrade(&prop);
         self.props_registry.insert(key, weak);
-        Ok(prop)
+        Ok(Some(prop))
+    }
+
+    /// Forgets the props read so far, their locations are about to change.
+    /// Called by compaction with the prop file locked, before any location
+    /// is updated.
+    pub fn forget_props(&self) {
+        self.props_registry.clear();
+    }
+
+    /// Registers `props` at their new locations once compaction is done,
+    /// still with the prop file locked. Readers holding locations from
+    /// before read them again.
+    pub fn props_moved(&self, props: &[Arc<NodeProp>]) {
+        for prop in props {
+            let (offset, length) = prop.location.get();
+            self.props_registry
+                .insert(Self::get_prop_key(offset, length), Arc::downgrade(prop));
+        }
+        self.prop_file_generation.fetch_add(1, Ordering::AcqRel);
     }
 
     pub fn insert_lazy_object(&self, version: Hash, offset: u32, item: SharedNode) {
-        let combined_index = (offset as u64) << 32 | (*version as u64);
+        let combined_index = cache_key(FileOffset(offset), version);
         let mut cuckoo_filter = self.cuckoo_filter.write().unwrap();
         cuckoo_filter.insert(&combined_index);
         if let Some(node) = unsafe { &*item }.get_lazy_data() {
-            let prop_key = Self::get_prop_key(node.prop.location.0, node.prop.location.1);
+            let (offset, length) = node.prop.location.get();
+            let prop_key = Self::get_prop_key(offset, length);
             self.props_registry
                 .insert(prop_key, Arc::downgrade(&node.prop));
         }
@@ -322,6 +423,14 @@ impl ProbCache {
         max_loads: u16,
         skipm: &mut HashSet<u64>,
     ) -> Result<SharedNode, BufIoError> {
+        if let FileIndex::Invalid = file_index {
+            // nothing was persisted, there is no location to load the node from
+            return Err(BufIoError::Io(io::Error::new(
+                io::ErrorKind::NotFound,
+                "can't load a node from an invalid file index",
+            )));
+        }
+
         let combined_index = Self::combine_index(&file_index);
 
         {
@@ -430,7 +539,7 @@ impl ProbCache {
         match file_index {
             FileIndex::Valid {
                 offset, version_id, ..
-            } => ((offset.0 as u64) << 32) | (**version_id as u64),
+            } => cache_key(*offset, *version_id),
             FileIndex::Invalid => u64::MAX, // Use max u64 value for Invalid
         }
     }
@@ -1,98 +1,93 @@
-use super::types::{FileOffset, Item};
-use std::sync::{Arc, RwLock};
-
-pub trait SyncPersist {
-    fn set_persistence(&self, flag: bool);
-    fn needs_persistence(&self) -> bool;
-}
-
-pub const CHUNK_SIZE: usize = 5;
-
-#[derive(Debug, Clone)]
-pub struct LazyItem<T: Clone> {
-    pub data: Option<Item<T>>,
-    pub offset: Option<FileOffset>,
-    pub decay_counter: usize,
-}
-
-#[derive(Debug, Clone)]
-pub struct LazyItemRef<T: Clone> {
-    pub item: Item<LazyItem<T>>,
-}
-
-#[derive(Debug, Clone)]
-pub struct LazyItems<T: Clone> {
-    pub items: Item<Vec<LazyItem<T>>>,
-}
-
-impl<T: Clone> LazyItem<T> {
-    pub fn with_data(data: T) -> Self {
-        LazyItem {
-            data: Some(Arc::new(RwLock::new(data))),
-            offset: None,
-            decay_counter: 0,
+use super::common::WaCustomError;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Items per chunk of the chunked lists the serializers write. Each chunk
+/// reserves a slot for every item and links to the next chunk, so reading a
+/// list follows one link per `CHUNK_SIZE` items.
+pub const CHUNK_SIZE: usize = 32;
+
+/// Chunk size of the lists written before it was recorded.
+const UNRECORDED_CHUNK_SIZE: usize = 5;
+
+/// File recording the `CHUNK_SIZE` the chunked lists in a directory were
+/// written with.
+const CHUNK_SIZE_FILE: &str = "chunk_size";
+
+/// Checks that the chunked lists under `root_path` were written with
+/// `CHUNK_SIZE`, as lists written with another size would be misread.
+///
+/// Lists are written to `.index` files. A directory holding some but no
+/// record was written with `UNRECORDED_CHUNK_SIZE`, otherwise nothing was
+/// written yet and `CHUNK_SIZE` is recorded.
+pub fn check_chunk_size(root_path: &Path) -> Result<(), WaCustomError> {
+    let fs_error = |e: io::Error| WaCustomError::FsError(e.to_string());
+    let path = root_path.join(CHUNK_SIZE_FILE);
+    let recorded = match fs::read_to_string(&path) {
+        Ok(recorded) => recorded.trim().to_string(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            if !has_index_files(root_path).map_err(fs_error)? {
+                return fs::write(&path, CHUNK_SIZE.to_string()).map_err(fs_error);
+            }
+            UNRECORDED_CHUNK_SIZE.to_string()
         }
+        Err(e) => return Err(fs_error(e)),
+    };
+    match recorded.parse::<usize>() {
+        Ok(CHUNK_SIZE) => Ok(()),
+        _ => Err(WaCustomError::DeserializationError(format!(
+            "{} was written with chunks of {} items, expected {}",
+            root_path.display(),
+            recorded,
+            CHUNK_SIZE
+        ))),
     }
 }
 
-impl<T: Clone> LazyItemRef<T> {
-    pub fn new(item: T) -> Self {
-        LazyItemRef {
-            item: Arc::new(RwLock::new(LazyItem {
-                data: Some(Arc::new(RwLock::new(item))),
-                offset: None,
-                decay_counter: 0,
-            })),
-        }
-    }
-
-    pub fn new_with_lock(item: Item<T>) -> Self {
-        LazyItemRef {
-            item: Arc::new(RwLock::new(LazyItem {
-                data: Some(item),
-                offset: None,
-                decay_counter: 0,
-            })),
+fn has_index_files(root_path: &Path) -> io::Result<bool> {
+    for entry in fs::read_dir(root_path)? {
+        if entry?
+            .path()
+            .extension()
+            .map_or(false, |ext| ext == "index")
+        {
+            return Ok(true);
         }
     }
-
-    pub fn get_data(&self) -> Option<Item<T>> {
-        self.item.read().unwrap().data.clone()
-    }
-
-    pub fn set_data(&self, new_data: T) {
-        self.item.write().unwrap().data = Some(Arc::new(RwLock::new(new_data)));
-    }
-
-    pub fn set_offset(&self, new_offset: Option<FileOffset>) {
-        self.item.write().unwrap().offset = new_offset;
-    }
+    Ok(false)
 }
 
-impl<T: Clone> LazyItems<T> {
-    pub fn new() -> Self {
-        LazyItems {
-            items: Arc::new(RwLock::new(Vec::new())),
-        }
-    }
-
-    pub fn push(&self, item: LazyItem<T>) {
-        self.items.write().unwrap().push(item);
-    }
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
 
-    pub fn get(&self, index: usize) -> Option<LazyItem<T>> {
-        self.items.read().unwrap().get(index).cloned()
-    }
+    use super::*;
 
-    pub fn len(&self) -> usize {
-        self.items.read().unwrap().len()
-    }
+    #[test]
+    fn test_chunk_size_is_checked() {
+        let temp_dir = tempdir().unwrap();
+        check_chunk_size(temp_dir.as_ref()).unwrap();
+        // recorded by the first check
+        check_chunk_size(temp_dir.as_ref()).unwrap();
 
-    pub fn is_empty(&self) -> bool {
-        self.items.read().unwrap().is_empty()
+        fs::write(temp_dir.as_ref().join(CHUNK_SIZE_FILE), "5").unwrap();
+        assert!(matches!(
+            check_chunk_size(temp_dir.as_ref()),
+            Err(WaCustomError::DeserializationError(_))
+        ));
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = LazyItem<T>> {
-        self.items.read().unwrap().clone().into_iter()
+    #[test]
+    fn test_unrecorded_chunk_size_defaults_to_5() {
+        let temp_dir = tempdir().unwrap();
+        fs::write(temp_dir.as_ref().join("0.index"), [0; 16]).unwrap();
+
+        assert!(matches!(
+            check_chunk_size(temp_dir.as_ref()),
+            Err(WaCustomError::DeserializationError(_))
+        ));
+        // nothing gets recorded for data written with another size
+        assert!(!temp_dir.as_ref().join(CHUNK_SIZE_FILE).exists());
     }
-}
\ No newline at end of file
+}
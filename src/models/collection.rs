@@ -1,10 +1,11 @@
-use lmdb::{Database, Environment, Transaction, WriteFlags};
+use lmdb::{Database, Environment, RwTransaction, Transaction, WriteFlags};
 use serde::{Deserialize, Serialize};
-use serde_cbor::to_vec;
+use serde_cbor::{from_slice, to_vec};
 use siphasher::sip::SipHasher24;
 use std::{fs, hash::Hasher, path::Path, sync::Arc};
 
 use super::common::WaCustomError;
+use super::types::DistanceMetric;
 
 #[derive(Deserialize, Clone, Serialize, Debug)]
 pub struct DenseVectorOptions {
@@ -23,6 +24,72 @@ pub struct SparseVectorOptions {
 pub struct CollectionConfig {
     pub max_vectors: Option<i32>,
     pub replication_factor: Option<i32>,
+    /// Number of nodes each of the collection's indexes keeps cached in
+    /// memory, `DEFAULT_PROB_CACHE_CAPACITY` for the dense index and
+    /// `DEFAULT_CACHE_CAPACITY` for the sparse one if not set
+    #[serde(default)]
+    pub cache_capacity: Option<usize>,
+    /// `k` used by queries that don't specify one
+    #[serde(default)]
+    pub default_k: Option<usize>,
+    /// `ef_search` used by queries that don't specify one, instead of the
+    /// one the dense index was created with
+    #[serde(default)]
+    pub default_ef_search: Option<u32>,
+}
+
+impl CollectionConfig {
+    /// Checks that the query defaults are positive and that `ef_search`
+    /// keeps at least `k` candidates.
+    pub fn validate(&self) -> Result<(), WaCustomError> {
+        if matches!(self.replication_factor, Some(n) if n < 1) {
+            return Err(WaCustomError::InvalidParams);
+        }
+        if self.cache_capacity == Some(0) {
+            return Err(WaCustomError::InvalidParams);
+        }
+        if self.default_k == Some(0) || self.default_ef_search == Some(0) {
+            return Err(WaCustomError::InvalidParams);
+        }
+        if let (Some(k), Some(ef_search)) = (self.default_k, self.default_ef_search) {
+            if (ef_search as usize) < k {
+                return Err(WaCustomError::InvalidParams);
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of copies of the raw embeddings kept besides the primary one.
+    pub fn replica_count(&self) -> usize {
+        self.replication_factor.map_or(0, |n| n.max(1) as usize - 1)
+    }
+
+    /// Fills in the `k` and `ef_search` a query didn't specify from the
+    /// collection's defaults.
+    pub fn query_params(
+        &self,
+        k: Option<usize>,
+        ef_search: Option<u32>,
+    ) -> (Option<usize>, Option<u32>) {
+        (k.or(self.default_k), ef_search.or(self.default_ef_search))
+    }
+}
+
+/// Quantization the collection's dense index encodes vectors with.
+#[derive(Deserialize, Clone, Serialize, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase", tag = "type")]
+pub enum QuantizationOptions {
+    #[default]
+    Scalar,
+    /// Vectors are kept at half precision until `training_sample_size` of
+    /// them have been collected to train the centroids on.
+    Product {
+        subspace_count: u16,
+        centroid_count: u16,
+        training_sample_size: usize,
+    },
+    /// Scalar quantization to a single bit per dimension.
+    Binary,
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
@@ -33,6 +100,13 @@ pub struct Collection {
     pub sparse_vector: SparseVectorOptions,
     pub metadata_schema: Option<String>, //object (optional)
     pub config: CollectionConfig,
+    /// metric the collection's indexes compare vectors with, collections
+    /// persisted before it could be chosen use cosine similarity
+    #[serde(default)]
+    pub distance_metric: DistanceMetric,
+    /// scalar for collections persisted before it could be chosen
+    #[serde(default)]
+    pub quantization: QuantizationOptions,
 }
 
 impl Collection {
@@ -43,10 +117,14 @@ impl Collection {
         sparse_vector_options: SparseVectorOptions,
         metadata_schema: Option<String>,
         config: CollectionConfig,
+        distance_metric: DistanceMetric,
+        quantization: QuantizationOptions,
+        collections_path: &Path,
     ) -> Result<Self, WaCustomError> {
         if name.is_empty() {
             return Err(WaCustomError::InvalidParams);
         }
+        config.validate()?;
 
         let collection = Collection {
             name,
@@ -55,9 +133,11 @@ impl Collection {
             sparse_vector: sparse_vector_options,
             metadata_schema,
             config,
+            distance_metric,
+            quantization,
         };
 
-        let collection_path = collection.get_path();
+        let collection_path = collection.get_path(collections_path);
         fs::create_dir_all(&collection_path).map_err(|e| WaCustomError::FsError(e.to_string()))?;
 
         Ok(collection)
@@ -65,23 +145,53 @@ impl Collection {
 
     /// Computes the SipHash of the collection name
     pub fn get_hash(&self) -> u64 {
-        let mut hasher = SipHasher24::new();
-        hasher.write(self.name.as_bytes());
-        let hash = hasher.finish();
-        hash
+        Self::hash_name(&self.name)
     }
 
     /// computes the key used to store the collection in the database
     pub fn get_key(&self) -> [u8; 8] {
-        let hash = self.get_hash();
-        let key = hash.to_le_bytes();
-        key
+        Self::key_for_name(&self.name)
+    }
+
+    fn hash_name(name: &str) -> u64 {
+        let mut hasher = SipHasher24::new();
+        hasher.write(name.as_bytes());
+        hasher.finish()
+    }
+
+    /// computes the key a collection named `name` is stored under
+    pub fn key_for_name(name: &str) -> [u8; 8] {
+        Self::hash_name(name).to_le_bytes()
+    }
+
+    /// loads the collection named `name` from disk (lmdb -> collections database)
+    ///
+    /// returns `None` if it was never persisted
+    pub fn load(
+        env: &Environment,
+        db: Database,
+        name: &str,
+    ) -> Result<Option<Self>, WaCustomError> {
+        let txn = env
+            .begin_ro_txn()
+            .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
+        let bytes = match txn.get(db, &Self::key_for_name(name)) {
+            Ok(bytes) => bytes,
+            Err(lmdb::Error::NotFound) => return Ok(None),
+            Err(e) => return Err(WaCustomError::DatabaseError(e.to_string())),
+        };
+        let collection: Collection =
+            from_slice(bytes).map_err(|e| WaCustomError::DeserializationError(e.to_string()))?;
+        // a different collection whose name hashes to the same key
+        if collection.name != name {
+            return Ok(None);
+        }
+        Ok(Some(collection))
     }
 
-    /// creates a path out of the collection name
-    pub fn get_path(&self) -> Arc<Path> {
-        let root_path = Path::new("./collections/");
-        root_path.join(&self.name).into()
+    /// creates a path out of the collection name, inside `collections_path`
+    pub fn get_path(&self, collections_path: &Path) -> Arc<Path> {
+        collections_path.join(&self.name).into()
     }
 
     /// serializes the collection
@@ -93,13 +203,24 @@ impl Collection {
     /// perists the collection instance on disk (lmdb -> collections database)
     #[allow(dead_code)]
     pub fn persist(&self, env: &Environment, db: Database) -> Result<(), WaCustomError> {
-        let key = self.get_key();
+        self.persist_under_key(env, db, self.get_key())
+    }
+
+    fn persist_under_key(
+        &self,
+        env: &Environment,
+        db: Database,
+        key: [u8; 8],
+    ) -> Result<(), WaCustomError> {
         let value = self.serialize()?;
 
         let mut txn = env
             .begin_rw_txn()
             .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
 
+        // refuse to overwrite a different collection whose name hashes to the same key
+        self.check_key_owner(&txn, db, &key)?;
+
         txn.put(db, &key, &value, WriteFlags::empty())
             .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
         txn.commit()
@@ -108,6 +229,38 @@ impl Collection {
         Ok(())
     }
 
+    /// persists a newly created collection, returns `false` without writing
+    /// anything if a collection with the same name was already persisted
+    pub fn persist_new(&self, env: &Environment, db: Database) -> Result<bool, WaCustomError> {
+        self.persist_new_under_key(env, db, self.get_key())
+    }
+
+    fn persist_new_under_key(
+        &self,
+        env: &Environment,
+        db: Database,
+        key: [u8; 8],
+    ) -> Result<bool, WaCustomError> {
+        let value = self.serialize()?;
+
+        let mut txn = env
+            .begin_rw_txn()
+            .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
+
+        self.check_key_owner(&txn, db, &key)?;
+
+        match txn.put(db, &key, &value, WriteFlags::NO_OVERWRITE) {
+            Ok(()) => {}
+            // the key already holds a collection of this name
+            Err(lmdb::Error::KeyExist) => return Ok(false),
+            Err(e) => return Err(WaCustomError::DatabaseError(e.to_string())),
+        }
+        txn.commit()
+            .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
+
+        Ok(true)
+    }
+
     /// deletes a collection instance from the disk (lmdb -> collections database)
     #[allow(dead_code)]
     pub fn delete(&self, env: &Environment, db: Database) -> Result<(), WaCustomError> {
@@ -117,6 +270,8 @@ impl Collection {
             .begin_rw_txn()
             .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
 
+        self.check_key_owner(&txn, db, &key)?;
+
         txn.del(db, &key, None)
             .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
         txn.commit()
@@ -124,4 +279,227 @@ impl Collection {
 
         Ok(())
     }
+    /// verifies that `key` is either free or already holds this collection,
+    /// returning `WaCustomError::KeyCollision` if it holds a different one
+    fn check_key_owner(
+        &self,
+        txn: &RwTransaction,
+        db: Database,
+        key: &[u8; 8],
+    ) -> Result<(), WaCustomError> {
+        let bytes = match txn.get(db, key) {
+            Ok(bytes) => bytes,
+            Err(lmdb::Error::NotFound) => return Ok(()),
+            Err(e) => return Err(WaCustomError::DatabaseError(e.to_string())),
+        };
+        let existing: Collection =
+            from_slice(bytes).map_err(|e| WaCustomError::DeserializationError(e.to_string()))?;
+        if existing.name != self.name {
+            return Err(WaCustomError::KeyCollision(format!(
+                "collection `{}` hashes to the same key as existing collection `{}`",
+                self.name, existing.name
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Collection, CollectionConfig, DenseVectorOptions, DistanceMetric, QuantizationOptions,
+        SparseVectorOptions, WaCustomError,
+    };
+    use crate::models::meta_persist::load_collections;
+    use lmdb::{DatabaseFlags, Environment, Transaction, WriteFlags};
+    use tempfile::tempdir;
+
+    fn collection(name: &str) -> Collection {
+        Collection {
+            name: name.to_string(),
+            description: None,
+            dense_vector: DenseVectorOptions {
+                enabled: true,
+                auto_create_index: false,
+                dimension: 4,
+            },
+            sparse_vector: SparseVectorOptions {
+                enabled: false,
+                auto_create_index: false,
+            },
+            metadata_schema: None,
+            config: CollectionConfig {
+                max_vectors: None,
+                replication_factor: None,
+                cache_capacity: None,
+                default_k: None,
+                default_ef_search: None,
+            },
+            distance_metric: DistanceMetric::Cosine,
+            quantization: QuantizationOptions::Scalar,
+        }
+    }
+
+    #[test]
+    fn test_key_collision_is_detected() {
+        let temp_dir = tempdir().unwrap();
+        let env = Environment::new()
+            .set_max_dbs(1)
+            .set_map_size(10485760) // 10MB
+            .open(temp_dir.as_ref())
+            .unwrap();
+        let db = env.create_db(None, DatabaseFlags::empty()).unwrap();
+
+        // force both names onto the same key
+        let key = [7u8; 8];
+        let first = collection("first");
+        let second = collection("second");
+
+        first.persist_under_key(&env, db, key).unwrap();
+        // persisting the same collection again is an update, not a collision
+        first.persist_under_key(&env, db, key).unwrap();
+
+        let res = second.persist_under_key(&env, db, key);
+        assert!(matches!(res, Err(WaCustomError::KeyCollision(_))));
+
+        // the original collection was not overwritten
+        let txn = env.begin_ro_txn().unwrap();
+        let stored: Collection = serde_cbor::from_slice(txn.get(db, &key).unwrap()).unwrap();
+        assert_eq!(stored.name, "first");
+    }
+
+    #[test]
+    fn test_existing_collection_is_not_recreated() {
+        let temp_dir = tempdir().unwrap();
+        let env = Environment::new()
+            .set_max_dbs(1)
+            .set_map_size(10485760) // 10MB
+            .open(temp_dir.as_ref())
+            .unwrap();
+        let db = env.create_db(None, DatabaseFlags::empty()).unwrap();
+
+        let first = collection("docs");
+        assert!(first.persist_new(&env, db).unwrap());
+
+        let mut second = collection("docs");
+        second.description = Some("recreated".to_string());
+        assert!(!second.persist_new(&env, db).unwrap());
+
+        // the original collection was not overwritten
+        let txn = env.begin_ro_txn().unwrap();
+        let stored: Collection =
+            serde_cbor::from_slice(txn.get(db, &first.get_key()).unwrap()).unwrap();
+        assert_eq!(stored.description, None);
+        txn.abort();
+
+        // a name hashing to a taken key is still a collision
+        let key = [7u8; 8];
+        first.persist_new_under_key(&env, db, key).unwrap();
+        assert!(matches!(
+            collection("other").persist_new_under_key(&env, db, key),
+            Err(WaCustomError::KeyCollision(_))
+        ));
+    }
+
+    #[test]
+    fn test_persisted_collections_are_listed() {
+        let temp_dir = tempdir().unwrap();
+        let env = Environment::new()
+            .set_max_dbs(1)
+            .set_map_size(10485760) // 10MB
+            .open(temp_dir.as_ref())
+            .unwrap();
+        let db = env.create_db(None, DatabaseFlags::empty()).unwrap();
+
+        assert!(load_collections(&env, db).unwrap().is_empty());
+
+        collection("first").persist(&env, db).unwrap();
+        collection("second").persist(&env, db).unwrap();
+        // a corrupt record is skipped
+        let mut txn = env.begin_rw_txn().unwrap();
+        txn.put(db, &[9u8; 8], &[0xff, 0x00], WriteFlags::empty())
+            .unwrap();
+        txn.commit().unwrap();
+
+        let mut names: Vec<_> = load_collections(&env, db)
+            .unwrap()
+            .into_iter()
+            .map(|c| c.name)
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["first", "second"]);
+    }
+
+    #[test]
+    fn test_query_params_fall_back_to_collection_defaults() {
+        let mut with_defaults = collection("with_defaults");
+        with_defaults.config.default_k = Some(7);
+        with_defaults.config.default_ef_search = Some(40);
+        assert!(with_defaults.config.validate().is_ok());
+
+        // the defaults are persisted along with the collection
+        let stored: Collection =
+            serde_cbor::from_slice(&with_defaults.serialize().unwrap()).unwrap();
+        assert_eq!(stored.config.query_params(None, None), (Some(7), Some(40)));
+        assert_eq!(
+            stored.config.query_params(Some(3), Some(100)),
+            (Some(3), Some(100))
+        );
+
+        let without_defaults = collection("without_defaults");
+        assert_eq!(
+            without_defaults.config.query_params(None, None),
+            (None, None)
+        );
+
+        // collections persisted before the defaults existed still load
+        #[derive(serde::Serialize)]
+        struct OldCollectionConfig {
+            max_vectors: Option<i32>,
+            replication_factor: Option<i32>,
+        }
+        let old = serde_cbor::to_vec(&OldCollectionConfig {
+            max_vectors: Some(10),
+            replication_factor: None,
+        })
+        .unwrap();
+        let config: CollectionConfig = serde_cbor::from_slice(&old).unwrap();
+        assert_eq!(config.query_params(None, None), (None, None));
+    }
+
+    #[test]
+    fn test_invalid_query_defaults_are_rejected() {
+        let mut config = collection("invalid").config;
+        config.default_k = Some(10);
+        config.default_ef_search = Some(5);
+        assert!(matches!(
+            config.validate(),
+            Err(WaCustomError::InvalidParams)
+        ));
+
+        config.default_ef_search = Some(10);
+        assert!(config.validate().is_ok());
+
+        config.default_k = Some(0);
+        assert!(matches!(
+            config.validate(),
+            Err(WaCustomError::InvalidParams)
+        ));
+    }
+
+    #[test]
+    fn test_replication_factor() {
+        let mut config = collection("replicated").config;
+        assert_eq!(config.replica_count(), 0);
+
+        config.replication_factor = Some(3);
+        assert!(config.validate().is_ok());
+        assert_eq!(config.replica_count(), 2);
+
+        config.replication_factor = Some(0);
+        assert!(matches!(
+            config.validate(),
+            Err(WaCustomError::InvalidParams)
+        ));
+    }
 }
@@ -5,6 +5,7 @@ use super::types::{MergedNode, MetricResult, VectorId};
 use crate::distance::DistanceError;
 use crate::models::types::VectorQt;
 use crate::quantization::QuantizationError;
+use crate::storage::InvertedIndexError;
 use sha2::{Digest, Sha256};
 use std::cmp::Ordering;
 use std::collections::hash_map::DefaultHasher;
@@ -392,6 +393,29 @@ pub enum WaCustomError {
     // put it in `Arc` to make it cloneable
     BufIo(Arc<BufIoError>),
     NotFound(String),
+    // (current size, soft limit) of the prop file, in bytes
+    PropFileSizeExceeded(u64, u64),
+    KeyCollision(String),
+    InvalidVersion(String),
+    // (position in the batch, cause) of the first vector that failed to insert
+    InsertFailed(usize, String),
+    // (position in the batch, dimensions, dimensions of the collection) of a
+    // vector of the wrong size
+    DimensionMismatch(usize, usize, usize),
+    // (position in the batch, id) of a vector whose id an earlier vector of
+    // the batch already has
+    DuplicateVectorId(usize, u64),
+    QuantizationError(String),
+    // (level, highest level of the index) of a level past the top of the index
+    InvalidLevel(u32, u8),
+    // a closure handed to the blocking thread pool that never completed
+    BlockingTaskFailed(String),
+    // (expected, current) version of a write made on condition the
+    // collection was still at the expected version
+    VersionConflict(
+        crate::models::versioning::Hash,
+        crate::models::versioning::Hash,
+    ),
 }
 
 impl fmt::Display for WaCustomError {
@@ -416,6 +440,38 @@ impl fmt::Display for WaCustomError {
             WaCustomError::DeserializationError(err) => write!(f, "Deserialization error: {}", err),
             WaCustomError::BufIo(err) => write!(f, "Buffer IO error: {}", err),
             WaCustomError::NotFound(msg) => write!(f, "{} Not Found!", msg),
+            WaCustomError::PropFileSizeExceeded(size, limit) => write!(
+                f,
+                "Prop file size ({} bytes) exceeds the soft limit ({} bytes), compaction is overdue",
+                size, limit
+            ),
+            WaCustomError::KeyCollision(msg) => write!(f, "Key collision: {}", msg),
+            WaCustomError::InvalidVersion(msg) => write!(f, "Invalid version: {}", msg),
+            WaCustomError::InsertFailed(index, msg) => {
+                write!(f, "Failed to insert vector at index {}: {}", index, msg)
+            }
+            WaCustomError::DimensionMismatch(index, dim, expected) => write!(
+                f,
+                "Vector at index {} has {} dimensions, but the collection expects {}",
+                index, dim, expected
+            ),
+            WaCustomError::DuplicateVectorId(index, id) => write!(
+                f,
+                "Vector at index {} has id {}, which appears earlier in the batch",
+                index, id
+            ),
+            WaCustomError::QuantizationError(msg) => write!(f, "Quantization error: {}", msg),
+            WaCustomError::BlockingTaskFailed(msg) => write!(f, "Blocking task failed: {}", msg),
+            WaCustomError::InvalidLevel(level, num_layers) => write!(
+                f,
+                "Invalid HNSW level {}, the index has levels 0 to {}",
+                level, num_layers
+            ),
+            WaCustomError::VersionConflict(expected, current) => write!(
+                f,
+                "Expected version {} but the collection is at version {}",
+                **expected, **current
+            ),
         }
     }
 }
@@ -445,6 +501,23 @@ impl From<BufIoError> for WaCustomError {
     }
 }
 
+impl From<InvertedIndexError> for WaCustomError {
+    fn from(error: InvertedIndexError) -> Self {
+        match error {
+            InvertedIndexError::DimensionOutOfRange(_) => WaCustomError::InvalidParams,
+            InvertedIndexError::VectorNotFound(vector_id) => {
+                WaCustomError::NotFound(format!("Sparse vector {}", vector_id))
+            }
+            InvertedIndexError::DuplicateVector(_)
+            | InvertedIndexError::DimensionAlreadyExplicit(_) => {
+                WaCustomError::KeyCollision(error.to_string())
+            }
+            InvertedIndexError::Locked => WaCustomError::LockError(error.to_string()),
+            InvertedIndexError::LogWrite(msg) => WaCustomError::FsError(msg),
+        }
+    }
+}
+
 #[allow(dead_code)]
 pub fn hash_float_vec(vec: Vec<f32>) -> Vec<u8> {
     // Create a new hasher instance
@@ -504,9 +577,7 @@ pub fn remove_duplicates_and_filter(
         .collect::<Vec<_>>();
 
     collected.sort_unstable_by(|(_, a), (_, b)| {
-        b.get_value()
-            .partial_cmp(&a.get_value())
-            .unwrap_or(Ordering::Equal)
+        b.score().partial_cmp(&a.score()).unwrap_or(Ordering::Equal)
     });
     if let Some(k) = k {
         collected.truncate(5 * k);
@@ -699,6 +770,69 @@ impl<K: Eq + Hash, V> TSHashTable<K, V> {
             .collect()
     }
 
+    /// Takes the entries off the table `batch_size` at a time, passing each
+    /// to `write` and calling `flush` once a batch is written. Returns the
+    /// number of entries written.
+    ///
+    /// Only one batch is off the table at a time, so draining a large table
+    /// doesn't copy it. If a write fails, the entries of its batch that
+    /// weren't written yet, the failed one included, are put back and the
+    /// remaining batches are never taken. Entries already written aren't put
+    /// back, so they aren't written twice.
+    pub fn drain_in_batches<E, W, F>(
+        &self,
+        batch_size: usize,
+        mut write: W,
+        mut flush: F,
+    ) -> Result<usize, E>
+    where
+        K: Clone,
+        W: FnMut(&K, &V) -> Result<(), E>,
+        F: FnMut() -> Result<(), E>,
+    {
+        let batch_size = batch_size.max(1);
+        let mut written = 0;
+        loop {
+            let batch = self.take(batch_size);
+            if batch.is_empty() {
+                return Ok(written);
+            }
+            let mut entries = batch.into_iter();
+            while let Some((k, v)) = entries.next() {
+                if let Err(err) = write(&k, &v) {
+                    self.insert(k, v);
+                    for (k, v) in entries {
+                        self.insert(k, v);
+                    }
+                    return Err(err);
+                }
+                written += 1;
+            }
+            flush()?;
+        }
+    }
+
+    /// Removes up to `count` entries from the table and returns them.
+    fn take(&self, count: usize) -> Vec<(K, V)>
+    where
+        K: Clone,
+    {
+        let mut taken = Vec::with_capacity(count);
+        for ht in &self.hash_table_list {
+            if taken.len() == count {
+                break;
+            }
+            let mut ht = ht.lock().unwrap();
+            let keys: Vec<K> = ht.keys().take(count - taken.len()).cloned().collect();
+            for k in keys {
+                if let Some(v) = ht.remove(&k) {
+                    taken.push((k, v));
+                }
+            }
+        }
+        taken
+    }
+
     pub fn from_list(size: i16, kv: Vec<(K, V)>) -> Self {
         let tsh = Self::new(size);
         for (k, v) in kv {
@@ -714,3 +848,91 @@ impl<K: Eq + Hash, V> TSHashTable<K, V> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::TSHashTable;
+    use std::cell::RefCell;
+
+    #[test]
+    fn test_drain_in_batches() {
+        let table = TSHashTable::new(16);
+        for k in 0..10_000u32 {
+            table.insert(k, ());
+        }
+
+        let mut written = Vec::new();
+        let mut flushes = 0;
+        let count = table
+            .drain_in_batches(
+                7,
+                |&k, _| {
+                    written.push(k);
+                    Ok::<_, ()>(())
+                },
+                || {
+                    flushes += 1;
+                    Ok(())
+                },
+            )
+            .unwrap();
+        assert_eq!(count, 10_000);
+        assert_eq!(flushes, 10_000usize.div_ceil(7));
+        written.sort_unstable();
+        assert_eq!(written, (0..10_000).collect::<Vec<_>>());
+        assert!(table.to_list().is_empty());
+    }
+
+    #[test]
+    fn test_drain_in_batches_keeps_unwritten_entries_queued() {
+        let table = TSHashTable::new(16);
+        for k in 0..100u32 {
+            table.insert(k, ());
+        }
+
+        // the 35th write fails, in the middle of the fourth batch
+        let written = RefCell::new(Vec::new());
+        let mut flushed = Vec::new();
+        let result = table.drain_in_batches(
+            10,
+            |&k, _| {
+                if written.borrow().len() == 34 {
+                    return Err("write failed");
+                }
+                written.borrow_mut().push(k);
+                Ok(())
+            },
+            || {
+                flushed = written.borrow().clone();
+                Ok(())
+            },
+        );
+        assert_eq!(result, Err("write failed"));
+        assert_eq!(flushed.len(), 30);
+
+        // only the entries that weren't written are still queued, the ones
+        // written before the failure aren't written again
+        let written = written.into_inner();
+        assert_eq!(written.len(), 34);
+        let mut queued: Vec<_> = table.to_list().into_iter().map(|(k, _)| k).collect();
+        queued.sort_unstable();
+        let expected: Vec<_> = (0..100).filter(|k| !written.contains(k)).collect();
+        assert_eq!(queued, expected);
+
+        // a second drain picks the rest up
+        let mut rest = Vec::new();
+        let count = table
+            .drain_in_batches(
+                10,
+                |&k, _| {
+                    rest.push(k);
+                    Ok::<_, &str>(())
+                },
+                || Ok(()),
+            )
+            .unwrap();
+        assert_eq!(count, 66);
+        rest.sort_unstable();
+        assert_eq!(rest, expected);
+    }
+}
@@ -1,18 +1,31 @@
-use std::{io::SeekFrom, sync::Arc};
+use rayon::prelude::*;
+use std::{collections::VecDeque, io::SeekFrom, sync::Arc};
 
 use super::{
-    buffered_io::BufferManager, common::WaCustomError, types::RawVectorEmbedding, versioning::Hash,
+    buffered_io::BufferManager,
+    common::WaCustomError,
+    types::{RawVectorEmbedding, VectorId},
+    versioning::Hash,
 };
+use crate::macros::key;
+use lmdb::{Transaction, WriteFlags};
 
+#[derive(Debug, PartialEq)]
 pub struct EmbeddingOffset {
     pub version: Hash,
     pub offset: u32,
 }
 
+/// Format byte prefixed to serialized offsets. Records written before the
+/// prefix existed are exactly 8 bytes (version, offset) and carry none, so a
+/// new format must never serialize to 8 bytes.
+const EMBEDDING_OFFSET_FORMAT_V1: u8 = 1;
+
 impl EmbeddingOffset {
     pub fn serialize(&self) -> Vec<u8> {
-        let mut result = Vec::with_capacity(8);
+        let mut result = Vec::with_capacity(9);
 
+        result.push(EMBEDDING_OFFSET_FORMAT_V1);
         result.extend_from_slice(&self.version.to_le_bytes());
         result.extend_from_slice(&self.offset.to_le_bytes());
 
@@ -20,46 +33,295 @@ impl EmbeddingOffset {
     }
 
     pub fn deserialize(bytes: &[u8]) -> Result<Self, &'static str> {
-        if bytes.len() != 8 {
-            return Err("Input must be exactly 8 bytes");
+        // legacy, unprefixed record
+        if bytes.len() == 8 {
+            return Ok(Self::from_fields(bytes));
         }
 
+        match bytes.first() {
+            Some(&EMBEDDING_OFFSET_FORMAT_V1) => {
+                if bytes.len() != 9 {
+                    return Err("Version 1 embedding offset must be exactly 9 bytes");
+                }
+                Ok(Self::from_fields(&bytes[1..]))
+            }
+            Some(_) => Err("Unknown embedding offset format"),
+            None => Err("Embedding offset is empty"),
+        }
+    }
+
+    // `bytes` must be exactly 8 bytes long
+    fn from_fields(bytes: &[u8]) -> Self {
         let version = u32::from_le_bytes(bytes[0..4].try_into().unwrap());
         let offset = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
 
-        Ok(Self {
+        Self {
             version: Hash::from(version),
             offset,
-        })
+        }
     }
 }
 
+/// Set on the length prefix of embeddings that carry metadata. Embeddings
+/// written before metadata existed never have it set.
+const HAS_METADATA: u32 = 1 << 31;
+
+/// Set on the length prefix of embeddings that end with a CRC32 checksum.
+/// Embeddings written before checksums existed are read unverified.
+const HAS_CHECKSUM: u32 = 1 << 30;
+
+const LEN_MASK: u32 = !(HAS_METADATA | HAS_CHECKSUM);
+
+/// Appends `emb` to the buffer, returning its offset.
+///
+/// The embedding is stored as a `u32` length followed by the archived
+/// embedding. If it has metadata, the JSON encoded metadata and its `u32`
+/// length follow the archive. A CRC32 of the length prefix and everything
+/// after it comes last, and the length prefix covers all of it.
 pub fn write_embedding(
     bufman: Arc<BufferManager>,
     emb: &RawVectorEmbedding,
 ) -> Result<u32, WaCustomError> {
     // TODO: select a better value for `N` (number of bytes to pre-allocate)
-    let serialized = rkyv::to_bytes::<_, 256>(emb)
-        .map_err(|e| WaCustomError::SerializationError(e.to_string()))?;
-
-    let len = serialized.len() as u32;
-    let cursor = bufman.open_cursor()?;
+    let mut serialized = rkyv::to_bytes::<_, 256>(emb)
+        .map_err(|e| WaCustomError::SerializationError(e.to_string()))?
+        .into_vec();
+
+    let mut flags = HAS_CHECKSUM;
+    if let Some(metadata) = &emb.metadata {
+        let metadata = serde_json::to_vec(metadata)
+            .map_err(|e| WaCustomError::SerializationError(e.to_string()))?;
+        serialized.extend_from_slice(&metadata);
+        serialized.extend_from_slice(&(metadata.len() as u32).to_le_bytes());
+        flags |= HAS_METADATA;
+    }
+    let len = (serialized.len() as u32 + 4) | flags;
+    let checksum = embedding_checksum(len, &serialized);
+    serialized.extend_from_slice(&checksum.to_le_bytes());
+
+    bufman.with_cursor(|cursor| {
+        let start = bufman.seek_with_cursor(cursor, SeekFrom::End(0))? as u32;
+        bufman.write_u32_with_cursor(cursor, len)?;
+        bufman.write_with_cursor(cursor, &serialized)?;
+        Ok(start)
+    })
+}
 
-    let start = bufman.seek_with_cursor(cursor, SeekFrom::End(0))? as u32;
-    bufman.write_u32_with_cursor(cursor, len)?;
-    bufman.write_with_cursor(cursor, &serialized)?;
+/// Appends `emb` to every copy of an embeddings file, the primary first,
+/// returning its offset.
+///
+/// The copies receive the same writes in the same order, so the embedding
+/// ends up at the same offset in all of them and a single `EmbeddingOffset`
+/// locates it in any copy. If writing any copy fails, every copy is cut back
+/// to the length it had before, so that they stay in sync for the next write.
+/// The caller must keep other writers off these files meanwhile.
+pub fn write_embedding_replicated(
+    bufmans: &[Arc<BufferManager>],
+    emb: &RawVectorEmbedding,
+) -> Result<u32, WaCustomError> {
+    if bufmans.is_empty() {
+        return Err(WaCustomError::FsError(
+            "No embeddings file to write to".into(),
+        ));
+    }
+    let lens = bufmans
+        .iter()
+        .map(|bufman| bufman.file_size())
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut offsets = Vec::with_capacity(bufmans.len());
+    let mut error = None;
+    for bufman in bufmans {
+        match write_embedding(bufman.clone(), emb) {
+            Ok(offset) => offsets.push(offset),
+            Err(e) => {
+                error.get_or_insert(e);
+            }
+        }
+    }
 
-    bufman.close_cursor(cursor)?;
+    let error = match error {
+        Some(e) => e,
+        None if offsets.iter().all(|&offset| offset == offsets[0]) => return Ok(offsets[0]),
+        None => WaCustomError::FsError(format!(
+            "Embedding copies out of sync, written at offsets {:?}",
+            offsets
+        )),
+    };
+
+    for (bufman, len) in bufmans.iter().zip(lens) {
+        bufman.truncate(len)?;
+    }
+    Err(error)
+}
 
-    Ok(start)
+/// Reads the embedding at `offset` from the first copy that holds an intact
+/// one, trying the primary first. Fails with the error of the last copy if
+/// none of them does.
+pub fn read_embedding_replicated(
+    bufmans: &[Arc<BufferManager>],
+    offset: u32,
+) -> Result<(RawVectorEmbedding, u32), WaCustomError> {
+    let mut error = WaCustomError::FsError("No embeddings file to read from".into());
+    for bufman in bufmans {
+        match read_embedding(bufman.clone(), offset) {
+            Ok(result) => return Ok(result),
+            Err(e) => error = e,
+        }
+    }
+    Err(error)
 }
 
+/// Number of embeddings each parallel task reads with its own cursor.
+const PARALLEL_READ_CHUNK_SIZE: usize = 64;
+
+/// Number of embeddings an `EmbeddingReader` reads ahead at once.
+const READ_AHEAD_SIZE: usize = 1024;
+
 pub fn read_embedding(
     bufman: Arc<BufferManager>,
     offset: u32,
 ) -> Result<(RawVectorEmbedding, u32), WaCustomError> {
+    bufman.with_cursor(|cursor| read_embedding_with_cursor(&bufman, cursor, offset))
+}
+
+/// Returns the offsets of the embeddings stored from `start` up to `end`,
+/// reading only their length prefixes.
+pub fn scan_embedding_offsets(
+    bufman: &BufferManager,
+    start: u32,
+    end: u32,
+) -> Result<Vec<u32>, WaCustomError> {
+    scan_embedding_offsets_limited(bufman, start, end, usize::MAX).map(|(offsets, _)| offsets)
+}
+
+/// Like `scan_embedding_offsets`, but stops after `limit` embeddings. Also
+/// returns the offset following the last embedding scanned, past `end` if
+/// that embedding is cut short by it. A length prefix cut short by `end`
+/// isn't scanned, the returned offset is where it starts.
+pub fn scan_embedding_offsets_limited(
+    bufman: &BufferManager,
+    start: u32,
+    end: u32,
+    limit: usize,
+) -> Result<(Vec<u32>, u32), WaCustomError> {
     let cursor = bufman.open_cursor()?;
+    let mut offsets = Vec::new();
+    let mut offset = start;
+
+    while offset + 4 <= end && offsets.len() < limit {
+        offsets.push(offset);
+        bufman
+            .seek_with_cursor(cursor, SeekFrom::Start(offset as u64))
+            .map_err(|e| WaCustomError::DeserializationError(e.to_string()))?;
+        let len = bufman
+            .read_u32_with_cursor(cursor)
+            .map_err(|e| WaCustomError::DeserializationError(e.to_string()))?;
+        offset += 4 + (len & LEN_MASK);
+    }
+
+    bufman.close_cursor(cursor)?;
+    Ok((offsets, offset))
+}
+
+/// Reads the embeddings at `offsets` in parallel, returning them in the
+/// same order.
+///
+/// Every task opens its own cursor, as a cursor's position must not be moved
+/// by more than one thread at a time.
+pub fn read_embeddings_parallel(
+    bufman: Arc<BufferManager>,
+    offsets: &[u32],
+) -> Result<Vec<RawVectorEmbedding>, WaCustomError> {
+    let chunks = offsets
+        .par_chunks(PARALLEL_READ_CHUNK_SIZE)
+        .map(|chunk| {
+            let cursor = bufman.open_cursor()?;
+            let embeddings = chunk
+                .iter()
+                .map(|&offset| {
+                    read_embedding_with_cursor(&bufman, cursor, offset).map(|(emb, _)| emb)
+                })
+                .collect::<Result<Vec<_>, _>>();
+            bufman.close_cursor(cursor)?;
+            embeddings
+        })
+        .collect::<Result<Vec<_>, WaCustomError>>()?;
 
+    Ok(chunks.into_iter().flatten().collect())
+}
+
+/// Iterates over the embeddings of a file, from a starting offset up to the
+/// length the file had when the reader was created. Each embedding is yielded
+/// with the offset of the one following it, like `read_embedding` returns.
+///
+/// Embeddings are read ahead in chunks with `read_embeddings_parallel`.
+/// Iteration ends after the first error.
+pub struct EmbeddingReader {
+    bufman: Arc<BufferManager>,
+    // offset of the first embedding not read ahead yet
+    offset: u32,
+    end: u32,
+    read_ahead_size: usize,
+    read_ahead: VecDeque<(RawVectorEmbedding, u32)>,
+}
+
+impl EmbeddingReader {
+    pub fn new(bufman: Arc<BufferManager>, start: u32) -> Result<Self, WaCustomError> {
+        let end = bufman.with_cursor(|cursor| bufman.seek_with_cursor(cursor, SeekFrom::End(0)))?;
+
+        Ok(Self {
+            bufman,
+            offset: start,
+            end: end as u32,
+            read_ahead_size: READ_AHEAD_SIZE,
+            read_ahead: VecDeque::new(),
+        })
+    }
+
+    /// The file length recorded when the reader was created, embeddings
+    /// appended after that are not read.
+    pub fn end(&self) -> u32 {
+        self.end
+    }
+
+    fn read_ahead(&mut self) -> Result<(), WaCustomError> {
+        let (offsets, next) = scan_embedding_offsets_limited(
+            &self.bufman,
+            self.offset,
+            self.end,
+            self.read_ahead_size,
+        )?;
+        let embeddings = read_embeddings_parallel(self.bufman.clone(), &offsets)?;
+
+        // every embedding is followed by the next one read, the last by `next`
+        let next_offsets = offsets.iter().skip(1).copied().chain([next]);
+        self.read_ahead
+            .extend(embeddings.into_iter().zip(next_offsets));
+        self.offset = next;
+        Ok(())
+    }
+}
+
+impl Iterator for EmbeddingReader {
+    type Item = Result<(RawVectorEmbedding, u32), WaCustomError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.read_ahead.is_empty() && self.offset < self.end {
+            if let Err(e) = self.read_ahead() {
+                self.offset = self.end;
+                return Some(Err(e));
+            }
+        }
+        self.read_ahead.pop_front().map(Ok)
+    }
+}
+
+fn read_embedding_with_cursor(
+    bufman: &BufferManager,
+    cursor: u64,
+    offset: u32,
+) -> Result<(RawVectorEmbedding, u32), WaCustomError> {
     bufman
         .seek_with_cursor(cursor, SeekFrom::Start(offset as u64))
         .map_err(|e| WaCustomError::DeserializationError(e.to_string()))?;
@@ -68,32 +330,151 @@ pub fn read_embedding(
         .read_u32_with_cursor(cursor)
         .map_err(|e| WaCustomError::DeserializationError(e.to_string()))?;
 
-    let mut buf = vec![0; len as usize];
+    let mut buf = vec![0; (len & LEN_MASK) as usize];
 
     bufman
         .read_with_cursor(cursor, &mut buf)
         .map_err(|e| WaCustomError::DeserializationError(e.to_string()))?;
 
-    let emb = unsafe { rkyv::from_bytes_unchecked(&buf) }.map_err(|e| {
-        WaCustomError::DeserializationError(format!("Failed to deserialize VectorEmbedding: {}", e))
-    })?;
+    // the payload is deserialized unchecked below, so it must be verified first
+    if len & HAS_CHECKSUM != 0 {
+        verify_checksum(len, &mut buf)?;
+    }
+
+    let metadata = if len & HAS_METADATA != 0 {
+        let metadata = split_metadata(&mut buf)?;
+        Some(serde_json::from_slice(&metadata).map_err(|e| {
+            WaCustomError::DeserializationError(format!("Failed to deserialize metadata: {}", e))
+        })?)
+    } else {
+        None
+    };
+
+    let mut emb = deserialize_embedding(&buf)?;
+    emb.metadata = metadata;
 
     let next = bufman
         .cursor_position(cursor)
         .map_err(|e| WaCustomError::DeserializationError(e.to_string()))? as u32;
 
-    bufman.close_cursor(cursor)?;
-
     Ok((emb, next))
 }
 
+/// Validates the archived embedding before deserializing it, so a malformed
+/// archive is reported as an error instead of being read out of bounds.
+fn deserialize_embedding(bytes: &[u8]) -> Result<RawVectorEmbedding, WaCustomError> {
+    // the archive must be aligned for validation, which a plain `Vec<u8>`
+    // doesn't guarantee
+    let mut aligned = rkyv::AlignedVec::with_capacity(bytes.len());
+    aligned.extend_from_slice(bytes);
+
+    rkyv::from_bytes::<RawVectorEmbedding>(&aligned).map_err(|e| {
+        WaCustomError::DeserializationError(format!("Failed to deserialize VectorEmbedding: {}", e))
+    })
+}
+
+fn embedding_checksum(len: u32, payload: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&len.to_le_bytes());
+    hasher.update(payload);
+    hasher.finalize()
+}
+
+/// Strips the trailing checksum off `buf`, checking it against the rest.
+fn verify_checksum(len: u32, buf: &mut Vec<u8>) -> Result<(), WaCustomError> {
+    let checksum_start = buf.len().checked_sub(4).ok_or_else(|| {
+        WaCustomError::DeserializationError("Truncated embedding checksum".into())
+    })?;
+    let stored = u32::from_le_bytes(buf[checksum_start..].try_into().unwrap());
+    buf.truncate(checksum_start);
+
+    let computed = embedding_checksum(len, buf);
+    if stored != computed {
+        return Err(WaCustomError::DeserializationError(format!(
+            "Embedding checksum mismatch: stored {:#010x}, computed {:#010x}",
+            stored, computed
+        )));
+    }
+    Ok(())
+}
+
+/// Splits the trailing metadata and its `u32` length off `buf`, leaving
+/// only the archived embedding, and returns the JSON encoded metadata.
+fn split_metadata(buf: &mut Vec<u8>) -> Result<Vec<u8>, WaCustomError> {
+    let truncated = || WaCustomError::DeserializationError("Truncated embedding metadata".into());
+    let len_start = buf.len().checked_sub(4).ok_or_else(truncated)?;
+    let metadata_len = u32::from_le_bytes(buf[len_start..].try_into().unwrap()) as usize;
+    let metadata_start = len_start.checked_sub(metadata_len).ok_or_else(truncated)?;
+
+    let metadata = buf[metadata_start..len_start].to_vec();
+    buf.truncate(metadata_start);
+    Ok(metadata)
+}
+
+/// Stores the JSON metadata of a vector in `txn`, used to filter search
+/// results. It's written with the vector's embedding, so an embedding
+/// without metadata removes whatever an earlier one of the id stored.
+pub fn put_metadata(
+    txn: &mut lmdb::RwTransaction,
+    db: lmdb::Database,
+    vector_id: &VectorId,
+    metadata: Option<&serde_json::Value>,
+) -> Result<(), WaCustomError> {
+    let metadata_key = key!(m:vector_id);
+    let Some(metadata) = metadata else {
+        return match txn.del(db, &metadata_key, None) {
+            Ok(()) | Err(lmdb::Error::NotFound) => Ok(()),
+            Err(e) => Err(WaCustomError::DatabaseError(format!(
+                "Failed to delete metadata: {}",
+                e
+            ))),
+        };
+    };
+    let bytes = serde_json::to_vec(metadata)
+        .map_err(|e| WaCustomError::SerializationError(e.to_string()))?;
+    txn.put(db, &metadata_key, &bytes, WriteFlags::empty())
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to put data: {}", e)))
+}
+
+/// Reads the metadata stored for a vector in `txn`, `None` if it has none.
+pub fn read_metadata(
+    txn: &impl Transaction,
+    db: lmdb::Database,
+    vector_id: &VectorId,
+) -> Result<Option<serde_json::Value>, WaCustomError> {
+    let metadata_key = key!(m:vector_id);
+    let bytes = match txn.get(db, &metadata_key) {
+        Ok(bytes) => bytes,
+        Err(lmdb::Error::NotFound) => return Ok(None),
+        Err(e) => {
+            return Err(WaCustomError::DatabaseError(format!(
+                "Failed to get metadata: {}",
+                e
+            )))
+        }
+    };
+    let metadata = serde_json::from_slice(bytes)
+        .map_err(|e| WaCustomError::DeserializationError(e.to_string()))?;
+
+    Ok(Some(metadata))
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{read_embedding, write_embedding, RawVectorEmbedding};
-    use crate::models::{buffered_io::BufferManager, types::VectorId};
+    use super::{
+        deserialize_embedding, read_embedding, read_embedding_replicated, read_embeddings_parallel,
+        scan_embedding_offsets, write_embedding, write_embedding_replicated, EmbeddingOffset,
+        EmbeddingReader, RawVectorEmbedding,
+    };
+    use crate::models::{
+        buffered_io::BufferManager, common::WaCustomError, types::VectorId, versioning::Hash,
+    };
     use rand::{distributions::Uniform, rngs::ThreadRng, thread_rng, Rng};
+    use serde_json::json;
+    use std::fs::OpenOptions;
+    use std::io::SeekFrom;
     use std::sync::Arc;
-    use tempfile::tempfile;
+    use tempfile::{tempdir, tempfile};
 
     fn get_random_embedding(rng: &mut ThreadRng) -> RawVectorEmbedding {
         let range = Uniform::new(-1.0, 1.0);
@@ -106,6 +487,7 @@ mod tests {
         RawVectorEmbedding {
             raw_vec: Arc::new(raw_vec),
             hash_vec: VectorId(rng.gen()),
+            metadata: None,
         }
     }
 
@@ -144,4 +526,248 @@ mod tests {
             assert_eq!(embedding, deserialized);
         }
     }
+
+    #[test]
+    fn test_embedding_metadata_serialization() {
+        let mut rng = thread_rng();
+        let mut with_metadata = get_random_embedding(&mut rng);
+        with_metadata.metadata = Some(json!({
+            "title": "a vector",
+            "tags": ["x", "y"],
+            "score": 0.75,
+        }));
+        // embeddings without metadata don't set `HAS_METADATA`, and read
+        // back as `None`
+        let without_metadata = get_random_embedding(&mut rng);
+        let tempfile = tempfile().unwrap();
+
+        let bufman = Arc::new(BufferManager::new(tempfile, 1.0).unwrap());
+        let first = write_embedding(bufman.clone(), &without_metadata).unwrap();
+        let second = write_embedding(bufman.clone(), &with_metadata).unwrap();
+        let third = write_embedding(bufman.clone(), &without_metadata).unwrap();
+
+        let (deserialized, next) = read_embedding(bufman.clone(), first).unwrap();
+        assert_eq!(deserialized, without_metadata);
+        assert_eq!(next, second);
+
+        let (deserialized, next) = read_embedding(bufman.clone(), second).unwrap();
+        assert_eq!(deserialized, with_metadata);
+        assert_eq!(next, third);
+
+        let (deserialized, _) = read_embedding(bufman.clone(), third).unwrap();
+        assert_eq!(deserialized.metadata, None);
+    }
+
+    #[test]
+    fn test_embedding_checksum_detects_corruption() {
+        let mut rng = thread_rng();
+        let embedding = get_random_embedding(&mut rng);
+        let tempfile = tempfile().unwrap();
+
+        let bufman = Arc::new(BufferManager::new(tempfile, 1.0).unwrap());
+        let offset = write_embedding(bufman.clone(), &embedding).unwrap();
+        assert_eq!(read_embedding(bufman.clone(), offset).unwrap().0, embedding);
+
+        // flip a byte inside the archived vector
+        let cursor = bufman.open_cursor().unwrap();
+        let position = SeekFrom::Start(offset as u64 + 24);
+        let mut byte = [0u8];
+        bufman.seek_with_cursor(cursor, position).unwrap();
+        bufman.read_with_cursor(cursor, &mut byte).unwrap();
+        bufman.seek_with_cursor(cursor, position).unwrap();
+        bufman.write_with_cursor(cursor, &[byte[0] ^ 0xff]).unwrap();
+        bufman.close_cursor(cursor).unwrap();
+
+        match read_embedding(bufman.clone(), offset) {
+            Err(WaCustomError::DeserializationError(msg)) => {
+                assert!(msg.contains("checksum mismatch"))
+            }
+            _ => panic!("expected the corrupted embedding to be rejected"),
+        }
+    }
+
+    #[test]
+    fn test_embedding_recovered_from_replica() {
+        let mut rng = thread_rng();
+        let embeddings: Vec<_> = (0..3).map(|_| get_random_embedding(&mut rng)).collect();
+        let bufmans: Vec<_> = (0..2)
+            .map(|_| Arc::new(BufferManager::new(tempfile().unwrap(), 1.0).unwrap()))
+            .collect();
+
+        let offsets: Vec<_> = embeddings
+            .iter()
+            .map(|embedding| write_embedding_replicated(&bufmans, embedding).unwrap())
+            .collect();
+        for (embedding, &offset) in embeddings.iter().zip(&offsets) {
+            assert_eq!(
+                &read_embedding(bufmans[1].clone(), offset).unwrap().0,
+                embedding
+            );
+        }
+
+        // corrupt the middle embedding in the primary copy only
+        let primary = &bufmans[0];
+        let cursor = primary.open_cursor().unwrap();
+        primary
+            .seek_with_cursor(cursor, SeekFrom::Start(offsets[1] as u64 + 24))
+            .unwrap();
+        primary.write_with_cursor(cursor, &[0xff; 8]).unwrap();
+        primary.close_cursor(cursor).unwrap();
+        assert!(read_embedding(primary.clone(), offsets[1]).is_err());
+
+        for (embedding, &offset) in embeddings.iter().zip(&offsets) {
+            let (recovered, _) = read_embedding_replicated(&bufmans, offset).unwrap();
+            assert_eq!(&recovered, embedding);
+        }
+
+        // without an intact copy the read still fails
+        assert!(read_embedding_replicated(&bufmans[..1], offsets[1]).is_err());
+        assert!(read_embedding_replicated(&[], offsets[0]).is_err());
+    }
+
+    #[test]
+    fn test_failed_replicated_write_keeps_copies_in_sync() {
+        let mut rng = thread_rng();
+        let embeddings: Vec<_> = (0..3).map(|_| get_random_embedding(&mut rng)).collect();
+        let healthy: Vec<_> = (0..2)
+            .map(|_| Arc::new(BufferManager::new(tempfile().unwrap(), 1.0).unwrap()))
+            .collect();
+        let first = write_embedding_replicated(&healthy, &embeddings[0]).unwrap();
+
+        // a copy that can't be read back can't be written either, as its
+        // buffers are filled from the file first
+        let dir = tempdir().unwrap();
+        let write_only = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .open(dir.path().join("copy.vec_raw"))
+            .unwrap();
+        let mut bufmans = healthy.clone();
+        bufmans.push(Arc::new(BufferManager::new(write_only, 1.0).unwrap()));
+
+        let len = healthy[0].file_size().unwrap();
+        assert!(write_embedding_replicated(&bufmans, &embeddings[1]).is_err());
+        for bufman in &healthy {
+            assert_eq!(bufman.file_size().unwrap(), len);
+        }
+
+        // the copies that did take the write were rolled back with it, so
+        // the next one lands at the same offset in all of them
+        let second = write_embedding_replicated(&healthy, &embeddings[2]).unwrap();
+        assert_eq!(second as u64, len);
+        for bufman in &healthy {
+            assert_eq!(
+                read_embedding(bufman.clone(), first).unwrap().0,
+                embeddings[0]
+            );
+            assert_eq!(
+                read_embedding(bufman.clone(), second).unwrap().0,
+                embeddings[2]
+            );
+        }
+    }
+
+    #[test]
+    fn test_malformed_embedding_is_rejected() {
+        let mut rng = thread_rng();
+        let embedding = get_random_embedding(&mut rng);
+        let archived = rkyv::to_bytes::<_, 256>(&embedding).unwrap();
+        assert_eq!(deserialize_embedding(&archived).unwrap(), embedding);
+
+        // the root (at the end of the archive) points past the buffer
+        let mut malformed = archived.to_vec();
+        let len = malformed.len();
+        malformed[len - 16..].fill(0x7f);
+        assert!(matches!(
+            deserialize_embedding(&malformed),
+            Err(WaCustomError::DeserializationError(_))
+        ));
+
+        // too short to even hold the root
+        assert!(matches!(
+            deserialize_embedding(&[0xff; 3]),
+            Err(WaCustomError::DeserializationError(_))
+        ));
+    }
+
+    #[test]
+    fn test_parallel_embedding_read_preserves_order() {
+        let mut rng = thread_rng();
+        let mut embeddings: Vec<_> = (0..300).map(|_| get_random_embedding(&mut rng)).collect();
+        embeddings[7].metadata = Some(json!({ "tag": "seven" }));
+        let tempfile = tempfile().unwrap();
+
+        let bufman = Arc::new(BufferManager::new(tempfile, 1.0).unwrap());
+        let mut written = Vec::new();
+        for embedding in &embeddings {
+            written.push(write_embedding(bufman.clone(), embedding).unwrap());
+        }
+        let (_, end) = read_embedding(bufman.clone(), *written.last().unwrap()).unwrap();
+
+        let offsets = scan_embedding_offsets(&bufman, 0, end).unwrap();
+        assert_eq!(offsets, written);
+        assert_eq!(
+            scan_embedding_offsets(&bufman, written[100], end).unwrap(),
+            &written[100..]
+        );
+
+        let deserialized = read_embeddings_parallel(bufman.clone(), &offsets).unwrap();
+        assert_eq!(deserialized, embeddings);
+    }
+
+    #[test]
+    fn test_embedding_reader() {
+        let mut rng = thread_rng();
+        let embeddings: Vec<_> = (0..20).map(|_| get_random_embedding(&mut rng)).collect();
+        let tempfile = tempfile().unwrap();
+
+        let bufman = Arc::new(BufferManager::new(tempfile, 1.0).unwrap());
+        let written: Vec<_> = embeddings
+            .iter()
+            .map(|embedding| write_embedding(bufman.clone(), embedding).unwrap())
+            .collect();
+
+        let mut reader = EmbeddingReader::new(bufman.clone(), 0).unwrap();
+        // read ahead across several chunks
+        reader.read_ahead_size = 6;
+        let end = reader.end();
+        // not read, it's past the length recorded by the reader
+        write_embedding(bufman.clone(), &embeddings[0]).unwrap();
+
+        let read: Vec<_> = reader.collect::<Result<_, _>>().unwrap();
+        assert_eq!(read.len(), 20);
+        for (i, (deserialized, next)) in read.iter().enumerate() {
+            assert_eq!(deserialized, &embeddings[i]);
+            assert_eq!(*next, written.get(i + 1).copied().unwrap_or(end));
+        }
+
+        let from_middle = EmbeddingReader::new(bufman.clone(), written[15]).unwrap();
+        let read: Vec<_> = from_middle.map(|result| result.unwrap().0).collect();
+        assert_eq!(read[..5], embeddings[15..]);
+        assert_eq!(read.len(), 6);
+    }
+
+    #[test]
+    fn test_embedding_offset_formats() {
+        let offset = EmbeddingOffset {
+            version: Hash::from(0xdead_beef),
+            offset: 4096,
+        };
+
+        let bytes = offset.serialize();
+        assert_eq!(bytes.len(), 9);
+        assert_eq!(EmbeddingOffset::deserialize(&bytes).unwrap(), offset);
+
+        // records written before the format byte was introduced
+        let mut legacy = Vec::new();
+        legacy.extend_from_slice(&0xdead_beef_u32.to_le_bytes());
+        legacy.extend_from_slice(&4096_u32.to_le_bytes());
+        assert_eq!(EmbeddingOffset::deserialize(&legacy).unwrap(), offset);
+
+        let mut unknown = bytes.clone();
+        unknown[0] = 0xff;
+        assert!(EmbeddingOffset::deserialize(&unknown).is_err());
+        assert!(EmbeddingOffset::deserialize(&bytes[..5]).is_err());
+        assert!(EmbeddingOffset::deserialize(&[]).is_err());
+    }
 }
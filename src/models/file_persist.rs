@@ -3,12 +3,13 @@ use super::common::WaCustomError;
 use super::lazy_load::SyncPersist;
 use super::prob_node::SharedNode;
 use super::serializer::prob::ProbSerialize;
-use super::types::{BytesToRead, FileOffset, NodeProp, VectorId};
+use super::types::{BytesToRead, EmbeddingSource, FileOffset, NodeProp, PropLocation, VectorId};
 use super::versioning::Hash;
 use crate::storage::Storage;
 use serde::{Deserialize, Serialize};
-use std::fs::File;
-use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 // pub fn read_node_from_file(
@@ -72,6 +73,8 @@ pub fn write_node_to_file(
 pub struct NodePropSerialize<'a> {
     pub id: &'a VectorId,
     pub value: Arc<Storage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<EmbeddingSource>,
 }
 
 #[allow(dead_code)]
@@ -79,14 +82,126 @@ pub struct NodePropSerialize<'a> {
 pub struct NodePropDeserialize {
     pub id: VectorId,
     pub value: Arc<Storage>,
+    #[serde(default)]
+    pub source: Option<EmbeddingSource>,
+}
+
+/// path of the prop file inside a collection's directory
+pub fn prop_file_path(collection_path: &Path) -> PathBuf {
+    collection_path.join("prop.data")
+}
+
+/// path of the prop file being written by a compaction of `prop_file_path`
+pub fn compacted_prop_file_path(prop_file_path: &Path) -> PathBuf {
+    prop_file_path.with_extension("data.compacting")
+}
+
+/// path of the journal of a compaction of `prop_file_path`, present from
+/// the moment the compaction is bound to complete until it has
+pub fn prop_journal_path(prop_file_path: &Path) -> PathBuf {
+    prop_file_path.with_extension("data.journal")
+}
+
+/// A node whose prop moves to `prop_offset` in the compacted prop file, by
+/// the version and offset of the node in the index files.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PropRelocation {
+    pub version_id: u32,
+    pub node_offset: u32,
+    pub prop_offset: u32,
+    pub prop_length: u32,
+}
+
+fn sync_dir(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
+/// Journals the compaction of `prop_file_path` into its compacted file,
+/// which must already be synced. Once this returns the compaction is bound
+/// to complete, through `finish_prop_compaction`.
+pub fn write_prop_journal(
+    prop_file_path: &Path,
+    relocations: &[PropRelocation],
+) -> Result<(), WaCustomError> {
+    let fs_error = |e: io::Error| WaCustomError::FsError(e.to_string());
+    let bytes = serde_cbor::to_vec(&relocations)
+        .map_err(|e| WaCustomError::SerializationError(e.to_string()))?;
+
+    // renamed in place only once complete, a torn journal is never read
+    let journal_path = prop_journal_path(prop_file_path);
+    let tmp_path = journal_path.with_extension("journal.tmp");
+    let mut tmp = File::create(&tmp_path).map_err(fs_error)?;
+    tmp.write_all(&bytes).map_err(fs_error)?;
+    tmp.sync_all().map_err(fs_error)?;
+    fs::rename(&tmp_path, &journal_path).map_err(fs_error)?;
+    sync_dir(prop_file_path).map_err(fs_error)
+}
+
+/// Completes a journaled compaction of `prop_file_path`: points the nodes in
+/// the index files at the new locations of their props, then moves the
+/// compacted file in place of the old one. Every step can be repeated, so a
+/// compaction interrupted half way is completed by calling this again, which
+/// is done when a collection is loaded.
+///
+/// Without a journal, a compacted file left by a compaction that didn't get
+/// that far is removed instead. Returns whether a compaction was completed.
+pub fn finish_prop_compaction(
+    prop_file_path: &Path,
+    index_manager: &BufferManagerFactory<Hash>,
+) -> Result<bool, WaCustomError> {
+    let fs_error = |e: io::Error| WaCustomError::FsError(e.to_string());
+    let compacted_path = compacted_prop_file_path(prop_file_path);
+    let journal_path = prop_journal_path(prop_file_path);
+
+    let bytes = match fs::read(&journal_path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            match fs::remove_file(&compacted_path) {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(fs_error(e)),
+            }
+            return Ok(false);
+        }
+        Err(e) => return Err(fs_error(e)),
+    };
+    let relocations: Vec<PropRelocation> = serde_cbor::from_slice(&bytes)
+        .map_err(|e| WaCustomError::DeserializationError(e.to_string()))?;
+
+    for relocation in &relocations {
+        let bufman = index_manager.get(Hash::from(relocation.version_id))?;
+        bufman.with_cursor(|cursor| -> Result<(), BufIoError> {
+            // the prop location follows the level byte
+            bufman.seek_with_cursor(cursor, SeekFrom::Start(relocation.node_offset as u64 + 1))?;
+            bufman.write_u32_with_cursor(cursor, relocation.prop_offset)?;
+            bufman.write_u32_with_cursor(cursor, relocation.prop_length)?;
+            Ok(())
+        })?;
+    }
+    index_manager.sync_all()?;
+
+    // already moved if an earlier attempt got past this point
+    match fs::rename(&compacted_path, prop_file_path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(fs_error(e)),
+    }
+    sync_dir(prop_file_path).map_err(fs_error)?;
+    fs::remove_file(&journal_path).map_err(fs_error)?;
+
+    Ok(true)
 }
 
 pub fn write_prop_to_file(
     id: &VectorId,
     value: Arc<Storage>,
+    source: Option<EmbeddingSource>,
     mut file: &File,
 ) -> Result<(FileOffset, BytesToRead), WaCustomError> {
-    let prop = NodePropSerialize { id, value };
+    let prop = NodePropSerialize { id, value, source };
     let prop_bytes =
         serde_cbor::to_vec(&prop).map_err(|e| WaCustomError::SerializationError(e.to_string()))?;
 
@@ -103,6 +218,20 @@ pub fn write_prop_to_file(
     ))
 }
 
+/// Checks the end of a freshly written prop against `soft_limit`, returning
+/// `WaCustomError::PropFileSizeExceeded` once `prop.data` has grown past it.
+/// The prop itself has already been written and stays readable.
+pub fn check_prop_file_size(
+    (offset, bytes_to_read): (FileOffset, BytesToRead),
+    soft_limit: u64,
+) -> Result<(), WaCustomError> {
+    let size = offset.0 as u64 + bytes_to_read.0 as u64;
+    if size > soft_limit {
+        return Err(WaCustomError::PropFileSizeExceeded(size, soft_limit));
+    }
+    Ok(())
+}
+
 pub fn read_prop_from_file(
     (offset, bytes_to_read): (FileOffset, BytesToRead),
     file: &mut File,
@@ -117,6 +246,87 @@ pub fn read_prop_from_file(
     Ok(NodeProp {
         id: prop.id,
         value: prop.value,
-        location: (offset, bytes_to_read),
+        location: PropLocation::new((offset, bytes_to_read)),
+        source: prop.source,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{check_prop_file_size, read_prop_from_file, write_prop_to_file};
+    use crate::models::common::WaCustomError;
+    use crate::models::types::{BytesToRead, EmbeddingSource, FileOffset, VectorId};
+    use crate::models::versioning::Hash;
+    use crate::storage::Storage;
+    use serde::Serialize;
+    use std::io::{Seek, SeekFrom, Write};
+    use std::sync::Arc;
+    use tempfile::tempfile;
+
+    #[test]
+    fn test_prop_file_soft_limit() {
+        let mut file = tempfile().unwrap();
+        let value = Arc::new(Storage::UnsignedByte {
+            mag: 14,
+            quant_vec: vec![1, 2, 3],
+        });
+
+        let first = write_prop_to_file(&VectorId(1), value.clone(), None, &file).unwrap();
+        let soft_limit = first.0 .0 as u64 + first.1 .0 as u64;
+        assert!(check_prop_file_size(first, soft_limit).is_ok());
+
+        let second = write_prop_to_file(&VectorId(2), value.clone(), None, &file).unwrap();
+        match check_prop_file_size(second, soft_limit) {
+            Err(WaCustomError::PropFileSizeExceeded(size, limit)) => {
+                assert!(size > limit);
+                assert_eq!(limit, soft_limit);
+            }
+            _ => panic!("expected the soft limit to be reported as exceeded"),
+        }
+
+        // props written past the limit are still readable
+        let prop = read_prop_from_file(first, &mut file).unwrap();
+        assert_eq!(prop.id, VectorId(1));
+        assert_eq!(prop.value, value);
+        let prop = read_prop_from_file(second, &mut file).unwrap();
+        assert_eq!(prop.id, VectorId(2));
+        assert_eq!(prop.value, value);
+    }
+
+    #[test]
+    fn test_prop_source_round_trips() {
+        let mut file = tempfile().unwrap();
+        let value = Arc::new(Storage::UnsignedByte {
+            mag: 14,
+            quant_vec: vec![1, 2, 3],
+        });
+        let source = EmbeddingSource {
+            version: Hash::from(7),
+            offset: Some(120),
+        };
+
+        let with_source =
+            write_prop_to_file(&VectorId(1), value.clone(), Some(source), &file).unwrap();
+        // written the way props were before they recorded their source
+        let legacy = serde_cbor::to_vec(&LegacyProp {
+            id: &VectorId(2),
+            value: value.clone(),
+        })
+        .unwrap();
+        let offset = file.seek(SeekFrom::End(0)).unwrap();
+        file.write_all(&legacy).unwrap();
+        let without_source = (FileOffset(offset as u32), BytesToRead(legacy.len() as u32));
+
+        let prop = read_prop_from_file(with_source, &mut file).unwrap();
+        assert_eq!(prop.source, Some(source));
+        let prop = read_prop_from_file(without_source, &mut file).unwrap();
+        assert_eq!(prop.id, VectorId(2));
+        assert_eq!(prop.source, None);
+    }
+
+    #[derive(Serialize)]
+    struct LegacyProp<'a> {
+        id: &'a VectorId,
+        value: Arc<Storage>,
+    }
+}
@@ -1,4 +1,6 @@
+use super::buffered_io::BufIoError;
 use super::cache_loader::{Cacheable, NodeRegistry};
+use super::chunked_list::CHUNK_SIZE;
 use super::common::WaCustomError;
 use super::identity_collections::{Identifiable, IdentityMap, IdentityMapKey, IdentitySet};
 use super::serializer::CustomSerialize;
@@ -40,8 +42,6 @@ pub trait SyncPersist {
     fn get_current_version_number(&self) -> u16;
 }
 
-pub const CHUNK_SIZE: usize = 5;
-
 #[derive(Debug, Eq, PartialEq, Hash, Copy, Clone, Serialize, Deserialize)]
 pub enum FileIndex {
     Valid {
@@ -195,14 +195,22 @@ where
     pub item: ArcShift<LazyItem<T>>,
 }
 
+/// Reads the chunks of a deserialized set past its first one.
+pub type ChunkLoader<T, E> =
+    Arc<dyn Fn() -> Result<Vec<EagerLazyItem<T, E>>, BufIoError> + Send + Sync>;
+
+/// Serialized in chunks of `C` items, neighbor lists use the default.
+/// Deserializing reads the first chunk only, the rest is read the first time
+/// the whole set is needed.
 #[derive(Clone)]
-pub struct EagerLazyItemSet<T, E>
+pub struct EagerLazyItemSet<T, E, const C: usize = CHUNK_SIZE>
 where
     T: Clone + Identifiable<Id = u64> + 'static,
     E: Clone + CustomSerialize + 'static,
 {
     pub serialized_offset: ArcShift<Option<u32>>,
     pub items: STM<IdentitySet<EagerLazyItem<T, E>>>,
+    pub unloaded_chunks: ArcShift<Option<ChunkLoader<T, E>>>,
 }
 
 #[derive(Clone)]
@@ -845,7 +853,7 @@ impl<T: Clone + 'static> LazyItemRef<T> {
     }
 }
 
-impl<T, E> EagerLazyItemSet<T, E>
+impl<T, E, const C: usize> EagerLazyItemSet<T, E, C>
 where
     T: Clone + Identifiable<Id = u64> + 'static,
     E: Clone + CustomSerialize + 'static,
@@ -854,6 +862,7 @@ where
         Self {
             serialized_offset: ArcShift::new(None),
             items: STM::new(IdentitySet::new(), 5, false),
+            unloaded_chunks: ArcShift::new(None),
         }
     }
 
@@ -861,10 +870,33 @@ where
         Self {
             serialized_offset: ArcShift::new(None),
             items: STM::new(set, 5, false),
+            unloaded_chunks: ArcShift::new(None),
         }
     }
 
+    /// Reads the chunks deserialization left unread into the set, if any.
+    pub fn load_rest(&self) -> Result<(), BufIoError> {
+        let mut unloaded_chunks = self.unloaded_chunks.clone();
+        let Some(loader) = unloaded_chunks.get().clone() else {
+            return Ok(());
+        };
+        let rest = loader()?;
+
+        let mut arc = self.items.clone();
+        arc.transactional_update(|set| {
+            let mut set = set.clone();
+            for item in &rest {
+                set.insert(item.clone());
+            }
+            set
+        })
+        .unwrap();
+        unloaded_chunks.update(None);
+        Ok(())
+    }
+
     pub fn insert(&self, item: EagerLazyItem<T, E>) {
+        self.load_rest().expect("Deserialization failed");
         let mut arc = self.items.clone();
 
         arc.transactional_update(|set| {
@@ -876,17 +908,20 @@ where
     }
 
     pub fn iter(&self) -> impl Iterator<Item = EagerLazyItem<T, E>> {
+        self.load_rest().expect("Deserialization failed");
         let mut arc = self.items.clone();
         let vec: Vec<_> = arc.get().iter().map(Clone::clone).collect();
         vec.into_iter()
     }
 
     pub fn is_empty(&self) -> bool {
+        // chunks are only left unread after a full first one
         let mut arc = self.items.clone();
         arc.get().is_empty()
     }
 
     pub fn len(&self) -> usize {
+        self.load_rest().expect("Deserialization failed");
         let mut arc = self.items.clone();
         arc.get().len()
     }
@@ -1122,15 +1157,16 @@ impl<T: Clone + 'static, const N: usize> LazyItemArray<T, N> {
         }
     }
 
+    /// Items past `N` are dropped, chunked lists pad the array with empty
+    /// slots up to a whole chunk.
     pub fn from_vec(vec: Vec<Option<LazyItem<T>>>) -> Self {
         let arr = LazyItemArray::new();
-        let _ = vec.iter().enumerate().map(|(index, value)| {
-            match value {
-                Some(val) => arr.insert(index, val.clone()),
-                None => {}
-            };
-        });
-        return arr;
+        for (index, value) in vec.into_iter().enumerate().take(N) {
+            if let Some(value) = value {
+                arr.insert(index, value);
+            }
+        }
+        arr
     }
 
     pub fn insert(&self, index: usize, value: LazyItem<T>) {
@@ -1293,6 +1329,35 @@ impl IncrementalSerializableGrowableData {
         .clone();
         vector_data_stm.get().get(insert_dimension)
     }
+
+    /// Clears `vec_id` from its slot, returns `true` if it was present.
+    pub fn remove(&self, vec_id: u32) -> bool {
+        let remove_dimension = (vec_id % 64) as usize;
+        let remove_index = (vec_id / 64) as usize;
+
+        let Some(vector_data_lazy_item) = self.items.get(remove_index) else {
+            return false;
+        };
+        let mut vector_data_stm = (*vector_data_lazy_item
+            .get_lazy_data()
+            .unwrap()
+            .get()
+            .clone()
+            .unwrap())
+        .clone();
+        let mut removed = false;
+        vector_data_stm
+            .transactional_update(|old| {
+                let mut new = old.clone();
+                removed = old.get(remove_dimension) == Some(vec_id);
+                if removed {
+                    new.set(remove_dimension, u32::MAX);
+                }
+                new
+            })
+            .unwrap();
+        removed
+    }
 }
 
 #[cfg(test)]
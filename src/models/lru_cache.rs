@@ -139,6 +139,15 @@ where
     evict_strategy: EvictStrategy,
     index: EvictionIndex,
     evict_hook: Option<fn(&V)>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// Lookups served by an `LRUCache` so far.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
 }
 
 /// Wrapper for the value that's returned from the LRUCache when
@@ -171,6 +180,8 @@ where
             evict_hook: None,
             capacity,
             evict_strategy,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
         }
     }
 
@@ -180,6 +191,28 @@ where
         Self::new(capacity, strategy)
     }
 
+    /// Number of entries the cache holds before it starts evicting.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Number of entries currently in the cache.
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Hits and misses of `get` and `get_or_insert` so far.
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
     pub fn set_evict_hook(&mut self, hook: Option<fn(&V)>) {
         self.evict_hook = hook;
     }
@@ -195,8 +228,10 @@ where
             *counter_val = new_counter;
             self.index
                 .on_cache_hit(old_counter, new_counter, key.clone().into());
+            self.hits.fetch_add(1, Ordering::Relaxed);
             Some(value.clone())
         } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
             None
         }
     }
@@ -246,8 +281,10 @@ where
             Ok(v) => {
                 if inserted {
                     // self.evict();
+                    self.misses.fetch_add(1, Ordering::Relaxed);
                     Ok(CachedValue::Miss(v))
                 } else {
+                    self.hits.fetch_add(1, Ordering::Relaxed);
                     Ok(CachedValue::Hit(v))
                 }
             }
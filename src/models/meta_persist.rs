@@ -1,3 +1,4 @@
+use crate::macros::key;
 use crate::models::common::*;
 use crate::models::types::*;
 use crate::models::versioning::*;
@@ -10,6 +11,7 @@ use std::sync::Arc;
 
 use super::collection::Collection;
 use super::lazy_load::FileIndex;
+use super::prob_node::SharedNode;
 
 /// updates the current version of a collection
 pub fn update_current_version(lmdb: &MetaDb, version_hash: Hash) -> Result<(), WaCustomError> {
@@ -32,6 +34,201 @@ pub fn update_current_version(lmdb: &MetaDb, version_hash: Hash) -> Result<(), W
     Ok(())
 }
 
+/// stores the root of the graph built for `branch` of a collection
+pub fn persist_branch_root(
+    lmdb: &MetaDb,
+    branch: BranchId,
+    file_index: FileIndex,
+) -> Result<(), WaCustomError> {
+    let env = lmdb.env.clone();
+    let db = lmdb.db.clone();
+
+    let mut txn = env
+        .begin_rw_txn()
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e)))?;
+
+    let bytes =
+        to_vec(&file_index).map_err(|e| WaCustomError::SerializationError(e.to_string()))?;
+
+    txn.put(*db, &key!(r:branch), &bytes, WriteFlags::empty())
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to put data: {}", e)))?;
+
+    txn.commit().map_err(|e| {
+        WaCustomError::DatabaseError(format!("Failed to commit transaction: {}", e))
+    })?;
+
+    Ok(())
+}
+
+/// retrieves the root of the graph built for `branch` of a collection
+pub fn retrieve_branch_root(
+    lmdb: &MetaDb,
+    branch: BranchId,
+) -> Result<Option<FileIndex>, WaCustomError> {
+    let env = lmdb.env.clone();
+    let db = lmdb.db.clone();
+    let txn = env
+        .begin_ro_txn()
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e)))?;
+
+    let bytes = match txn.get(*db, &key!(r:branch)) {
+        Ok(bytes) => bytes,
+        Err(lmdb::Error::NotFound) => return Ok(None),
+        Err(e) => return Err(WaCustomError::DatabaseError(e.to_string())),
+    };
+    let file_index =
+        from_slice(bytes).map_err(|e| WaCustomError::DeserializationError(e.to_string()))?;
+
+    Ok(Some(file_index))
+}
+
+/// records where `nodes` were written in the index files, keyed by vector id
+/// and level, so that a node can be found without walking the graph. Nodes
+/// that haven't been written yet are skipped.
+pub fn persist_node_locations(lmdb: &MetaDb, nodes: &[SharedNode]) -> Result<(), WaCustomError> {
+    if nodes.is_empty() {
+        return Ok(());
+    }
+    let env = lmdb.env.clone();
+    let db = lmdb.db.clone();
+
+    let mut txn = env
+        .begin_rw_txn()
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e)))?;
+
+    for &node in nodes {
+        let item = unsafe { &*node };
+        let (Some(data), Some(file_index)) = (item.get_lazy_data(), item.get_file_index()) else {
+            continue;
+        };
+        let FileIndex::Valid {
+            offset: FileOffset(offset),
+            version_id,
+            ..
+        } = file_index
+        else {
+            continue;
+        };
+        let bytes =
+            to_vec(&file_index).map_err(|e| WaCustomError::SerializationError(e.to_string()))?;
+        txn.put(
+            *db,
+            &key!(n:data.prop.id, data.hnsw_level, version_id, offset),
+            &bytes,
+            WriteFlags::empty(),
+        )
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to put data: {}", e)))?;
+    }
+
+    txn.commit().map_err(|e| {
+        WaCustomError::DatabaseError(format!("Failed to commit transaction: {}", e))
+    })?;
+
+    Ok(())
+}
+
+/// retrieves the location of the latest node of `vector_id` on `level`, as
+/// recorded by `persist_node_locations`
+pub fn retrieve_node_location(
+    lmdb: &MetaDb,
+    vector_id: &VectorId,
+    level: HNSWLevel,
+) -> Result<Option<FileIndex>, WaCustomError> {
+    // a vector uploaded again gets a new node, written after the old one
+    let written_at = |file_index: &FileIndex| match file_index {
+        FileIndex::Valid {
+            offset,
+            version_number,
+            ..
+        } => (*version_number, offset.0),
+        FileIndex::Invalid => (0, 0),
+    };
+    let prefix = key!(n:vector_id, level);
+    let mut latest: Option<FileIndex> = None;
+    for file_index in retrieve_node_locations_with_prefix(lmdb, &prefix)? {
+        if latest.map_or(true, |latest| written_at(&file_index) > written_at(&latest)) {
+            latest = Some(file_index);
+        }
+    }
+    Ok(latest)
+}
+
+/// retrieves the locations of all the nodes recorded by
+/// `persist_node_locations`, the root nodes aren't among them
+pub fn retrieve_node_locations(lmdb: &MetaDb) -> Result<Vec<FileIndex>, WaCustomError> {
+    retrieve_node_locations_with_prefix(lmdb, &[6])
+}
+
+/// Moves the embedding offsets stored before they were keyed by branch, which
+/// all branches shared, to the keys of each of `branches`. Returns the number
+/// of offsets moved.
+pub fn migrate_shared_embedding_offsets(
+    lmdb: &MetaDb,
+    branches: &[BranchId],
+) -> Result<usize, WaCustomError> {
+    let env = lmdb.env.clone();
+    let db = lmdb.db.clone();
+    let mut txn = env
+        .begin_rw_txn()
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e)))?;
+
+    let mut legacy = Vec::new();
+    {
+        let mut cursor = txn
+            .open_ro_cursor(*db)
+            .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
+        for (key, value) in cursor.iter_from([1u8]) {
+            if key.first() != Some(&1) {
+                break;
+            }
+            // legacy keys are a `1` prefix followed by the 8 byte id
+            if key.len() == 9 {
+                legacy.push((key.to_vec(), value.to_vec()));
+            }
+        }
+    }
+
+    for (key, value) in &legacy {
+        let id = VectorId(u64::from_le_bytes(key[1..].try_into().unwrap()));
+        for branch in branches {
+            txn.put(*db, &key!(e:branch, id), value, WriteFlags::empty())
+                .map_err(|e| WaCustomError::DatabaseError(format!("Failed to put data: {}", e)))?;
+        }
+        txn.del(*db, key, None)
+            .map_err(|e| WaCustomError::DatabaseError(format!("Failed to delete data: {}", e)))?;
+    }
+    txn.commit().map_err(|e| {
+        WaCustomError::DatabaseError(format!("Failed to commit transaction: {}", e))
+    })?;
+
+    Ok(legacy.len())
+}
+
+fn retrieve_node_locations_with_prefix(
+    lmdb: &MetaDb,
+    prefix: &[u8],
+) -> Result<Vec<FileIndex>, WaCustomError> {
+    let env = lmdb.env.clone();
+    let db = lmdb.db.clone();
+    let txn = env
+        .begin_ro_txn()
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e)))?;
+    let mut cursor = txn
+        .open_ro_cursor(*db)
+        .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
+
+    let mut locations = Vec::new();
+    for (key, value) in cursor.iter_from(prefix) {
+        if !key.starts_with(prefix) {
+            break;
+        }
+        locations.push(
+            from_slice(value).map_err(|e| WaCustomError::DeserializationError(e.to_string()))?,
+        );
+    }
+    Ok(locations)
+}
+
 /// retrieves the current version of a collection
 pub fn retrieve_current_version(lmdb: &MetaDb) -> Result<Hash, WaCustomError> {
     let env = lmdb.env.clone();
@@ -70,6 +267,8 @@ pub struct DenseIndexData {
     pub size: usize,
     pub lower_bound: Option<f32>,
     pub upper_bound: Option<f32>,
+    #[serde(default)]
+    pub level_seed: Option<u64>,
 }
 
 impl TryFrom<Arc<DenseIndex>> for DenseIndexData {
@@ -97,6 +296,7 @@ impl TryFrom<Arc<DenseIndex>> for DenseIndexData {
             size: 0,
             lower_bound: None,
             upper_bound: None,
+            level_seed: dense_index.level_seed,
         };
         Ok(dense_index_data)
     }
@@ -113,11 +313,14 @@ pub fn lmdb_init_db(env: &Environment, name: &str) -> lmdb::Result<Database> {
 
 pub(crate) fn load_collections(env: &Environment, db: Database) -> lmdb::Result<Vec<Collection>> {
     let mut collections = Vec::new();
-    let txn = env.begin_ro_txn().unwrap();
-    let mut cursor = txn.open_ro_cursor(db).unwrap();
-    for (_k, v) in cursor.iter() {
-        let col: Collection = from_slice(&v[..]).unwrap();
-        collections.push(col);
+    let txn = env.begin_ro_txn()?;
+    let mut cursor = txn.open_ro_cursor(db)?;
+    for (k, v) in cursor.iter() {
+        // one unreadable record shouldn't hide the other collections
+        match from_slice::<Collection>(v) {
+            Ok(col) => collections.push(col),
+            Err(err) => log::warn!("skipping unreadable collection record {:?}: {}", k, err),
+        }
     }
     Ok(collections)
 }
@@ -1,5 +1,6 @@
 pub mod buffered_io;
 pub mod cache_loader;
+pub mod chunked_list;
 pub mod collection;
 pub mod common;
 pub mod cuckoo_filter_tree;
@@ -15,10 +16,12 @@ pub mod lazy_load;
 pub mod lookup_table;
 pub mod lru_cache;
 pub mod meta_persist;
+pub mod oplog;
 pub mod prob_lazy_load;
 pub mod prob_node;
 pub mod rpc;
 pub mod serializer;
+pub mod sparse_log;
 pub mod types;
 pub mod user;
 pub mod versioning;
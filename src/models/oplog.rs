@@ -0,0 +1,157 @@
+use super::common::WaCustomError;
+use super::versioning::Hash;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::{BufWriter, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+/// A write operation recorded in the replication log.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase", tag = "type")]
+pub enum OpLogOp {
+    Upsert {
+        id: u64,
+        values: Vec<f32>,
+    },
+    // deletes are not applied by the index yet, so nothing records this variant
+    #[allow(dead_code)]
+    Delete {
+        id: u64,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OpLogEntry {
+    pub version_number: u32,
+    pub version_hash: Hash,
+    pub op: OpLogOp,
+}
+
+/// path of the replication log inside a collection's directory
+pub fn oplog_path(collection_path: &Path) -> PathBuf {
+    collection_path.join("oplog.data")
+}
+
+/// Appends `entries` to the log at `path` and syncs it to disk.
+///
+/// Each entry is stored as a little endian `u32` length followed by the CBOR
+/// encoded entry. Entries are encoded one at a time as they're written, so a
+/// batch is never held in memory as a whole. If the batch fails halfway, the
+/// log is cut back to where it ended before. Callers hold the collection's
+/// version lock, so that concurrent batches don't interleave.
+pub fn append_to_oplog(
+    path: &Path,
+    entries: impl IntoIterator<Item = OpLogEntry>,
+) -> Result<(), WaCustomError> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| WaCustomError::FsError(e.to_string()))?;
+    let start = file
+        .metadata()
+        .map_err(|e| WaCustomError::FsError(e.to_string()))?
+        .len();
+
+    let mut writer = BufWriter::new(&file);
+    let written = entries
+        .into_iter()
+        .try_for_each(|entry| {
+            let bytes = serde_cbor::to_vec(&entry)
+                .map_err(|e| WaCustomError::SerializationError(e.to_string()))?;
+            writer
+                .write_all(&(bytes.len() as u32).to_le_bytes())
+                .and_then(|_| writer.write_all(&bytes))
+                .map_err(|e| WaCustomError::FsError(e.to_string()))
+        })
+        .and_then(|_| {
+            writer
+                .flush()
+                .map_err(|e| WaCustomError::FsError(e.to_string()))
+        });
+    drop(writer);
+    if let Err(err) = written {
+        let _ = file.set_len(start);
+        return Err(err);
+    }
+    file.sync_data()
+        .map_err(|e| WaCustomError::FsError(e.to_string()))?;
+
+    Ok(())
+}
+
+/// Reads all entries with a version number greater than `from_version`, in
+/// the order they were committed. A missing log is treated as empty.
+pub fn read_oplog_since(path: &Path, from_version: u32) -> Result<Vec<OpLogEntry>, WaCustomError> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(WaCustomError::FsError(e.to_string())),
+    };
+
+    let mut entries = Vec::new();
+    let mut pos = 0;
+    while pos < bytes.len() {
+        if pos + 4 > bytes.len() {
+            return Err(WaCustomError::DeserializationError(
+                "Truncated oplog entry length".to_string(),
+            ));
+        }
+        let len = u32::from_le_bytes(bytes[pos..pos + 4].try_into().unwrap()) as usize;
+        pos += 4;
+        if pos + len > bytes.len() {
+            return Err(WaCustomError::DeserializationError(
+                "Truncated oplog entry".to_string(),
+            ));
+        }
+        let entry: OpLogEntry = serde_cbor::from_slice(&bytes[pos..pos + len])
+            .map_err(|e| WaCustomError::DeserializationError(e.to_string()))?;
+        pos += len;
+
+        if entry.version_number > from_version {
+            entries.push(entry);
+        }
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn upserts(version_number: u32, ids: &[u64]) -> Vec<OpLogEntry> {
+        ids.iter()
+            .map(|&id| OpLogEntry {
+                version_number,
+                version_hash: Hash::from(version_number * 31),
+                op: OpLogOp::Upsert {
+                    id,
+                    values: vec![id as f32, 0.5],
+                },
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_oplog_tail_from_version() {
+        let dir = tempdir().unwrap();
+        let path = oplog_path(dir.as_ref());
+
+        assert!(read_oplog_since(&path, 0).unwrap().is_empty());
+
+        let writes = vec![upserts(1, &[1, 2]), upserts(2, &[3]), upserts(3, &[1, 4])];
+        for batch in &writes {
+            append_to_oplog(&path, batch.iter().cloned()).unwrap();
+        }
+
+        let all = read_oplog_since(&path, 0).unwrap();
+        assert_eq!(all, writes.concat());
+
+        let tail = read_oplog_since(&path, 1).unwrap();
+        assert_eq!(tail, writes[1..].concat());
+
+        assert!(read_oplog_since(&path, 3).unwrap().is_empty());
+    }
+}
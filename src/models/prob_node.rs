@@ -106,8 +106,16 @@ impl ProbNode {
         &self.prop.id
     }
 
-    pub fn add_neighbor(&self, neighbor_id: u32, neighbor_node: SharedNode, dist: MetricResult) {
-        let mut neighbor_dist = dist.get_value();
+    /// Adds a neighbor, keeping the ones with the best scores. Once the list
+    /// is full, returns the neighbor that no longer fits, either one pushed
+    /// out by the new neighbor or the new neighbor itself.
+    pub fn add_neighbor(
+        &self,
+        neighbor_id: u32,
+        neighbor_node: SharedNode,
+        dist: MetricResult,
+    ) -> Option<(u32, SharedNode)> {
+        let mut neighbor_dist = dist.score();
         let neighbor = Box::new((neighbor_id, neighbor_node, dist));
         let mut neighbor_ptr = Box::into_raw(neighbor);
         let mut inserted = false;
@@ -118,7 +126,7 @@ impl ProbNode {
                     if let Some((_, _, current_neighbor_similarity)) =
                         unsafe { current_neighbor.as_ref() }
                     {
-                        if neighbor_dist < current_neighbor_similarity.get_value() {
+                        if neighbor_dist < current_neighbor_similarity.score() {
                             return None;
                         }
                     }
@@ -127,7 +135,7 @@ impl ProbNode {
 
             if let Ok(prev_neighbor_ptr) = result {
                 if let Some((_, _, prev_neighbor_dist)) = unsafe { prev_neighbor_ptr.as_ref() } {
-                    neighbor_dist = prev_neighbor_dist.get_value();
+                    neighbor_dist = prev_neighbor_dist.score();
                     neighbor_ptr = prev_neighbor_ptr;
                 } else {
                     inserted = true;
@@ -136,9 +144,40 @@ impl ProbNode {
             }
         }
 
-        if !inserted {
-            unsafe {
-                drop(Box::from_raw(neighbor_ptr));
+        if inserted {
+            return None;
+        }
+        let dropped = unsafe { Box::from_raw(neighbor_ptr) };
+        Some((dropped.0, dropped.1))
+    }
+
+    /// Removes the neighbor `is_neighbor` accepts, if it's in the list. Its
+    /// slot is left empty for the next neighbor added.
+    ///
+    /// Ids are truncated to `u32` in the list, so only the neighbors stored
+    /// with `neighbor_id` are passed to `is_neighbor`, which tells apart the
+    /// nodes that share it.
+    pub fn remove_neighbor(&self, neighbor_id: u32, is_neighbor: impl Fn(SharedNode) -> bool) {
+        for neighbor in &self.neighbors {
+            let current = neighbor.load(Ordering::Acquire);
+            let Some((id, node, _)) = (unsafe { current.as_ref() }) else {
+                continue;
+            };
+            if *id == neighbor_id
+                && is_neighbor(*node)
+                && neighbor
+                    .compare_exchange(
+                        current,
+                        ptr::null_mut(),
+                        Ordering::AcqRel,
+                        Ordering::Acquire,
+                    )
+                    .is_ok()
+            {
+                unsafe {
+                    drop(Box::from_raw(current));
+                }
+                return;
             }
         }
     }
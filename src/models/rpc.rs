@@ -1,6 +1,7 @@
 use super::types::MetricResult;
 use crate::models::user::{AddUserResp, AuthResp, Statistics};
 use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
 use std::collections::HashMap;
 
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
@@ -28,6 +29,7 @@ pub struct VectorANN {
     pub vector: Vec<f32>,
     pub filter: Option<Filter>,
     pub nn_count: Option<usize>,
+    pub ef_search: Option<u32>,
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
@@ -36,6 +38,7 @@ pub struct BatchVectorANN {
     pub vectors: Vec<Vec<f32>>,
     pub filter: Option<Filter>,
     pub nn_count: Option<usize>,
+    pub ef_search: Option<u32>,
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
@@ -93,6 +96,8 @@ pub enum RPCResponseBody {
 pub struct Vector {
     pub id: u64,
     pub values: Vec<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Value>,
 }
 
 // #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -157,3 +162,117 @@ pub enum Filter {
     },
     Logical(LogicalOperator),
 }
+
+impl MetadataColumnValue {
+    /// Orders a stored metadata `value` relative to `self`, `None` if the
+    /// types can't be compared.
+    fn compare_json(&self, value: &serde_json::Value) -> Option<Ordering> {
+        match (self, value) {
+            (Self::StringValue(s), serde_json::Value::String(v)) => Some(v.as_str().cmp(s)),
+            (Self::IntValue(i), serde_json::Value::Number(n)) => {
+                n.as_f64()?.partial_cmp(&(*i as f64))
+            }
+            (Self::FloatValue(f), serde_json::Value::Number(n)) => n.as_f64()?.partial_cmp(f),
+            _ => None,
+        }
+    }
+
+    fn eq_json(&self, value: &serde_json::Value) -> bool {
+        self.compare_json(value) == Some(Ordering::Equal)
+    }
+}
+
+impl ComparisonOperator {
+    /// Columns missing from the metadata only satisfy `$ne` and `$nin`.
+    fn matches(&self, value: Option<&serde_json::Value>) -> bool {
+        let Some(value) = value else {
+            return matches!(self, Self::Ne(_) | Self::Nin(_));
+        };
+        match self {
+            Self::Eq(expected) => expected.eq_json(value),
+            Self::Ne(expected) => !expected.eq_json(value),
+            Self::Gt(bound) => bound.compare_json(value) == Some(Ordering::Greater),
+            Self::Gte(bound) => matches!(
+                bound.compare_json(value),
+                Some(Ordering::Greater | Ordering::Equal)
+            ),
+            Self::Lt(bound) => bound.compare_json(value) == Some(Ordering::Less),
+            Self::Lte(bound) => matches!(
+                bound.compare_json(value),
+                Some(Ordering::Less | Ordering::Equal)
+            ),
+            Self::In(values) => values.iter().any(|expected| expected.eq_json(value)),
+            Self::Nin(values) => !values.iter().any(|expected| expected.eq_json(value)),
+        }
+    }
+}
+
+impl Filter {
+    /// Checks whether a vector's metadata (a JSON object) satisfies the filter.
+    pub fn matches(&self, metadata: &serde_json::Value) -> bool {
+        match self {
+            Self::Comparison { column } => column
+                .iter()
+                .all(|(name, operator)| operator.matches(metadata.get(name))),
+            Self::Logical(LogicalOperator::And(filters)) => {
+                filters.iter().all(|filter| filter.matches(metadata))
+            }
+            Self::Logical(LogicalOperator::Or(filters)) => {
+                filters.iter().any(|filter| filter.matches(metadata))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Filter;
+    use serde_json::json;
+
+    fn metadata() -> Vec<serde_json::Value> {
+        (0..10)
+            .map(|i| json!({ "group": i % 3, "score": i as f64 / 10.0, "tag": format!("t{}", i % 2) }))
+            .collect()
+    }
+
+    fn matching(filter: &Filter) -> Vec<usize> {
+        metadata()
+            .iter()
+            .enumerate()
+            .filter(|(_, metadata)| filter.matches(metadata))
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    #[test]
+    fn test_filter_matches_subset() {
+        let eq: Filter = serde_json::from_value(json!({ "group": { "$eq": 1 } })).unwrap();
+        assert_eq!(matching(&eq), vec![1, 4, 7]);
+
+        let range: Filter = serde_json::from_value(json!({
+            "$and": [{ "score": { "$gte": 0.2 } }, { "score": { "$lt": 0.5 } }]
+        }))
+        .unwrap();
+        assert_eq!(matching(&range), vec![2, 3, 4]);
+
+        let combined: Filter = serde_json::from_value(json!({
+            "$or": [{ "tag": { "$eq": "t1" } }, { "group": { "$in": [0] } }]
+        }))
+        .unwrap();
+        assert_eq!(matching(&combined), vec![0, 1, 3, 5, 6, 7, 9]);
+
+        // missing columns only satisfy negative operators
+        let missing: Filter = serde_json::from_value(json!({ "color": { "$ne": "red" } })).unwrap();
+        assert_eq!(matching(&missing).len(), 10);
+    }
+
+    #[test]
+    fn test_filter_matches_nothing() {
+        let filter: Filter = serde_json::from_value(json!({ "group": { "$gt": 5 } })).unwrap();
+        assert!(matching(&filter).is_empty());
+
+        // comparing a string column to a number never matches
+        let mismatched: Filter = serde_json::from_value(json!({ "tag": { "$eq": 1 } })).unwrap();
+        assert!(matching(&mismatched).is_empty());
+    }
+}
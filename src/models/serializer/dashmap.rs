@@ -7,10 +7,7 @@ use crate::models::identity_collections::IdentityMapKey;
 use crate::models::lazy_load::{FileIndex, SyncPersist};
 use crate::models::types::FileOffset;
 use crate::models::versioning::Hash;
-use crate::models::{
-    cache_loader::NodeRegistry,
-    lazy_load::{LazyItem, CHUNK_SIZE},
-};
+use crate::models::{cache_loader::NodeRegistry, chunked_list::CHUNK_SIZE, lazy_load::LazyItem};
 use std::collections::HashSet;
 use std::{io::SeekFrom, sync::Arc};
 
@@ -5,14 +5,14 @@ use crate::models::{
     buffered_io::{BufIoError, BufferManagerFactory},
     cache_loader::{Cacheable, NodeRegistry},
     identity_collections::{Identifiable, IdentitySet},
-    lazy_load::{EagerLazyItem, EagerLazyItemSet, FileIndex, CHUNK_SIZE},
+    lazy_load::{ChunkLoader, EagerLazyItem, EagerLazyItemSet, FileIndex},
     types::{FileOffset, STM},
     versioning::Hash,
 };
 use std::collections::HashSet;
 use std::{io::SeekFrom, sync::Arc};
 
-impl<T, E> CustomSerialize for EagerLazyItemSet<T, E>
+impl<T, E, const C: usize> CustomSerialize for EagerLazyItemSet<T, E, C>
 where
     T: Cacheable + CustomSerialize + Clone + Identifiable<Id = u64> + 'static,
     E: Clone + CustomSerialize + 'static,
@@ -23,6 +23,8 @@ where
         version: Hash,
         cursor: u64,
     ) -> Result<u32, BufIoError> {
+        // chunks are rewritten in place, so all items must be there
+        self.load_rest()?;
         if self.is_empty() {
             self.serialized_offset.clone().update(Some(u32::MAX));
             return Ok(u32::MAX);
@@ -47,13 +49,13 @@ where
         if first_serialize {
             self.serialized_offset.clone().update(Some(start_offset));
         }
-        for chunk_start in (0..total_items).step_by(CHUNK_SIZE) {
-            let chunk_end = std::cmp::min(chunk_start + CHUNK_SIZE, total_items);
+        for chunk_start in (0..total_items).step_by(C) {
+            let chunk_end = std::cmp::min(chunk_start + C, total_items);
             let is_last_chunk = chunk_end == total_items;
 
             // Write placeholders for item offsets
             let placeholder_start = bufman.cursor_position(cursor)? as u32;
-            for _ in 0..CHUNK_SIZE {
+            for _ in 0..C {
                 bufman.write_u32_with_cursor(cursor, u32::MAX)?;
             }
             // Write placeholder for next chunk link
@@ -94,60 +96,103 @@ where
         max_loads: u16,
         skipm: &mut HashSet<u64>,
     ) -> Result<Self, BufIoError> {
-        match file_index {
-            FileIndex::Invalid => Ok(EagerLazyItemSet::new()),
+        let offset = match file_index {
             FileIndex::Valid {
                 offset: FileOffset(offset),
-                version_number,
-                version_id,
-            } => {
-                if offset == u32::MAX {
-                    return Ok(EagerLazyItemSet::new());
-                }
-                let bufman = bufmans.get(version_id)?;
-                let cursor = bufman.open_cursor()?;
-                bufman.seek_with_cursor(cursor, SeekFrom::Start(offset as u64))?;
+                ..
+            } if offset != u32::MAX => offset,
+            _ => return Ok(Self::new()),
+        };
+        let (items, next_chunk) =
+            Self::deserialize_chunk(bufmans.clone(), file_index, cache.clone(), max_loads, skipm)?;
+        let unloaded_chunks = next_chunk.map(|next_chunk| -> ChunkLoader<T, E> {
+            Arc::new(move || {
                 let mut items = Vec::new();
-                let mut current_chunk = offset;
-                loop {
-                    for i in 0..CHUNK_SIZE {
-                        bufman.seek_with_cursor(
-                            cursor,
-                            SeekFrom::Start(current_chunk as u64 + (i as u64 * 4)),
-                        )?;
-                        let item_offset = bufman.read_u32_with_cursor(cursor)?;
-                        if item_offset == u32::MAX {
-                            continue;
-                        }
-                        let item_file_index = FileIndex::Valid {
-                            offset: FileOffset(item_offset),
-                            version_number,
-                            version_id,
-                        };
-                        let item = EagerLazyItem::deserialize(
-                            bufmans.clone(),
-                            item_file_index,
-                            cache.clone(),
-                            max_loads,
-                            skipm,
-                        )?;
-                        items.push(item);
-                    }
-                    bufman.seek_with_cursor(
-                        cursor,
-                        SeekFrom::Start(current_chunk as u64 + CHUNK_SIZE as u64 * 4),
+                let mut skipm = HashSet::new();
+                let mut next_chunk = Some(next_chunk);
+                while let Some(chunk) = next_chunk {
+                    let (chunk_items, next) = Self::deserialize_chunk(
+                        bufmans.clone(),
+                        chunk,
+                        cache.clone(),
+                        max_loads,
+                        &mut skipm,
                     )?;
-                    // Read next chunk link
-                    current_chunk = bufman.read_u32_with_cursor(cursor)?;
-                    if current_chunk == u32::MAX {
-                        break;
-                    }
+                    items.extend(chunk_items);
+                    next_chunk = next;
                 }
-                Ok(EagerLazyItemSet {
-                    serialized_offset: ArcShift::new(Some(offset)),
-                    items: STM::new(IdentitySet::from_iter(items.into_iter()), 5, false),
-                })
+                Ok(items)
+            })
+        });
+        Ok(Self {
+            serialized_offset: ArcShift::new(Some(offset)),
+            items: STM::new(IdentitySet::from_iter(items.into_iter()), 5, false),
+            unloaded_chunks: ArcShift::new(unloaded_chunks),
+        })
+    }
+}
+
+impl<T, E, const C: usize> EagerLazyItemSet<T, E, C>
+where
+    T: Cacheable + CustomSerialize + Clone + Identifiable<Id = u64> + 'static,
+    E: Clone + CustomSerialize + 'static,
+{
+    /// Reads the chunk of at most `C` items at `file_index`, along
+    /// with where the next chunk is, if there is one. Lets a caller that only
+    /// needs the first few items leave the rest of the set unread, as
+    /// `deserialize` does.
+    pub fn deserialize_chunk(
+        bufmans: Arc<BufferManagerFactory<Hash>>,
+        file_index: FileIndex,
+        cache: Arc<NodeRegistry>,
+        max_loads: u16,
+        skipm: &mut HashSet<u64>,
+    ) -> Result<(Vec<EagerLazyItem<T, E>>, Option<FileIndex>), BufIoError> {
+        let FileIndex::Valid {
+            offset: FileOffset(offset),
+            version_number,
+            version_id,
+        } = file_index
+        else {
+            return Ok((Vec::new(), None));
+        };
+        if offset == u32::MAX {
+            return Ok((Vec::new(), None));
+        }
+        let bufman = bufmans.get(version_id)?;
+        let cursor = bufman.open_cursor()?;
+        let mut item_offsets = Vec::with_capacity(C);
+        bufman.seek_with_cursor(cursor, SeekFrom::Start(offset as u64))?;
+        for _ in 0..C {
+            item_offsets.push(bufman.read_u32_with_cursor(cursor)?);
+        }
+        // Read next chunk link
+        let next_chunk = bufman.read_u32_with_cursor(cursor)?;
+        bufman.close_cursor(cursor)?;
+
+        let mut items = Vec::with_capacity(C);
+        for item_offset in item_offsets {
+            if item_offset == u32::MAX {
+                continue;
             }
+            let item_file_index = FileIndex::Valid {
+                offset: FileOffset(item_offset),
+                version_number,
+                version_id,
+            };
+            items.push(EagerLazyItem::deserialize(
+                bufmans.clone(),
+                item_file_index,
+                cache.clone(),
+                max_loads,
+                skipm,
+            )?);
         }
+        let next_chunk = (next_chunk != u32::MAX).then_some(FileIndex::Valid {
+            offset: FileOffset(next_chunk),
+            version_number,
+            version_id,
+        });
+        Ok((items, next_chunk))
     }
 }
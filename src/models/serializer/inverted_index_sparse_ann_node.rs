@@ -4,16 +4,23 @@ use crate::storage::inverted_index_sparse_ann_basic::{
     InvertedIndexSparseAnnNodeBasic, InvertedIndexSparseAnnNodeBasicDashMap,
     InvertedIndexSparseAnnNodeBasicTSHashmap,
 };
-use crate::storage::inverted_index_sparse_ann_new_ds::InvertedIndexNewDSNode;
+use crate::storage::inverted_index_sparse_ann_new_ds::{
+    InvertedIndexNewDSNode, InvertedIndexSparseAnnNewDS,
+};
 use crate::{
     models::{
         buffered_io::{BufIoError, BufferManagerFactory},
         cache_loader::NodeRegistry,
-        lazy_load::FileIndex,
+        lazy_load::{FileIndex, IncrementalSerializableGrowableData, LazyItemArray},
+        types::FileOffset,
     },
     storage::inverted_index_sparse_ann::InvertedIndexSparseAnnNode,
 };
+use arcshift::ArcShift;
+use dashmap::DashMap;
 use std::collections::HashSet;
+use std::io::{self, SeekFrom};
+use std::sync::atomic::AtomicU8;
 use std::sync::Arc;
 
 #[allow(unused_variables)]
@@ -94,7 +101,9 @@ impl CustomSerialize for InvertedIndexSparseAnnNodeBasicTSHashmap {
     }
 }
 
-#[allow(unused_variables)]
+// Layout: dim_index (u32), implicit (u8), quantization (u8), `quantization + 1`
+// data bucket offsets (u32 each), children offset (u32), followed by the buckets
+// and the children array.
 impl CustomSerialize for InvertedIndexNewDSNode {
     fn serialize(
         &self,
@@ -102,9 +111,30 @@ impl CustomSerialize for InvertedIndexNewDSNode {
         version: Hash,
         cursor: u64,
     ) -> Result<u32, BufIoError> {
-        //todo! Implement serialize
+        let bufman = bufmans.get(version)?;
+        let start_pos = bufman.cursor_position(cursor)? as u32;
+        bufman.write_u32_with_cursor(cursor, self.dim_index)?;
+        bufman.write_u8_with_cursor(cursor, if self.implicit { 1 } else { 0 })?;
+        bufman.write_u8_with_cursor(cursor, self.quantization)?;
+        let placeholder_pos = bufman.cursor_position(cursor)?;
+        for _ in 0..self.data.len() + 1 {
+            bufman.write_u32_with_cursor(cursor, u32::MAX)?;
+        }
 
-        Ok(0u32)
+        let mut data_offsets = Vec::with_capacity(self.data.len());
+        for bucket in self.data.iter() {
+            data_offsets.push(bucket.serialize(bufmans.clone(), version, cursor)?);
+        }
+        let children_offset = self.lazy_children.serialize(bufmans, version, cursor)?;
+
+        let current_pos = bufman.cursor_position(cursor)?;
+        bufman.seek_with_cursor(cursor, SeekFrom::Start(placeholder_pos))?;
+        for data_offset in data_offsets {
+            bufman.write_u32_with_cursor(cursor, data_offset)?;
+        }
+        bufman.write_u32_with_cursor(cursor, children_offset)?;
+        bufman.seek_with_cursor(cursor, SeekFrom::Start(current_pos))?;
+        Ok(start_pos)
     }
 
     fn deserialize(
@@ -114,9 +144,107 @@ impl CustomSerialize for InvertedIndexNewDSNode {
         max_loads: u16,
         skipm: &mut HashSet<u64>,
     ) -> Result<Self, BufIoError> {
-        //todo! Implement deserialize
+        match file_index {
+            FileIndex::Invalid => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Cannot deserialize InvertedIndexNewDSNode with an invalid FileIndex",
+            )
+            .into()),
+            FileIndex::Valid {
+                offset: FileOffset(offset),
+                version_number,
+                version_id,
+            } => {
+                let bufman = bufmans.get(version_id)?;
+                let cursor = bufman.open_cursor()?;
+                bufman.seek_with_cursor(cursor, SeekFrom::Start(offset as u64))?;
+                let dim_index = bufman.read_u32_with_cursor(cursor)?;
+                let implicit = bufman.read_u8_with_cursor(cursor)? != 0;
+                let quantization = bufman.read_u8_with_cursor(cursor)?;
+                let mut data_offsets = Vec::with_capacity(quantization as usize + 1);
+                for _ in 0..=quantization {
+                    data_offsets.push(bufman.read_u32_with_cursor(cursor)?);
+                }
+                let children_offset = bufman.read_u32_with_cursor(cursor)?;
+                bufman.close_cursor(cursor)?;
+
+                let mut data = Vec::with_capacity(data_offsets.len());
+                for data_offset in data_offsets {
+                    data.push(IncrementalSerializableGrowableData::deserialize(
+                        bufmans.clone(),
+                        FileIndex::Valid {
+                            offset: FileOffset(data_offset),
+                            version_number,
+                            version_id,
+                        },
+                        cache.clone(),
+                        max_loads,
+                        skipm,
+                    )?);
+                }
+                // the upper bound isn't stored, the highest non-empty bucket gives it back
+                let max_quantized_value = data
+                    .iter()
+                    .rposition(|bucket| !bucket.items.is_empty())
+                    .unwrap_or(0) as u8;
+
+                let lazy_children = LazyItemArray::deserialize(
+                    bufmans,
+                    FileIndex::Valid {
+                        offset: FileOffset(children_offset),
+                        version_number,
+                        version_id,
+                    },
+                    cache,
+                    max_loads,
+                    skipm,
+                )?;
+
+                Ok(Self {
+                    dim_index,
+                    implicit,
+                    quantization,
+                    data: data.into(),
+                    lazy_children,
+                    max_quantized_value: Arc::new(AtomicU8::new(max_quantized_value)),
+                })
+            }
+        }
+    }
+}
+
+impl CustomSerialize for InvertedIndexSparseAnnNewDS {
+    fn serialize(
+        &self,
+        bufmans: Arc<BufferManagerFactory<Hash>>,
+        version: Hash,
+        cursor: u64,
+    ) -> Result<u32, BufIoError> {
+        self.root.shared_get().serialize(bufmans, version, cursor)
+    }
 
-        Ok(InvertedIndexNewDSNode::new(0, false))
+    fn deserialize(
+        bufmans: Arc<BufferManagerFactory<Hash>>,
+        file_index: FileIndex,
+        cache: Arc<NodeRegistry>,
+        max_loads: u16,
+        skipm: &mut HashSet<u64>,
+    ) -> Result<Self, BufIoError> {
+        let root = InvertedIndexNewDSNode::deserialize(
+            bufmans,
+            file_index,
+            cache.clone(),
+            max_loads,
+            skipm,
+        )?;
+        Ok(Self {
+            root: ArcShift::new(root),
+            cache,
+            // not persisted, `remove_sparse_vector` falls back to scanning the tree
+            vector_dims: Arc::new(DashMap::new()),
+            max_dim: None,
+            write_log: None,
+        })
     }
 }
 
@@ -1,9 +1,10 @@
 use super::CustomSerialize;
 use crate::models::buffered_io::{BufIoError, BufferManagerFactory};
 use crate::models::cache_loader::Cacheable;
+use crate::models::chunked_list::CHUNK_SIZE;
+use crate::models::lazy_load::FileIndex;
 use crate::models::lazy_load::LazyItemVec;
 use crate::models::lazy_load::SyncPersist;
-use crate::models::lazy_load::{FileIndex, CHUNK_SIZE};
 use crate::models::types::FileOffset;
 use crate::models::versioning::Hash;
 use crate::models::{
@@ -1,8 +1,9 @@
 use super::CustomSerialize;
 use crate::models::buffered_io::{BufIoError, BufferManagerFactory};
 use crate::models::cache_loader::{Cacheable, NodeRegistry};
+use crate::models::chunked_list::CHUNK_SIZE;
 use crate::models::identity_collections::{IdentityMap, IdentityMapKey};
-use crate::models::lazy_load::{FileIndex, LazyItem, LazyItemMap, SyncPersist, CHUNK_SIZE};
+use crate::models::lazy_load::{FileIndex, LazyItem, LazyItemMap, SyncPersist};
 use crate::models::types::FileOffset;
 use crate::models::versioning::Hash;
 use std::collections::HashSet;
@@ -2,8 +2,9 @@ use super::CustomSerialize;
 use crate::models::{
     buffered_io::{BufIoError, BufferManagerFactory},
     cache_loader::{Cacheable, NodeRegistry},
+    chunked_list::CHUNK_SIZE,
     identity_collections::{Identifiable, IdentitySet},
-    lazy_load::{FileIndex, LazyItem, LazyItemSet, SyncPersist, CHUNK_SIZE},
+    lazy_load::{FileIndex, LazyItem, LazyItemSet, SyncPersist},
     types::FileOffset,
     versioning::Hash,
 };
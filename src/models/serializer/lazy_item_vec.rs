@@ -2,7 +2,8 @@ use super::CustomSerialize;
 use crate::models::{
     buffered_io::{BufIoError, BufferManagerFactory},
     cache_loader::{Cacheable, NodeRegistry},
-    lazy_load::{FileIndex, LazyItem, LazyItemVec, SyncPersist, CHUNK_SIZE},
+    chunked_list::CHUNK_SIZE,
+    lazy_load::{FileIndex, LazyItem, LazyItemVec, SyncPersist},
     types::FileOffset,
     versioning::Hash,
 };
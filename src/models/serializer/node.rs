@@ -1,6 +1,6 @@
 use super::CustomSerialize;
 use crate::models::{
-    buffered_io::{BufIoError, BufferManagerFactory},
+    buffered_io::{BufIoError, BufferManager, BufferManagerFactory},
     cache_loader::NodeRegistry,
     lazy_load::{EagerLazyItemSet, FileIndex, LazyItemRef},
     types::{BytesToRead, FileOffset, HNSWLevel, MergedNode, PropState},
@@ -13,6 +13,48 @@ use std::{
     sync::Arc,
 };
 
+/// Longest prop a node may point to, longer ones can only come from a
+/// corrupted file and would make loading the prop allocate that much.
+pub const MAX_PROP_LENGTH: u32 = 64 * 1024 * 1024;
+
+/// Offset left in a parent or child placeholder until the linked node has
+/// been serialized and the placeholder is patched.
+const UNPATCHED: u32 = u32::MAX;
+
+fn invalid_data(message: String) -> BufIoError {
+    io::Error::new(io::ErrorKind::InvalidData, message).into()
+}
+
+/// Re-reads the placeholders written by `MergedNode::serialize` and checks
+/// each now holds the offset it was patched with. Every entry is the
+/// position of a placeholder and the offset expected there. Parent and child
+/// `links` must also not be `UNPATCHED`, the neighbors offset may be, that
+/// is how an empty neighbor set is written.
+pub(crate) fn check_placeholders(
+    bufman: &BufferManager,
+    cursor: u64,
+    links: &[(u32, u32)],
+    neighbors: (u32, u32),
+) -> Result<(), BufIoError> {
+    for &(placeholder, expected) in links.iter().chain([&neighbors]) {
+        bufman.seek_with_cursor(cursor, SeekFrom::Start(placeholder as u64))?;
+        let offset = bufman.read_u32_with_cursor(cursor)?;
+        if offset != expected {
+            return Err(invalid_data(format!(
+                "placeholder at {} holds offset {}, expected {}",
+                placeholder, offset, expected
+            )));
+        }
+    }
+    if let Some((placeholder, _)) = links.iter().find(|(_, offset)| *offset == UNPATCHED) {
+        return Err(invalid_data(format!(
+            "link placeholder at {} was never patched",
+            placeholder
+        )));
+    }
+    Ok(())
+}
+
 impl CustomSerialize for MergedNode {
     fn serialize(
         &self,
@@ -31,8 +73,9 @@ impl CustomSerialize for MergedNode {
         let prop_state = prop.get();
         match &*prop_state {
             PropState::Ready(node_prop) => {
-                bufman.write_u32_with_cursor(cursor, node_prop.location.0 .0)?;
-                bufman.write_u32_with_cursor(cursor, node_prop.location.1 .0)?;
+                let (FileOffset(offset), BytesToRead(length)) = node_prop.location.get();
+                bufman.write_u32_with_cursor(cursor, offset)?;
+                bufman.write_u32_with_cursor(cursor, length)?;
             }
             PropState::Pending((FileOffset(offset), BytesToRead(length))) => {
                 bufman.write_u32_with_cursor(cursor, *offset)?;
@@ -55,7 +98,7 @@ impl CustomSerialize for MergedNode {
         // Write placeholders only for present parent and child
         let parent_placeholder = if parent_present {
             let pos = bufman.cursor_position(cursor)? as u32;
-            bufman.write_u32_with_cursor(cursor, 0)?;
+            bufman.write_u32_with_cursor(cursor, UNPATCHED)?;
             bufman.write_u16_with_cursor(cursor, 0)?;
             bufman.write_u32_with_cursor(cursor, 0)?;
             Some(pos)
@@ -65,7 +108,7 @@ impl CustomSerialize for MergedNode {
 
         let child_placeholder = if child_present {
             let pos = bufman.cursor_position(cursor)? as u32;
-            bufman.write_u32_with_cursor(cursor, 0)?;
+            bufman.write_u32_with_cursor(cursor, UNPATCHED)?;
             bufman.write_u16_with_cursor(cursor, 0)?;
             bufman.write_u32_with_cursor(cursor, 0)?;
             Some(pos)
@@ -114,6 +157,22 @@ impl CustomSerialize for MergedNode {
         bufman.seek_with_cursor(cursor, SeekFrom::Start(neighbors_placeholder as u64))?;
         bufman.write_u32_with_cursor(cursor, neighbors_offset)?;
 
+        if cfg!(debug_assertions) {
+            let links: Vec<_> = [
+                parent_placeholder.zip(parent_offset),
+                child_placeholder.zip(child_offset),
+            ]
+            .into_iter()
+            .flatten()
+            .collect();
+            check_placeholders(
+                &bufman,
+                cursor,
+                &links,
+                (neighbors_placeholder, neighbors_offset),
+            )?;
+        }
+
         // Return to the end of the serialized data
         bufman.seek_with_cursor(cursor, SeekFrom::Start(end_pos))?;
 
@@ -139,6 +198,13 @@ impl CustomSerialize for MergedNode {
                 version_id,
             } => {
                 let bufman = bufmans.get(version_id)?;
+                let file_size = bufman.file_size()?;
+                if offset as u64 >= file_size {
+                    return Err(invalid_data(format!(
+                        "MergedNode offset {} is past the end of the file ({} bytes)",
+                        offset, file_size
+                    )));
+                }
                 let cursor = bufman.open_cursor()?;
                 bufman.seek_with_cursor(cursor, SeekFrom::Start(offset as u64))?;
                 // Read basic fields
@@ -169,7 +235,27 @@ impl CustomSerialize for MergedNode {
                     ));
                 }
                 let neighbors_offset = bufman.read_u32_with_cursor(cursor)?;
+                let end_offset = bufman.cursor_position(cursor)?;
                 bufman.close_cursor(cursor)?;
+                if end_offset > file_size {
+                    return Err(invalid_data(format!(
+                        "MergedNode at offset {} runs past the end of the file ({} bytes)",
+                        offset, file_size
+                    )));
+                }
+                // an empty neighbor set is never written and keeps the placeholder
+                if neighbors_offset != UNPATCHED && neighbors_offset as u64 >= file_size {
+                    return Err(invalid_data(format!(
+                        "neighbors offset {} is past the end of the file ({} bytes)",
+                        neighbors_offset, file_size
+                    )));
+                }
+                if prop_length.0 > MAX_PROP_LENGTH {
+                    return Err(invalid_data(format!(
+                        "prop length {} exceeds the maximum of {} bytes",
+                        prop_length.0, MAX_PROP_LENGTH
+                    )));
+                }
                 // Deserialize parent
                 let parent =
                     if let Some((offset, version_number, version_id)) = parent_offset_and_version {
@@ -30,9 +30,9 @@ impl ProbSerialize for ProbNode {
         bufman.write_u8_with_cursor(cursor, self.hnsw_level.0)?;
 
         // Serialize prop
-        let (FileOffset(offset), BytesToRead(length)) = &self.prop.location;
-        bufman.write_u32_with_cursor(cursor, *offset)?;
-        bufman.write_u32_with_cursor(cursor, *length)?;
+        let (FileOffset(offset), BytesToRead(length)) = self.prop.location.get();
+        bufman.write_u32_with_cursor(cursor, offset)?;
+        bufman.write_u32_with_cursor(cursor, length)?;
 
         // 10 bytes for parent offset + 10 bytes for child offset + 4 bytes for neighbors offset + 4 bytes for versions
         bufman.write_with_cursor(cursor, &[u8::MAX; 28])?;
@@ -118,10 +118,16 @@ impl ProbSerialize for ProbNode {
                 bufman.seek_with_cursor(cursor, SeekFrom::Start(offset as u64))?;
                 // Read basic fields
                 let hnsw_level = HNSWLevel(bufman.read_u8_with_cursor(cursor)?);
-                // Read prop
-                let prop_offset = FileOffset(bufman.read_u32_with_cursor(cursor)?);
-                let prop_length = BytesToRead(bufman.read_u32_with_cursor(cursor)?);
-                let prop = cache.get_prop(prop_offset, prop_length)?;
+                // Read prop, again if the prop file gets compacted meanwhile
+                let prop = loop {
+                    let generation = cache.prop_file_generation();
+                    bufman.seek_with_cursor(cursor, SeekFrom::Start(offset as u64 + 1))?;
+                    let prop_offset = FileOffset(bufman.read_u32_with_cursor(cursor)?);
+                    let prop_length = BytesToRead(bufman.read_u32_with_cursor(cursor)?);
+                    if let Some(prop) = cache.get_prop(prop_offset, prop_length, generation)? {
+                        break prop;
+                    }
+                };
 
                 let parent_offset = bufman.read_u32_with_cursor(cursor)?;
                 let parent_version_number = bufman.read_u16_with_cursor(cursor)?;
@@ -8,7 +8,7 @@ use crate::{
         lazy_load::{FileIndex, SyncPersist},
         prob_lazy_load::{lazy_item::ProbLazyItem, lazy_item_array::ProbLazyItemArray},
         prob_node::{ProbNode, SharedNode},
-        types::{FileOffset, HNSWLevel, MetricResult, NodeProp, VectorId},
+        types::{FileOffset, HNSWLevel, MetricResult, NodeProp, PropLocation, VectorId},
         versioning::{Hash, Version, VersionControl},
     },
     storage::Storage,
@@ -133,12 +133,13 @@ fn create_prob_node(id: u64, prop_file: &RwLock<File>) -> ProbNode {
         quant_vec: vec![1, 2, 3],
     });
     let mut prop_file_guard = prop_file.write().unwrap();
-    let location = write_prop_to_file(&id, value.clone(), &mut *prop_file_guard).unwrap();
+    let location = write_prop_to_file(&id, value.clone(), None, &mut *prop_file_guard).unwrap();
     drop(prop_file_guard);
     let prop = Arc::new(NodeProp {
         id,
         value,
-        location,
+        location: PropLocation::new(location),
+        source: None,
     });
     ProbNode::new(
         HNSWLevel(2),
@@ -228,6 +229,50 @@ fn test_prob_node_acyclic_serialization() {
     node.assert_eq(&deserialized, &mut tester);
 }
 
+#[test]
+fn test_same_offset_in_different_versions_is_cached_separately() {
+    let (bufmans, cache, _, cursor, prop_file, _temp_dir) = setup_test(Hash::from(0));
+    bufmans
+        .get(Hash::from(0))
+        .unwrap()
+        .close_cursor(cursor)
+        .unwrap();
+
+    let mut file_indices = Vec::new();
+    for (id, version_id) in [(1, Hash::from(1)), (2, Hash::from(2))] {
+        let node = create_prob_node(id, &prop_file);
+        let bufman = bufmans.get(version_id).unwrap();
+        let cursor = bufman.open_cursor().unwrap();
+        let offset = node.serialize(&bufmans, version_id, cursor).unwrap();
+        bufman.close_cursor(cursor).unwrap();
+        file_indices.push(FileIndex::Valid {
+            offset: FileOffset(offset),
+            version_number: *version_id as u16,
+            version_id,
+        });
+    }
+    assert_eq!(file_indices[0].get_offset(), file_indices[1].get_offset());
+
+    let first = cache.get_object(file_indices[0]).unwrap();
+    let second = cache.get_object(file_indices[1]).unwrap();
+    assert_ne!(first, second);
+
+    let id_of = |node: SharedNode| {
+        unsafe { &*node }
+            .try_get_data(&cache)
+            .unwrap()
+            .prop
+            .id
+            .clone()
+    };
+    assert_eq!(id_of(first), VectorId(1));
+    assert_eq!(id_of(second), VectorId(2));
+
+    // loading either again hits the cache
+    assert_eq!(cache.get_object(file_indices[0]).unwrap(), first);
+    assert_eq!(cache.get_object(file_indices[1]).unwrap(), second);
+}
+
 #[test]
 fn test_prob_lazy_item_array_serialization() {
     let root_version_id = Hash::from(0);
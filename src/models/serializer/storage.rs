@@ -53,6 +53,14 @@ impl SimpleSerialize for Storage {
                     bufman.write_with_cursor(cursor, &el.to_le_bytes())?;
                 }
             }
+            Self::Sparse { indices, values } => {
+                bufman.write_u8_with_cursor(cursor, 3)?;
+                bufman.write_u32_with_cursor(cursor, indices.len() as u32)?;
+                for (index, value) in indices.iter().zip(values) {
+                    bufman.write_u32_with_cursor(cursor, *index)?;
+                    bufman.write_u8_with_cursor(cursor, *value)?;
+                }
+            }
         }
 
         Ok(start)
@@ -116,6 +124,18 @@ impl SimpleSerialize for Storage {
 
                 Self::HalfPrecisionFP { mag, quant_vec }
             }
+            3 => {
+                let len = bufman.read_u32_with_cursor(cursor)? as usize;
+                let mut indices = Vec::with_capacity(len);
+                let mut values = Vec::with_capacity(len);
+
+                for _ in 0..len {
+                    indices.push(bufman.read_u32_with_cursor(cursor)?);
+                    values.push(bufman.read_u8_with_cursor(cursor)?);
+                }
+
+                Self::Sparse { indices, values }
+            }
             _ => {
                 return Err(
                     io::Error::new(io::ErrorKind::InvalidData, "Invalid Storage variant").into(),
@@ -1,12 +1,18 @@
 use crate::distance::cosine::CosineSimilarity;
 use crate::models::buffered_io::BufferManager;
+use crate::models::chunked_list::CHUNK_SIZE;
 use crate::models::lazy_load::*;
 use crate::models::serializer::*;
 use crate::models::types::*;
 use crate::models::versioning::BranchId;
 use crate::models::versioning::VersionHash;
 use crate::models::versioning::{Version, VersionControl};
+use crate::storage::inverted_index_sparse_ann_new_ds::{
+    InvertedIndexNewDSNode, InvertedIndexSparseAnnNewDS,
+};
 use crate::storage::Storage;
+use ::dashmap::DashMap;
+use arcshift::ArcShift;
 use half::f16;
 use lmdb::DatabaseFlags;
 use lmdb::Environment;
@@ -38,6 +44,127 @@ fn setup_test(
     (bufmans, cache, bufman, cursor, dir)
 }
 
+/// Serializes `value` into a fresh index file of `version_id`, reads it back
+/// through a new `NodeRegistry` and asserts that `shape` describes both the
+/// same way. Returns the deserialized value for further checks.
+fn assert_round_trip<T, S>(
+    value: &T,
+    version_id: Hash,
+    version_number: u16,
+    shape: impl Fn(&T) -> S,
+) -> T
+where
+    T: CustomSerialize,
+    S: PartialEq + std::fmt::Debug,
+{
+    let (bufmans, cache, bufman, cursor, _temp_dir) = setup_test(version_id);
+    let offset = value.serialize(bufmans, version_id, cursor).unwrap();
+    bufman.close_cursor(cursor).unwrap();
+    let file_index = FileIndex::Valid {
+        offset: FileOffset(offset),
+        version_number,
+        version_id,
+    };
+
+    let deserialized: T = cache.load_item(file_index).unwrap();
+    assert_eq!(shape(&deserialized), shape(value));
+    deserialized
+}
+
+/// version id, version number and level, if loaded, of a linked node
+type LinkShape = (Hash, u16, Option<u8>);
+
+/// what a `MergedNode` has to keep through a round trip
+#[derive(Debug, PartialEq)]
+struct MergedNodeShape {
+    hnsw_level: u8,
+    prop_location: PropPersistRef,
+    parent: Option<LinkShape>,
+    child: Option<LinkShape>,
+    neighbors: Vec<(LinkShape, MetricResult)>,
+}
+
+fn link_shape(item: &LazyItem<MergedNode>) -> Option<LinkShape> {
+    if item.is_invalid() {
+        return None;
+    }
+    let level = item
+        .get_lazy_data()
+        .and_then(|mut data| data.get().clone())
+        .map(|node| node.hnsw_level.0);
+    Some((
+        item.get_current_version(),
+        item.get_current_version_number(),
+        level,
+    ))
+}
+
+fn merged_node_shape(node: &MergedNode) -> MergedNodeShape {
+    let mut neighbors: Vec<_> = node
+        .get_neighbors()
+        .iter()
+        .filter_map(|EagerLazyItem(distance, item)| Some((link_shape(&item)?, distance)))
+        .collect();
+    neighbors.sort_by_key(|((version_id, version_number, _), _)| (**version_id, *version_number));
+
+    MergedNodeShape {
+        hnsw_level: node.hnsw_level.0,
+        prop_location: node.get_prop_location(),
+        parent: link_shape(node.get_parent().item.get()),
+        child: link_shape(node.get_child().item.get()),
+        neighbors,
+    }
+}
+
+/// A node told apart from the others by its prop, nodes with the same prop
+/// are the same item of a set.
+fn node_with_prop(level: u8, prop_offset: u32) -> MergedNode {
+    let node = MergedNode::new(HNSWLevel(level));
+    node.set_prop_pending((FileOffset(prop_offset), BytesToRead(40)));
+    node
+}
+
+#[test]
+fn test_merged_node_round_trip() {
+    let node = MergedNode::new(HNSWLevel(2));
+    node.set_prop_pending((FileOffset(128), BytesToRead(40)));
+    node.set_parent(LazyItem::new(1.into(), 1, MergedNode::new(HNSWLevel(3))));
+    node.set_child(LazyItem::new(2.into(), 2, MergedNode::new(HNSWLevel(1))));
+    node.add_ready_neighbors(vec![
+        (
+            LazyItem::from_data(3.into(), 3, node_with_prop(2, 256)),
+            MetricResult::CosineSimilarity(CosineSimilarity(0.9)),
+        ),
+        (
+            LazyItem::from_data(4.into(), 4, node_with_prop(2, 512)),
+            MetricResult::CosineSimilarity(CosineSimilarity(0.7)),
+        ),
+    ]);
+
+    let deserialized = assert_round_trip(&node, 0.into(), 0, merged_node_shape);
+
+    // make sure none of the links got lost on both sides
+    let shape = merged_node_shape(&deserialized);
+    assert_eq!(shape.parent, Some((1.into(), 1, Some(3))));
+    assert_eq!(shape.child, Some((2.into(), 2, Some(1))));
+    assert_eq!(shape.neighbors.len(), 2);
+}
+
+#[test]
+fn test_merged_node_without_neighbors_round_trip() {
+    let node = MergedNode::new(HNSWLevel(2));
+    node.set_prop_pending((FileOffset(64), BytesToRead(40)));
+    node.set_parent(LazyItem::new(1.into(), 1, MergedNode::new(HNSWLevel(3))));
+    node.set_child(LazyItem::new(2.into(), 2, MergedNode::new(HNSWLevel(1))));
+
+    let deserialized = assert_round_trip(&node, 0.into(), 0, merged_node_shape);
+
+    assert!(deserialized.get_neighbors().is_empty());
+    let shape = merged_node_shape(&deserialized);
+    assert_eq!(shape.parent, Some((1.into(), 1, Some(3))));
+    assert_eq!(shape.child, Some((2.into(), 2, Some(1))));
+}
+
 #[test]
 fn test_lazy_item_serialization() {
     let node = MergedNode::new(HNSWLevel(2));
@@ -197,7 +324,7 @@ fn test_lazy_item_set_serialization() {
 #[test]
 fn test_eager_lazy_item_set_serialization() {
     let root_version_id = Hash::from(0);
-    let lazy_items = EagerLazyItemSet::new();
+    let lazy_items: EagerLazyItemSet<MergedNode, f32> = EagerLazyItemSet::new();
     lazy_items.insert(EagerLazyItem(
         1.0,
         LazyItem::from_data(1.into(), 1, MergedNode::new(HNSWLevel(2))),
@@ -280,6 +407,74 @@ fn test_merged_node_acyclic_serialization() {
     assert_eq!(deserialized.get_neighbors().len(), 0);
 }
 
+#[test]
+fn test_merged_node_deserialization_rejects_out_of_bounds_data() {
+    let root_version_id = Hash::from(0);
+    let node = MergedNode::new(HNSWLevel(2));
+
+    let (bufmans, cache, bufman, cursor, _temp_dir) = setup_test(root_version_id);
+
+    let offset = node
+        .serialize(bufmans.clone(), root_version_id, cursor)
+        .unwrap();
+    let file_index = |offset| FileIndex::Valid {
+        offset: FileOffset(offset),
+        version_number: 0,
+        version_id: root_version_id,
+    };
+    let assert_invalid_data =
+        |offset| match cache.clone().load_item::<MergedNode>(file_index(offset)) {
+            Err(BufIoError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+            Err(e) => panic!("expected InvalidData, got {:?}", e),
+            Ok(_) => panic!("expected InvalidData, got a node"),
+        };
+
+    // the node itself is out of range
+    let file_size = bufman.file_size().unwrap() as u32;
+    assert_invalid_data(file_size);
+    assert_invalid_data(u32::MAX);
+
+    // a node without parent or child is level (1 byte), prop offset and
+    // length (4 + 4 bytes), indicator (1 byte) and neighbors offset (4 bytes)
+    let prop_length_offset = offset as u64 + 5;
+    let neighbors_offset_offset = offset as u64 + 10;
+
+    // the neighbors are out of range
+    bufman
+        .seek_with_cursor(cursor, SeekFrom::Start(neighbors_offset_offset))
+        .unwrap();
+    let neighbors_offset = bufman.read_u32_with_cursor(cursor).unwrap();
+    bufman
+        .seek_with_cursor(cursor, SeekFrom::Start(neighbors_offset_offset))
+        .unwrap();
+    bufman
+        .write_u32_with_cursor(cursor, file_size + 100)
+        .unwrap();
+    assert_invalid_data(offset);
+    bufman
+        .seek_with_cursor(cursor, SeekFrom::Start(neighbors_offset_offset))
+        .unwrap();
+    bufman
+        .write_u32_with_cursor(cursor, neighbors_offset)
+        .unwrap();
+
+    // the prop is too long to be real
+    bufman
+        .seek_with_cursor(cursor, SeekFrom::Start(prop_length_offset))
+        .unwrap();
+    bufman.write_u32_with_cursor(cursor, u32::MAX).unwrap();
+    assert_invalid_data(offset);
+
+    // within bounds again it loads
+    bufman
+        .seek_with_cursor(cursor, SeekFrom::Start(prop_length_offset))
+        .unwrap();
+    bufman.write_u32_with_cursor(cursor, 0).unwrap();
+    bufman.close_cursor(cursor).unwrap();
+    let deserialized: MergedNode = cache.load_item(file_index(offset)).unwrap();
+    assert_eq!(deserialized.hnsw_level, HNSWLevel(2));
+}
+
 #[test]
 fn test_merged_node_with_neighbors_serialization() {
     let root_version_id = Hash::from(0);
@@ -349,6 +544,151 @@ fn test_merged_node_with_neighbors_serialization() {
     }
 }
 
+#[test]
+fn test_merged_node_neighbors_load_by_chunk() {
+    let root_version_id = Hash::from(0);
+    let node = MergedNode::new(HNSWLevel(2));
+    let neighbors_count = 3 * CHUNK_SIZE + 2;
+    for i in 1..=neighbors_count {
+        node.add_ready_neighbor(
+            LazyItem::from_data((i as u32).into(), i as u16, node_with_prop(1, i as u32)),
+            MetricResult::CosineSimilarity(CosineSimilarity(i as f32 / neighbors_count as f32)),
+        );
+    }
+
+    let (bufmans, cache, bufman, cursor, _temp_dir) = setup_test(root_version_id);
+
+    node.serialize(bufmans.clone(), root_version_id, cursor)
+        .unwrap();
+    bufman.close_cursor(cursor).unwrap();
+    let neighbors_offset = node.neighbors.serialized_offset.clone().get().unwrap();
+
+    let mut skipm = HashSet::new();
+    let (first_chunk, mut next_chunk) =
+        EagerLazyItemSet::<MergedNode, MetricResult>::deserialize_chunk(
+            bufmans.clone(),
+            FileIndex::Valid {
+                offset: FileOffset(neighbors_offset),
+                version_number: 0,
+                version_id: root_version_id,
+            },
+            cache.clone(),
+            1000,
+            &mut skipm,
+        )
+        .unwrap();
+    assert_eq!(first_chunk.len(), CHUNK_SIZE);
+    assert!(next_chunk.is_some());
+
+    // the remaining neighbors are only read when asked for
+    let mut loaded = first_chunk.len();
+    let mut chunks = 1;
+    while let Some(chunk) = next_chunk {
+        let (items, next) = EagerLazyItemSet::<MergedNode, MetricResult>::deserialize_chunk(
+            bufmans.clone(),
+            chunk,
+            cache.clone(),
+            1000,
+            &mut skipm,
+        )
+        .unwrap();
+        loaded += items.len();
+        chunks += 1;
+        next_chunk = next;
+    }
+    assert_eq!(loaded, neighbors_count);
+    assert_eq!(chunks, 4);
+
+    // deserializing the set reads its first chunk, the rest once it's needed
+    let neighbors = EagerLazyItemSet::<MergedNode, MetricResult>::deserialize(
+        bufmans,
+        FileIndex::Valid {
+            offset: FileOffset(neighbors_offset),
+            version_number: 0,
+            version_id: root_version_id,
+        },
+        cache,
+        1000,
+        &mut HashSet::new(),
+    )
+    .unwrap();
+    assert_eq!(neighbors.items.clone().get().len(), CHUNK_SIZE);
+    assert!(neighbors.unloaded_chunks.clone().get().is_some());
+    assert!(!neighbors.is_empty());
+    assert_eq!(neighbors.len(), neighbors_count);
+    assert!(neighbors.unloaded_chunks.clone().get().is_none());
+}
+
+/// Serializes sets of `C` item chunks holding each of `counts` items, and
+/// checks they are read back in the expected number of chunks.
+fn check_eager_lazy_item_set_chunks<const C: usize>(counts: &[usize]) {
+    let root_version_id = Hash::from(0);
+    for &count in counts {
+        let set = EagerLazyItemSet::<MergedNode, MetricResult, C>::new();
+        for i in 1..=count as u32 {
+            set.insert(EagerLazyItem(
+                MetricResult::CosineSimilarity(CosineSimilarity(i as f32 / count as f32)),
+                LazyItem::from_data(i.into(), i as u16, node_with_prop(1, i)),
+            ));
+        }
+        assert_eq!(set.len(), count);
+
+        let (bufmans, cache, bufman, cursor, _temp_dir) = setup_test(root_version_id);
+        let offset = set
+            .serialize(bufmans.clone(), root_version_id, cursor)
+            .unwrap();
+        bufman.close_cursor(cursor).unwrap();
+        let file_index = FileIndex::Valid {
+            offset: FileOffset(offset),
+            version_number: 0,
+            version_id: root_version_id,
+        };
+
+        let mut skipm = HashSet::new();
+        let mut chunks = 0;
+        let mut next_chunk = Some(file_index);
+        while let Some(chunk) = next_chunk {
+            let (items, next) = EagerLazyItemSet::<MergedNode, MetricResult, C>::deserialize_chunk(
+                bufmans.clone(),
+                chunk,
+                cache.clone(),
+                1000,
+                &mut skipm,
+            )
+            .unwrap();
+            assert!(items.len() <= C);
+            chunks += 1;
+            next_chunk = next;
+        }
+        assert_eq!(
+            chunks,
+            count.div_ceil(C),
+            "{} items in chunks of {}",
+            count,
+            C
+        );
+
+        let mut skipm = HashSet::new();
+        let deserialized = EagerLazyItemSet::<MergedNode, MetricResult, C>::deserialize(
+            bufmans, file_index, cache, 1000, &mut skipm,
+        )
+        .unwrap();
+        assert_eq!(deserialized.len(), count);
+    }
+}
+
+#[test]
+fn test_eager_lazy_item_set_chunk_boundaries() {
+    check_eager_lazy_item_set_chunks::<1>(&[1, 2, 7]);
+    check_eager_lazy_item_set_chunks::<5>(&[4, 5, 6, 10, 11]);
+    check_eager_lazy_item_set_chunks::<CHUNK_SIZE>(&[
+        CHUNK_SIZE - 1,
+        CHUNK_SIZE,
+        CHUNK_SIZE + 1,
+        2 * CHUNK_SIZE + 1,
+    ]);
+}
+
 #[test]
 fn test_merged_node_with_parent_child_serialization() {
     let root_version_id = Hash::from(0);
@@ -403,6 +743,57 @@ fn test_merged_node_with_parent_child_serialization() {
         .is_some());
 }
 
+#[test]
+fn test_merged_node_placeholders_are_patched() {
+    use super::node::check_placeholders;
+    use std::io::SeekFrom;
+
+    let root_version_id = Hash::from(0);
+    let node = MergedNode::new(HNSWLevel(2));
+    node.set_parent(LazyItem::new(1.into(), 1, MergedNode::new(HNSWLevel(3))));
+    node.set_child(LazyItem::new(2.into(), 2, MergedNode::new(HNSWLevel(1))));
+
+    let (bufmans, _cache, bufman, cursor, _temp_dir) = setup_test(root_version_id);
+    // serializing runs the check itself in debug builds
+    let offset = node.serialize(bufmans, root_version_id, cursor).unwrap();
+
+    // level (1 byte), prop offset and length (4 + 4 bytes) and indicator
+    // (1 byte) come first, then the parent and child placeholders (10 bytes
+    // each) and the neighbors offset
+    let parent_placeholder = offset + 10;
+    let child_placeholder = offset + 20;
+    let neighbors_placeholder = offset + 30;
+    let read_u32_at = |pos: u32| {
+        bufman
+            .seek_with_cursor(cursor, SeekFrom::Start(pos as u64))
+            .unwrap();
+        bufman.read_u32_with_cursor(cursor).unwrap()
+    };
+    let parent_offset = read_u32_at(parent_placeholder);
+    let child_offset = read_u32_at(child_placeholder);
+    let neighbors_offset = read_u32_at(neighbors_placeholder);
+    // the node has no neighbors, an empty set is written as u32::MAX
+    assert_eq!(neighbors_offset, u32::MAX);
+
+    let links = [
+        (parent_placeholder, parent_offset),
+        (child_placeholder, child_offset),
+    ];
+    let neighbors = (neighbors_placeholder, neighbors_offset);
+    check_placeholders(&bufman, cursor, &links, neighbors).unwrap();
+
+    // a parent placeholder left unpatched is caught
+    bufman
+        .seek_with_cursor(cursor, SeekFrom::Start(parent_placeholder as u64))
+        .unwrap();
+    bufman.write_u32_with_cursor(cursor, u32::MAX).unwrap();
+    assert!(check_placeholders(&bufman, cursor, &links, neighbors).is_err());
+    let unpatched = [(parent_placeholder, u32::MAX), links[1]];
+    assert!(check_placeholders(&bufman, cursor, &unpatched, neighbors).is_err());
+
+    bufman.close_cursor(cursor).unwrap();
+}
+
 #[test]
 fn test_lazy_item_with_versions_serialization() {
     let temp_dir = tempdir().unwrap();
@@ -459,6 +850,59 @@ fn test_lazy_item_with_versions_serialization() {
     );
 }
 
+#[test]
+fn test_node_version_chain_round_trip() {
+    let temp_dir = tempdir().unwrap();
+    let env = Arc::new(
+        Environment::new()
+            .set_max_dbs(2)
+            .set_map_size(10485760) // 10MB
+            .open(temp_dir.as_ref())
+            .unwrap(),
+    );
+    let db = Arc::new(env.create_db(None, DatabaseFlags::empty()).unwrap());
+    let vcs = VersionControl::new(env, db).unwrap().0;
+    let bufmans = Arc::new(BufferManagerFactory::new(
+        temp_dir.as_ref().into(),
+        |root, ver: &Hash| root.join(format!("{}.index", **ver)),
+        1.0,
+    ));
+    let cache = get_cache(bufmans.clone());
+
+    let v0_hash = vcs.generate_hash("main", 0.into()).unwrap();
+    let node_v0 = LazyItem::new(v0_hash, 0, MergedNode::new(HNSWLevel(2)));
+
+    // the newer version has its own edges, told apart here by the level
+    let (v1_hash, _) = vcs.add_next_version("main").unwrap();
+    let node_v1 = LazyItem::new(v1_hash, 1, MergedNode::new(HNSWLevel(3)));
+    node_v0.add_version(cache.clone(), node_v1);
+
+    let bufman = bufmans.get(v0_hash).unwrap();
+    let cursor = bufman.open_cursor().unwrap();
+    let offset = node_v0.serialize(bufmans.clone(), v0_hash, cursor).unwrap();
+    bufman.close_cursor(cursor).unwrap();
+    bufmans.flush_all().unwrap();
+
+    // a fresh cache, so nothing is served from memory
+    let cache = get_cache(bufmans);
+    let deserialized: LazyItem<MergedNode> = cache
+        .clone()
+        .load_item(FileIndex::Valid {
+            offset: FileOffset(offset),
+            version_number: 0,
+            version_id: v0_hash,
+        })
+        .unwrap();
+
+    let latest = deserialized.get_version(cache.clone(), 1).unwrap();
+    assert_eq!(latest.get_current_version(), v1_hash);
+    assert_eq!(latest.get_current_version_number(), 1);
+    assert_eq!(latest.get_data(cache.clone()).hnsw_level, HNSWLevel(3));
+
+    let original = deserialized.get_version(cache.clone(), 0).unwrap();
+    assert_eq!(original.get_data(cache).hnsw_level, HNSWLevel(2));
+}
+
 #[test]
 fn test_lazy_item_cyclic_serialization() {
     let root_version = Hash::from(0);
@@ -528,6 +972,67 @@ fn test_lazy_item_cyclic_serialization() {
     }
 }
 
+#[test]
+fn test_parent_cycle_deserialization_terminates() {
+    let root_version = Hash::from(0);
+    let nodes: Vec<_> = (0..3)
+        .map(|_| LazyItem::new(root_version, 0, MergedNode::new(HNSWLevel(2))))
+        .collect();
+    // node0 -> node1 -> node2 -> node0
+    for i in 0..3 {
+        nodes[i]
+            .get_lazy_data()
+            .unwrap()
+            .get()
+            .clone()
+            .unwrap()
+            .set_parent(nodes[(i + 1) % 3].clone());
+    }
+
+    let (bufmans, cache, bufman, cursor, _temp_dir) = setup_test(root_version);
+
+    let offset = LazyItemRef::from_lazy(nodes[0].clone())
+        .serialize(bufmans, root_version, cursor)
+        .unwrap();
+    let file_index = FileIndex::Valid {
+        offset: FileOffset(offset),
+        version_number: 0,
+        version_id: root_version,
+    };
+    bufman.close_cursor(cursor).unwrap();
+
+    let parent_of = |item: &LazyItem<MergedNode>| {
+        let mut parent_ref = item
+            .get_lazy_data()
+            .unwrap()
+            .get()
+            .clone()
+            .unwrap()
+            .get_parent();
+        parent_ref.item.get().clone()
+    };
+
+    // plenty of loads are allowed, the cycle has to end the recursion, which
+    // `NodeRegistry::get_object` does by not loading a node seen already
+    let deserialized: LazyItem<MergedNode> = cache.load_item(file_index).unwrap();
+    let node1 = parent_of(&deserialized);
+    assert!(node1.get_lazy_data().unwrap().get().is_some());
+    let node2 = parent_of(&node1);
+    assert!(node2.get_lazy_data().unwrap().get().is_some());
+
+    // the link back to node0 is left unloaded, pointing at node0
+    let LazyItem::Valid {
+        data: mut node0_data,
+        file_index: mut node0_file_index,
+        ..
+    } = parent_of(&node2)
+    else {
+        panic!("expected a lazy reference back to node0");
+    };
+    assert!(node0_data.get().is_none());
+    assert_eq!(node0_file_index.get().clone(), Some(file_index));
+}
+
 #[test]
 fn test_lazy_item_complex_cyclic_serialization() {
     let root_version_id = Hash::from(0);
@@ -663,7 +1168,7 @@ fn test_lazy_item_set_linked_chunk_serialization() {
 #[test]
 fn test_eager_lazy_item_set_linked_chunk_serialization() {
     let root_version_id = Hash::from(0);
-    let lazy_items = EagerLazyItemSet::new();
+    let lazy_items: EagerLazyItemSet<MergedNode, f32> = EagerLazyItemSet::new();
     for i in 1..13 {
         lazy_items.insert(EagerLazyItem(
             3.4,
@@ -909,6 +1414,10 @@ fn test_storage_serialization() {
             mag: 4234.34,
             quant_vec: vec![f16::from_f32(534.324), f16::from_f32(6453.3)],
         },
+        Storage::Sparse {
+            indices: vec![3, 17, 250],
+            values: vec![12, 0, 255],
+        },
     ];
     let (bufmans, cache, bufman, cursor, _dir) = setup_test(1.into());
     bufman.close_cursor(cursor).unwrap();
@@ -1033,7 +1542,7 @@ fn test_lazy_item_vec_linked_chunk_serialization() {
 #[test]
 fn test_eager_lazy_item_multiple_serialization() {
     let value: u32 = rand::random();
-    let set = EagerLazyItemSet::new();
+    let set: EagerLazyItemSet<MergedNode, f32> = EagerLazyItemSet::new();
 
     for _ in 0..0 {
         let item = EagerLazyItem(
@@ -1076,3 +1585,48 @@ fn test_eager_lazy_item_multiple_serialization() {
 
     assert_eq!(set.len(), deserialized.len());
 }
+
+#[test]
+fn test_sparse_inverted_index_serialization() {
+    let version = Hash::from(0);
+    let (bufmans, cache, bufman, cursor, _temp_dir) = setup_test(version);
+    let index = InvertedIndexSparseAnnNewDS {
+        root: ArcShift::new(InvertedIndexNewDSNode::new(0, false, 31)),
+        cache,
+        vector_dims: Arc::new(DashMap::new()),
+        max_dim: None,
+        write_log: None,
+    };
+
+    // dims like 100 are only reachable through implicit intermediate nodes
+    let vectors = vec![
+        SparseVector::new(1, vec![(0, 0.25), (5, 0.9), (100, 0.5)]),
+        SparseVector::new(2, vec![(5, 0.1), (17, 0.75), (100, 1.0)]),
+        SparseVector::new(3, vec![(64, 0.4), (1000, 0.6)]),
+    ];
+    for vector in vectors.clone() {
+        index.add_sparse_vector(vector).unwrap();
+    }
+
+    let offset = index.serialize(bufmans.clone(), version, cursor).unwrap();
+    bufman.close_cursor(cursor).unwrap();
+
+    let file_index = FileIndex::Valid {
+        offset: FileOffset(offset),
+        version_number: 0,
+        version_id: version,
+    };
+    let deserialized: InvertedIndexSparseAnnNewDS =
+        get_cache(bufmans.clone()).load_item(file_index).unwrap();
+    assert_eq!(deserialized.root.quantization, 31);
+    assert_eq!(deserialized.root.data.len(), 32);
+
+    for vector in vectors {
+        for (dim_index, _) in vector.entries {
+            let original = index.get(dim_index, vector.vector_id);
+            assert!(original.is_some());
+            assert_eq!(deserialized.get(dim_index, vector.vector_id), original);
+        }
+    }
+    assert_eq!(deserialized.get(17, 1), None);
+}
@@ -0,0 +1,137 @@
+use super::common::WaCustomError;
+use super::types::SparseVector;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// A sparse vector as recorded in the write log.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct SparseLogEntry {
+    id: u32,
+    entries: Vec<(u32, f32)>,
+}
+
+/// path of the sparse write log inside a collection's directory
+pub fn sparse_log_path(collection_path: &Path) -> PathBuf {
+    collection_path.join("sparse.log")
+}
+
+/// Append-only log of the vectors added to a sparse index. Each vector is
+/// synced to disk before it's inserted into the index, and the whole log is
+/// replayed into a fresh index on startup.
+///
+/// Entries use the same framing as the replication log, a little endian
+/// `u32` length followed by the CBOR encoded entry.
+pub struct SparseWriteLog {
+    file: Mutex<File>,
+}
+
+impl SparseWriteLog {
+    /// Opens the log at `path`, creating it if it doesn't exist, and returns
+    /// it together with the vectors recorded so far, in the order they were
+    /// added. A torn entry at the end, left by a crash in the middle of an
+    /// append, was never acknowledged, so it's cut off.
+    pub fn open(path: &Path) -> Result<(Self, Vec<SparseVector>), WaCustomError> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)
+            .map_err(|e| WaCustomError::FsError(e.to_string()))?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)
+            .map_err(|e| WaCustomError::FsError(e.to_string()))?;
+
+        let mut vectors = Vec::new();
+        let mut pos = 0;
+        while pos + 4 <= bytes.len() {
+            let len = u32::from_le_bytes(bytes[pos..pos + 4].try_into().unwrap()) as usize;
+            if pos + 4 + len > bytes.len() {
+                break;
+            }
+            let entry: SparseLogEntry = serde_cbor::from_slice(&bytes[pos + 4..pos + 4 + len])
+                .map_err(|e| WaCustomError::DeserializationError(e.to_string()))?;
+            vectors.push(SparseVector::new(entry.id, entry.entries));
+            pos += 4 + len;
+        }
+
+        if pos < bytes.len() {
+            file.set_len(pos as u64)
+                .map_err(|e| WaCustomError::FsError(e.to_string()))?;
+            file.sync_data()
+                .map_err(|e| WaCustomError::FsError(e.to_string()))?;
+        }
+
+        Ok((
+            Self {
+                file: Mutex::new(file),
+            },
+            vectors,
+        ))
+    }
+
+    /// Appends `vector` to the log and syncs it to disk.
+    pub fn append(&self, vector: &SparseVector) -> Result<(), WaCustomError> {
+        let bytes = serde_cbor::to_vec(&SparseLogEntry {
+            id: vector.vector_id,
+            entries: vector.entries.clone(),
+        })
+        .map_err(|e| WaCustomError::SerializationError(e.to_string()))?;
+        let mut buf = Vec::with_capacity(4 + bytes.len());
+        buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&bytes);
+
+        let mut file = self
+            .file
+            .lock()
+            .map_err(|_| WaCustomError::LockError("sparse write log".to_string()))?;
+        file.write_all(&buf)
+            .map_err(|e| WaCustomError::FsError(e.to_string()))?;
+        file.sync_data()
+            .map_err(|e| WaCustomError::FsError(e.to_string()))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_sparse_log_replays_appends_and_drops_torn_tail() {
+        let dir = tempdir().unwrap();
+        let path = sparse_log_path(dir.as_ref());
+
+        let (log, vectors) = SparseWriteLog::open(&path).unwrap();
+        assert!(vectors.is_empty());
+        log.append(&SparseVector::new(1, vec![(3, 0.5), (9, 1.0)]))
+            .unwrap();
+        log.append(&SparseVector::new(2, vec![(4, 0.25)])).unwrap();
+        drop(log);
+
+        // half written length prefix of a third entry
+        let complete_len = std::fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(&[7, 0])
+            .unwrap();
+
+        let (log, vectors) = SparseWriteLog::open(&path).unwrap();
+        let ids: Vec<_> = vectors.iter().map(|v| v.vector_id).collect();
+        assert_eq!(ids, vec![1, 2]);
+        assert_eq!(vectors[0].entries, vec![(3, 0.5), (9, 1.0)]);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), complete_len);
+
+        log.append(&SparseVector::new(5, vec![(1, 2.0)])).unwrap();
+        drop(log);
+        let (_, vectors) = SparseWriteLog::open(&path).unwrap();
+        let ids: Vec<_> = vectors.iter().map(|v| v.vector_id).collect();
+        assert_eq!(ids, vec![1, 2, 5]);
+    }
+}
@@ -1,14 +1,16 @@
-use super::buffered_io::BufferManagerFactory;
-use super::cache_loader::ProbCache;
+use super::buffered_io::{BufIoError, BufferManager, BufferManagerFactory};
+use super::cache_loader::{ProbCache, DEFAULT_PROB_CACHE_CAPACITY};
 use super::collection::Collection;
-use super::embedding_persist::{write_embedding, EmbeddingOffset};
-use super::file_persist::write_node_to_file;
+use super::embedding_persist::{write_embedding_replicated, EmbeddingOffset};
+use super::file_persist::{finish_prop_compaction, prop_file_path, write_node_to_file};
 use super::meta_persist::{
     delete_dense_index, lmdb_init_collections_db, lmdb_init_db, load_collections,
-    load_dense_index_data, persist_dense_index, retrieve_current_version,
+    load_dense_index_data, migrate_shared_embedding_offsets, persist_dense_index,
+    persist_node_locations, retrieve_current_version, retrieve_node_location,
 };
 use super::prob_lazy_load::lazy_item::ProbLazyItem;
 use super::prob_node::{ProbNode, SharedNode};
+use super::sparse_log::{sparse_log_path, SparseWriteLog};
 use super::versioning::VersionControl;
 use crate::config_loader::Config;
 use crate::distance::cosine::CosineSimilarity;
@@ -18,16 +20,24 @@ use crate::distance::{
     hamming::HammingDistance, DistanceFunction,
 };
 use crate::macros::key;
+use crate::models::chunked_list::check_chunk_size;
 use crate::models::common::*;
+use crate::models::embedding_persist::put_metadata;
 use crate::models::identity_collections::*;
 use crate::models::lazy_load::*;
 use crate::models::versioning::*;
 use crate::quantization::{
-    product::ProductQuantization, scalar::ScalarQuantization, Quantization, QuantizationError,
-    StorageType,
+    product::ProductQuantization,
+    reservoir::{ReservoirSampler, MAX_TRAINING_SAMPLE_SIZE},
+    scalar::ScalarQuantization,
+    Quantization, QuantizationError, StorageType,
+};
+use crate::storage::inverted_index_sparse_ann_new_ds::{
+    InvertedIndexSparseAnnNewDS, DEFAULT_CACHE_CAPACITY,
 };
 use crate::storage::Storage;
 use arcshift::ArcShift;
+use dashmap::mapref::entry::Entry;
 use dashmap::DashMap;
 use lmdb::{Database, DatabaseFlags, Environment, Transaction, WriteFlags};
 use serde::{Deserialize, Serialize};
@@ -35,14 +45,25 @@ use siphasher::sip::SipHasher24;
 use std::collections::HashSet;
 use std::hash::{DefaultHasher, Hash as StdHash, Hasher};
 use std::path::Path;
-use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
-use std::sync::{mpsc, Arc, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, RwLock};
 use std::{fmt, ptr};
 use std::{fs::*, thread};
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub struct HNSWLevel(pub u8);
 
+impl HNSWLevel {
+    /// Checks `level` is a level of an index with `num_layers` levels above
+    /// level 0.
+    pub fn new(level: u32, num_layers: u8) -> Result<Self, WaCustomError> {
+        match u8::try_from(level) {
+            Ok(level) if level <= num_layers => Ok(Self(level)),
+            _ => Err(WaCustomError::InvalidLevel(level, num_layers)),
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub struct FileOffset(pub u32);
 
@@ -77,11 +98,65 @@ impl Identifiable for MergedNode {
 
 pub type PropPersistRef = (FileOffset, BytesToRead);
 
+/// Where a prop is stored in `prop.data`. Compacting the file moves props
+/// around, so the location can be updated in place.
+pub struct PropLocation(AtomicU64);
+
+impl PropLocation {
+    pub fn new((FileOffset(offset), BytesToRead(length)): PropPersistRef) -> Self {
+        Self(AtomicU64::new((offset as u64) << 32 | length as u64))
+    }
+
+    pub fn get(&self) -> PropPersistRef {
+        let location = self.0.load(Ordering::Acquire);
+        (
+            FileOffset((location >> 32) as u32),
+            BytesToRead(location as u32),
+        )
+    }
+
+    pub fn set(&self, (FileOffset(offset), BytesToRead(length)): PropPersistRef) {
+        self.0
+            .store((offset as u64) << 32 | length as u64, Ordering::Release);
+    }
+}
+
+impl Clone for PropLocation {
+    fn clone(&self) -> Self {
+        Self::new(self.get())
+    }
+}
+
+impl PartialEq for PropLocation {
+    fn eq(&self, other: &Self) -> bool {
+        self.get() == other.get()
+    }
+}
+
+impl fmt::Debug for PropLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.get().fmt(f)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct NodeProp {
     pub id: VectorId,
     pub value: Arc<Storage>,
-    pub location: PropPersistRef,
+    pub location: PropLocation,
+    /// The upload the node was built from, `None` for the root and for props
+    /// written before it was recorded.
+    pub source: Option<EmbeddingSource>,
+}
+
+/// The raw embedding a node was built from: the version it was stored under
+/// and its offset in that version's raw vectors file. Nodes indexed in a
+/// transaction are created before their embeddings are written, so they only
+/// know the version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EmbeddingSource {
+    pub version: Hash,
+    pub offset: Option<u32>,
 }
 
 impl StdHash for NodeProp {
@@ -111,6 +186,7 @@ pub enum PropState {
     rkyv::Serialize,
     rkyv::Deserialize,
 )]
+#[archive(check_bytes)]
 pub struct VectorId(pub u64);
 
 impl VectorId {
@@ -150,17 +226,66 @@ impl MetricResult {
             MetricResult::DotProductDistance(value) => value.0,
         }
     }
+
+    /// Distance between the vectors in the metric's own units, lower meaning
+    /// more similar. Cosine similarities are turned into cosine distances and
+    /// dot products, which have no distance form, are negated.
+    pub fn distance(&self) -> f32 {
+        match self {
+            MetricResult::CosineSimilarity(value) => 1.0 - value.0,
+            MetricResult::CosineDistance(value) => value.0,
+            MetricResult::EuclideanDistance(value) => value.0,
+            MetricResult::HammingDistance(value) => value.0,
+            MetricResult::DotProductDistance(value) => -value.0,
+        }
+    }
+
+    /// Similarity normalized to `[0, 1]`, higher meaning more similar, so
+    /// results of any metric can be ranked by sorting on it in descending
+    /// order. Distances are inverted; dot products are unbounded, so they are
+    /// log-compressed before being squashed into the range, which keeps large
+    /// products distinguishable from each other.
+    pub fn score(&self) -> f32 {
+        match self {
+            MetricResult::CosineSimilarity(value) => ((value.0 + 1.0) / 2.0).clamp(0.0, 1.0),
+            MetricResult::CosineDistance(value) => (1.0 - value.0 / 2.0).clamp(0.0, 1.0),
+            MetricResult::EuclideanDistance(value) => 1.0 / (1.0 + value.0.max(0.0)),
+            MetricResult::HammingDistance(value) => 1.0 / (1.0 + value.0.max(0.0)),
+            MetricResult::DotProductDistance(value) => {
+                let compressed = value.0.signum() * value.0.abs().ln_1p();
+                0.5 + compressed.atan() / std::f32::consts::PI
+            }
+        }
+    }
 }
 
-#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[derive(Debug, Clone, Default, PartialEq, serde::Deserialize, serde::Serialize)]
 #[serde(rename_all = "snake_case")]
 pub enum DistanceMetric {
+    #[default]
     Cosine,
     Euclidean,
     Hamming,
     DotProduct,
 }
 
+impl std::str::FromStr for DistanceMetric {
+    type Err = String;
+
+    /// Parses the metric names accepted when creating a collection.
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "cosine" => Ok(Self::Cosine),
+            "euclidean" => Ok(Self::Euclidean),
+            "dot" => Ok(Self::DotProduct),
+            _ => Err(format!(
+                "unknown distance metric `{}`, expected one of `cosine`, `euclidean` or `dot`",
+                name
+            )),
+        }
+    }
+}
+
 impl DistanceFunction for DistanceMetric {
     type Item = MetricResult;
     fn calculate(&self, x: &Storage, y: &Storage) -> Result<Self::Item, DistanceError> {
@@ -185,12 +310,80 @@ impl DistanceFunction for DistanceMetric {
     }
 }
 
+impl DistanceMetric {
+    /// Whether the metric can compare vectors quantized to `storage_type`.
+    /// Euclidean distances aren't computed on sub-byte storage, and no
+    /// storage has a hamming distance yet.
+    pub fn supports_storage(&self, storage_type: StorageType) -> bool {
+        match (self, storage_type) {
+            (Self::Hamming, _) => false,
+            (Self::Euclidean, StorageType::SubByte(_)) => false,
+            _ => true,
+        }
+    }
+
+    /// Compares two raw, unquantized vectors, e.g. to rescore the candidates
+    /// of a graph search. Hamming distances count the dimensions whose signs
+    /// differ, the bits binary quantization keeps.
+    pub fn calculate_raw(&self, x: &[f32], y: &[f32]) -> MetricResult {
+        match self {
+            Self::Cosine => {
+                MetricResult::CosineSimilarity(CosineSimilarity(cosine_similarity(x, y)))
+            }
+            Self::Euclidean => MetricResult::EuclideanDistance(EuclideanDistance(
+                x.iter()
+                    .zip(y)
+                    .map(|(a, b)| (a - b) * (a - b))
+                    .sum::<f32>()
+                    .sqrt(),
+            )),
+            Self::Hamming => MetricResult::HammingDistance(HammingDistance(
+                x.iter()
+                    .zip(y)
+                    .filter(|(a, b)| (**a > 0.0) != (**b > 0.0))
+                    .count() as f32,
+            )),
+            Self::DotProduct => MetricResult::DotProductDistance(DotProductDistance(
+                x.iter().zip(y).map(|(a, b)| a * b).sum(),
+            )),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum QuantizationMetric {
     Scalar,
     Product(ProductQuantization),
 }
 
+impl QuantizationMetric {
+    /// Whether vectors have to be sampled to train the quantizer before it
+    /// can encode them.
+    pub fn needs_training(&self) -> bool {
+        match self {
+            Self::Scalar => false,
+            Self::Product(product) => !product.is_trained(),
+        }
+    }
+
+    /// Compares two vectors this quantizer encoded. Product codes mean
+    /// nothing without the codebook, so they're compared as the centroids
+    /// they point to.
+    pub fn distance(
+        &self,
+        distance_metric: &DistanceMetric,
+        x: &Storage,
+        y: &Storage,
+    ) -> Result<MetricResult, DistanceError> {
+        match self {
+            Self::Product(product) if product.is_trained() => {
+                Ok(distance_metric.calculate_raw(&product.dequantize(x)?, &product.dequantize(y)?))
+            }
+            _ => distance_metric.calculate(x, y),
+        }
+    }
+}
+
 impl Quantization for QuantizationMetric {
     fn quantize(
         &self,
@@ -204,10 +397,14 @@ impl Quantization for QuantizationMetric {
         }
     }
 
-    fn train(&mut self, vectors: &[&[f32]]) -> Result<(), QuantizationError> {
+    fn train(
+        &mut self,
+        vectors: &[&[f32]],
+        storage_type: StorageType,
+    ) -> Result<(), QuantizationError> {
         match self {
-            Self::Scalar => ScalarQuantization.train(vectors),
-            Self::Product(product) => product.train(vectors),
+            Self::Scalar => ScalarQuantization.train(vectors, storage_type),
+            Self::Product(product) => product.train(vectors, storage_type),
         }
     }
 }
@@ -258,7 +455,7 @@ impl MergedNode {
     pub fn get_prop_location(&self) -> PropPersistRef {
         let mut arc = self.prop.clone();
         match arc.get() {
-            PropState::Ready(node_prop) => node_prop.location.clone(),
+            PropState::Ready(node_prop) => node_prop.location.get(),
             PropState::Pending(location) => *location,
         }
     }
@@ -373,6 +570,19 @@ impl MetaDb {
     }
 }
 
+/// How the neighbors of a new node are picked among the candidates found
+/// for it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NeighborSelection {
+    /// the nearest candidates
+    #[default]
+    Simple,
+    /// the nearest candidates that are nearer to the new node than to any
+    /// neighbor picked before them, which keeps the neighbors from clustering
+    Heuristic,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HNSWHyperParams {
     pub num_layers: u8,
@@ -381,6 +591,8 @@ pub struct HNSWHyperParams {
     pub max_cache_size: usize,
     pub level_0_neighbors_count: usize,
     pub neighbors_count: usize,
+    #[serde(default)]
+    pub neighbor_selection: NeighborSelection,
 }
 
 impl HNSWHyperParams {
@@ -392,6 +604,7 @@ impl HNSWHyperParams {
             max_cache_size: config.hnsw.default_max_cache_size,
             level_0_neighbors_count: config.hnsw.default_level_0_neighbors_count,
             neighbors_count: config.hnsw.default_neighbors_count,
+            neighbor_selection: NeighborSelection::default(),
         }
     }
 }
@@ -402,25 +615,28 @@ pub struct DenseIndexTransaction {
     pub serialization_table: Arc<TSHashTable<SharedNode, ()>>,
     pub lazy_item_versions_table: Arc<TSHashTable<(VectorId, u16, u8), SharedNode>>,
     serializer_thread_handle: thread::JoinHandle<Result<(), WaCustomError>>,
-    raw_embedding_serializer_thread_handle: thread::JoinHandle<Result<(), WaCustomError>>,
+    raw_embedding_serializer_thread_handle:
+        thread::JoinHandle<Result<Vec<RawVectorEmbedding>, WaCustomError>>,
     serialization_signal: mpsc::Sender<()>,
     pub raw_embedding_channel: mpsc::Sender<RawVectorEmbedding>,
     batch_count: Arc<AtomicUsize>,
+    // tells the raw embedding serializer thread to drop what it was sent
+    aborted: Arc<AtomicBool>,
 }
 
 impl DenseIndexTransaction {
-    pub fn new(dense_index: Arc<DenseIndex>) -> Result<Self, WaCustomError> {
-        let branch_info = dense_index
-            .vcs
-            .get_branch_info("main")
-            .map_err(|err| {
-                WaCustomError::DatabaseError(format!("Unable to get main branch info: {}", err))
-            })?
-            .unwrap();
+    /// The nodes indexed in the transaction are written to the index files
+    /// in batches of `flush_batch_size`, see `Config::commit_flush_batch_size`.
+    pub fn new(
+        dense_index: Arc<DenseIndex>,
+        flush_batch_size: usize,
+    ) -> Result<Self, WaCustomError> {
+        dense_index.ensure_writable()?;
+        let branch_info = dense_index.current_branch()?;
         let version_number = *branch_info.get_current_version() + 1;
         let id = dense_index
             .vcs
-            .generate_hash("main", Version::from(version_number))
+            .generate_hash(branch_info.get_branch_name(), Version::from(version_number))
             .map_err(|err| {
                 WaCustomError::DatabaseError(format!("Unable to get transaction hash: {}", err))
             })?;
@@ -442,31 +658,51 @@ impl DenseIndexTransaction {
                     if batches_processed >= batch_count.load(Ordering::SeqCst) {
                         break;
                     }
-                    let list = serialization_table.to_list();
-                    for (node, _) in list {
-                        serialization_table.delete(&node);
-                        let version = unsafe { &*node }.get_current_version();
-                        let offset = write_node_to_file(node, &dense_index.index_manager)?;
-                        dense_index.cache.insert_lazy_object(version, offset, node);
-                    }
+                    serialization_table.drain_in_batches(
+                        flush_batch_size,
+                        |&node, _| {
+                            let version = unsafe { &*node }.get_current_version();
+                            let offset = write_node_to_file(node, &dense_index.index_manager)?;
+                            dense_index.cache.insert_lazy_object(version, offset, node);
+                            Ok::<_, WaCustomError>(())
+                        },
+                        || Ok(dense_index.index_manager.flush_all()?),
+                    )?;
+                    dense_index.record_node_locations()?;
                     batches_processed += 1;
                 }
                 dense_index.index_manager.flush_all()?;
+                dense_index.record_node_locations()?;
                 Ok(())
             })
         };
 
         let (raw_embedding_channel, rx) = mpsc::channel();
+        let aborted = Arc::new(AtomicBool::new(false));
 
         let raw_embedding_serializer_thread_handle = {
-            let bufman = dense_index.vec_raw_manager.get(id)?;
+            let bufmans = dense_index.vec_raw_bufmans(id)?;
+            let write_lock = dense_index.vec_raw_write_lock(id);
+            let aborted = aborted.clone();
+            let branch = dense_index.branch_of(id)?;
 
             thread::spawn(move || {
                 let mut offsets = Vec::new();
+                let mut raw_embs = Vec::new();
                 for raw_emb in rx {
-                    let offset = write_embedding(bufman.clone(), &raw_emb)?;
-                    let embedding_key = key!(e:raw_emb.hash_vec);
+                    let offset = {
+                        let _guard = write_lock.lock().unwrap();
+                        write_embedding_replicated(&bufmans, &raw_emb)?
+                    };
+                    let embedding_key = key!(e:branch, raw_emb.hash_vec);
                     offsets.push((embedding_key, offset));
+                    raw_embs.push(raw_emb);
+                }
+
+                // an aborted transaction leaves no trace of its embeddings in
+                // LMDB, its raw embeddings file is never read
+                if aborted.load(Ordering::SeqCst) {
+                    return Ok(raw_embs);
                 }
 
                 let env = dense_index.lmdb.env.clone();
@@ -475,6 +711,9 @@ impl DenseIndexTransaction {
                 let mut txn = env.begin_rw_txn().map_err(|e| {
                     WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e))
                 })?;
+                for raw_emb in &raw_embs {
+                    put_metadata(&mut txn, *db, &raw_emb.hash_vec, raw_emb.metadata.as_ref())?;
+                }
                 for (key, offset) in offsets {
                     let offset = EmbeddingOffset {
                         version: id,
@@ -491,8 +730,10 @@ impl DenseIndexTransaction {
                 txn.commit().map_err(|e| {
                     WaCustomError::DatabaseError(format!("Failed to commit transaction: {}", e))
                 })?;
-                bufman.flush()?;
-                Ok(())
+                for bufman in &bufmans {
+                    bufman.flush()?;
+                }
+                Ok(raw_embs)
             })
         };
 
@@ -505,6 +746,7 @@ impl DenseIndexTransaction {
             batch_count,
             raw_embedding_channel,
             raw_embedding_serializer_thread_handle,
+            aborted,
             version_number: version_number as u16,
         })
     }
@@ -521,15 +763,21 @@ impl DenseIndexTransaction {
         self.serialization_signal.send(()).unwrap();
     }
 
-    pub fn pre_commit(self) -> Result<(), WaCustomError> {
+    /// Waits for the serializer threads to finish, returning the raw
+    /// embeddings written in this transaction.
+    pub fn pre_commit(self) -> Result<Vec<RawVectorEmbedding>, WaCustomError> {
         // sending a signal without incrementing the batch count will stop the serialization
         self.serialization_signal.send(()).unwrap();
         self.serializer_thread_handle.join().unwrap()?;
         drop(self.raw_embedding_channel);
-        self.raw_embedding_serializer_thread_handle
-            .join()
-            .unwrap()?;
-        Ok(())
+        self.raw_embedding_serializer_thread_handle.join().unwrap()
+    }
+
+    /// Waits for the serializer threads to finish like `pre_commit`, but
+    /// without recording the transaction's embeddings or their metadata.
+    pub fn abort(self) -> Result<(), WaCustomError> {
+        self.aborted.store(true, Ordering::SeqCst);
+        self.pre_commit().map(|_| ())
     }
 }
 
@@ -548,6 +796,36 @@ pub struct SamplingData {
     pub below_01: AtomicUsize,
 }
 
+/// Creates the buffer manager of the raw embeddings files kept in
+/// `base_path`, one `<version hash>.vec_raw` file per version.
+pub fn vec_raw_manager(
+    base_path: Arc<Path>,
+    flush_eagerness_factor: f32,
+) -> Arc<BufferManagerFactory<Hash>> {
+    Arc::new(BufferManagerFactory::new(
+        base_path,
+        |root, ver: &Hash| root.join(format!("{}.vec_raw", **ver)),
+        flush_eagerness_factor,
+    ))
+}
+
+/// Creates the buffer managers of `replica_count` copies of a collection's
+/// raw embeddings files, the `i`th one kept under `replica_{i}` in the
+/// collection's directory.
+pub fn vec_raw_replica_managers(
+    collection_path: &Path,
+    replica_count: usize,
+    flush_eagerness_factor: f32,
+) -> Result<Vec<Arc<BufferManagerFactory<Hash>>>, WaCustomError> {
+    (1..=replica_count)
+        .map(|i| {
+            let replica_path = collection_path.join(format!("replica_{}", i));
+            create_dir_all(&replica_path).map_err(|e| WaCustomError::FsError(e.to_string()))?;
+            Ok(vec_raw_manager(replica_path.into(), flush_eagerness_factor))
+        })
+        .collect()
+}
+
 #[derive(Clone)]
 pub struct DenseIndex {
     pub database_name: String,
@@ -566,12 +844,38 @@ pub struct DenseIndex {
     pub cache: Arc<ProbCache>,
     pub index_manager: Arc<BufferManagerFactory<Hash>>,
     pub vec_raw_manager: Arc<BufferManagerFactory<Hash>>,
+    /// copies of the raw embeddings files kept besides `vec_raw_manager`,
+    /// `replication_factor - 1` of them
+    pub vec_raw_replica_managers: Vec<Arc<BufferManagerFactory<Hash>>>,
+    /// locks held while appending to the raw embeddings files of a version,
+    /// so that all of their copies receive the embeddings in the same order,
+    /// see `vec_raw_write_lock`
+    pub vec_raw_write_locks: Arc<DashMap<Hash, Arc<Mutex<()>>>>,
     pub is_configured: Arc<AtomicBool>,
     pub values_range: Arc<RwLock<(f32, f32)>>,
-    pub vectors: Arc<RwLock<Vec<(u64, Vec<f32>)>>>,
+    pub vectors: Arc<RwLock<Vec<(u64, Vec<f32>, Option<serde_json::Value>)>>>,
     pub sampling_data: Arc<SamplingData>,
     pub vectors_collected: Arc<AtomicUsize>,
     pub sample_threshold: usize,
+    /// vectors sampled out of those collected before `sample_threshold` is
+    /// reached, for quantizers that need training
+    pub training_sample: Arc<Mutex<ReservoirSampler>>,
+    /// version number the index was rolled back to, queries only see the
+    /// graph as of this version while it's set
+    pub rolled_back_to: Arc<RwLock<Option<u16>>>,
+    /// held while the first write after a rollback branches off the rolled
+    /// back version, see `branch_from_rollback`
+    pub rollback_branch_lock: Arc<Mutex<()>>,
+    /// held by a write from checking the version it was made against to
+    /// moving the index to the next version, see `run_upload`
+    pub version_lock: Arc<Mutex<()>>,
+    /// set while pending embeddings are being indexed on demand
+    pub is_indexing: Arc<AtomicBool>,
+    /// seeds the level each vector is inserted up to, for reproducible graphs
+    pub level_seed: Option<u64>,
+    /// nodes created whose location isn't recorded yet, oldest first, see
+    /// `record_node_locations`
+    pub unlocated_nodes: Arc<Mutex<Vec<SharedNode>>>,
 }
 
 unsafe impl Send for DenseIndex {}
@@ -594,9 +898,11 @@ impl DenseIndex {
         cache: Arc<ProbCache>,
         index_manager: Arc<BufferManagerFactory<Hash>>,
         vec_raw_manager: Arc<BufferManagerFactory<Hash>>,
+        vec_raw_replica_managers: Vec<Arc<BufferManagerFactory<Hash>>>,
         values_range: (f32, f32),
         sample_threshold: usize,
         is_configured: bool,
+        level_seed: Option<u64>,
     ) -> Self {
         DenseIndex {
             database_name,
@@ -615,12 +921,23 @@ impl DenseIndex {
             cache,
             index_manager,
             vec_raw_manager,
+            vec_raw_replica_managers,
+            vec_raw_write_locks: Arc::new(DashMap::new()),
             is_configured: Arc::new(AtomicBool::new(is_configured)),
             values_range: Arc::new(RwLock::new(values_range)),
             vectors: Arc::new(RwLock::new(Vec::new())),
             sampling_data: Arc::new(SamplingData::default()),
             vectors_collected: Arc::new(AtomicUsize::new(0)),
             sample_threshold,
+            training_sample: Arc::new(Mutex::new(ReservoirSampler::new(
+                sample_threshold.min(MAX_TRAINING_SAMPLE_SIZE),
+            ))),
+            rolled_back_to: Arc::new(RwLock::new(None)),
+            rollback_branch_lock: Arc::new(Mutex::new(())),
+            version_lock: Arc::new(Mutex::new(())),
+            is_indexing: Arc::new(AtomicBool::new(false)),
+            level_seed,
+            unlocated_nodes: Arc::new(Mutex::new(Vec::new())),
         }
     }
 
@@ -644,12 +961,223 @@ impl DenseIndex {
         self.root_vec.load(Ordering::SeqCst)
     }
 
+    /// Compares two vectors of the index with its distance metric, through
+    /// the codebook for product quantized ones.
+    pub fn distance(&self, x: &Storage, y: &Storage) -> Result<MetricResult, DistanceError> {
+        let mut quantization_metric = self.quantization_metric.clone();
+        quantization_metric
+            .get()
+            .distance(&self.distance_metric, x, y)
+    }
+
     /// Returns FileIndex (offset) corresponding to the root
     /// node. Returns None if the it's not set or the root node is an
     /// invalid LazyItem
     pub fn root_vec_offset(&self) -> Option<FileIndex> {
         unsafe { &*self.get_root_vec() }.get_file_index()
     }
+
+    /// Buffer managers of every copy of the raw embeddings file of
+    /// `version`, the primary first.
+    pub fn vec_raw_bufmans(&self, version: Hash) -> Result<Vec<Arc<BufferManager>>, BufIoError> {
+        let mut bufmans = vec![self.vec_raw_manager.get(version)?];
+        bufmans.extend(self.vec_raw_replica_bufmans(version)?);
+        Ok(bufmans)
+    }
+
+    /// Buffer managers of the replicas of the raw embeddings file of
+    /// `version`, without the primary.
+    pub fn vec_raw_replica_bufmans(
+        &self,
+        version: Hash,
+    ) -> Result<Vec<Arc<BufferManager>>, BufIoError> {
+        self.vec_raw_replica_managers
+            .iter()
+            .map(|manager| manager.get(version))
+            .collect()
+    }
+
+    /// Lock to hold while appending to the raw embeddings files of
+    /// `version`. Appends to the files of different versions don't wait on
+    /// each other.
+    pub fn vec_raw_write_lock(&self, version: Hash) -> Arc<Mutex<()>> {
+        self.vec_raw_write_locks
+            .entry(version)
+            .or_default()
+            .value()
+            .clone()
+    }
+
+    /// Flushes all copies of the raw embeddings files.
+    pub fn flush_vec_raw(&self) -> Result<(), BufIoError> {
+        self.vec_raw_manager.flush_all()?;
+        for manager in &self.vec_raw_replica_managers {
+            manager.flush_all()?;
+        }
+        Ok(())
+    }
+
+    /// Makes everything written so far durable: fsyncs the index, raw
+    /// embedding and prop files and flushes the LMDB environment to disk.
+    pub fn sync(&self) -> Result<(), WaCustomError> {
+        self.index_manager.sync_all()?;
+        self.vec_raw_manager.sync_all()?;
+        for manager in &self.vec_raw_replica_managers {
+            manager.sync_all()?;
+        }
+        self.prop_file
+            .read()
+            .map_err(|_| WaCustomError::LockError("Failed to lock the prop file".to_string()))?
+            .sync_all()
+            .map_err(|e| WaCustomError::FsError(e.to_string()))?;
+        self.lmdb
+            .env
+            .sync(true)
+            .map_err(|e| WaCustomError::DatabaseError(e.to_string()))
+    }
+
+    /// Returns the branch `version` belongs to. Embedding offsets are keyed by
+    /// it, so that an upsert on a branch isn't seen by the others.
+    pub fn branch_of(&self, version: Hash) -> Result<BranchId, WaCustomError> {
+        let txn = self
+            .lmdb
+            .env
+            .begin_ro_txn()
+            .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
+        let version_hash = self
+            .vcs
+            .get_version_hash(&version, &txn)
+            .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?
+            .ok_or_else(|| {
+                WaCustomError::InvalidVersion(format!("version {} doesn't exist", *version))
+            })?;
+        Ok(version_hash.branch)
+    }
+
+    /// Returns the branch the current version belongs to, which new versions
+    /// are added to.
+    pub fn current_branch(&self) -> Result<BranchInfo, WaCustomError> {
+        self.vcs
+            .get_version_branch(self.get_current_version())
+            .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?
+            .ok_or_else(|| WaCustomError::NotFound("branch of the current version".to_string()))
+    }
+
+    /// Points the current version back to `version`, an earlier version of
+    /// the same branch. Subsequent queries traverse the graph as of that
+    /// version.
+    pub fn rollback_to(&self, version: Hash) -> Result<(), WaCustomError> {
+        if !self
+            .current_open_transaction
+            .load(Ordering::SeqCst)
+            .is_null()
+        {
+            return Err(WaCustomError::LockError(
+                "Cannot rollback while there's an ongoing transaction".to_string(),
+            ));
+        }
+        let version_hash = self.vcs.rollback_to(self.get_current_version(), version)?;
+        self.set_current_version(version);
+        *self.rolled_back_to.write().unwrap() = Some(*version_hash.version as u16);
+        Ok(())
+    }
+
+    /// Returns an error if the index was rolled back, as new versions can't
+    /// be created on top of a rolled back one. Writes go through
+    /// `branch_from_rollback` first, which lifts this.
+    pub fn ensure_writable(&self) -> Result<(), WaCustomError> {
+        match *self.rolled_back_to.read().unwrap() {
+            Some(version) => Err(WaCustomError::InvalidVersion(format!(
+                "collection is rolled back to version {}, writes are not allowed",
+                version
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns false if `node` was created after the version the index was
+    /// rolled back to.
+    pub fn is_visible(&self, node: SharedNode) -> Result<bool, WaCustomError> {
+        match *self.rolled_back_to.read().unwrap() {
+            Some(version) => Ok(ProbLazyItem::get_version(node, version, &self.cache)?.is_some()),
+            None => Ok(true),
+        }
+    }
+
+    /// Returns the version of `node` that queries should see, the latest one
+    /// unless the index was rolled back.
+    pub fn get_visible_version(&self, node: SharedNode) -> Result<SharedNode, WaCustomError> {
+        match *self.rolled_back_to.read().unwrap() {
+            Some(version) => {
+                Ok(ProbLazyItem::get_version(node, version, &self.cache)?.unwrap_or(node))
+            }
+            None => Ok(ProbLazyItem::get_latest_version(node, &self.cache)?.0),
+        }
+    }
+
+    /// Records the location in the index files of the nodes created so far
+    /// that have been written, so that `find_node` finds them after a
+    /// restart too. Called after the nodes of an indexing run are written.
+    pub fn record_node_locations(&self) -> Result<(), WaCustomError> {
+        let lock_error = || WaCustomError::LockError("Failed to lock unlocated nodes".to_string());
+        let written = {
+            let mut unlocated = self.unlocated_nodes.lock().map_err(|_| lock_error())?;
+            let (written, pending): (Vec<SharedNode>, Vec<SharedNode>) =
+                std::mem::take(&mut *unlocated)
+                    .into_iter()
+                    .partition(|&node| unsafe { &*node }.get_file_index().is_some());
+            *unlocated = pending;
+            written
+        };
+
+        if let Err(e) = persist_node_locations(&self.lmdb, &written) {
+            let mut unlocated = self.unlocated_nodes.lock().map_err(|_| lock_error())?;
+            let created_since = std::mem::replace(&mut *unlocated, written);
+            unlocated.extend(created_since);
+            return Err(e);
+        }
+        // loading a node by its location returns the one in memory
+        for node in written {
+            if let Some(FileIndex::Valid {
+                offset: FileOffset(offset),
+                version_id,
+                ..
+            }) = unsafe { &*node }.get_file_index()
+            {
+                self.cache.insert_lazy_object(version_id, offset, node);
+            }
+        }
+        Ok(())
+    }
+
+    /// Finds the node of `vector_id` on `level`, whether or not it can be
+    /// reached from the root. For a vector uploaded more than once, this is
+    /// the node of its latest upload.
+    pub fn find_node(
+        &self,
+        vector_id: &VectorId,
+        level: HNSWLevel,
+    ) -> Result<Option<SharedNode>, WaCustomError> {
+        let unlocated = self
+            .unlocated_nodes
+            .lock()
+            .map_err(|_| WaCustomError::LockError("Failed to lock unlocated nodes".to_string()))?
+            .iter()
+            .rev()
+            .copied()
+            .find(|&node| {
+                unsafe { &*node }
+                    .get_lazy_data()
+                    .is_some_and(|data| data.prop.id == *vector_id && data.hnsw_level == level)
+            });
+        if unlocated.is_some() {
+            return Ok(unlocated);
+        }
+        match retrieve_node_location(&self.lmdb, vector_id, level)? {
+            Some(file_index) => Ok(Some(self.cache.get_object(file_index)?)),
+            None => Ok(None),
+        }
+    }
 }
 
 // Quantized vector embedding
@@ -661,15 +1189,22 @@ pub struct QuantizedVectorEmbedding {
 
 // Raw vector embedding
 #[derive(Debug, Clone, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, PartialEq)]
+#[archive(check_bytes)]
 pub struct RawVectorEmbedding {
     pub raw_vec: Arc<Vec<f32>>,
     pub hash_vec: VectorId,
+    // stored as JSON after the archived embedding, see `write_embedding`
+    #[with(rkyv::with::Skip)]
+    pub metadata: Option<serde_json::Value>,
 }
 
 pub struct CollectionsMap {
     /// holds an in-memory map of all dense indexes for all collections
     inner: DashMap<String, Arc<DenseIndex>>,
     inner_collections: DashMap<String, Arc<Collection>>,
+    /// sparse indexes of collections with sparse vectors enabled, rebuilt
+    /// from their write logs on startup
+    inner_sparse_indexes: DashMap<String, Arc<InvertedIndexSparseAnnNewDS>>,
     lmdb_env: Arc<Environment>,
     // made it public temporarily
     // just to be able to persist collections from outside CollectionsMap
@@ -679,6 +1214,28 @@ pub struct CollectionsMap {
     lmdb_inverted_index_db: Database,
 }
 
+/// creates the sparse index of a collection and replays its write log into it
+fn open_sparse_index(
+    collection: &Collection,
+    collections_path: &Path,
+) -> Result<InvertedIndexSparseAnnNewDS, WaCustomError> {
+    let collection_path = collection.get_path(collections_path);
+    create_dir_all(&collection_path).map_err(|e| WaCustomError::FsError(e.to_string()))?;
+    check_chunk_size(&collection_path)?;
+    let index = InvertedIndexSparseAnnNewDS::new(
+        &collection_path,
+        collection
+            .config
+            .cache_capacity
+            .unwrap_or(DEFAULT_CACHE_CAPACITY),
+    );
+    let (write_log, vectors) = SparseWriteLog::open(&sparse_log_path(&collection_path))?;
+    for vector in vectors {
+        index.add_sparse_vector(vector)?;
+    }
+    Ok(index.with_write_log(write_log))
+}
+
 impl CollectionsMap {
     fn new(env: Arc<Environment>) -> lmdb::Result<Self> {
         let collections_db = lmdb_init_collections_db(&env)?;
@@ -687,6 +1244,7 @@ impl CollectionsMap {
         let res = Self {
             inner: DashMap::new(),
             inner_collections: DashMap::new(),
+            inner_sparse_indexes: DashMap::new(),
             lmdb_env: env,
             lmdb_collections_db: collections_db,
             lmdb_dense_index_db: dense_index_db,
@@ -709,7 +1267,7 @@ impl CollectionsMap {
         )
         .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
 
-        let root_path = Path::new("./collections");
+        let root_path = &config.collections_path;
 
         // let bufmans = cache.get_bufmans();
 
@@ -727,9 +1285,9 @@ impl CollectionsMap {
                     .insert(coll.name.clone(), Arc::new(dense_index));
             }
 
-            // if collection has inverted index load it from the lmdb
+            // if collection has inverted index replay it from its write log
             if coll.sparse_vector.enabled {
-                println!("inverted index should be loaded here!")
+                collections_map.get_or_create_sparse_index(&coll, root_path)?;
             }
         }
         Ok(collections_map)
@@ -752,22 +1310,30 @@ impl CollectionsMap {
             |root, ver: &Hash| root.join(format!("{}.index", **ver)),
             config.flush_eagerness_factor,
         ));
-        let vec_raw_manager = Arc::new(BufferManagerFactory::new(
-            collection_path.clone(),
-            |root, ver: &Hash| root.join(format!("{}.vec_raw", **ver)),
+        let vec_raw_manager =
+            vec_raw_manager(collection_path.clone(), config.flush_eagerness_factor);
+        let vec_raw_replica_managers = vec_raw_replica_managers(
+            &collection_path,
+            coll.config.replica_count(),
             config.flush_eagerness_factor,
-        ));
+        )?;
+        // a compaction interrupted by a crash is finished before the prop
+        // file is opened, the index files may point into the compacted one
+        let prop_file_path = prop_file_path(&collection_path);
+        finish_prop_compaction(&prop_file_path, &index_manager)?;
         let prop_file = Arc::new(RwLock::new(
             OpenOptions::new()
                 .create(true)
                 .read(true)
                 .append(true)
-                .open(collection_path.join("prop.data"))
+                .open(&prop_file_path)
                 .unwrap(),
         ));
-        // TODO: May be the value can be taken from config
-        let cache = Arc::new(ProbCache::new(
+        let cache = Arc::new(ProbCache::with_capacity(
             1000,
+            coll.config
+                .cache_capacity
+                .unwrap_or(DEFAULT_PROB_CACHE_CAPACITY),
             index_manager.clone(),
             prop_file.clone(),
         ));
@@ -793,6 +1359,13 @@ impl CollectionsMap {
             db,
         };
         let current_version = retrieve_current_version(&lmdb)?;
+        let branches: Vec<_> = vcs
+            .list_branches()
+            .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?
+            .iter()
+            .map(|branch| BranchId::new(branch.get_branch_name()))
+            .collect();
+        migrate_shared_embedding_offsets(&lmdb, &branches)?;
         let dense_index = DenseIndex::new(
             coll.name.clone(),
             root,
@@ -809,11 +1382,19 @@ impl CollectionsMap {
             cache,
             index_manager,
             vec_raw_manager,
+            vec_raw_replica_managers,
             // TODO: persist
             (-1.0, 1.0),
             0,
             true,
+            dense_index_data.level_seed,
         );
+        let rolled_back_to = dense_index
+            .vcs
+            .rolled_back_version(current_version)
+            .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
+        *dense_index.rolled_back_to.write().unwrap() =
+            rolled_back_to.map(|version| *version as u16);
 
         Ok(dense_index)
     }
@@ -828,6 +1409,16 @@ impl CollectionsMap {
         )
     }
 
+    /// Persists the state of a dense index already in the map, e.g. after its
+    /// quantizer was trained.
+    pub fn persist_dense_index(&self, dense_index: Arc<DenseIndex>) -> Result<(), WaCustomError> {
+        persist_dense_index(
+            &self.lmdb_env,
+            self.lmdb_dense_index_db.clone(),
+            dense_index,
+        )
+    }
+
     /// inserts a collection into the collections map
     #[allow(dead_code)]
     pub fn insert_collection(&self, collection: Arc<Collection>) -> Result<(), WaCustomError> {
@@ -872,6 +1463,60 @@ impl CollectionsMap {
         self.inner_collections.get(name).map(|index| index.clone())
     }
 
+    /// Returns the `Collection` by collection's name, falling back to
+    /// LMDB if it isn't in the in-memory DashMap
+    ///
+    /// A collection read from LMDB is added to the DashMap, along with
+    /// its DenseIndex if one has been persisted. If the collection
+    /// doesn't exist in LMDB either, None is returned
+    pub fn get_or_load_collection(
+        &self,
+        name: &str,
+        config: &Config,
+    ) -> Result<Option<Arc<Collection>>, WaCustomError> {
+        if let Some(collection) = self.get_collection(name) {
+            return Ok(Some(collection));
+        }
+
+        let Some(collection) =
+            Collection::load(&self.lmdb_env, self.lmdb_collections_db.clone(), name)?
+        else {
+            return Ok(None);
+        };
+        let collection = Arc::new(collection);
+
+        if collection.dense_vector.enabled
+            && !self.inner.contains_key(name)
+            && self.has_dense_index_data(&collection.get_key())?
+        {
+            let dense_index =
+                self.load_dense_index(&collection, &config.collections_path, config)?;
+            self.inner
+                .entry(name.to_owned())
+                .or_insert_with(|| Arc::new(dense_index));
+        }
+
+        let collection = self
+            .inner_collections
+            .entry(name.to_owned())
+            .or_insert(collection)
+            .clone();
+        Ok(Some(collection))
+    }
+
+    /// a collection's DenseIndex is only persisted once it has been created
+    fn has_dense_index_data(&self, key: &[u8; 8]) -> Result<bool, WaCustomError> {
+        let txn = self
+            .lmdb_env
+            .begin_ro_txn()
+            .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
+        match txn.get(self.lmdb_dense_index_db, key) {
+            Ok(_) => Ok(true),
+            Err(lmdb::Error::NotFound) => Ok(false),
+            Err(e) => Err(WaCustomError::DatabaseError(e.to_string())),
+        }
+    }
+
     #[allow(dead_code)]
     pub fn remove(&self, name: &str) -> Result<Option<(String, Arc<DenseIndex>)>, WaCustomError> {
         match self.inner.remove(name) {
@@ -888,6 +1533,34 @@ impl CollectionsMap {
         }
     }
 
+    /// Returns the sparse index of a collection, creating it in the
+    /// collection's directory under `collections_path` if it doesn't have
+    /// one yet
+    ///
+    /// A new index is rebuilt from the collection's sparse write log, which
+    /// then records every vector added to it.
+    pub fn get_or_create_sparse_index(
+        &self,
+        collection: &Collection,
+        collections_path: &Path,
+    ) -> Result<Arc<InvertedIndexSparseAnnNewDS>, WaCustomError> {
+        match self.inner_sparse_indexes.entry(collection.name.clone()) {
+            Entry::Occupied(entry) => Ok(entry.get().clone()),
+            Entry::Vacant(entry) => {
+                let index = Arc::new(open_sparse_index(collection, collections_path)?);
+                entry.insert(index.clone());
+                Ok(index)
+            }
+        }
+    }
+
+    /// removes the sparse index of a collection from the in-memory map
+    pub fn remove_sparse_index(&self, name: &str) -> Option<Arc<InvertedIndexSparseAnnNewDS>> {
+        self.inner_sparse_indexes
+            .remove(name)
+            .map(|(_, index)| index)
+    }
+
     /// removes a collection from the in-memory map
     ///
     /// returns the removed collection in case of success
@@ -904,7 +1577,6 @@ impl CollectionsMap {
         }
     }
 
-    #[allow(dead_code)]
     pub fn iter(
         &self,
     ) -> dashmap::iter::Iter<
@@ -941,7 +1613,11 @@ pub struct AppEnv {
 
 pub fn get_app_env(config: &Config) -> Result<Arc<AppEnv>, WaCustomError> {
     let path = Path::new("./_mdb"); // TODO: prefix the customer & database name
+    open_app_env(path, config)
+}
 
+/// Opens the database in `path` and loads the collections recorded in it.
+pub(crate) fn open_app_env(path: &Path, config: &Config) -> Result<Arc<AppEnv>, WaCustomError> {
     // Ensure the directory exists
     create_dir_all(&path).map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
     // Initialize the environment
@@ -1075,3 +1751,359 @@ impl SparseQueryVector {
         Self { vector_id, entries }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        CollectionsMap, DistanceMetric, HNSWLevel, MetricResult, SparseVector,
+        DEFAULT_CACHE_CAPACITY,
+    };
+    use crate::config_loader::Config;
+    use crate::distance::{
+        cosine::CosineSimilarity, dotproduct::DotProductDistance, euclidean::EuclideanDistance,
+        DistanceFunction,
+    };
+    use crate::models::collection::{
+        Collection, CollectionConfig, DenseVectorOptions, QuantizationOptions, SparseVectorOptions,
+    };
+    use crate::models::common::WaCustomError;
+    use crate::storage::{InvertedIndexError, Storage};
+    use lmdb::Environment;
+    use std::sync::Arc;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_hnsw_level_out_of_range_is_an_error() {
+        assert_eq!(HNSWLevel::new(0, 5).unwrap(), HNSWLevel(0));
+        assert_eq!(HNSWLevel::new(5, 5).unwrap(), HNSWLevel(5));
+        assert!(matches!(
+            HNSWLevel::new(6, 5),
+            Err(WaCustomError::InvalidLevel(6, 5))
+        ));
+        // too large for a `u8`, rejected rather than truncated
+        assert!(matches!(
+            HNSWLevel::new(257, 5),
+            Err(WaCustomError::InvalidLevel(257, 5))
+        ));
+    }
+
+    #[test]
+    fn test_collection_is_loaded_after_restart() {
+        let temp_dir = tempdir().unwrap();
+        let env = Arc::new(
+            Environment::new()
+                .set_max_dbs(10)
+                .set_map_size(10485760) // 10MB
+                .open(temp_dir.as_ref())
+                .unwrap(),
+        );
+        let config: Config = toml::from_str(include_str!("../../config.toml")).unwrap();
+        let collections_map = CollectionsMap::new(env.clone()).unwrap();
+
+        let collection = Collection {
+            name: "restarted".to_string(),
+            description: Some("persisted before the restart".to_string()),
+            dense_vector: DenseVectorOptions {
+                enabled: true,
+                auto_create_index: false,
+                dimension: 4,
+            },
+            sparse_vector: SparseVectorOptions {
+                enabled: false,
+                auto_create_index: false,
+            },
+            metadata_schema: None,
+            config: CollectionConfig {
+                max_vectors: None,
+                replication_factor: None,
+                cache_capacity: None,
+                default_k: None,
+                default_ef_search: None,
+            },
+            distance_metric: DistanceMetric::Cosine,
+            quantization: QuantizationOptions::Scalar,
+        };
+        collection
+            .persist(&env, collections_map.lmdb_collections_db)
+            .unwrap();
+        collections_map
+            .insert_collection(Arc::new(collection))
+            .unwrap();
+
+        // what a restart leaves behind: the record in lmdb, nothing in memory
+        collections_map.inner_collections.clear();
+        assert!(collections_map.get_collection("restarted").is_none());
+
+        let loaded = collections_map
+            .get_or_load_collection("restarted", &config)
+            .unwrap()
+            .unwrap();
+        assert_eq!(loaded.name, "restarted");
+        assert_eq!(
+            loaded.description.as_deref(),
+            Some("persisted before the restart")
+        );
+        // no dense index was created for it, so none is registered
+        assert!(collections_map.get("restarted").is_none());
+        // and it is now served from memory
+        assert!(collections_map.get_collection("restarted").is_some());
+
+        assert!(collections_map
+            .get_or_load_collection("missing", &config)
+            .unwrap()
+            .is_none());
+    }
+
+    fn ranking(results: &[MetricResult]) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..results.len()).collect();
+        order.sort_by(|&a, &b| results[b].score().partial_cmp(&results[a].score()).unwrap());
+        order
+    }
+
+    #[test]
+    fn test_metric_scores_rank_consistently() {
+        let query = [1.0f32, 0.0];
+        // from most to least similar to the query
+        let candidates = [[0.9f32, 0.1], [0.5, 0.5], [0.0, 1.0], [-0.7, 0.3]];
+
+        let cosine: Vec<_> = candidates
+            .iter()
+            .map(|c| {
+                let dot = query[0] * c[0] + query[1] * c[1];
+                let norm = (c[0] * c[0] + c[1] * c[1]).sqrt();
+                MetricResult::CosineSimilarity(CosineSimilarity(dot / norm))
+            })
+            .collect();
+        let dot_product: Vec<_> = candidates
+            .iter()
+            .map(|c| {
+                MetricResult::DotProductDistance(DotProductDistance(
+                    query[0] * c[0] + query[1] * c[1],
+                ))
+            })
+            .collect();
+        let euclidean: Vec<_> = candidates
+            .iter()
+            .map(|c| {
+                let d = ((query[0] - c[0]).powi(2) + (query[1] - c[1]).powi(2)).sqrt();
+                MetricResult::EuclideanDistance(EuclideanDistance(d))
+            })
+            .collect();
+
+        let expected = vec![0, 1, 2, 3];
+        assert_eq!(ranking(&cosine), expected);
+        assert_eq!(ranking(&dot_product), expected);
+        assert_eq!(ranking(&euclidean), expected);
+
+        for result in cosine.iter().chain(&dot_product).chain(&euclidean) {
+            assert!((0.0..=1.0).contains(&result.score()));
+        }
+
+        // large dot products stay ordered instead of saturating
+        let large = [
+            MetricResult::DotProductDistance(DotProductDistance(5000.0)),
+            MetricResult::DotProductDistance(DotProductDistance(50000.0)),
+        ];
+        assert_eq!(ranking(&large), vec![1, 0]);
+    }
+    #[test]
+    fn test_sparse_index_uses_collection_cache_capacity() {
+        let temp_dir = tempdir().unwrap();
+        let env = Arc::new(
+            Environment::new()
+                .set_max_dbs(10)
+                .set_map_size(10485760) // 10MB
+                .open(temp_dir.as_ref())
+                .unwrap(),
+        );
+        let collections_map = CollectionsMap::new(env).unwrap();
+        let collection = |name: &str, cache_capacity| Collection {
+            name: name.to_string(),
+            description: None,
+            dense_vector: DenseVectorOptions {
+                enabled: false,
+                auto_create_index: false,
+                dimension: 4,
+            },
+            sparse_vector: SparseVectorOptions {
+                enabled: true,
+                auto_create_index: false,
+            },
+            metadata_schema: None,
+            config: CollectionConfig {
+                max_vectors: None,
+                replication_factor: None,
+                cache_capacity,
+                default_k: None,
+                default_ef_search: None,
+            },
+            distance_metric: DistanceMetric::DotProduct,
+            quantization: QuantizationOptions::Scalar,
+        };
+
+        let sized = collections_map
+            .get_or_create_sparse_index(&collection("sized", Some(64)), temp_dir.as_ref())
+            .unwrap();
+        assert_eq!(sized.cache.capacity(), 64);
+
+        let defaulted = collections_map
+            .get_or_create_sparse_index(&collection("default", None), temp_dir.as_ref())
+            .unwrap();
+        assert_eq!(defaulted.cache.capacity(), DEFAULT_CACHE_CAPACITY);
+    }
+
+    #[test]
+    fn test_sparse_vectors_survive_a_restart() {
+        let temp_dir = tempdir().unwrap();
+        let env = Arc::new(
+            Environment::new()
+                .set_max_dbs(10)
+                .set_map_size(10485760) // 10MB
+                .open(temp_dir.as_ref())
+                .unwrap(),
+        );
+        let mut config: Config = toml::from_str(include_str!("../../config.toml")).unwrap();
+        config.collections_path = temp_dir.as_ref().join("collections");
+        let collection = Collection {
+            name: "sparse".to_string(),
+            description: None,
+            dense_vector: DenseVectorOptions {
+                enabled: false,
+                auto_create_index: false,
+                dimension: 4,
+            },
+            sparse_vector: SparseVectorOptions {
+                enabled: true,
+                auto_create_index: false,
+            },
+            metadata_schema: None,
+            config: CollectionConfig {
+                max_vectors: None,
+                replication_factor: None,
+                cache_capacity: None,
+                default_k: None,
+                default_ef_search: None,
+            },
+            distance_metric: DistanceMetric::DotProduct,
+            quantization: QuantizationOptions::Scalar,
+        };
+
+        let collections_map = CollectionsMap::new(env.clone()).unwrap();
+        collection
+            .persist(&env, collections_map.lmdb_collections_db)
+            .unwrap();
+        let index = collections_map
+            .get_or_create_sparse_index(&collection, &config.collections_path)
+            .unwrap();
+        index
+            .add_sparse_vector(SparseVector::new(1, vec![(3, 1.0), (70, 0.5)]))
+            .unwrap();
+        index
+            .add_sparse_vector(SparseVector::new(2, vec![(3, 0.25)]))
+            .unwrap();
+        drop(index);
+        drop(collections_map);
+
+        let collections_map = CollectionsMap::load(env, &config).unwrap();
+        let index = collections_map
+            .get_or_create_sparse_index(&collection, &config.collections_path)
+            .unwrap();
+        assert_eq!(index.iter_vector_ids().collect::<Vec<_>>(), vec![1, 2]);
+        let dims: Vec<_> = index
+            .reconstruct(1)
+            .unwrap()
+            .entries
+            .iter()
+            .map(|&(dim_index, _)| dim_index)
+            .collect();
+        assert_eq!(dims, vec![3, 70]);
+        assert_eq!(
+            index.add_sparse_vector(SparseVector::new(2, vec![(5, 1.0)])),
+            Err(InvertedIndexError::DuplicateVector(2))
+        );
+    }
+
+    #[test]
+    fn test_collections_keep_their_distance_metric() {
+        let temp_dir = tempdir().unwrap();
+        let env = Arc::new(
+            Environment::new()
+                .set_max_dbs(10)
+                .set_map_size(10485760) // 10MB
+                .open(temp_dir.as_ref())
+                .unwrap(),
+        );
+        let config: Config = toml::from_str(include_str!("../../config.toml")).unwrap();
+        let collections_map = CollectionsMap::new(env.clone()).unwrap();
+
+        let x = Storage::UnsignedByte {
+            mag: 25,
+            quant_vec: vec![3, 4],
+        };
+        let y = Storage::UnsignedByte {
+            mag: 25,
+            quant_vec: vec![4, 3],
+        };
+
+        for name in ["cosine", "euclidean", "dot"] {
+            let metric: DistanceMetric = name.parse().unwrap();
+            let collection = Collection {
+                name: name.to_string(),
+                description: None,
+                dense_vector: DenseVectorOptions {
+                    enabled: true,
+                    auto_create_index: false,
+                    dimension: 2,
+                },
+                sparse_vector: SparseVectorOptions {
+                    enabled: false,
+                    auto_create_index: false,
+                },
+                metadata_schema: None,
+                config: CollectionConfig {
+                    max_vectors: None,
+                    replication_factor: None,
+                    cache_capacity: None,
+                    default_k: None,
+                    default_ef_search: None,
+                },
+                distance_metric: metric.clone(),
+                quantization: QuantizationOptions::Scalar,
+            };
+            collection
+                .persist(&env, collections_map.lmdb_collections_db)
+                .unwrap();
+        }
+
+        for (name, expected) in [
+            (
+                "cosine",
+                MetricResult::CosineSimilarity(CosineSimilarity(24.0 / 25.0)),
+            ),
+            (
+                "euclidean",
+                MetricResult::EuclideanDistance(EuclideanDistance(2.0f32.sqrt())),
+            ),
+            (
+                "dot",
+                MetricResult::DotProductDistance(DotProductDistance(24.0)),
+            ),
+        ] {
+            let loaded = collections_map
+                .get_or_load_collection(name, &config)
+                .unwrap()
+                .unwrap();
+            assert_eq!(loaded.distance_metric, name.parse().unwrap());
+
+            // queries against the collection are scored with its metric
+            let result = loaded.distance_metric.calculate(&x, &y).unwrap();
+            assert_eq!(
+                std::mem::discriminant(&result),
+                std::mem::discriminant(&expected)
+            );
+            assert!((result.get_value() - expected.get_value()).abs() < 1e-4);
+        }
+
+        assert!("manhattan".parse::<DistanceMetric>().is_err());
+    }
+}
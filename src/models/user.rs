@@ -28,11 +28,20 @@ pub struct User {
     pub u_session_key_expiry: String,
 }
 
+/// Health statistics of a collection's dense index. The root vector is not
+/// counted.
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub struct Statistics {
-    pub mean: f64,
-    pub std_dev: f64,
-    pub min_val: i32,
-    pub max_val: i32,
-    pub count: i32,
+    /// number of indexed vectors
+    pub count: u64,
+    /// number of nodes on each HNSW level, starting at level 0
+    pub level_node_counts: Vec<u64>,
+    /// average number of neighbors of a level 0 node
+    pub avg_neighbor_degree: f64,
+    /// level 0 nodes that aren't connected to any other node
+    pub nodes_without_neighbors: u64,
+    /// mean of the quantized values of all indexed vectors
+    pub quantized_mean: f64,
+    /// variance of the quantized values of all indexed vectors
+    pub quantized_variance: f64,
 }
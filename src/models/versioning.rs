@@ -1,7 +1,9 @@
 use crate::macros::key;
-use lmdb::{Database, Environment, RoTransaction, Transaction, WriteFlags};
+use crate::models::common::WaCustomError;
+use lmdb::{Cursor, Database, Environment, RoTransaction, Transaction, WriteFlags};
 use serde::{Deserialize, Serialize};
 use siphasher::sip::SipHasher24;
+use std::collections::HashSet;
 use std::hash::Hasher;
 use std::ops::Deref;
 use std::sync::Arc;
@@ -203,6 +205,18 @@ impl BranchInfo {
     pub fn get_current_version(&self) -> Version {
         self.current_version
     }
+
+    pub fn get_branch_name(&self) -> &str {
+        &self.branch_name
+    }
+
+    pub fn get_parent_branch(&self) -> BranchId {
+        self.parent_branch
+    }
+
+    pub fn get_parent_version(&self) -> Version {
+        self.parent_version
+    }
 }
 
 pub struct VersionControl {
@@ -262,7 +276,7 @@ impl VersionControl {
         let mut txn = self.env.begin_rw_txn()?;
         let bytes = txn.get(*self.db, &branch_key)?;
 
-        let mut branch_info: BranchInfo = BranchInfo::deserialize(bytes).unwrap();
+        let mut branch_info = BranchInfo::deserialize(bytes).map_err(|_| lmdb::Error::Corrupted)?;
         let new_version = Version(*branch_info.current_version + 1);
         branch_info.current_version = new_version;
         let bytes = branch_info.serialize();
@@ -297,7 +311,8 @@ impl VersionControl {
         }
 
         let parent_bytes = txn.get(*self.db, &parent_key)?;
-        let parent_info = BranchInfo::deserialize(parent_bytes).unwrap();
+        let parent_info =
+            BranchInfo::deserialize(parent_bytes).map_err(|_| lmdb::Error::Corrupted)?;
 
         let new_branch_info = BranchInfo {
             branch_name: branch_name.to_string(),
@@ -314,6 +329,153 @@ impl VersionControl {
         Ok(())
     }
 
+    /// Creates the branch `branch_name` off `from_version`, returning the hash
+    /// of the new branch's first version.
+    pub fn create_branch(
+        &self,
+        branch_name: &str,
+        from_version: Hash,
+    ) -> Result<Hash, WaCustomError> {
+        let branch_id = BranchId::new(branch_name);
+        let branch_key = key!(b:branch_id);
+
+        let mut txn = self
+            .env
+            .begin_rw_txn()
+            .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
+
+        match txn.get(*self.db, &branch_key) {
+            Ok(_) => {
+                return Err(WaCustomError::KeyCollision(format!(
+                    "branch `{}` already exists",
+                    branch_name
+                )))
+            }
+            Err(lmdb::Error::NotFound) => {}
+            Err(e) => return Err(WaCustomError::DatabaseError(e.to_string())),
+        }
+
+        let parent = match txn.get(*self.db, &key!(v:from_version)) {
+            Ok(bytes) => VersionHash::deserialize(bytes)
+                .map_err(|e| WaCustomError::DeserializationError(e.to_string()))?,
+            Err(lmdb::Error::NotFound) => {
+                return Err(WaCustomError::InvalidVersion(format!(
+                    "version {} doesn't exist",
+                    *from_version
+                )))
+            }
+            Err(e) => return Err(WaCustomError::DatabaseError(e.to_string())),
+        };
+
+        let branch_info = BranchInfo {
+            branch_name: branch_name.to_string(),
+            current_version: Version(0),
+            parent_branch: parent.branch,
+            parent_version: parent.version,
+        };
+        let version_hash = VersionHash::new(branch_id, Version(0));
+        let hash = version_hash.calculate_hash();
+
+        txn.put(
+            *self.db,
+            &branch_key,
+            &branch_info.serialize(),
+            WriteFlags::empty(),
+        )
+        .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
+        txn.put(
+            *self.db,
+            &key!(v:hash),
+            &version_hash.serialize(),
+            WriteFlags::empty(),
+        )
+        .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
+        txn.commit()
+            .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
+
+        Ok(hash)
+    }
+
+    pub fn list_branches(&self) -> lmdb::Result<Vec<BranchInfo>> {
+        let txn = self.env.begin_ro_txn()?;
+        let mut cursor = txn.open_ro_cursor(*self.db)?;
+
+        let mut branches = Vec::new();
+        for (key, value) in cursor.iter_from(key!(b:BranchId(0))) {
+            // branch keys are a `2` prefix followed by the 8 byte branch id
+            if key.len() != 9 || key[0] != 2 {
+                break;
+            }
+            branches.push(BranchInfo::deserialize(value).map_err(|_| lmdb::Error::Corrupted)?);
+        }
+
+        Ok(branches)
+    }
+
+    /// Returns the hash of the latest version of `branch_name`.
+    pub fn get_branch_head(&self, branch_name: &str) -> lmdb::Result<Option<Hash>> {
+        let branch_id = BranchId::new(branch_name);
+        let Some(branch_info) = self.get_branch_info_by_id(branch_id)? else {
+            return Ok(None);
+        };
+
+        Ok(self
+            .list_versions()?
+            .into_iter()
+            .find(|(_, version_hash)| {
+                version_hash.branch == branch_id
+                    && *version_hash.version == *branch_info.current_version
+            })
+            .map(|(hash, _)| hash))
+    }
+
+    /// Returns the info of the branch `version` belongs to.
+    pub fn get_version_branch(&self, version: Hash) -> lmdb::Result<Option<BranchInfo>> {
+        let txn = self.env.begin_ro_txn()?;
+        let version_hash = self.get_version_hash(&version, &txn)?;
+        txn.abort();
+
+        match version_hash {
+            Some(version_hash) => self.get_branch_info_by_id(version_hash.branch),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns `version` along with all the versions it was derived from, on
+    /// its own branch and on the branches it was created off.
+    pub fn get_version_ancestry(&self, version: Hash) -> lmdb::Result<HashSet<Hash>> {
+        let versions = self.list_versions()?;
+        let mut ancestry = HashSet::new();
+
+        let Some((_, version_hash)) = versions.iter().find(|(hash, _)| *hash == version) else {
+            return Ok(ancestry);
+        };
+        let mut branch = version_hash.branch;
+        let mut max_version = version_hash.version;
+
+        loop {
+            ancestry.extend(
+                versions
+                    .iter()
+                    .filter(|(_, version_hash)| {
+                        version_hash.branch == branch && *version_hash.version <= *max_version
+                    })
+                    .map(|(hash, _)| *hash),
+            );
+
+            match self.get_branch_info_by_id(branch)? {
+                // `main` is its own parent
+                Some(info) if info.parent_branch != branch => {
+                    branch = info.parent_branch;
+                    max_version = info.parent_version;
+                }
+                _ => break,
+            }
+        }
+
+        Ok(ancestry)
+    }
+
     pub fn branch_exists(&self, branch_name: &str) -> lmdb::Result<bool> {
         let branch_id = BranchId::new(branch_name);
         let branch_key = key!(b:branch_id);
@@ -335,7 +497,10 @@ impl VersionControl {
     }
 
     pub fn get_branch_info(&self, branch_name: &str) -> lmdb::Result<Option<BranchInfo>> {
-        let branch_id = BranchId::new(branch_name);
+        self.get_branch_info_by_id(BranchId::new(branch_name))
+    }
+
+    fn get_branch_info_by_id(&self, branch_id: BranchId) -> lmdb::Result<Option<BranchInfo>> {
         let branch_key = key!(b:branch_id);
 
         let txn = self.env.begin_ro_txn()?;
@@ -348,7 +513,7 @@ impl VersionControl {
             Err(err) => return Err(err),
         };
 
-        let branch_info = BranchInfo::deserialize(bytes).unwrap();
+        let branch_info = BranchInfo::deserialize(bytes).map_err(|_| lmdb::Error::Corrupted)?;
 
         txn.abort();
 
@@ -370,11 +535,96 @@ impl VersionControl {
             Err(err) => return Err(err),
         };
 
-        let version_hash = VersionHash::deserialize(bytes).unwrap();
+        let version_hash = VersionHash::deserialize(bytes).map_err(|_| lmdb::Error::Corrupted)?;
 
         Ok(Some(version_hash))
     }
 
+    /// Lists all versions with their hashes, ordered by version number.
+    pub fn list_versions(&self) -> lmdb::Result<Vec<(Hash, VersionHash)>> {
+        let txn = self.env.begin_ro_txn()?;
+        let mut cursor = txn.open_ro_cursor(*self.db)?;
+
+        let mut versions = Vec::new();
+        for (key, value) in cursor.iter_from(key!(v:Hash(0))) {
+            // version keys are a `0` prefix followed by the 4 byte hash
+            if key.len() != 5 || key[0] != 0 {
+                break;
+            }
+            let hash = Hash(u32::from_le_bytes(key[1..].try_into().unwrap()));
+            let version_hash =
+                VersionHash::deserialize(value).map_err(|_| lmdb::Error::Corrupted)?;
+            versions.push((hash, version_hash));
+        }
+
+        versions.sort_by_key(|(_, version_hash)| (*version_hash.version, *version_hash.timestamp));
+        Ok(versions)
+    }
+
+    /// Points `current_version` back to `target`, an earlier version of the
+    /// same branch as `current`.
+    pub fn rollback_to(&self, current: Hash, target: Hash) -> Result<VersionHash, WaCustomError> {
+        let mut txn = self
+            .env
+            .begin_rw_txn()
+            .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
+
+        let get_version_hash = |hash: Hash| match txn.get(*self.db, &key!(v:hash)) {
+            Ok(bytes) => VersionHash::deserialize(bytes)
+                .map_err(|e| WaCustomError::DeserializationError(e.to_string())),
+            Err(lmdb::Error::NotFound) => Err(WaCustomError::InvalidVersion(format!(
+                "version {} doesn't exist",
+                *hash
+            ))),
+            Err(e) => Err(WaCustomError::DatabaseError(e.to_string())),
+        };
+        let current_version = get_version_hash(current)?;
+        let target_version = get_version_hash(target)?;
+
+        if target_version.branch != current_version.branch {
+            return Err(WaCustomError::InvalidVersion(format!(
+                "version {} belongs to another branch",
+                *target
+            )));
+        }
+        if *target_version.version > *current_version.version {
+            return Err(WaCustomError::InvalidVersion(format!(
+                "version {} is newer than the current version",
+                *target
+            )));
+        }
+
+        txn.put(
+            *self.db,
+            &"current_version",
+            &target.to_le_bytes(),
+            WriteFlags::empty(),
+        )
+        .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
+        txn.commit()
+            .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
+
+        Ok(target_version)
+    }
+
+    /// Returns the version number of `current` if newer versions were added
+    /// to its branch after it, i.e. if the branch was rolled back to it.
+    pub fn rolled_back_version(&self, current: Hash) -> lmdb::Result<Option<Version>> {
+        let txn = self.env.begin_ro_txn()?;
+        let Some(version_hash) = self.get_version_hash(&current, &txn)? else {
+            return Ok(None);
+        };
+        txn.abort();
+
+        let latest = self
+            .get_branch_info_by_id(version_hash.branch)?
+            .map(|info| info.get_current_version());
+        Ok(match latest {
+            Some(latest) if *latest > *version_hash.version => Some(version_hash.version),
+            _ => None,
+        })
+    }
+
     pub fn trace_to_main(&self, start_branch: &str) -> lmdb::Result<Vec<BranchInfo>> {
         let mut branch_path = Vec::new();
         let branch_id = BranchId::new(start_branch);
@@ -393,7 +643,7 @@ impl VersionControl {
                 }
             };
 
-            let branch_info = BranchInfo::deserialize(bytes).unwrap();
+            let branch_info = BranchInfo::deserialize(bytes).map_err(|_| lmdb::Error::Corrupted)?;
 
             Ok(Some(branch_info))
         };
@@ -1,5 +1,7 @@
 pub mod product;
+pub mod reservoir;
 pub mod scalar;
+pub mod sparse;
 
 use serde::{Deserialize, Serialize};
 
@@ -14,7 +16,11 @@ pub trait Quantization: std::fmt::Debug + Send + Sync {
         range: (f32, f32),
     ) -> Result<Storage, QuantizationError>;
 
-    fn train(&mut self, vectors: &[&[f32]]) -> Result<(), QuantizationError>;
+    fn train(
+        &mut self,
+        vectors: &[&[f32]],
+        storage_type: StorageType,
+    ) -> Result<(), QuantizationError>;
 
 }
 
@@ -36,3 +42,13 @@ pub enum QuantizationError {
     Untrained,
 
 }
+
+impl std::fmt::Display for QuantizationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidInput(msg) => write!(f, "Invalid input: {}", msg),
+            Self::TrainingFailed => write!(f, "Training failed"),
+            Self::Untrained => write!(f, "Quantizer is not trained"),
+        }
+    }
+}
@@ -1,41 +1,513 @@
 use serde::{Deserialize, Serialize};
 
-use super::{Quantization, QuantizationError, StorageType};
+use super::{scalar::ScalarQuantization, Quantization, QuantizationError, StorageType};
+use crate::distance::DistanceError;
 use crate::storage::Storage;
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct ProductQuantization {
-
     pub centroids: Option<Centroid>,
-
+    /// number of subvectors each vector is split into, each encoded by the
+    /// index of its nearest centroid
+    #[serde(default)]
+    pub subspace_count: u16,
+    /// codebooks replaced by retraining, oldest first. Codes record the
+    /// codebook they point into as `mag`, its index here or the number of
+    /// retired codebooks for the current one
+    #[serde(default)]
+    pub retired_codebooks: Vec<Vec<f32>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Centroid {
-
     pub number_of_centroids: u16,
-    pub centroids: Vec<u16>,
+    /// `number_of_centroids` centroids per subspace, subspace after subspace,
+    /// empty until trained
+    pub centroids: Vec<f32>,
+}
+
+/// Lloyd iterations run per subspace, unless the assignments settle earlier.
+const MAX_KMEANS_ITERATIONS: usize = 25;
+
+/// How a training run went.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TrainingStats {
+    /// k-means iterations run, the most any subspace needed
+    pub iterations: usize,
+    /// mean squared distance between the training vectors and their
+    /// reconstruction from the centroids
+    pub distortion: f32,
+}
+
+impl ProductQuantization {
+    /// An untrained quantizer splitting vectors into `subspace_count`
+    /// subvectors with `number_of_centroids` centroids each.
+    pub fn new(subspace_count: u16, number_of_centroids: u16) -> Self {
+        Self {
+            centroids: Some(Centroid {
+                number_of_centroids,
+                centroids: Vec::new(),
+            }),
+            subspace_count,
+            retired_codebooks: Vec::new(),
+        }
+    }
+
+    /// Whether the centroids have been learnt from a training sample.
+    pub fn is_trained(&self) -> bool {
+        self.centroids
+            .as_ref()
+            .is_some_and(|centroids| !centroids.centroids.is_empty())
+    }
+
+    /// Checks that every centroid can be addressed by a code of the width
+    /// implied by `storage_type`, e.g. at most 256 centroids for byte codes.
+    pub fn validate_centroids(&self, storage_type: StorageType) -> Result<(), QuantizationError> {
+        let Some(centroids) = &self.centroids else {
+            return Ok(());
+        };
+        let code_bits = match storage_type {
+            StorageType::UnsignedByte => 8,
+            StorageType::SubByte(resolution) => resolution as u32,
+            StorageType::HalfPrecisionFP => 16,
+        };
+        let max_centroids = 1u32 << code_bits.min(16);
+        if centroids.number_of_centroids as u32 > max_centroids {
+            return Err(QuantizationError::InvalidInput(format!(
+                "{} centroids can't be indexed by {}-bit codes (at most {})",
+                centroids.number_of_centroids, code_bits, max_centroids
+            )));
+        }
+        Ok(())
+    }
+
+    /// Learns the centroids of each subspace from `vectors` with k-means. The
+    /// centroids learnt before are retired, vectors encoded with them are
+    /// still decoded with them.
+    pub fn train_with_stats(
+        &mut self,
+        vectors: &[&[f32]],
+        storage_type: StorageType,
+    ) -> Result<TrainingStats, QuantizationError> {
+        self.validate_centroids(storage_type)?;
+        let subspace_count = self.subspace_count as usize;
+        let Some(centroids) = &mut self.centroids else {
+            return Err(QuantizationError::InvalidInput(
+                "the number of centroids isn't set".to_string(),
+            ));
+        };
+        let k = centroids.number_of_centroids as usize;
+        let Some(dim) = vectors.first().map(|vector| vector.len()) else {
+            return Err(QuantizationError::TrainingFailed);
+        };
+        if k == 0 || vectors.len() < k {
+            return Err(QuantizationError::InvalidInput(format!(
+                "{} vectors can't train {} centroids",
+                vectors.len(),
+                k
+            )));
+        }
+        if subspace_count == 0
+            || dim % subspace_count != 0
+            || vectors.iter().any(|vector| vector.len() != dim)
+        {
+            return Err(QuantizationError::InvalidInput(format!(
+                "vectors of dimension {} can't be split into {} subspaces",
+                dim, subspace_count
+            )));
+        }
 
+        let d = dim / subspace_count;
+        let mut codebook = Vec::with_capacity(subspace_count * k * d);
+        let mut iterations = 0;
+        for subspace in 0..subspace_count {
+            let subvectors: Vec<&[f32]> = vectors
+                .iter()
+                .map(|vector| &vector[subspace * d..(subspace + 1) * d])
+                .collect();
+            let (subspace_centroids, subspace_iterations) = kmeans(&subvectors, k);
+            codebook.extend(subspace_centroids);
+            iterations = iterations.max(subspace_iterations);
+        }
+        let retired = std::mem::replace(&mut centroids.centroids, codebook);
+        if !retired.is_empty() {
+            self.retired_codebooks.push(retired);
+        }
+
+        let distortion = vectors
+            .iter()
+            .map(|vector| squared_distance(vector, &self.reconstruct(vector)))
+            .sum::<f32>()
+            / vectors.len() as f32;
+        Ok(TrainingStats {
+            iterations,
+            distortion,
+        })
+    }
+
+    /// Index of the centroid of `subspace` nearest to `subvector`.
+    fn nearest_centroid(&self, subspace: usize, subvector: &[f32]) -> usize {
+        let centroids = self.centroids.as_ref().unwrap();
+        let k = centroids.number_of_centroids as usize;
+        let d = subvector.len();
+        let codebook = &centroids.centroids[subspace * k * d..(subspace + 1) * k * d];
+        codebook
+            .chunks_exact(d)
+            .map(|centroid| squared_distance(centroid, subvector))
+            .enumerate()
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(i, _)| i)
+            .unwrap()
+    }
+
+    /// The code of each subvector of `vector`, the index of its nearest
+    /// centroid.
+    fn encode(&self, vector: &[f32]) -> Vec<u8> {
+        let d = vector.len() / self.subspace_count as usize;
+        vector
+            .chunks_exact(d)
+            .enumerate()
+            .map(|(subspace, subvector)| self.nearest_centroid(subspace, subvector) as u8)
+            .collect()
+    }
+
+    /// The codebook vectors encoded under `generation` point into, if it's
+    /// still known.
+    fn codebook(&self, generation: usize) -> Option<&[f32]> {
+        if generation == self.retired_codebooks.len() {
+            self.centroids
+                .as_ref()
+                .map(|centroids| &centroids.centroids[..])
+        } else {
+            self.retired_codebooks
+                .get(generation)
+                .map(|codebook| &codebook[..])
+        }
+    }
+
+    /// The vector made of the centroids of `codebook` that `codes` point to.
+    fn decode(&self, codebook: &[f32], codes: &[u8]) -> Vec<f32> {
+        let k = self.centroids.as_ref().unwrap().number_of_centroids as usize;
+        let d = codebook.len() / (k * self.subspace_count as usize);
+        codes
+            .iter()
+            .enumerate()
+            .flat_map(|(subspace, &code)| {
+                let start = (subspace * k + code as usize) * d;
+                codebook[start..start + d].iter().copied()
+            })
+            .collect()
+    }
+
+    /// Replaces each subvector of `vector` by its nearest centroid.
+    fn reconstruct(&self, vector: &[f32]) -> Vec<f32> {
+        let codebook = &self.centroids.as_ref().unwrap().centroids;
+        self.decode(codebook, &self.encode(vector))
+    }
+
+    /// The values `storage` stands for, looking codes up in the codebook
+    /// they were made with. Vectors quantized before training are stored at
+    /// half precision.
+    pub fn dequantize(&self, storage: &Storage) -> Result<Vec<f32>, DistanceError> {
+        match storage {
+            Storage::UnsignedByte { mag, quant_vec }
+                if self.is_trained() && quant_vec.len() == self.subspace_count as usize =>
+            {
+                let codebook = self
+                    .codebook(*mag as usize)
+                    .ok_or(DistanceError::StorageMismatch)?;
+                Ok(self.decode(codebook, quant_vec))
+            }
+            Storage::HalfPrecisionFP { quant_vec, .. } => {
+                Ok(quant_vec.iter().map(|v| v.to_f32()).collect())
+            }
+            _ => Err(DistanceError::StorageMismatch),
+        }
+    }
+}
+
+fn squared_distance(x: &[f32], y: &[f32]) -> f32 {
+    x.iter().zip(y).map(|(a, b)| (a - b) * (a - b)).sum()
+}
+
+/// Runs k-means over `subvectors` and returns the `k` centroids one after
+/// another, along with the number of iterations run. Centroids start out
+/// spread over the sample, each next one being the subvector farthest from
+/// those picked so far.
+fn kmeans(subvectors: &[&[f32]], k: usize) -> (Vec<f32>, usize) {
+    let d = subvectors[0].len();
+    let mut centroids: Vec<f32> = subvectors[0].to_vec();
+    let mut nearest_distances: Vec<f32> = subvectors
+        .iter()
+        .map(|subvector| squared_distance(subvectors[0], subvector))
+        .collect();
+    for _ in 1..k {
+        let (farthest, _) = nearest_distances
+            .iter()
+            .enumerate()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .unwrap();
+        let centroid = subvectors[farthest];
+        centroids.extend_from_slice(centroid);
+        for (distance, subvector) in nearest_distances.iter_mut().zip(subvectors) {
+            *distance = distance.min(squared_distance(centroid, subvector));
+        }
+    }
+
+    let mut assignments = vec![usize::MAX; subvectors.len()];
+
+    let mut iterations = 0;
+    while iterations < MAX_KMEANS_ITERATIONS {
+        iterations += 1;
+        let mut changed = false;
+        for (subvector, assignment) in subvectors.iter().zip(assignments.iter_mut()) {
+            let nearest = centroids
+                .chunks_exact(d)
+                .map(|centroid| squared_distance(centroid, subvector))
+                .enumerate()
+                .min_by(|(_, a), (_, b)| a.total_cmp(b))
+                .map(|(i, _)| i)
+                .unwrap();
+            if *assignment != nearest {
+                *assignment = nearest;
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+
+        let mut sums = vec![0.0; k * d];
+        let mut counts = vec![0usize; k];
+        for (subvector, &assignment) in subvectors.iter().zip(&assignments) {
+            counts[assignment] += 1;
+            for (sum, value) in sums[assignment * d..(assignment + 1) * d]
+                .iter_mut()
+                .zip(subvector.iter())
+            {
+                *sum += value;
+            }
+        }
+        // centroids left without subvectors keep their position
+        for (i, &count) in counts.iter().enumerate() {
+            if count > 0 {
+                for j in 0..d {
+                    centroids[i * d + j] = sums[i * d + j] / count as f32;
+                }
+            }
+        }
+    }
+
+    (centroids, iterations)
 }
 
 #[allow(unused_variables)]
 impl Quantization for ProductQuantization {
-
     fn quantize(
         &self,
         vector: &[f32],
         storage_type: StorageType,
         range: (f32, f32),
-
     ) -> Result<Storage, QuantizationError> {
+        // until the centroids are trained vectors are kept at half precision
+        if !self.is_trained() {
+            return ScalarQuantization.quantize(vector, StorageType::HalfPrecisionFP, range);
+        }
+        if vector.len() % self.subspace_count as usize != 0 {
+            return Err(QuantizationError::InvalidInput(format!(
+                "a vector of dimension {} can't be split into {} subspaces",
+                vector.len(),
+                self.subspace_count
+            )));
+        }
+        // one byte code per subspace, compared through the codebook with
+        // `dequantize`
+        self.validate_centroids(StorageType::UnsignedByte)?;
+        Ok(Storage::UnsignedByte {
+            mag: self.retired_codebooks.len() as u32,
+            quant_vec: self.encode(vector),
+        })
+    }
 
-        unimplemented!("Product quantization is not implemented yet");
+    fn train(
+        &mut self,
+        vectors: &[&[f32]],
+        storage_type: StorageType,
+    ) -> Result<(), QuantizationError> {
+        self.train_with_stats(vectors, storage_type).map(|_| ())
+    }
+}
 
+#[cfg(test)]
+mod tests {
+    use super::{Centroid, ProductQuantization};
+    use crate::quantization::{Quantization, QuantizationError, StorageType};
+    use crate::storage::Storage;
+
+    fn with_centroids(number_of_centroids: u16) -> ProductQuantization {
+        ProductQuantization {
+            centroids: Some(Centroid {
+                number_of_centroids,
+                centroids: Vec::new(),
+            }),
+            subspace_count: 4,
+            retired_codebooks: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_train_rejects_centroids_wider_than_codes() {
+        let mut product = with_centroids(1000);
+        let vectors: [&[f32]; 2] = [&[0.1, 0.2], &[0.3, 0.4]];
+        assert!(matches!(
+            product.train(&vectors, StorageType::UnsignedByte),
+            Err(QuantizationError::InvalidInput(_))
+        ));
+        assert!(matches!(
+            product.validate_centroids(StorageType::SubByte(4)),
+            Err(QuantizationError::InvalidInput(_))
+        ));
+        assert!(product
+            .validate_centroids(StorageType::HalfPrecisionFP)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_byte_codes_accept_256_centroids() {
+        assert!(with_centroids(256)
+            .validate_centroids(StorageType::UnsignedByte)
+            .is_ok());
+        assert!(with_centroids(257)
+            .validate_centroids(StorageType::UnsignedByte)
+            .is_err());
+        assert!(ProductQuantization::default()
+            .validate_centroids(StorageType::UnsignedByte)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_untrained_quantizer_keeps_vectors_at_half_precision() {
+        let product = ProductQuantization::new(2, 256);
+        assert!(!product.is_trained());
+        let storage = product
+            .quantize(
+                &[0.5, -0.25, 0.125, 1.0],
+                StorageType::UnsignedByte,
+                (-1.0, 1.0),
+            )
+            .unwrap();
+        let Storage::HalfPrecisionFP { quant_vec, .. } = storage else {
+            panic!("expected half precision storage, got {:?}", storage);
+        };
+        let values: Vec<f32> = quant_vec.iter().map(|v| v.to_f32()).collect();
+        assert_eq!(values, vec![0.5, -0.25, 0.125, 1.0]);
     }
 
-    fn train(&mut self, vectors: &[&[f32]]) -> Result<(), QuantizationError> {
+    #[test]
+    fn test_trained_quantizer_maps_vectors_to_nearest_centroids() {
+        // two clusters in each half of the vector
+        let vectors: Vec<Vec<f32>> = (0..20)
+            .map(|i| {
+                let jitter = (i % 5) as f32 * 0.01;
+                if i % 2 == 0 {
+                    vec![0.9 + jitter, 0.9, -0.5, -0.5 + jitter]
+                } else {
+                    vec![-0.9, -0.9 + jitter, 0.5 + jitter, 0.5]
+                }
+            })
+            .collect();
+        let vectors: Vec<&[f32]> = vectors.iter().map(|v| &v[..]).collect();
+
+        let mut product = ProductQuantization::new(2, 2);
+        let stats = product
+            .train_with_stats(&vectors, StorageType::UnsignedByte)
+            .unwrap();
+        assert!(product.is_trained());
+        assert!(stats.iterations >= 1);
+        // only the jitter within each cluster is lost
+        assert!(stats.distortion < 0.001);
+        assert_eq!(
+            product.centroids.as_ref().unwrap().centroids.len(),
+            2 * 2 * 2
+        );
+
+        let storage = product
+            .quantize(
+                &[0.92, 0.88, -0.51, -0.49],
+                StorageType::UnsignedByte,
+                (-1.0, 1.0),
+            )
+            .unwrap();
+        // one code per subspace
+        let Storage::UnsignedByte { quant_vec, .. } = &storage else {
+            panic!("expected byte codes, got {:?}", storage);
+        };
+        assert_eq!(quant_vec.len(), 2);
+        let values = product.dequantize(&storage).unwrap();
+        for (value, expected) in values.iter().zip([0.92, 0.9, -0.5, -0.48]) {
+            assert!((value - expected).abs() < 0.02);
+        }
+        let other = product
+            .quantize(
+                &[-0.9, -0.88, 0.51, 0.5],
+                StorageType::UnsignedByte,
+                (-1.0, 1.0),
+            )
+            .unwrap();
+        assert_ne!(storage, other);
+
+        // too few vectors for the centroids
+        let mut product = ProductQuantization::new(2, 64);
+        assert!(matches!(
+            product.train(&vectors, StorageType::UnsignedByte),
+            Err(QuantizationError::InvalidInput(_))
+        ));
+    }
+
+    #[test]
+    fn test_codes_decode_with_the_codebook_they_were_made_with() {
+        let clusters = |center: f32| -> Vec<Vec<f32>> {
+            (0..10)
+                .map(|i| {
+                    let sign = if i % 2 == 0 { 1.0 } else { -1.0 };
+                    vec![sign * center, sign * center]
+                })
+                .collect()
+        };
+        let mut product = ProductQuantization::new(1, 2);
+
+        let first = clusters(0.9);
+        let first: Vec<&[f32]> = first.iter().map(|v| &v[..]).collect();
+        product
+            .train_with_stats(&first, StorageType::UnsignedByte)
+            .unwrap();
+        let before = product
+            .quantize(&[0.8, 0.8], StorageType::UnsignedByte, (-1.0, 1.0))
+            .unwrap();
+        assert!(matches!(before, Storage::UnsignedByte { mag: 0, .. }));
+
+        let second = clusters(0.1);
+        let second: Vec<&[f32]> = second.iter().map(|v| &v[..]).collect();
+        product
+            .train_with_stats(&second, StorageType::UnsignedByte)
+            .unwrap();
+        assert_eq!(product.retired_codebooks.len(), 1);
+        let after = product
+            .quantize(&[0.8, 0.8], StorageType::UnsignedByte, (-1.0, 1.0))
+            .unwrap();
+        assert!(matches!(after, Storage::UnsignedByte { mag: 1, .. }));
 
-        unimplemented!("K-means clustering for product quantization is not implemented yet");
-        
+        // each code is looked up in its own codebook
+        for (storage, expected) in [(&before, 0.9), (&after, 0.1)] {
+            for value in product.dequantize(storage).unwrap() {
+                assert!((value - expected).abs() < 1e-5);
+            }
+        }
+        assert!(product
+            .dequantize(&Storage::UnsignedByte {
+                mag: 2,
+                quant_vec: vec![0],
+            })
+            .is_err());
     }
 }
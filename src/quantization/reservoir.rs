@@ -0,0 +1,105 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+/// Upper bound on the vectors kept to train a quantizer on, however many are
+/// collected before training.
+pub const MAX_TRAINING_SAMPLE_SIZE: usize = 25_000;
+
+/// Seed of the random picks, so the same vectors offered in the same order
+/// always give the same sample, and the same trained quantizer.
+const SAMPLER_SEED: u64 = 0x5eed;
+
+/// Keeps a uniform random sample of at most `capacity` of the vectors offered
+/// to it, without knowing in advance how many will be.
+#[derive(Debug)]
+pub struct ReservoirSampler {
+    capacity: usize,
+    seen: usize,
+    samples: Vec<Vec<f32>>,
+    rng: StdRng,
+}
+
+impl ReservoirSampler {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            seen: 0,
+            samples: Vec::new(),
+            rng: StdRng::seed_from_u64(SAMPLER_SEED),
+        }
+    }
+
+    /// Offers a vector, which replaces a random sample with probability
+    /// `capacity / seen` once the reservoir is full.
+    pub fn offer(&mut self, vector: &[f32]) {
+        self.seen += 1;
+        if self.samples.len() < self.capacity {
+            self.samples.push(vector.to_vec());
+            return;
+        }
+        let i = self.rng.gen_range(0..self.seen);
+        if i < self.capacity {
+            self.samples[i] = vector.to_vec();
+        }
+    }
+
+    /// Number of vectors offered so far.
+    pub fn seen(&self) -> usize {
+        self.seen
+    }
+
+    pub fn samples(&self) -> &[Vec<f32>] {
+        &self.samples
+    }
+
+    /// Empties the reservoir, once its samples have been trained on.
+    pub fn clear(&mut self) {
+        self.seen = 0;
+        self.samples = Vec::new();
+        self.rng = StdRng::seed_from_u64(SAMPLER_SEED);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ReservoirSampler;
+
+    #[test]
+    fn test_reservoir_keeps_at_most_capacity_vectors() {
+        let mut sampler = ReservoirSampler::new(10);
+        for i in 0..5 {
+            sampler.offer(&[i as f32]);
+        }
+        // everything is kept until the reservoir is full
+        assert_eq!(sampler.samples().len(), 5);
+
+        for i in 5..1000 {
+            sampler.offer(&[i as f32]);
+        }
+        assert_eq!(sampler.seen(), 1000);
+        assert_eq!(sampler.samples().len(), 10);
+        // later vectors made it into the sample too
+        assert!(sampler.samples().iter().any(|sample| sample[0] >= 10.0));
+
+        sampler.clear();
+        assert_eq!(sampler.seen(), 0);
+        assert!(sampler.samples().is_empty());
+    }
+
+    #[test]
+    fn test_reservoir_sample_is_reproducible() {
+        let sample = |sampler: &mut ReservoirSampler| {
+            for i in 0..1000 {
+                sampler.offer(&[i as f32]);
+            }
+            sampler.samples().to_vec()
+        };
+        let mut sampler = ReservoirSampler::new(10);
+        let first = sample(&mut sampler);
+        assert_eq!(first, sample(&mut ReservoirSampler::new(10)));
+
+        // a cleared reservoir samples as a new one does
+        sampler.clear();
+        assert_eq!(first, sample(&mut sampler));
+    }
+}
@@ -43,7 +43,11 @@ impl Quantization for ScalarQuantization {
         }
     }
 
-    fn train(&mut self, _vectors: &[&[f32]]) -> Result<(), QuantizationError> {
+    fn train(
+        &mut self,
+        _vectors: &[&[f32]],
+        _storage_type: StorageType,
+    ) -> Result<(), QuantizationError> {
         Ok(())
     }
 }
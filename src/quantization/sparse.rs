@@ -0,0 +1,125 @@
+use super::{Quantization, QuantizationError, StorageType};
+use crate::storage::Storage;
+
+/// Quantizes sparse vector values, expected to lie in `[0, 1]`, to
+/// `0..=quantization`. This is what the sparse inverted index stores.
+#[derive(Debug, Clone, Copy)]
+pub struct SparseQuantization {
+    pub quantization: u8,
+}
+
+impl SparseQuantization {
+    pub fn new(quantization: u8) -> Self {
+        Self { quantization }
+    }
+
+    pub fn quantize_value(&self, value: f32) -> u8 {
+        let max = self.quantization as f32;
+        ((value * max).clamp(0.0, max) as u8).min(self.quantization)
+    }
+
+    /// Maps a value produced by `quantize_value` back to an approximate float.
+    pub fn dequantize_value(&self, quantized_value: u8) -> f32 {
+        quantized_value as f32 / self.quantization as f32
+    }
+
+    /// Quantizes the `(dim_index, value)` entries of a sparse vector into
+    /// `Storage::Sparse`, sorted by dimension. Zero entries are left out.
+    pub fn quantize_sparse(&self, entries: &[(u32, f32)]) -> Storage {
+        let mut entries: Vec<_> = entries
+            .iter()
+            .filter(|(_, value)| *value != 0.0)
+            .map(|&(dim_index, value)| (dim_index, self.quantize_value(value)))
+            .collect();
+        entries.sort_unstable_by_key(|&(dim_index, _)| dim_index);
+        let (indices, values) = entries.into_iter().unzip();
+        Storage::Sparse { indices, values }
+    }
+}
+
+impl Quantization for SparseQuantization {
+    /// Treats `vector` as a dense view of a sparse vector, keeping its non zero
+    /// entries. Only `StorageType::UnsignedByte` is supported and `range` is
+    /// ignored, values are always expected in `[0, 1]`.
+    fn quantize(
+        &self,
+        vector: &[f32],
+        storage_type: StorageType,
+        _range: (f32, f32),
+    ) -> Result<Storage, QuantizationError> {
+        if !matches!(storage_type, StorageType::UnsignedByte) {
+            return Err(QuantizationError::InvalidInput(
+                "sparse vectors can only be stored as unsigned bytes".to_string(),
+            ));
+        }
+        let entries: Vec<_> = vector
+            .iter()
+            .enumerate()
+            .map(|(dim_index, &value)| (dim_index as u32, value))
+            .collect();
+        Ok(self.quantize_sparse(&entries))
+    }
+
+    fn train(
+        &mut self,
+        _vectors: &[&[f32]],
+        _storage_type: StorageType,
+    ) -> Result<(), QuantizationError> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SparseQuantization;
+    use crate::distance::{
+        cosine::CosineSimilarity, dotproduct::DotProductDistance, DistanceFunction,
+    };
+    use crate::quantization::{Quantization, StorageType};
+    use crate::storage::Storage;
+
+    #[test]
+    fn test_quantize_sparse_vector_round_trip() {
+        let quantization = SparseQuantization::new(63);
+        let entries = [(17, 0.5), (3, 1.0), (9, 0.0), (120, 0.25)];
+
+        let storage = quantization.quantize_sparse(&entries);
+        let Storage::Sparse { indices, values } = &storage else {
+            panic!("expected sparse storage, got {:?}", storage);
+        };
+        assert_eq!(indices, &vec![3, 17, 120]);
+        assert_eq!(values, &vec![63, 31, 15]);
+
+        // reading the stored values back gives the originals, up to a bucket
+        for (dim_index, quantized_value) in indices.iter().zip(values) {
+            let (_, original) = entries.iter().find(|(d, _)| d == dim_index).unwrap();
+            let restored = quantization.dequantize_value(*quantized_value);
+            assert!((restored - original).abs() <= 1.0 / 63.0);
+        }
+
+        // survives serialization like any other storage
+        let bytes = serde_cbor::to_vec(&storage).unwrap();
+        let deserialized: Storage = serde_cbor::from_slice(&bytes).unwrap();
+        assert_eq!(deserialized, storage);
+
+        // the dense view of the same vector quantizes to the same storage
+        let mut dense = vec![0.0; 121];
+        for &(dim_index, value) in &entries {
+            dense[dim_index as usize] = value;
+        }
+        let from_dense = quantization
+            .quantize(&dense, StorageType::UnsignedByte, (0.0, 1.0))
+            .unwrap();
+        assert_eq!(from_dense, storage);
+        assert!(quantization
+            .quantize(&dense, StorageType::HalfPrecisionFP, (0.0, 1.0))
+            .is_err());
+
+        // and is scored by the shared distance functions
+        let other = quantization.quantize_sparse(&[(3, 1.0), (50, 0.5)]);
+        let DotProductDistance(dot) = DotProductDistance(0.0).calculate(&storage, &other).unwrap();
+        assert_eq!(dot, (63 * 63) as f32);
+        let CosineSimilarity(cs) = CosineSimilarity(0.0).calculate(&storage, &storage).unwrap();
+        assert!((cs - 1.0).abs() < 1e-6);
+    }
+}
@@ -10,6 +10,7 @@ use std::{path::Path, sync::RwLock};
 
 use std::sync::Arc;
 
+use crate::models::common::TSHashTable;
 use crate::models::cuckoo_filter_tree::CuckooFilterTreeNode;
 use crate::models::versioning::Hash;
 use crate::models::{
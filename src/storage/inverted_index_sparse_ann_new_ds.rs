@@ -1,6 +1,8 @@
 use rayon::prelude::*;
-use std::array::from_fn;
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, BinaryHeap, HashMap, HashSet};
 use std::path::Path;
+use std::sync::atomic::{self, AtomicU8};
 use std::sync::Arc;
 
 use crate::models::buffered_io::BufferManagerFactory;
@@ -8,24 +10,45 @@ use crate::models::cache_loader::NodeRegistry;
 use crate::models::lazy_load::IncrementalSerializableGrowableData;
 use crate::models::lazy_load::LazyItem;
 use crate::models::lazy_load::LazyItemArray;
+use crate::models::sparse_log::SparseWriteLog;
 use crate::models::types::SparseVector;
 use crate::models::versioning::Hash;
+use crate::quantization::sparse::SparseQuantization;
+use crate::storage::{InvertedIndexError, Storage};
 use arcshift::ArcShift;
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
 
-// TODO: Add more powers for larger jumps
-// TODO: Or switch to dynamic calculation of power of max power of 4
-const POWERS_OF_4: [u32; 8] = [1, 4, 16, 64, 256, 1024, 4096, 16384];
+/// Highest quantized value used when none is configured, i.e. 64 buckets.
+pub const DEFAULT_QUANTIZATION: u8 = 63;
+pub const DEFAULT_CACHE_CAPACITY: usize = 1000;
 
-/// Returns the largest power of 4 that is less than or equal to `n`.
-/// Iteratively multiplies by 4 until the result exceeds `n`.
+/// Checks that `quantization` is one less than a power of two, so that the
+/// quantized values `0..=quantization` fill a whole number of bits.
+pub fn validate_quantization(quantization: u8) -> Result<(), String> {
+    if quantization == 0 || !(quantization as u16 + 1).is_power_of_two() {
+        return Err(format!(
+            "Quantization must be one less than a power of two, got {}",
+            quantization
+        ));
+    }
+    Ok(())
+}
+
+/// Returns the largest power of 4 that is less than or equal to `n`, along
+/// with its exponent. The exponent is half the position of the most
+/// significant bit, so it is at most 15 for any `u32`, which is also the
+/// highest child index of a node.
 pub fn largest_power_of_4_below(n: u32) -> (usize, u32) {
     assert_ne!(n, 0, "Cannot find largest power of 4 below 0");
-    POWERS_OF_4
-        .into_iter()
-        .enumerate()
-        .rev()
-        .find(|&(_, pow4)| pow4 <= n)
-        .unwrap()
+    let msb_position = 31 - n.leading_zeros();
+    let exponent = (msb_position / 2) as usize;
+    (exponent, power_of_4(exponent))
+}
+
+/// Returns `4^exponent`, i.e. the dimension offset of the child at `exponent`.
+fn power_of_4(exponent: usize) -> u32 {
+    1 << (2 * exponent)
 }
 
 /// Calculates the path from `current_dim_index` to `target_dim_index`.
@@ -47,18 +70,28 @@ fn calculate_path(target_dim_index: u32, current_dim_index: u32) -> Vec<usize> {
 pub struct InvertedIndexNewDSNode {
     pub dim_index: u32,
     pub implicit: bool,
-    pub data: Arc<[IncrementalSerializableGrowableData; 64]>, // Storing vec_ids in chunks of 64 for each quantized u8 value
+    // Highest quantized value, the node has `quantization + 1` buckets
+    pub quantization: u8,
+    pub data: Arc<[IncrementalSerializableGrowableData]>, // Storing vec_ids in chunks of 64 for each quantized u8 value
     pub lazy_children: LazyItemArray<InvertedIndexNewDSNode, 16>,
+    // Highest quantized value stored at this node, used as the WAND upper bound
+    pub max_quantized_value: Arc<AtomicU8>,
 }
 
 impl InvertedIndexNewDSNode {
-    pub fn new(dim_index: u32, implicit: bool) -> Self {
-        let data = Arc::new(from_fn(|_| IncrementalSerializableGrowableData::new()));
+    /// Creates a node with `quantization + 1` buckets, `quantization` is
+    /// expected to have been checked with `validate_quantization`.
+    pub fn new(dim_index: u32, implicit: bool, quantization: u8) -> Self {
+        let data = (0..=quantization)
+            .map(|_| IncrementalSerializableGrowableData::new())
+            .collect();
         InvertedIndexNewDSNode {
             dim_index,
             implicit,
+            quantization,
             data,
             lazy_children: LazyItemArray::new(),
+            max_quantized_value: Arc::new(AtomicU8::new(0)),
         }
     }
 
@@ -71,11 +104,11 @@ impl InvertedIndexNewDSNode {
     ) -> ArcShift<InvertedIndexNewDSNode> {
         let mut current_node = node;
         for &child_index in path {
-            let new_dim_index = current_node.dim_index + POWERS_OF_4[child_index];
+            let new_dim_index = current_node.dim_index + power_of_4(child_index);
             let new_child = LazyItem::new(
                 0.into(),
                 0u16,
-                InvertedIndexNewDSNode::new(new_dim_index, true),
+                InvertedIndexNewDSNode::new(new_dim_index, true, current_node.quantization),
             );
             loop {
                 if let Some(child) = current_node
@@ -92,16 +125,31 @@ impl InvertedIndexNewDSNode {
         current_node
     }
 
-    pub fn quantize(value: f32) -> u8 {
-        ((value * 63.0).clamp(0.0, 63.0) as u8).min(63)
+    pub fn quantize(value: f32, quantization: u8) -> u8 {
+        SparseQuantization::new(quantization).quantize_value(value)
     }
 
-    pub fn insert(mut node: ArcShift<InvertedIndexNewDSNode>, value: f32, vector_id: u32) {
-        let quantized_value = Self::quantize(value);
-        let mut data: Arc<[IncrementalSerializableGrowableData; 64]> = node.get().data.clone();
+    /// Maps a value produced by `quantize` back to an approximate float.
+    pub fn dequantize(quantized_value: u8, quantization: u8) -> f32 {
+        SparseQuantization::new(quantization).dequantize_value(quantized_value)
+    }
 
-        if let Some(growable_data) = Arc::make_mut(&mut data).get_mut(quantized_value as usize) {
-            growable_data.insert(vector_id);
+    pub fn insert(node: ArcShift<InvertedIndexNewDSNode>, value: f32, vector_id: u32) {
+        let quantized_value = Self::quantize(value, node.quantization);
+        Self::insert_quantized(node, quantized_value, vector_id);
+    }
+
+    pub fn insert_quantized(
+        node: ArcShift<InvertedIndexNewDSNode>,
+        quantized_value: u8,
+        vector_id: u32,
+    ) {
+        node.max_quantized_value
+            .fetch_max(quantized_value, atomic::Ordering::Relaxed);
+
+        // buckets share their storage across clones of the node
+        if let Some(growable_data) = node.data.get(quantized_value as usize) {
+            growable_data.clone().insert(vector_id);
         };
     }
 
@@ -137,26 +185,213 @@ impl InvertedIndexNewDSNode {
             }
         }
     }
+
+    /// Collects all `(vector_id, quantized_value)` pairs stored at this node.
+    pub fn postings(&self, cache: Arc<NodeRegistry>) -> Vec<(u32, u8)> {
+        let mut postings = Vec::new();
+        for (quantized_value, growable_data) in self.data.iter().enumerate() {
+            for item in growable_data.items.iter() {
+                let arc_vector_data = item.get_data(cache.clone());
+                let vector_data = (*arc_vector_data).clone().get().clone();
+                postings.extend(
+                    vector_data
+                        .data
+                        .iter()
+                        .filter(|&&vec_id| vec_id != u32::MAX)
+                        .map(|&vec_id| (vec_id, quantized_value as u8)),
+                );
+            }
+        }
+        postings
+    }
+
+    /// Removes `vector_id` from every bucket of this node, returns `true` if it was found.
+    pub fn remove(&self, vector_id: u32) -> bool {
+        let mut removed = false;
+        for growable_data in self.data.iter() {
+            removed |= growable_data.remove(vector_id);
+        }
+        removed
+    }
+
+    /// Removes `vector_id` from this node and all of its descendants.
+    fn remove_from_subtree(&self, vector_id: u32, cache: Arc<NodeRegistry>) -> bool {
+        let mut removed = self.remove(vector_id);
+        for child_index in 0..16 {
+            if let Some(child) = self.lazy_children.get(child_index) {
+                removed |= child
+                    .get_data(cache.clone())
+                    .remove_from_subtree(vector_id, cache.clone());
+            }
+        }
+        removed
+    }
+
+    /// Adds the ids of all vectors stored at this node and its descendants to
+    /// `vector_ids`.
+    fn collect_vector_ids(&self, cache: Arc<NodeRegistry>, vector_ids: &mut BTreeSet<u32>) {
+        vector_ids.extend(
+            self.postings(cache.clone())
+                .into_iter()
+                .map(|(vector_id, _)| vector_id),
+        );
+        for child_index in 0..16 {
+            if let Some(child) = self.lazy_children.get(child_index) {
+                child
+                    .get_data(cache.clone())
+                    .collect_vector_ids(cache.clone(), vector_ids);
+            }
+        }
+    }
+
+    /// Adds `(dim_index, quantized_value)` for every node of this subtree that
+    /// stores `vector_id` to `entries`.
+    fn collect_vector_entries(
+        &self,
+        vector_id: u32,
+        cache: Arc<NodeRegistry>,
+        entries: &mut Vec<(u32, u8)>,
+    ) {
+        if let Some(quantized_value) = self.get_value(&[], vector_id, cache.clone()) {
+            entries.push((self.dim_index, quantized_value));
+        }
+        for child_index in 0..16 {
+            if let Some(child) = self.lazy_children.get(child_index) {
+                child.get_data(cache.clone()).collect_vector_entries(
+                    vector_id,
+                    cache.clone(),
+                    entries,
+                );
+            }
+        }
+    }
+
+    /// Upper bound on the dequantized value of any posting at this node.
+    pub fn max_value(&self) -> f32 {
+        Self::dequantize(
+            self.max_quantized_value.load(atomic::Ordering::Relaxed),
+            self.quantization,
+        )
+    }
+}
+
+/// Posting list of a single query dimension, as traversed by `search_wand`.
+struct WandTerm {
+    // (vector_id, score contribution) sorted by vector id
+    postings: Vec<(u32, f32)>,
+    position: usize,
+    upper_bound: f32,
+}
+
+impl WandTerm {
+    fn current(&self) -> Option<u32> {
+        self.postings
+            .get(self.position)
+            .map(|&(vector_id, _)| vector_id)
+    }
+
+    /// Moves the cursor to the first posting with an id not less than `vector_id`.
+    fn advance_to(&mut self, vector_id: u32) {
+        self.position += self.postings[self.position..].partition_point(|&(id, _)| id < vector_id);
+    }
+}
+
+/// Entry of the top-k heap in `search_wand`, ordered so that the lowest score
+/// sits at the top of the `BinaryHeap`.
+#[derive(PartialEq)]
+struct WandCandidate {
+    vector_id: u32,
+    score: f32,
+}
+
+impl Eq for WandCandidate {}
+
+impl PartialOrd for WandCandidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for WandCandidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .score
+            .partial_cmp(&self.score)
+            .unwrap_or(Ordering::Equal)
+    }
 }
 
 #[derive(Clone)]
 pub struct InvertedIndexSparseAnnNewDS {
     pub root: ArcShift<InvertedIndexNewDSNode>,
     pub cache: Arc<NodeRegistry>,
+    // dims touched by each inserted vector, so removal doesn't have to scan the tree
+    pub vector_dims: Arc<DashMap<u32, HashSet<u32>>>,
+    // dims at or above this are rejected by `add_sparse_vector`, unbounded if `None`
+    pub max_dim: Option<u32>,
+    // vectors are recorded here before they're inserted, if set
+    pub write_log: Option<Arc<SparseWriteLog>>,
 }
 
 impl InvertedIndexSparseAnnNewDS {
-    pub fn new() -> Self {
+    /// Creates an index whose files are written under `root_path`, with a
+    /// node cache of `cache_capacity` entries. Collections sharing a process
+    /// need separate root paths, otherwise their `<version>.index` files
+    /// collide.
+    pub fn new(root_path: &Path, cache_capacity: usize) -> Self {
+        Self::with_options(root_path, cache_capacity, DEFAULT_QUANTIZATION).unwrap()
+    }
+
+    /// Creates an index in the current directory with the default cache
+    /// capacity.
+    pub fn new_default() -> Self {
+        Self::new(Path::new("."), DEFAULT_CACHE_CAPACITY)
+    }
+
+    /// Creates an index whose values are quantized to `0..=quantization`,
+    /// e.g. 15, 31, 63 or 127.
+    pub fn with_quantization(quantization: u8) -> Result<Self, String> {
+        Self::with_options(Path::new("."), DEFAULT_CACHE_CAPACITY, quantization)
+    }
+
+    /// Creates an index under `root_path` with the given cache capacity and
+    /// quantization.
+    pub fn with_options(
+        root_path: &Path,
+        cache_capacity: usize,
+        quantization: u8,
+    ) -> Result<Self, String> {
+        validate_quantization(quantization)?;
         let bufmans = Arc::new(BufferManagerFactory::new(
-            Path::new(".").into(),
+            root_path.into(),
             |root, ver: &Hash| root.join(format!("{}.index", **ver)),
             1.0,
         ));
-        let cache = Arc::new(NodeRegistry::new(1000, bufmans));
-        InvertedIndexSparseAnnNewDS {
-            root: ArcShift::new(InvertedIndexNewDSNode::new(0, false)),
+        let cache = Arc::new(NodeRegistry::with_capacity(
+            cache_capacity,
+            cache_capacity,
+            bufmans,
+        ));
+        Ok(InvertedIndexSparseAnnNewDS {
+            root: ArcShift::new(InvertedIndexNewDSNode::new(0, false, quantization)),
             cache,
-        }
+            vector_dims: Arc::new(DashMap::new()),
+            max_dim: None,
+            write_log: None,
+        })
+    }
+
+    /// Limits the dimensions accepted by `add_sparse_vector` to `0..max_dim`.
+    pub fn with_max_dim(mut self, max_dim: u32) -> Self {
+        self.max_dim = Some(max_dim);
+        self
+    }
+
+    /// Records every vector added from now on in `write_log`, before it's
+    /// inserted.
+    pub fn with_write_log(mut self, write_log: SparseWriteLog) -> Self {
+        self.write_log = Some(Arc::new(write_log));
+        self
     }
 
     /// Finds the node at a given dimension
@@ -182,24 +417,554 @@ impl InvertedIndexSparseAnnNewDS {
 
     //Inserts vec_id, quantized value u8 at particular node based on path
     pub fn insert(&self, dim_index: u32, value: f32, vector_id: u32) {
+        let quantized_value = self.quantization().quantize_value(value);
+        self.insert_quantized(dim_index, quantized_value, vector_id);
+    }
+
+    fn insert_quantized(&self, dim_index: u32, quantized_value: u8, vector_id: u32) {
         let path = calculate_path(dim_index, self.root.dim_index);
         let node = InvertedIndexNewDSNode::find_or_create_node(
             self.root.clone(),
             &path,
             self.cache.clone(),
         );
-        //value will be quantized while being inserted into the Node.
-        InvertedIndexNewDSNode::insert(node, value, vector_id)
+        InvertedIndexNewDSNode::insert_quantized(node, quantized_value, vector_id);
+        self.vector_dims
+            .entry(vector_id)
+            .or_default()
+            .insert(dim_index);
+    }
+
+    /// Quantization applied to the values stored in this index.
+    pub fn quantization(&self) -> SparseQuantization {
+        SparseQuantization::new(self.root.quantization)
     }
 
-    /// Adds a sparse vector to the index.
-    pub fn add_sparse_vector(&self, vector: SparseVector) -> Result<(), String> {
+    /// Adds a sparse vector to the index. A vector id can only be added once,
+    /// until it's removed again. Nothing is inserted if any of its dimensions
+    /// is out of range, or if it can't be recorded in the write log.
+    pub fn add_sparse_vector(&self, vector: SparseVector) -> Result<(), InvertedIndexError> {
         let vector_id = vector.vector_id;
-        vector.entries.par_iter().for_each(|(dim_index, value)| {
-            if *value != 0.0 {
-                self.insert(*dim_index, *value, vector_id);
+        if let Some(max_dim) = self.max_dim {
+            if let Some(&(dim_index, _)) = vector
+                .entries
+                .iter()
+                .find(|&&(dim_index, value)| dim_index >= max_dim && value != 0.0)
+            {
+                return Err(InvertedIndexError::DimensionOutOfRange(dim_index));
             }
-        });
+        }
+        // claim the id first, so that concurrent adds of the same id can't
+        // both end up in the log
+        match self.vector_dims.entry(vector_id) {
+            Entry::Occupied(_) => return Err(InvertedIndexError::DuplicateVector(vector_id)),
+            Entry::Vacant(entry) => {
+                entry.insert(HashSet::new());
+            }
+        }
+        if let Some(write_log) = &self.write_log {
+            if let Err(e) = write_log.append(&vector) {
+                self.vector_dims.remove(&vector_id);
+                return Err(InvertedIndexError::LogWrite(e.to_string()));
+            }
+        }
+        let Storage::Sparse { indices, values } =
+            self.quantization().quantize_sparse(&vector.entries)
+        else {
+            unreachable!("sparse quantization always produces sparse storage");
+        };
+        indices
+            .par_iter()
+            .zip(values.par_iter())
+            .for_each(|(dim_index, quantized_value)| {
+                self.insert_quantized(*dim_index, *quantized_value, vector_id);
+            });
         Ok(())
     }
+
+    /// Removes a sparse vector from the index.
+    ///
+    /// Vectors inserted before the index was reloaded from disk have no entry
+    /// in `vector_dims`, for those the whole tree is scanned instead.
+    pub fn remove_sparse_vector(&self, vector_id: u32) -> Result<(), InvertedIndexError> {
+        let removed = match self.vector_dims.remove(&vector_id) {
+            Some((_, dims)) => dims.into_iter().fold(false, |removed, dim_index| {
+                match self.find_node(dim_index) {
+                    Some(node) => node.remove(vector_id) || removed,
+                    None => removed,
+                }
+            }),
+            None => self
+                .root
+                .shared_get()
+                .remove_from_subtree(vector_id, self.cache.clone()),
+        };
+        if !removed {
+            return Err(InvertedIndexError::VectorNotFound(vector_id));
+        }
+        Ok(())
+    }
+
+    /// Iterates over the ids of all vectors in the index, in ascending order.
+    /// Walks the whole tree, so vectors loaded from disk are included too.
+    pub fn iter_vector_ids(&self) -> impl Iterator<Item = u32> {
+        let mut vector_ids = BTreeSet::new();
+        self.root
+            .shared_get()
+            .collect_vector_ids(self.cache.clone(), &mut vector_ids);
+        vector_ids.into_iter()
+    }
+
+    /// Rebuilds the sparse vector stored under `vector_id` from the tree, with
+    /// its entries sorted by dimension. Values are dequantized, so they only
+    /// match the inserted ones up to the quantization step.
+    pub fn reconstruct(&self, vector_id: u32) -> Option<SparseVector> {
+        let mut entries = Vec::new();
+        self.root
+            .shared_get()
+            .collect_vector_entries(vector_id, self.cache.clone(), &mut entries);
+        if entries.is_empty() {
+            return None;
+        }
+        entries.sort_unstable_by_key(|&(dim_index, _)| dim_index);
+        let quantization = self.quantization();
+        let entries = entries
+            .into_iter()
+            .map(|(dim_index, quantized_value)| {
+                (dim_index, quantization.dequantize_value(quantized_value))
+            })
+            .collect();
+        Some(SparseVector::new(vector_id, entries))
+    }
+
+    /// Returns the `k` vector ids with the highest dot product against `query`,
+    /// in descending order of score. Stored values are dequantized for scoring.
+    pub fn search(&self, query: SparseVector, k: usize) -> Vec<(u32, f32)> {
+        let mut scores: HashMap<u32, f32> = HashMap::new();
+
+        for (dim_index, query_value) in query.entries {
+            if query_value == 0.0 {
+                continue;
+            }
+            let Some(node) = self.find_node(dim_index) else {
+                continue;
+            };
+            for (vector_id, quantized_value) in node.postings(self.cache.clone()) {
+                *scores.entry(vector_id).or_insert(0.0) += query_value
+                    * InvertedIndexNewDSNode::dequantize(quantized_value, node.quantization);
+            }
+        }
+
+        let mut results: Vec<(u32, f32)> = scores.into_iter().collect();
+        results.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+        results.truncate(k);
+        results
+    }
+
+    /// Same as `search`, but uses WAND (Weak AND) to skip vectors whose score
+    /// upper bound cannot beat the current k-th best score.
+    pub fn search_wand(&self, query: SparseVector, k: usize) -> Vec<(u32, f32)> {
+        if k == 0 {
+            return Vec::new();
+        }
+
+        let mut terms = Vec::new();
+        for (dim_index, query_value) in query.entries {
+            if query_value == 0.0 {
+                continue;
+            }
+            let Some(node) = self.find_node(dim_index) else {
+                continue;
+            };
+            let mut postings: Vec<(u32, f32)> = node
+                .postings(self.cache.clone())
+                .into_iter()
+                .map(|(vector_id, quantized_value)| {
+                    (
+                        vector_id,
+                        query_value
+                            * InvertedIndexNewDSNode::dequantize(
+                                quantized_value,
+                                node.quantization,
+                            ),
+                    )
+                })
+                .collect();
+            if postings.is_empty() {
+                continue;
+            }
+            postings.sort_unstable_by_key(|&(vector_id, _)| vector_id);
+            terms.push(WandTerm {
+                postings,
+                position: 0,
+                // stored values are never negative, so a negative query value
+                // can only lower the score
+                upper_bound: (query_value * node.max_value()).max(0.0),
+            });
+        }
+
+        let mut heap: BinaryHeap<WandCandidate> = BinaryHeap::with_capacity(k + 1);
+        loop {
+            terms.retain(|term| term.current().is_some());
+            terms.sort_by_key(|term| term.current());
+
+            let threshold = if heap.len() < k {
+                f32::NEG_INFINITY
+            } else {
+                heap.peek().map_or(f32::NEG_INFINITY, |c| c.score)
+            };
+
+            // The pivot is the first term at which the accumulated upper bounds
+            // exceed the threshold; no vector before its current id can qualify.
+            let mut bound = 0.0;
+            let Some(pivot) = terms.iter().position(|term| {
+                bound += term.upper_bound;
+                bound > threshold
+            }) else {
+                break;
+            };
+            let pivot_id = terms[pivot].current().unwrap();
+
+            if terms[0].current() == Some(pivot_id) {
+                let mut score = 0.0;
+                for term in terms.iter_mut() {
+                    if term.current() != Some(pivot_id) {
+                        break;
+                    }
+                    while term.current() == Some(pivot_id) {
+                        score += term.postings[term.position].1;
+                        term.position += 1;
+                    }
+                }
+                if heap.len() < k || score > threshold {
+                    heap.push(WandCandidate {
+                        vector_id: pivot_id,
+                        score,
+                    });
+                    if heap.len() > k {
+                        heap.pop();
+                    }
+                }
+            } else {
+                for term in terms[..pivot].iter_mut() {
+                    term.advance_to(pivot_id);
+                }
+            }
+        }
+
+        let mut results: Vec<(u32, f32)> = heap
+            .into_iter()
+            .map(|candidate| (candidate.vector_id, candidate.score))
+            .collect();
+        results.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+        results
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        calculate_path, largest_power_of_4_below, power_of_4, validate_quantization,
+        InvertedIndexNewDSNode, InvertedIndexSparseAnnNewDS,
+    };
+    use crate::models::lazy_load::FileIndex;
+    use crate::models::serializer::CustomSerialize;
+    use crate::models::types::{FileOffset, SparseVector};
+    use crate::models::versioning::Hash;
+    use crate::storage::InvertedIndexError;
+    use quickcheck_macros::quickcheck;
+    use tempfile::tempdir;
+
+    fn sample_vectors() -> Vec<SparseVector> {
+        vec![
+            SparseVector::new(0, vec![(0, 0.9), (5, 0.1), (17, 0.5)]),
+            SparseVector::new(1, vec![(0, 0.1), (5, 0.9), (100, 0.6)]),
+            SparseVector::new(2, vec![(17, 0.2), (100, 0.8)]),
+        ]
+    }
+
+    #[test]
+    fn test_search_ranks_identical_vector_first() {
+        let index = InvertedIndexSparseAnnNewDS::new_default();
+        let vectors = sample_vectors();
+        for vector in vectors.clone() {
+            index.add_sparse_vector(vector).unwrap();
+        }
+
+        for vector in vectors {
+            let expected_id = vector.vector_id;
+            let results = index.search(vector, 2);
+            assert_eq!(results.len(), 2);
+            assert_eq!(results[0].0, expected_id);
+            assert!(results[0].1 >= results[1].1);
+        }
+    }
+
+    #[test]
+    fn test_remove_sparse_vector() {
+        let index = InvertedIndexSparseAnnNewDS::new_default();
+        for vector in sample_vectors() {
+            index.add_sparse_vector(vector).unwrap();
+        }
+
+        assert!(index.get(5, 1).is_some());
+        index.remove_sparse_vector(1).unwrap();
+        for (dim_index, _) in &sample_vectors()[1].entries {
+            assert_eq!(index.get(*dim_index, 1), None);
+        }
+        // other vectors sharing those dims are untouched
+        assert!(index.get(5, 0).is_some());
+        assert!(index.get(100, 2).is_some());
+        assert_eq!(
+            index.remove_sparse_vector(1),
+            Err(InvertedIndexError::VectorNotFound(1))
+        );
+    }
+
+    #[test]
+    fn test_add_duplicate_sparse_vector() {
+        let index = InvertedIndexSparseAnnNewDS::new_default();
+        let vector = sample_vectors().remove(0);
+        index.add_sparse_vector(vector.clone()).unwrap();
+        assert_eq!(
+            index.add_sparse_vector(vector.clone()),
+            Err(InvertedIndexError::DuplicateVector(vector.vector_id))
+        );
+
+        // once removed, the id can be added again
+        index.remove_sparse_vector(vector.vector_id).unwrap();
+        index.add_sparse_vector(vector).unwrap();
+    }
+
+    #[test]
+    fn test_search_wand_matches_exhaustive_search() {
+        let index = InvertedIndexSparseAnnNewDS::new_default();
+        let mut vectors = Vec::new();
+        for vector_id in 0..60u32 {
+            let entries = (0..40u32)
+                .filter(|dim| (vector_id * 7 + dim * 13) % 5 == 0)
+                .map(|dim| (dim, ((vector_id * 31 + dim * 17) % 97) as f32 / 97.0))
+                .collect();
+            vectors.push(SparseVector::new(vector_id, entries));
+        }
+        for vector in vectors.clone() {
+            index.add_sparse_vector(vector).unwrap();
+        }
+
+        for k in [1, 5, 10] {
+            for query in vectors.iter().step_by(7) {
+                let exhaustive = index.search(query.clone(), k);
+                let wand = index.search_wand(query.clone(), k);
+                assert_eq!(exhaustive.len(), wand.len());
+                for (expected, actual) in exhaustive.iter().zip(wand.iter()) {
+                    assert!((expected.1 - actual.1).abs() < 1e-5);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_validate_quantization() {
+        for quantization in [1, 3, 15, 31, 63, 127, 255] {
+            assert!(validate_quantization(quantization).is_ok());
+        }
+        for quantization in [0, 2, 16, 62, 64, 100, 128] {
+            assert!(validate_quantization(quantization).is_err());
+        }
+        assert!(InvertedIndexSparseAnnNewDS::with_quantization(62).is_err());
+    }
+
+    #[test]
+    fn test_quantization_boundaries() {
+        for quantization in [15u8, 31, 63, 127] {
+            let max = quantization as f32;
+            assert_eq!(InvertedIndexNewDSNode::quantize(0.0, quantization), 0);
+            assert_eq!(InvertedIndexNewDSNode::quantize(-0.5, quantization), 0);
+            assert_eq!(
+                InvertedIndexNewDSNode::quantize(1.0, quantization),
+                quantization
+            );
+            assert_eq!(
+                InvertedIndexNewDSNode::quantize(1.5, quantization),
+                quantization
+            );
+
+            for bucket in 0..quantization {
+                // values are truncated, so anything inside a bucket maps to its lower edge
+                let inside = (bucket as f32 + 0.5) / max;
+                let quantized = InvertedIndexNewDSNode::quantize(inside, quantization);
+                assert_eq!(quantized, bucket);
+                let dequantized = InvertedIndexNewDSNode::dequantize(quantized, quantization);
+                assert!(inside - dequantized < 1.0 / max);
+            }
+        }
+    }
+
+    #[test]
+    fn test_search_with_custom_quantization() {
+        for quantization in [15u8, 31, 63, 127] {
+            let index = InvertedIndexSparseAnnNewDS::with_quantization(quantization).unwrap();
+            assert_eq!(index.root.data.len(), quantization as usize + 1);
+            for vector in sample_vectors() {
+                index.add_sparse_vector(vector).unwrap();
+            }
+
+            assert_eq!(index.get(100, 2), Some((0.8 * quantization as f32) as u8));
+            // implicit nodes created on the way to dim 100 use the same resolution
+            let node = index.find_node(100).unwrap();
+            assert_eq!(node.data.len(), quantization as usize + 1);
+
+            let results = index.search(sample_vectors()[2].clone(), 1);
+            assert_eq!(results[0].0, 2);
+        }
+    }
+
+    #[test]
+    fn test_largest_power_of_4_below() {
+        assert_eq!(largest_power_of_4_below(1), (0, 1));
+        assert_eq!(largest_power_of_4_below(3), (0, 1));
+        assert_eq!(largest_power_of_4_below(16383), (6, 4096));
+        assert_eq!(largest_power_of_4_below(16384), (7, 16384));
+        assert_eq!(largest_power_of_4_below(65535), (7, 16384));
+        assert_eq!(largest_power_of_4_below(65536), (8, 65536));
+        assert_eq!(largest_power_of_4_below(1 << 30), (15, 1 << 30));
+        assert_eq!(largest_power_of_4_below(u32::MAX), (15, 1 << 30));
+    }
+
+    #[test]
+    fn test_calculate_path_beyond_16384() {
+        assert_eq!(calculate_path(16384, 0), vec![7]);
+        assert_eq!(calculate_path(16385, 0), vec![7, 0]);
+        assert_eq!(calculate_path(65536, 0), vec![8]);
+        assert_eq!(calculate_path(65536 + 16384, 16384), vec![8]);
+        assert_eq!(calculate_path(3 << 30, 0), vec![15, 15, 15]);
+        // every base 4 digit of u32::MAX is 3
+        assert_eq!(calculate_path(u32::MAX, 0).len(), 48);
+    }
+
+    #[quickcheck]
+    fn prop_calculate_path_is_minimal(target_dim_index: u32, current_dim_index: u32) -> bool {
+        if target_dim_index < current_dim_index {
+            return true; // Skip invalid cases
+        }
+        let diff = target_dim_index - current_dim_index;
+        let path = calculate_path(target_dim_index, current_dim_index);
+        let sum: u64 = path.iter().map(|&index| power_of_4(index) as u64).sum();
+        // the fewest powers of 4 adding up to `diff` is the sum of its base 4 digits
+        let digit_sum: u32 = (0..16).map(|digit| (diff >> (2 * digit)) & 3).sum();
+        sum == diff as u64 && path.len() == digit_sum as usize
+    }
+
+    #[test]
+    fn test_insert_dim_beyond_16384() {
+        let index = InvertedIndexSparseAnnNewDS::new_default();
+        let dims = [16383, 16384, 16385, 65536, 1_000_000, 1 << 30];
+        let vector = SparseVector::new(3, dims.iter().map(|&dim| (dim, 0.5)).collect());
+        index.add_sparse_vector(vector.clone()).unwrap();
+
+        for dim_index in dims {
+            assert!(index.get(dim_index, 3).is_some());
+            assert_eq!(index.find_node(dim_index).unwrap().dim_index, dim_index);
+        }
+        assert_eq!(index.get(16386, 3), None);
+        assert_eq!(index.search(vector, 1)[0].0, 3);
+    }
+
+    #[test]
+    fn test_indexes_in_separate_root_paths() {
+        let version = Hash::from(0);
+        let dirs = [tempdir().unwrap(), tempdir().unwrap()];
+        let indexes: Vec<_> = dirs
+            .iter()
+            .map(|dir| InvertedIndexSparseAnnNewDS::new(dir.as_ref(), 100))
+            .collect();
+        for (vector_id, index) in indexes.iter().enumerate() {
+            let vector = SparseVector::new(vector_id as u32, vec![(5, 0.5), (17, 0.9)]);
+            index.add_sparse_vector(vector).unwrap();
+        }
+
+        // both indexes write the same version, each to its own file
+        let mut offsets = Vec::new();
+        for index in &indexes {
+            let bufmans = index.cache.get_bufmans();
+            let bufman = bufmans.get(version).unwrap();
+            let cursor = bufman.open_cursor().unwrap();
+            offsets.push(index.serialize(bufmans.clone(), version, cursor).unwrap());
+            bufman.close_cursor(cursor).unwrap();
+            bufmans.flush_all().unwrap();
+        }
+        for dir in &dirs {
+            assert!(dir.path().join(format!("{}.index", *version)).exists());
+        }
+
+        for (vector_id, (index, offset)) in indexes.iter().zip(offsets).enumerate() {
+            let file_index = FileIndex::Valid {
+                offset: FileOffset(offset),
+                version_number: 0,
+                version_id: version,
+            };
+            let deserialized: InvertedIndexSparseAnnNewDS =
+                index.cache.clone().load_item(file_index).unwrap();
+            let other_id = 1 - vector_id as u32;
+            assert!(deserialized.get(17, vector_id as u32).is_some());
+            assert_eq!(deserialized.get(17, other_id), None);
+        }
+    }
+
+    #[test]
+    fn test_iter_vector_ids() {
+        let index = InvertedIndexSparseAnnNewDS::new_default();
+        assert_eq!(index.iter_vector_ids().count(), 0);
+
+        // the vectors share dims 0, 5, 17 and 100, each id is yielded once
+        for vector in sample_vectors() {
+            index.add_sparse_vector(vector).unwrap();
+        }
+        assert_eq!(index.iter_vector_ids().collect::<Vec<_>>(), vec![0, 1, 2]);
+
+        index.remove_sparse_vector(1).unwrap();
+        assert_eq!(index.iter_vector_ids().collect::<Vec<_>>(), vec![0, 2]);
+    }
+
+    #[test]
+    fn test_add_sparse_vector_beyond_max_dim() {
+        let index = InvertedIndexSparseAnnNewDS::new_default().with_max_dim(1000);
+        index
+            .add_sparse_vector(SparseVector::new(0, vec![(0, 0.5), (999, 0.5)]))
+            .unwrap();
+
+        let vector = SparseVector::new(1, vec![(5, 0.5), (1000, 0.5)]);
+        assert_eq!(
+            index.add_sparse_vector(vector),
+            Err(InvertedIndexError::DimensionOutOfRange(1000))
+        );
+        // the in range entry wasn't inserted either
+        assert_eq!(index.get(5, 1), None);
+        assert_eq!(index.iter_vector_ids().collect::<Vec<_>>(), vec![0]);
+    }
+
+    #[test]
+    fn test_reconstruct_sparse_vector() {
+        let index = InvertedIndexSparseAnnNewDS::new_default();
+        for vector in sample_vectors() {
+            index.add_sparse_vector(vector).unwrap();
+        }
+        let entries = vec![(100_000, 0.3), (3, 1.0), (17, 0.0), (64, 0.75), (5, 0.5)];
+        index
+            .add_sparse_vector(SparseVector::new(7, entries.clone()))
+            .unwrap();
+
+        let reconstructed = index.reconstruct(7).unwrap();
+        assert_eq!(reconstructed.vector_id, 7);
+        let dims: Vec<_> = reconstructed.entries.iter().map(|&(dim, _)| dim).collect();
+        assert_eq!(dims, vec![3, 5, 64, 100_000]);
+        for (dim_index, value) in reconstructed.entries {
+            let (_, original) = entries.iter().find(|(dim, _)| *dim == dim_index).unwrap();
+            assert!((value - original).abs() <= 1.0 / 63.0);
+        }
+
+        assert!(index.reconstruct(8).is_none());
+        index.remove_sparse_vector(7).unwrap();
+        assert!(index.reconstruct(7).is_none());
+    }
 }
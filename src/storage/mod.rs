@@ -11,6 +11,7 @@ pub mod sparse_ann_query_new_ds;
 
 use half::f16;
 use serde::{Deserialize, Serialize};
+use std::fmt;
 
 #[derive(
     Debug,
@@ -36,4 +37,44 @@ pub enum Storage {
         mag: f32,
         quant_vec: Vec<f16>,
     },
+    /// quantized values of the non zero dimensions of a sparse vector, with
+    /// `indices` sorted
+    Sparse {
+        indices: Vec<u32>,
+        values: Vec<u8>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum InvertedIndexError {
+    // dimension index the tree has no room for
+    DimensionOutOfRange(u32),
+    DuplicateVector(u32),
+    VectorNotFound(u32),
+    // dimension index that already holds its values
+    DimensionAlreadyExplicit(u32),
+    Locked,
+    // the vector couldn't be recorded in the write log, so it wasn't added
+    LogWrite(String),
+}
+
+impl fmt::Display for InvertedIndexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::DimensionOutOfRange(dim_index) => {
+                write!(f, "Dimension-Index {} is out of range", dim_index)
+            }
+            Self::DuplicateVector(vector_id) => {
+                write!(f, "Vector {} is already in the index", vector_id)
+            }
+            Self::VectorNotFound(vector_id) => {
+                write!(f, "Vector {} not found in the index", vector_id)
+            }
+            Self::DimensionAlreadyExplicit(dim_index) => {
+                write!(f, "Dimension-Index {} is already explicit", dim_index)
+            }
+            Self::Locked => write!(f, "Inverted index lock is poisoned"),
+            Self::LogWrite(msg) => write!(f, "Failed to write sparse vector log: {}", msg),
+        }
+    }
 }
@@ -94,11 +94,11 @@ impl<const LEN: usize> AsRef<[u32; LEN]> for Page<LEN> {
 impl<const LEN: usize> CustomSerialize for Pagepool<LEN> {
     fn serialize(
         &self,
-        bufmans: std::sync::Arc<crate::models::buffered_io::BufferManagerFactory>,
+        bufmans: std::sync::Arc<crate::models::buffered_io::BufferManagerFactory<crate::models::versioning::Hash>>,
         version: crate::models::versioning::Hash,
         cursor: u64,
     ) -> Result<u32, crate::models::buffered_io::BufIoError> {
-        let bufman = bufmans.get(&version)?;
+        let bufman = bufmans.get(version)?;
 
         // Move the cursor to the end of the file and start writing from there
         bufman.seek_with_cursor(cursor, SeekFrom::End(0))?;
@@ -130,7 +130,7 @@ impl<const LEN: usize> CustomSerialize for Pagepool<LEN> {
     }
 
     fn deserialize(
-        bufmans: std::sync::Arc<crate::models::buffered_io::BufferManagerFactory>,
+        bufmans: std::sync::Arc<crate::models::buffered_io::BufferManagerFactory<crate::models::versioning::Hash>>,
         file_index: crate::models::lazy_load::FileIndex,
         cache: std::sync::Arc<crate::models::cache_loader::NodeRegistry>,
         max_loads: u16,
@@ -142,7 +142,7 @@ impl<const LEN: usize> CustomSerialize for Pagepool<LEN> {
                 version_id,
                 version_number,
             } => {
-                let bufman = bufmans.get(&version_id)?;
+                let bufman = bufmans.get(version_id)?;
                 let cursor = bufman.open_cursor()?;
 
                 bufman.seek_with_cursor(cursor, SeekFrom::Start(offset as u64))?;
@@ -188,7 +188,7 @@ impl<const LEN: usize> CustomSerialize for Pagepool<LEN> {
 
 impl<const LEN: usize> CustomSerialize for Page<LEN> {
     fn deserialize(
-        bufmans: std::sync::Arc<crate::models::buffered_io::BufferManagerFactory>,
+        bufmans: std::sync::Arc<crate::models::buffered_io::BufferManagerFactory<crate::models::versioning::Hash>>,
         file_index: crate::models::lazy_load::FileIndex,
         _cache: std::sync::Arc<crate::models::cache_loader::NodeRegistry>,
         _max_loads: u16,
@@ -200,7 +200,7 @@ impl<const LEN: usize> CustomSerialize for Page<LEN> {
                 version_id,
                 ..
             } => {
-                let bufman = bufmans.get(&version_id)?;
+                let bufman = bufmans.get(version_id)?;
                 let cursor = bufman.open_cursor()?;
                 bufman.seek_with_cursor(cursor, SeekFrom::Start(offset as u64))?;
 
@@ -221,11 +221,11 @@ impl<const LEN: usize> CustomSerialize for Page<LEN> {
 
     fn serialize(
         &self,
-        bufmans: std::sync::Arc<crate::models::buffered_io::BufferManagerFactory>,
+        bufmans: std::sync::Arc<crate::models::buffered_io::BufferManagerFactory<crate::models::versioning::Hash>>,
         version: crate::models::versioning::Hash,
         cursor: u64,
     ) -> Result<u32, crate::models::buffered_io::BufIoError> {
-        let bufman = bufmans.get(&version)?;
+        let bufman = bufmans.get(version)?;
         let start_offset = bufman.cursor_position(cursor)? as u32;
 
         // If the chunk is already serialised then do an early return
@@ -260,7 +260,7 @@ mod page_tests {
     use tempfile::{tempdir, TempDir};
 
     fn setup_test() -> (
-        Arc<BufferManagerFactory>,
+        Arc<BufferManagerFactory<Hash>>,
         Arc<BufferManager>,
         u64,
         TempDir,
@@ -271,11 +271,12 @@ mod page_tests {
         let dir = tempdir().unwrap();
         let bufmans = Arc::new(BufferManagerFactory::new(
             dir.as_ref().into(),
-            |root, ver| root.join(format!("{}.index", **ver)),
+            |root, ver: &Hash| root.join(format!("{}.index", **ver)),
+            1.0,
         ));
 
         let cache = Arc::new(NodeRegistry::new(1000, bufmans.clone()));
-        let bufman = bufmans.get(&root_version_id).unwrap();
+        let bufman = bufmans.get(root_version_id).unwrap();
         let cursor = bufman.open_cursor().unwrap();
         (bufmans, bufman, cursor, dir, cache)
     }
@@ -6,29 +6,62 @@ use crate::distance::DistanceFunction;
 use crate::macros::key;
 use crate::models::buffered_io::*;
 use crate::models::common::*;
-use crate::models::dot_product::dot_product_f32;
 use crate::models::embedding_persist::*;
 use crate::models::file_persist::*;
 use crate::models::fixedset::PerformantFixedSet;
+use crate::models::lazy_load::FileIndex;
+use crate::models::meta_persist::{
+    persist_branch_root, retrieve_branch_root, retrieve_node_locations, update_current_version,
+};
 use crate::models::prob_lazy_load::lazy_item::ProbLazyItem;
 use crate::models::prob_node::ProbNode;
 use crate::models::prob_node::SharedNode;
+use crate::models::rpc::Filter;
 use crate::models::types::*;
-use crate::models::versioning::Hash;
+use crate::models::versioning::{BranchId, Hash};
+use crate::quantization::product::TrainingStats;
+use crate::quantization::reservoir::ReservoirSampler;
 use crate::quantization::{Quantization, StorageType};
 use crate::storage::Storage;
-use lmdb::{Transaction, WriteFlags};
-use rand::Rng;
+use lmdb::{Cursor, Transaction, WriteFlags};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use rayon::iter::{IndexedParallelIterator, IntoParallelIterator, ParallelIterator};
 use smallvec::SmallVec;
 use std::array::TryFromSliceError;
-use std::fs::File;
-use std::io::SeekFrom;
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::path::Path;
 use std::ptr;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::sync::RwLock;
+use std::time::Instant;
 
+/// Picks the highest level `id` is inserted up to. Without a `level_seed`
+/// on `dense_index` the level is random, with one it only depends on the seed
+/// and `id`, so the same vectors end up on the same levels whatever order
+/// they are indexed in.
+///
+/// Fails if the level isn't one of the `num_layers` levels above level 0.
+pub fn max_insert_level(
+    dense_index: &DenseIndex,
+    id: &VectorId,
+    num_layers: u8,
+) -> Result<HNSWLevel, WaCustomError> {
+    let x: f32 = match dense_index.level_seed {
+        Some(seed) => StdRng::seed_from_u64(seed ^ id.0.wrapping_mul(0x9e37_79b9_7f4a_7c15)).gen(),
+        None => rand::random(),
+    };
+    let level = get_max_insert_level(x.into(), dense_index.levels_prob.clone());
+    // a negative level is as far out of range as one past the top
+    HNSWLevel::new(level.try_into().unwrap_or(u32::MAX), num_layers)
+}
+
+/// Creates the root node of every level. The root vector is random, pass
+/// `seed` to make it (and hence the graph built on it) reproducible.
 pub fn create_root_node(
     quantization_metric: &QuantizationMetric,
     storage_type: StorageType,
@@ -38,27 +71,28 @@ pub fn create_root_node(
     index_manager: Arc<BufferManagerFactory<Hash>>,
     values_range: (f32, f32),
     hnsw_params: &HNSWHyperParams,
+    seed: Option<u64>,
 ) -> Result<SharedNode, WaCustomError> {
+    let mut rng = match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
     let vec = (0..dim)
-        .map(|_| {
-            let mut rng = rand::thread_rng();
-
-            let random_number: f32 = rng.gen_range(values_range.0..values_range.1);
-            random_number
-        })
+        .map(|_| rng.gen_range(values_range.0..values_range.1))
         .collect::<Vec<f32>>();
     let vec_hash = VectorId(u64::MAX);
 
     let vector_list = Arc::new(quantization_metric.quantize(&vec, storage_type, values_range)?);
 
     let mut prop_file_guard = prop_file.write().unwrap();
-    let location = write_prop_to_file(&vec_hash, vector_list.clone(), &mut *prop_file_guard)?;
+    let location = write_prop_to_file(&vec_hash, vector_list.clone(), None, &mut *prop_file_guard)?;
     drop(prop_file_guard);
 
     let prop = Arc::new(NodeProp {
         id: vec_hash,
         value: vector_list.clone(),
-        location,
+        location: PropLocation::new(location),
+        source: None,
     });
 
     let mut root = ProbLazyItem::new(
@@ -101,6 +135,18 @@ pub fn create_root_node(
     Ok(root)
 }
 
+/// Searches from `cur_entry` down to level 0. The entry node may still be
+/// pending (known only by its file index), in which case it is loaded through
+/// the index cache.
+///
+/// `filter` is applied while traversing level 0, the upper levels are only
+/// used to find an entry point. Results from those levels can still fail the
+/// filter, `finalize_ann_results` drops them.
+///
+/// Once `deadline` passes, the traversal stops following neighbors further
+/// and returns the best candidates found so far. Every remaining level is
+/// still descended through the neighbors of its entry node, so level 0
+/// results are returned even if the deadline passed before the search began.
 pub fn ann_search(
     config: &Config,
     dense_index: Arc<DenseIndex>,
@@ -108,35 +154,50 @@ pub fn ann_search(
     cur_entry: SharedNode,
     cur_level: HNSWLevel,
     hnsw_params: &HNSWHyperParams,
+    filter: Option<&Filter>,
+    deadline: Option<Instant>,
 ) -> Result<Vec<(SharedNode, MetricResult)>, WaCustomError> {
     let fvec = vector_emb.quantized_vec.clone();
-    let mut skipm = PerformantFixedSet::new(if cur_level.0 == 0 {
-        hnsw_params.level_0_neighbors_count
-    } else {
-        hnsw_params.neighbors_count
-    });
-    skipm.insert(vector_emb.hash_vec.0 as u32);
 
+    let cur_entry = resolve_node(&dense_index, cur_entry)?;
     let cur_node = unsafe { &*cur_entry }.try_get_data(&dense_index.cache)?;
 
-    let z = traverse_find_nearest(
-        config,
-        &dense_index,
-        cur_entry,
-        &fvec,
-        &mut 0,
-        &mut skipm,
-        cur_level,
-        false,
-        true,
-        hnsw_params.ef_search,
-        hnsw_params.ef_construction,
-    )?;
+    let z = if cur_level.0 == 0 {
+        let matcher = filter
+            .map(|filter| FilterMatcher::new(&dense_index, filter))
+            .transpose()?;
+        search_level_0(
+            &dense_index,
+            cur_entry,
+            &fvec,
+            &vector_emb.hash_vec,
+            hnsw_params.ef_search as usize,
+            matcher.as_ref(),
+            deadline,
+        )?
+    } else {
+        let mut skipm = PerformantFixedSet::new(hnsw_params.neighbors_count);
+        skipm.insert(vector_emb.hash_vec.0 as u32);
+        traverse_find_nearest(
+            config,
+            &dense_index,
+            cur_entry,
+            &fvec,
+            &mut 0,
+            &mut skipm,
+            cur_level,
+            false,
+            true,
+            hnsw_params.ef_search,
+            hnsw_params.ef_construction,
+            hnsw_params.ef_search as usize,
+            None,
+            deadline,
+        )?
+    };
 
     let mut z = if z.is_empty() {
-        let dist = dense_index
-            .distance_metric
-            .calculate(&fvec, &cur_node.prop.value)?;
+        let dist = dense_index.distance(&fvec, &cur_node.prop.value)?;
 
         vec![(cur_entry, dist)]
     } else {
@@ -153,6 +214,8 @@ pub fn ann_search(
                 .get_child(),
             HNSWLevel(cur_level.0 - 1),
             hnsw_params,
+            filter,
+            deadline,
         )?;
 
         z.extend(results);
@@ -161,11 +224,67 @@ pub fn ann_search(
     Ok(z)
 }
 
+/// Returns `node` itself if it's loaded, otherwise the node loaded through the
+/// index cache from the file index the pending item points at.
+fn resolve_node(dense_index: &DenseIndex, node: SharedNode) -> Result<SharedNode, WaCustomError> {
+    let item = unsafe { &*node };
+    if !item.is_pending() {
+        return Ok(node);
+    }
+    let Some(file_index) = item.get_file_index() else {
+        return Err(WaCustomError::NodeError(
+            "pending node has no file index".to_string(),
+        ));
+    };
+    dense_index
+        .cache
+        .get_object(file_index.clone())
+        .map_err(|e| {
+            log::error!("Error loading node at {:?}: {}", file_index, e);
+            e.into()
+        })
+}
+
+/// Fetches the neighbors of `vector_id` on every HNSW level, the returned
+/// list is indexed by level and holds `None` for the levels the vector
+/// wasn't inserted into. Neighbors are ordered best scoring first.
+///
+/// Nodes are looked up by id, so a vector is found even if no other node
+/// links to it anymore.
 pub fn vector_fetch(
-    _dense_index: Arc<DenseIndex>,
-    _vector_id: VectorId,
+    dense_index: Arc<DenseIndex>,
+    vector_id: VectorId,
 ) -> Result<Vec<Option<(VectorId, Vec<(VectorId, MetricResult)>)>>, WaCustomError> {
-    Ok(Vec::new())
+    let num_layers = dense_index.hnsw_params.read().unwrap().num_layers;
+    let mut levels = vec![None; num_layers as usize + 1];
+
+    for level in 0..=num_layers {
+        let Some(item) = dense_index.find_node(&vector_id, HNSWLevel(level))? else {
+            continue;
+        };
+        let visible = dense_index.get_visible_version(item)?;
+        let node = unsafe { &*visible }.try_get_data(&dense_index.cache)?;
+        let mut neighbors = Vec::new();
+        for neighbor in node.get_neighbors_raw().iter() {
+            let Some((_, neighbor, dist)) = (unsafe { neighbor.load(Ordering::Relaxed).as_ref() })
+            else {
+                continue;
+            };
+            // the slot only keeps the low bits of the id, and the root node
+            // isn't a vector of the collection
+            let id = unsafe { &**neighbor }
+                .try_get_data(&dense_index.cache)?
+                .get_id()
+                .clone();
+            if id.0 != u64::MAX {
+                neighbors.push((id, *dist));
+            }
+        }
+        neighbors.sort_by(|(_, a), (_, b)| b.score().total_cmp(&a.score()));
+        levels[level as usize] = Some((vector_id.clone(), neighbors));
+    }
+
+    Ok(levels)
 }
 
 pub fn finalize_ann_results(
@@ -173,21 +292,43 @@ pub fn finalize_ann_results(
     results: Vec<(SharedNode, MetricResult)>,
     query: &[f32],
     k: Option<usize>,
+    filter: Option<&Filter>,
 ) -> Result<Vec<(VectorId, MetricResult)>, WaCustomError> {
+    let txn =
+        dense_index.lmdb.env.begin_ro_txn().map_err(|e| {
+            WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e))
+        })?;
+    let db = *dense_index.lmdb.db;
+    let mut live = Vec::with_capacity(results.len());
+    for (node, dist) in results {
+        let node = resolve_node(&dense_index, node)?;
+        let data = unsafe { &*node }.try_get_data(&dense_index.cache)?;
+        if is_tombstoned(&txn, db, data)? {
+            continue;
+        }
+        if let Some(filter) = filter {
+            if !matches_filter(&txn, db, data.get_id(), filter)? {
+                continue;
+            }
+        }
+        live.push((node, dist));
+    }
+    txn.abort();
+    let results = live;
     let filtered = remove_duplicates_and_filter(results, k);
+    let distance_metric = dense_index.distance_metric.clone().get().clone();
     let mut results = Vec::new();
 
     for (id, _) in filtered {
-        let raw = get_embedding_by_id(dense_index.clone(), &id)?;
-        let dp = dot_product_f32(query, &raw.raw_vec);
-        let mag_query = query.iter().map(|x| x * x).sum::<f32>().sqrt();
-        let mag_raw = raw.raw_vec.iter().map(|x| x * x).sum::<f32>().sqrt();
-        let cs = dp / (mag_query * mag_raw);
-        results.push((id, MetricResult::CosineSimilarity(CosineSimilarity(cs))));
+        // a node whose embedding is gone can't be rescored
+        let Some(raw) = get_embedding_by_id(dense_index.clone(), &id)? else {
+            continue;
+        };
+        results.push((id, distance_metric.calculate_raw(query, &raw.raw_vec)));
     }
     results.sort_unstable_by(|(_, a), (_, b)| {
-        b.get_value()
-            .partial_cmp(&a.get_value())
+        b.score()
+            .partial_cmp(&a.score())
             .unwrap_or(std::cmp::Ordering::Greater)
     });
     if let Some(k) = k {
@@ -196,6 +337,145 @@ pub fn finalize_ann_results(
     Ok(results)
 }
 
+/// A vector scored by `exact_search`, ordered by its score.
+struct ExactMatch {
+    id: VectorId,
+    result: MetricResult,
+}
+
+impl PartialEq for ExactMatch {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == std::cmp::Ordering::Equal
+    }
+}
+
+impl Eq for ExactMatch {}
+
+impl PartialOrd for ExactMatch {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ExactMatch {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.result.score().total_cmp(&other.result.score())
+    }
+}
+
+/// Scores the embeddings stored on the current branch against `query` with
+/// the collection's distance metric and returns the `k` best, scored the
+/// same way `finalize_ann_results` rescores approximate results, so the two
+/// can be compared directly. With `indexed_only`, embeddings still waiting
+/// to be indexed are left out, as the graph search can't return them yet.
+///
+/// Streams every raw embeddings file the branch reads from, so it's only
+/// meant for small collections and as the ground truth when measuring the
+/// recall of the graph search.
+pub fn exact_search(
+    dense_index: &DenseIndex,
+    query: &[f32],
+    k: usize,
+    filter: Option<&Filter>,
+    indexed_only: bool,
+) -> Result<Vec<(VectorId, MetricResult)>, WaCustomError> {
+    if k == 0 {
+        return Ok(Vec::new());
+    }
+    let current_version = dense_index.get_current_version();
+    let branch = dense_index.branch_of(current_version)?;
+    let distance_metric = dense_index.distance_metric.clone().get().clone();
+    let env = dense_index.lmdb.env.clone();
+    let db = dense_index.lmdb.db.clone();
+
+    let txn = env
+        .begin_ro_txn()
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e)))?;
+    let pending = if indexed_only {
+        match txn.get(*db, &"next_embedding_offset") {
+            Ok(bytes) => Some(
+                EmbeddingOffset::deserialize(bytes)
+                    .map_err(|e| WaCustomError::DeserializationError(e.to_string()))?,
+            ),
+            // nothing was indexed yet
+            Err(lmdb::Error::NotFound) => Some(EmbeddingOffset {
+                version: current_version,
+                offset: 0,
+            }),
+            Err(err) => return Err(WaCustomError::DatabaseError(err.to_string())),
+        }
+    } else {
+        None
+    };
+
+    // the raw embeddings files the branch stores embeddings in
+    let start_key = key!(e:branch, VectorId(0));
+    let mut versions = HashSet::new();
+    {
+        let mut cursor = txn
+            .open_ro_cursor(*db)
+            .map_err(|e| WaCustomError::DatabaseError(format!("Failed to open cursor: {}", e)))?;
+        for (key, value) in cursor.iter_from(&start_key) {
+            if key.len() != 17 || key[..9] != start_key[..9] {
+                break;
+            }
+            let embedding_offset = EmbeddingOffset::deserialize(value)
+                .map_err(|e| WaCustomError::DeserializationError(e.to_string()))?;
+            versions.insert(embedding_offset.version);
+        }
+    }
+
+    // the `k` best matches so far, the worst on top
+    let mut best = BinaryHeap::new();
+    for version in versions {
+        let bufman = dense_index.vec_raw_manager.get(version)?;
+        let mut offset = 0;
+        for item in EmbeddingReader::new(bufman, 0)? {
+            let (embedding, next) = item?;
+            let embedding_offset = std::mem::replace(&mut offset, next);
+            if pending.as_ref().is_some_and(|pending| {
+                pending.version == version && embedding_offset >= pending.offset
+            }) {
+                // the rest of the file is pending too
+                break;
+            }
+
+            // only the embedding the branch stores under the id counts, not
+            // one it was overwritten with or one of another branch
+            let stored = match txn.get(*db, &key!(e:branch, embedding.hash_vec)) {
+                Ok(bytes) => EmbeddingOffset::deserialize(bytes)
+                    .map_err(|e| WaCustomError::DeserializationError(e.to_string()))?,
+                Err(lmdb::Error::NotFound) => continue,
+                Err(err) => return Err(WaCustomError::DatabaseError(err.to_string())),
+            };
+            if stored.version != version || stored.offset != embedding_offset {
+                continue;
+            }
+            // the same metadata the graph search filters on
+            if let Some(filter) = filter {
+                if !matches_filter(&txn, *db, &embedding.hash_vec, filter)? {
+                    continue;
+                }
+            }
+
+            let result = distance_metric.calculate_raw(query, &embedding.raw_vec);
+            best.push(Reverse(ExactMatch {
+                id: embedding.hash_vec,
+                result,
+            }));
+            if best.len() > k {
+                best.pop();
+            }
+        }
+    }
+
+    Ok(best
+        .into_sorted_vec()
+        .into_iter()
+        .map(|Reverse(found)| (found.id, found.result))
+        .collect())
+}
+
 /// Retrieves a raw embedding vector from the vector store by its ID.
 ///
 /// This function performs the following steps to retrieve the embedding:
@@ -212,14 +492,14 @@ pub fn finalize_ann_results(
 ///
 /// # Returns
 ///
-/// * `Ok(RawVectorEmbedding)` - On success, returns the embedding associated with the given `vector_id`.
+/// * `Ok(Some(RawVectorEmbedding))` - On success, returns the embedding associated with the given `vector_id`.
+/// * `Ok(None)` - If no embedding was stored under `vector_id`.
 /// * `Err(WaCustomError)` - On failure, returns a custom error indicating the reason for the failure.
 ///
 /// # Errors
 ///
 /// This function may return an `Err` variant of `WaCustomError` in cases where:
 /// * There is an error beginning the LMDB transaction (e.g., database access issues).
-/// * The `vector_id` does not exist in the database, leading to a failure when retrieving the serialized offset.
 /// * Deserialization of the embedding offset fails.
 /// * There are issues with accessing or reading from the buffer manager.
 ///
@@ -233,7 +513,8 @@ pub fn finalize_ann_results(
 /// let dense_index = Arc::new(DenseIndex::new());
 /// let vector_id = VectorId::Int(42); // Example vector ID
 /// match get_embedding_by_id(dense_index.clone(), vector_id) {
-///     Ok(embedding) => println!("Embedding: {:?}", embedding),
+///     Ok(Some(embedding)) => println!("Embedding: {:?}", embedding),
+///     Ok(None) => println!("No such embedding"),
 ///     Err(err) => eprintln!("Error retrieving embedding: {:?}", err),
 /// }
 /// ```
@@ -249,7 +530,8 @@ pub fn finalize_ann_results(
 pub fn get_embedding_by_id(
     dense_index: Arc<DenseIndex>,
     vector_id: &VectorId,
-) -> Result<RawVectorEmbedding, WaCustomError> {
+) -> Result<Option<RawVectorEmbedding>, WaCustomError> {
+    let branch = dense_index.branch_of(dense_index.get_current_version())?;
     let env = dense_index.lmdb.env.clone();
     let db = dense_index.lmdb.db.clone();
 
@@ -257,11 +539,18 @@ pub fn get_embedding_by_id(
         .begin_ro_txn()
         .map_err(|e| WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e)))?;
 
-    let embedding_key = key!(e:vector_id);
+    let embedding_key = key!(e:branch, vector_id);
 
-    let offset_serialized = txn.get(*db, &embedding_key).map_err(|e| {
-        WaCustomError::DatabaseError(format!("Failed to get serialized embedding offset: {}", e))
-    })?;
+    let offset_serialized = match txn.get(*db, &embedding_key) {
+        Ok(bytes) => bytes,
+        Err(lmdb::Error::NotFound) => return Ok(None),
+        Err(e) => {
+            return Err(WaCustomError::DatabaseError(format!(
+                "Failed to get serialized embedding offset: {}",
+                e
+            )))
+        }
+    };
 
     let embedding_offset = EmbeddingOffset::deserialize(offset_serialized)
         .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
@@ -270,106 +559,231 @@ pub fn get_embedding_by_id(
 
     let offset = embedding_offset.offset;
     let current_version = embedding_offset.version;
-    let bufman = dense_index.vec_raw_manager.get(current_version)?;
-    let (embedding, _next) = read_embedding(bufman.clone(), offset)?;
+    let bufmans = dense_index.vec_raw_bufmans(current_version)?;
+    let (embedding, _next) = read_embedding_replicated(&bufmans, offset)?;
 
-    Ok(embedding)
+    Ok(Some(embedding))
 }
 
-// fn auto_config_storage_type(dense_index: Arc<DenseIndex>, vectors: &[&[f32]]) {
-//     let threshold = 0.0;
-//     let iterations = 32;
+/// Checks whether an embedding was stored under `vector_id`, without reading
+/// it from the raw vectors file.
+pub fn vector_exists(
+    dense_index: &DenseIndex,
+    vector_id: &VectorId,
+) -> Result<bool, WaCustomError> {
+    let branch = dense_index.branch_of(dense_index.get_current_version())?;
+    let env = dense_index.lmdb.env.clone();
+    let db = dense_index.lmdb.db.clone();
 
-//     let vec = concat_vectors(vectors);
+    let txn = env
+        .begin_ro_txn()
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e)))?;
 
-//     // First iteration with k = 16
-//     let initial_centroids_16 = generate_initial_centroids(&vec, 16);
-//     let (_, counts_16) = kmeans(&vec, &initial_centroids_16, iterations);
-//     let storage_type = if should_continue(&counts_16, threshold, 8) {
-//         // Second iteration with k = 8
-//         let initial_centroids_8 = generate_initial_centroids(&vec, 8);
-//         let (_, counts_8) = kmeans(&vec, &initial_centroids_8, iterations);
-//         if should_continue(&counts_8, threshold, 4) {
-//             // Third iteration with k = 4
-//             let initial_centroids_4 = generate_initial_centroids(&vec, 4);
-//             let (_, counts_4) = kmeans(&vec, &initial_centroids_4, iterations);
+    let embedding_key = key!(e:branch, vector_id);
 
-//             if should_continue(&counts_4, threshold, 2) {
-//                 StorageType::SubByte(1)
-//             } else {
-//                 StorageType::SubByte(2)
-//             }
-//         } else {
-//             // StorageType::SubByte(3)
-//             StorageType::UnsignedByte
-//         }
-//     } else {
-//         StorageType::UnsignedByte
-//     };
+    match txn.get(*db, &embedding_key) {
+        Ok(_) => Ok(true),
+        Err(lmdb::Error::NotFound) => Ok(false),
+        Err(e) => Err(WaCustomError::DatabaseError(e.to_string())),
+    }
+}
 
-//     dense_index.storage_type.update_shared(storage_type);
-// }
+/// Rebuilds the HNSW graph of `dense_index` from its raw embeddings, e.g.
+/// after its quantization or distance metric changed.
+///
+/// The graph is built under a new version starting from a fresh root, and
+/// the root is only swapped once all of its nodes are written. Searches use
+/// the old graph until then, and the old version's files are left in place,
+/// so a failed reindex leaves the index as it was.
+pub fn reindex(config: &Config, dense_index: Arc<DenseIndex>) -> Result<(), WaCustomError> {
+    if !dense_index
+        .current_open_transaction
+        .load(Ordering::SeqCst)
+        .is_null()
+    {
+        return Err(WaCustomError::LockError(
+            "Cannot reindex while there's an ongoing transaction".to_string(),
+        ));
+    }
+    branch_from_rollback(config, &dense_index)?;
 
-pub fn insert_embedding(
-    bufman: Arc<BufferManager>,
+    let branch = dense_index.current_branch()?;
+    let embeddings = collect_embeddings(
+        &dense_index,
+        BranchId::new(branch.get_branch_name()),
+        |_| true,
+    )?;
+
+    let (version, version_number) = dense_index
+        .vcs
+        .add_next_version(branch.get_branch_name())
+        .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
+
+    let root = build_graph(
+        config,
+        &dense_index,
+        embeddings,
+        version,
+        *version_number as u16,
+    )?;
+
+    // every embedding is indexed now, new uploads start from the new version
+    start_indexed_version(&dense_index, version)?;
+    dense_index.set_root_vec(root);
+
+    Ok(())
+}
+
+/// Creates the branch `branch_name` off `from_version`, along with its own
+/// graph built from the embeddings stored as of that version. The current
+/// branch is left checked out.
+///
+/// The branch gets its own copy of the embedding offsets as of
+/// `from_version`, so upserts on either branch aren't seen by the other.
+pub fn create_branch(
+    config: &Config,
     dense_index: Arc<DenseIndex>,
-    emb: &RawVectorEmbedding,
-    current_version: Hash,
-) -> Result<(), WaCustomError> {
-    let env = dense_index.lmdb.env.clone();
-    let db = dense_index.lmdb.db.clone();
+    branch_name: &str,
+    from_version: Hash,
+) -> Result<Hash, WaCustomError> {
+    if !dense_index
+        .current_open_transaction
+        .load(Ordering::SeqCst)
+        .is_null()
+    {
+        return Err(WaCustomError::LockError(
+            "Cannot create a branch while there's an ongoing transaction".to_string(),
+        ));
+    }
 
-    let mut txn = env
-        .begin_rw_txn()
-        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e)))?;
+    let from_branch = dense_index.branch_of(from_version)?;
+    let version = dense_index.vcs.create_branch(branch_name, from_version)?;
+    let ancestry = dense_index
+        .vcs
+        .get_version_ancestry(from_version)
+        .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
+    let branch_id = BranchId::new(branch_name);
+    copy_embedding_offsets(&dense_index, from_branch, branch_id, |version| {
+        ancestry.contains(&version)
+    })?;
+    let embeddings = collect_embeddings(&dense_index, branch_id, |_| true)?;
 
-    let count_unindexed = match txn.get(*db, &"count_unindexed") {
-        Ok(bytes) => {
-            let bytes = bytes.try_into().map_err(|e: TryFromSliceError| {
-                WaCustomError::DeserializationError(e.to_string())
-            })?;
-            u32::from_le_bytes(bytes)
-        }
-        Err(lmdb::Error::NotFound) => 0,
-        Err(err) => return Err(WaCustomError::DatabaseError(err.to_string())),
-    };
+    let root = build_graph(config, &dense_index, embeddings, version, 0)?;
+    remember_branch_root(&dense_index, branch_id, root)?;
 
-    let offset = write_embedding(bufman, emb)?;
+    Ok(version)
+}
 
-    let offset = EmbeddingOffset {
-        version: current_version,
-        offset,
+/// Lets writes continue on a rolled back `dense_index`. The versions after
+/// the one it was rolled back to stay in the history of its branch, so a new
+/// branch is created off the rolled back version and checked out, and new
+/// versions are added to it. Does nothing if the index wasn't rolled back.
+///
+/// The branch is named after the rolled back branch and version, e.g.
+/// `main@3`, with a counter appended if that name is taken.
+pub fn branch_from_rollback(
+    config: &Config,
+    dense_index: &Arc<DenseIndex>,
+) -> Result<(), WaCustomError> {
+    if dense_index.rolled_back_to.read().unwrap().is_none() {
+        return Ok(());
+    }
+    let _guard = dense_index
+        .rollback_branch_lock
+        .lock()
+        .map_err(|_| WaCustomError::LockError("Failed to lock rollback branching".to_string()))?;
+    // another write branched off while this one waited for the lock
+    let Some(version) = *dense_index.rolled_back_to.read().unwrap() else {
+        return Ok(());
     };
-    let offset_serialized = offset.serialize();
 
-    let embedding_key = key!(e:emb.hash_vec);
+    let branch = dense_index.current_branch()?;
+    let base_name = format!("{}@{}", branch.get_branch_name(), version);
+    let mut branch_name = base_name.clone();
+    let mut suffix = 1;
+    while dense_index
+        .vcs
+        .branch_exists(&branch_name)
+        .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?
+    {
+        branch_name = format!("{}.{}", base_name, suffix);
+        suffix += 1;
+    }
 
-    txn.put(*db, &embedding_key, &offset_serialized, WriteFlags::empty())
-        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to put data: {}", e)))?;
+    create_branch(
+        config,
+        dense_index.clone(),
+        &branch_name,
+        dense_index.get_current_version(),
+    )?;
+    checkout_branch(config, dense_index.clone(), &branch_name)
+}
 
-    txn.put(
-        *db,
-        &"count_unindexed",
-        &(count_unindexed + 1).to_le_bytes(),
-        WriteFlags::empty(),
-    )
-    .map_err(|e| {
-        WaCustomError::DatabaseError(format!("Failed to update `count_unindexed`: {}", e))
-    })?;
+/// Switches `dense_index` to the latest version of `branch_name` and its
+/// graph, so that queries search it and new inserts extend it.
+pub fn checkout_branch(
+    config: &Config,
+    dense_index: Arc<DenseIndex>,
+    branch_name: &str,
+) -> Result<(), WaCustomError> {
+    if !dense_index
+        .current_open_transaction
+        .load(Ordering::SeqCst)
+        .is_null()
+    {
+        return Err(WaCustomError::LockError(
+            "Cannot checkout a branch while there's an ongoing transaction".to_string(),
+        ));
+    }
 
-    txn.commit().map_err(|e| {
-        WaCustomError::DatabaseError(format!("Failed to commit transaction: {}", e))
-    })?;
+    let branch_id = BranchId::new(branch_name);
+    let head = dense_index
+        .vcs
+        .get_branch_head(branch_name)
+        .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?
+        .ok_or_else(|| WaCustomError::NotFound(format!("branch `{}`", branch_name)))?;
+
+    // the graph being left must include everything uploaded to it
+    index_pending_embeddings(config, &dense_index, config.upload_process_batch_size)?;
+    let current_branch = BranchId::new(dense_index.current_branch()?.get_branch_name());
+    remember_branch_root(&dense_index, current_branch, dense_index.get_root_vec())?;
+
+    let root_file_index = retrieve_branch_root(&dense_index.lmdb, branch_id)?
+        .ok_or_else(|| WaCustomError::NotFound(format!("root of branch `{}`", branch_name)))?;
+    let root = dense_index
+        .cache
+        .get_lazy_object(root_file_index, 1000, &mut HashSet::new())?;
+
+    start_indexed_version(&dense_index, head)?;
+    dense_index.set_root_vec(root);
+    *dense_index.rolled_back_to.write().unwrap() = None;
 
     Ok(())
 }
 
-pub fn index_embeddings(
-    config: &Config,
-    dense_index: Arc<DenseIndex>,
-    upload_process_batch_size: usize,
-    serialization_table: Arc<TSHashTable<SharedNode, ()>>,
-    lazy_item_versions_table: Arc<TSHashTable<(VectorId, u16, u8), SharedNode>>,
+/// Reads the raw embeddings of `dense_index` on `branch` that were stored
+/// under a version accepted by `include`, along with where they're stored.
+fn collect_embeddings(
+    dense_index: &DenseIndex,
+    branch: BranchId,
+    include: impl Fn(Hash) -> bool,
+) -> Result<Vec<(EmbeddingOffset, RawVectorEmbedding)>, WaCustomError> {
+    let mut embeddings = Vec::new();
+    for_each_embedding(dense_index, branch, include, |offset, embedding| {
+        embeddings.push((offset, embedding));
+        Ok(())
+    })?;
+    Ok(embeddings)
+}
+
+/// Hands the raw embeddings of `dense_index` on `branch` that were stored
+/// under a version accepted by `include` to `f`, one at a time, along with
+/// where they're stored.
+fn for_each_embedding(
+    dense_index: &DenseIndex,
+    branch: BranchId,
+    include: impl Fn(Hash) -> bool,
+    mut f: impl FnMut(EmbeddingOffset, RawVectorEmbedding) -> Result<(), WaCustomError>,
 ) -> Result<(), WaCustomError> {
     let env = dense_index.lmdb.env.clone();
     let db = dense_index.lmdb.db.clone();
@@ -377,877 +791,4639 @@ pub fn index_embeddings(
     let txn = env
         .begin_ro_txn()
         .map_err(|e| WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e)))?;
-
-    let mut count_indexed = match txn.get(*db, &"count_indexed") {
-        Ok(bytes) => {
-            let bytes = bytes.try_into().map_err(|e: TryFromSliceError| {
-                WaCustomError::DeserializationError(e.to_string())
-            })?;
-            u32::from_le_bytes(bytes)
+    let mut cursor = txn
+        .open_ro_cursor(*db)
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to open cursor: {}", e)))?;
+
+    let start_key = key!(e:branch, VectorId(0));
+    for (key, value) in cursor.iter_from(&start_key) {
+        // the keys of the branch's embeddings share the first 9 bytes
+        if key.len() != 17 || key[..9] != start_key[..9] {
+            break;
         }
-        Err(lmdb::Error::NotFound) => 0,
-        Err(err) => return Err(WaCustomError::DatabaseError(err.to_string())),
-    };
-    let mut count_unindexed = match txn.get(*db, &"count_unindexed") {
-        Ok(bytes) => {
-            let bytes = bytes.try_into().map_err(|e: TryFromSliceError| {
-                WaCustomError::DeserializationError(e.to_string())
-            })?;
-            u32::from_le_bytes(bytes)
+        let embedding_offset = EmbeddingOffset::deserialize(value)
+            .map_err(|e| WaCustomError::DeserializationError(e.to_string()))?;
+        if !include(embedding_offset.version) {
+            continue;
         }
-        Err(lmdb::Error::NotFound) => 0,
-        Err(err) => return Err(WaCustomError::DatabaseError(err.to_string())),
-    };
-
-    let embedding_offset = match txn.get(*db, &"next_embedding_offset") {
-        Ok(bytes) => EmbeddingOffset::deserialize(bytes)
-            .map_err(|e| WaCustomError::DeserializationError(e.to_string()))?,
-        Err(err) => return Err(WaCustomError::DatabaseError(err.to_string())),
-    };
-    let version = embedding_offset.version;
-    let version_hash = dense_index
-        .vcs
-        .get_version_hash(&version, &txn)
-        .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?
-        .expect("Current version hash not found");
-    let version_number = *version_hash.version as u16;
+        let bufmans = dense_index.vec_raw_bufmans(embedding_offset.version)?;
+        let (embedding, _next) = read_embedding_replicated(&bufmans, embedding_offset.offset)?;
+        f(embedding_offset, embedding)?;
+    }
 
-    txn.abort();
+    Ok(())
+}
 
-    let hnsw_params = dense_index.hnsw_params.clone();
-    let hnsw_params_guard = hnsw_params.read().unwrap();
+/// The ids of the embeddings stored on `branch`, along with where the latest
+/// embedding of each is stored, in id order.
+pub fn embedding_offsets(
+    dense_index: &DenseIndex,
+    branch: BranchId,
+) -> Result<Vec<(VectorId, EmbeddingOffset)>, WaCustomError> {
+    let env = dense_index.lmdb.env.clone();
+    let db = dense_index.lmdb.db.clone();
 
-    let mut index = |embeddings: Vec<RawVectorEmbedding>,
-                     next_offset: u32|
-     -> Result<(), WaCustomError> {
-        let mut quantization_arc = dense_index.quantization_metric.clone();
-        let quantization = quantization_arc.get();
+    let txn = env
+        .begin_ro_txn()
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e)))?;
+    let mut cursor = txn
+        .open_ro_cursor(*db)
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to open cursor: {}", e)))?;
+
+    let start_key = key!(e:branch, VectorId(0));
+    let mut offsets = Vec::new();
+    for (key, value) in cursor.iter_from(&start_key) {
+        if key.len() != 17 || key[..9] != start_key[..9] {
+            break;
+        }
+        let embedding_offset = EmbeddingOffset::deserialize(value)
+            .map_err(|e| WaCustomError::DeserializationError(e.to_string()))?;
+        let id = VectorId(u64::from_le_bytes(key[9..].try_into().unwrap()));
+        offsets.push((id, embedding_offset));
+    }
 
-        let results: Vec<()> = embeddings
-            .into_iter()
-            .map(|raw_emb| {
-                let lp = &dense_index.levels_prob;
-                let iv = get_max_insert_level(rand::random::<f32>().into(), lp.clone());
-                let quantized_vec = Arc::new(
-                    quantization
-                        .quantize(
-                            &raw_emb.raw_vec,
-                            dense_index.storage_type.clone().get().clone(),
-                            *dense_index.values_range.read().unwrap(),
-                        )
-                        .expect("Quantization failed"),
-                );
-                let mut prop_file_guard = dense_index.prop_file.write().unwrap();
-                let location = write_prop_to_file(
-                    &raw_emb.hash_vec,
-                    quantized_vec.clone(),
-                    &mut *prop_file_guard,
-                )
-                .expect("failed to write prop");
-                drop(prop_file_guard);
-                let prop = Arc::new(NodeProp {
-                    id: raw_emb.hash_vec.clone(),
-                    value: quantized_vec.clone(),
-                    location,
-                });
-                let embedding = QuantizedVectorEmbedding {
-                    quantized_vec,
-                    hash_vec: raw_emb.hash_vec,
-                };
+    Ok(offsets)
+}
 
-                let current_level = HNSWLevel(iv.try_into().unwrap());
+/// Gives the branch `to` the embeddings of the branch `from` that were stored
+/// under a version accepted by `include`.
+fn copy_embedding_offsets(
+    dense_index: &DenseIndex,
+    from: BranchId,
+    to: BranchId,
+    include: impl Fn(Hash) -> bool,
+) -> Result<(), WaCustomError> {
+    let env = dense_index.lmdb.env.clone();
+    let db = dense_index.lmdb.db.clone();
 
-                let mut current_entry = dense_index.get_root_vec();
+    let mut txn = env
+        .begin_rw_txn()
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e)))?;
 
-                loop {
-                    let data = unsafe { &*current_entry }
-                        .try_get_data(&dense_index.cache)
-                        .expect("Unable to load data");
-                    if data.hnsw_level.0 > current_level.0 {
-                        current_entry = data.get_child();
-                    } else if data.hnsw_level == current_level {
-                        break;
-                    } else {
-                        panic!("missing node");
-                    }
-                }
+    let start_key = key!(e:from, VectorId(0));
+    let mut copied = Vec::new();
+    {
+        let mut cursor = txn
+            .open_ro_cursor(*db)
+            .map_err(|e| WaCustomError::DatabaseError(format!("Failed to open cursor: {}", e)))?;
+        for (key, value) in cursor.iter_from(&start_key) {
+            if key.len() != 17 || key[..9] != start_key[..9] {
+                break;
+            }
+            let embedding_offset = EmbeddingOffset::deserialize(value)
+                .map_err(|e| WaCustomError::DeserializationError(e.to_string()))?;
+            if include(embedding_offset.version) {
+                let id = VectorId(u64::from_le_bytes(key[9..].try_into().unwrap()));
+                copied.push((id, value.to_vec()));
+            }
+        }
+    }
 
-                index_embedding(
-                    config,
-                    dense_index.clone(),
-                    ptr::null_mut(),
-                    embedding,
-                    prop,
-                    current_entry,
-                    current_level,
-                    version,
-                    version_number,
-                    serialization_table.clone(),
-                    lazy_item_versions_table.clone(),
-                    &hnsw_params_guard,
-                    2,
-                )
-                .expect("index_embedding failed");
-            })
-            .collect();
+    for (id, value) in copied {
+        txn.put(*db, &key!(e:to, id), &value, WriteFlags::empty())
+            .map_err(|e| WaCustomError::DatabaseError(format!("Failed to put data: {}", e)))?;
+    }
+    txn.commit()
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to commit transaction: {}", e)))
+}
 
-        let batch_size = results.len() as u32;
-        count_indexed += batch_size;
-        count_unindexed -= batch_size;
+/// Indexes `embeddings` into a new graph under `version`, returning its root
+/// once all of its nodes are written.
+fn build_graph(
+    config: &Config,
+    dense_index: &Arc<DenseIndex>,
+    embeddings: Vec<(EmbeddingOffset, RawVectorEmbedding)>,
+    version: Hash,
+    version_number: u16,
+) -> Result<SharedNode, WaCustomError> {
+    let hnsw_params = dense_index.hnsw_params.read().unwrap().clone();
+    let quantization = dense_index.quantization_metric.clone().get().clone();
+    let storage_type = *dense_index.storage_type.clone().get();
+    let values_range = *dense_index.values_range.read().unwrap();
+
+    let root = create_root_node(
+        &quantization,
+        storage_type,
+        dense_index.dim,
+        dense_index.prop_file.clone(),
+        version,
+        dense_index.index_manager.clone(),
+        values_range,
+        &hnsw_params,
+        None,
+    )?;
 
-        let mut txn = env.begin_rw_txn().map_err(|e| {
-            WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e))
-        })?;
+    let serialization_table = Arc::new(TSHashTable::new(16));
+    let lazy_item_versions_table = Arc::new(TSHashTable::new(16));
 
-        let next_embedding_offset = EmbeddingOffset {
-            version,
-            offset: next_offset,
+    for (offset, emb) in embeddings {
+        let max_level = max_insert_level(dense_index, &emb.hash_vec, hnsw_params.num_layers)?;
+        let quantized_vec =
+            Arc::new(quantization.quantize(&emb.raw_vec, storage_type, values_range)?);
+        let source = EmbeddingSource {
+            version: offset.version,
+            offset: Some(offset.offset),
         };
-        let next_embedding_offset_serialized = next_embedding_offset.serialize();
 
-        txn.put(
-            *db,
-            &"next_embedding_offset",
-            &next_embedding_offset_serialized,
-            WriteFlags::empty(),
-        )
-        .map_err(|e| {
-            WaCustomError::DatabaseError(format!("Failed to update `next_embedding_offset`: {}", e))
-        })?;
+        let mut prop_file_guard = dense_index.prop_file.write().unwrap();
+        let location = write_prop_to_file(
+            &emb.hash_vec,
+            quantized_vec.clone(),
+            Some(source),
+            &*prop_file_guard,
+        )?;
+        drop(prop_file_guard);
+        if let Err(err) = check_prop_file_size(location, config.prop_file.soft_size_limit) {
+            log::warn!("{}", err);
+        }
 
-        txn.put(
-            *db,
-            &"count_indexed",
-            &count_indexed.to_le_bytes(),
-            WriteFlags::empty(),
-        )
-        .map_err(|e| {
-            WaCustomError::DatabaseError(format!("Failed to update `count_indexed`: {}", e))
-        })?;
+        let prop = Arc::new(NodeProp {
+            id: emb.hash_vec.clone(),
+            value: quantized_vec.clone(),
+            location: PropLocation::new(location),
+            source: Some(source),
+        });
 
-        txn.put(
-            *db,
-            &"count_unindexed",
-            &count_unindexed.to_le_bytes(),
-            WriteFlags::empty(),
-        )
-        .map_err(|e| {
-            WaCustomError::DatabaseError(format!("Failed to update `count_unindexed`: {}", e))
-        })?;
+        index_embedding(
+            config,
+            dense_index.clone(),
+            ptr::null_mut(),
+            QuantizedVectorEmbedding {
+                quantized_vec,
+                hash_vec: emb.hash_vec,
+            },
+            prop,
+            root,
+            HNSWLevel(hnsw_params.num_layers),
+            version,
+            version_number,
+            serialization_table.clone(),
+            lazy_item_versions_table.clone(),
+            &hnsw_params,
+            max_level,
+        )?;
+    }
 
-        txn.commit().map_err(|e| {
-            WaCustomError::DatabaseError(format!("Failed to commit transaction: {}", e))
-        })?;
+    let list = Arc::into_inner(serialization_table).unwrap().to_list();
+    for (node, _) in list {
+        write_node_to_file(node, &dense_index.index_manager)?;
+    }
+    dense_index.index_manager.flush_all()?;
+    dense_index.record_node_locations()?;
 
-        Ok(())
-    };
+    Ok(root)
+}
 
-    let bufman = dense_index.vec_raw_manager.get(version)?;
+/// Returns the number of indexed and unindexed embeddings of `dense_index`.
+pub fn get_embedding_counts(dense_index: &DenseIndex) -> Result<(u32, u32), WaCustomError> {
+    let env = dense_index.lmdb.env.clone();
+    let db = dense_index.lmdb.db.clone();
 
-    let mut i = embedding_offset.offset;
-    let cursor = bufman.open_cursor()?;
-    let file_len = bufman.seek_with_cursor(cursor, SeekFrom::End(0))? as u32;
-    bufman.seek_with_cursor(cursor, SeekFrom::Start(0))?;
+    let txn = env
+        .begin_ro_txn()
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e)))?;
 
-    let mut embeddings = Vec::new();
+    let mut counts = [0u32; 2];
+    for (count, key) in counts.iter_mut().zip(["count_indexed", "count_unindexed"]) {
+        *count = match txn.get(*db, &key) {
+            Ok(bytes) => {
+                let bytes = bytes.try_into().map_err(|e: TryFromSliceError| {
+                    WaCustomError::DeserializationError(e.to_string())
+                })?;
+                u32::from_le_bytes(bytes)
+            }
+            Err(lmdb::Error::NotFound) => 0,
+            Err(err) => return Err(WaCustomError::DatabaseError(err.to_string())),
+        };
+    }
 
-    loop {
-        if i == file_len {
-            index(embeddings, i)?;
-            bufman.close_cursor(cursor)?;
-            break;
-        }
+    Ok((counts[0], counts[1]))
+}
 
-        let (embedding, next) = read_embedding(bufman.clone(), i)?;
-        embeddings.push(embedding);
-        i = next;
+/// Offers `vectors` to the training sample of `dense_index`, if its quantizer
+/// needs training.
+pub fn sample_for_training<'a>(
+    dense_index: &DenseIndex,
+    vectors: impl IntoIterator<Item = &'a [f32]>,
+) {
+    if !dense_index
+        .quantization_metric
+        .clone()
+        .get()
+        .needs_training()
+    {
+        return;
+    }
+    let mut sampler = dense_index.training_sample.lock().unwrap();
+    for vector in vectors {
+        sampler.offer(vector);
+    }
+}
 
-        if embeddings.len() == upload_process_batch_size {
-            index(embeddings, i)?;
-            embeddings = Vec::new();
-        }
+/// Trains the quantizer of `dense_index` on the vectors sampled so far, if it
+/// needs training. Returns whether it was trained.
+pub fn train_quantizer(dense_index: &DenseIndex) -> Result<bool, WaCustomError> {
+    let mut quantization_arc = dense_index.quantization_metric.clone();
+    let mut quantization = quantization_arc.get().clone();
+    if !quantization.needs_training() {
+        return Ok(false);
     }
 
-    Ok(())
+    let mut sampler = dense_index.training_sample.lock().unwrap();
+    let samples: Vec<&[f32]> = sampler.samples().iter().map(|v| &v[..]).collect();
+    let storage_type = dense_index.storage_type.clone().get().clone();
+    quantization.train(&samples, storage_type)?;
+    log::info!(
+        "Trained the quantizer of {} on {} of {} vectors",
+        dense_index.database_name,
+        samples.len(),
+        sampler.seen()
+    );
+    sampler.clear();
+    quantization_arc.update(quantization);
+    Ok(true)
 }
 
-pub fn index_embeddings_in_transaction(
-    ctx: Arc<AppContext>,
-    dense_index: Arc<DenseIndex>,
-    version: Hash,
-    version_number: u16,
-    transaction: &DenseIndexTransaction,
-    vecs: Vec<(u64, Vec<f32>)>,
-) -> Result<(), WaCustomError> {
-    let quantization = &*dense_index.quantization_metric;
-    let hnsw_params = dense_index.hnsw_params.clone();
-    let hnsw_params_guard = hnsw_params.read().unwrap();
-    let index = |vecs: Vec<(u64, Vec<f32>)>| {
-        for (id, values) in vecs {
-            let raw_emb = RawVectorEmbedding {
-                hash_vec: VectorId(id),
-                raw_vec: Arc::new(values),
-            };
-            transaction.post_raw_embedding(raw_emb.clone());
-            let lp = &dense_index.levels_prob;
-            let max_level = get_max_insert_level(rand::random::<f32>().into(), lp.clone());
-            let quantized_vec = Arc::new(quantization.quantize(
-                &raw_emb.raw_vec,
-                dense_index.storage_type.clone().get().clone(),
-                *dense_index.values_range.read().unwrap(),
-            )?);
+/// Retrains the product quantizer of `dense_index` on a random sample of at
+/// most `sample_size` of its raw embeddings, replacing any centroids learnt
+/// before. Returns the number of embeddings sampled and how training went.
+///
+/// Nodes already in the graph keep the codes they were quantized with, which
+/// are still decoded with the centroids they were made with.
+pub fn retrain_quantizer(
+    dense_index: &DenseIndex,
+    sample_size: usize,
+) -> Result<(usize, TrainingStats), WaCustomError> {
+    let mut quantization_arc = dense_index.quantization_metric.clone();
+    let QuantizationMetric::Product(mut product) = quantization_arc.get().clone() else {
+        return Err(WaCustomError::InvalidParams);
+    };
 
-            let mut prop_file_guard = dense_index.prop_file.write().unwrap();
-            let location = write_prop_to_file(
-                &raw_emb.hash_vec,
-                quantized_vec.clone(),
-                &mut *prop_file_guard,
-            )?;
-            drop(prop_file_guard);
+    let current_branch = dense_index.branch_of(dense_index.get_current_version())?;
+    let mut sampler = ReservoirSampler::new(sample_size);
+    for_each_embedding(
+        dense_index,
+        current_branch,
+        |_| true,
+        |_, embedding| {
+            sampler.offer(&embedding.raw_vec);
+            Ok(())
+        },
+    )?;
+    let samples: Vec<&[f32]> = sampler.samples().iter().map(|v| &v[..]).collect();
+    let storage_type = dense_index.storage_type.clone().get().clone();
+    let stats = product.train_with_stats(&samples, storage_type)?;
+    quantization_arc.update(QuantizationMetric::Product(product));
 
-            let prop = Arc::new(NodeProp {
-                id: raw_emb.hash_vec.clone(),
-                value: quantized_vec.clone(),
-                location,
-            });
+    Ok((samples.len(), stats))
+}
 
-            let embedding = QuantizedVectorEmbedding {
-                quantized_vec,
-                hash_vec: raw_emb.hash_vec,
+/// Loads the nodes of the top `levels` HNSW levels of `dense_index`, all of
+/// them if `None`, into its cache so the first queries don't have to read
+/// them from disk. Stops once the cache is full. Returns the number of nodes
+/// loaded.
+pub fn warm_cache(dense_index: &DenseIndex, levels: Option<u8>) -> Result<usize, WaCustomError> {
+    let cache = &dense_index.cache;
+    let cached_before = cache.len();
+    let mut levels_left = levels.map_or(usize::MAX, usize::from);
+    let mut level_entry = dense_index.get_root_vec();
+
+    while !level_entry.is_null() && levels_left > 0 {
+        let mut visited = HashSet::new();
+        let mut queue = VecDeque::from([level_entry]);
+        level_entry = ptr::null_mut();
+
+        while let Some(node) = queue.pop_front() {
+            if cache.len() >= cache.capacity() {
+                return Ok(cache.len() - cached_before);
+            }
+            let node = match unsafe { &*node }.get_file_index() {
+                // loaded one at a time, so the capacity check above holds
+                Some(file_index) if unsafe { &*node }.is_pending() => {
+                    cache.get_lazy_object(file_index, 1, &mut HashSet::new())?
+                }
+                _ => node,
+            };
+            if !visited.insert(node as usize) {
+                continue;
+            }
+            let Some(data) = unsafe { &*node }.get_lazy_data() else {
+                continue;
             };
+            if level_entry.is_null() {
+                level_entry = data.get_child();
+            }
+            queue.extend(data.get_neighbors());
+        }
+        levels_left -= 1;
+    }
 
-            // Start from root at highest level
-            let root_entry = dense_index.get_root_vec();
-            let highest_level = HNSWLevel(hnsw_params_guard.num_layers);
+    Ok(cache.len() - cached_before)
+}
 
-            index_embedding(
-                &ctx.config,
-                dense_index.clone(),
-                ptr::null_mut(),
-                embedding,
-                prop,
-                root_entry,
-                highest_level,
-                version,
-                version_number,
-                transaction.serialization_table.clone(),
-                transaction.lazy_item_versions_table.clone(),
-                &*hnsw_params_guard,
-                max_level as u8, // Pass max_level to let index_embedding control node creation
-            )?;
+/// Rewrites the prop file of `dense_index`, stored at `prop_file_path`, with
+/// only the props still referenced by one of its nodes, and points those
+/// nodes, in memory and on disk, at their new locations. Returns the number
+/// of bytes reclaimed.
+///
+/// The swap is journaled, see `finish_prop_compaction`, so a compaction that
+/// fails or crashes after the new file is complete is finished the next time
+/// the collection is loaded, and one that doesn't get that far leaves the
+/// old file untouched.
+///
+/// Searches can keep running meanwhile, nodes loaded while props move read
+/// their location again. Fails with `WaCustomError::LockError` while a
+/// transaction is open or embeddings are being indexed.
+pub fn compact_prop_file(
+    dense_index: &DenseIndex,
+    prop_file_path: &Path,
+) -> Result<u64, WaCustomError> {
+    if !dense_index
+        .current_open_transaction
+        .load(Ordering::SeqCst)
+        .is_null()
+    {
+        return Err(WaCustomError::LockError(
+            "Cannot compact the prop file while there's an ongoing transaction".to_string(),
+        ));
+    }
+    if dense_index.is_indexing.swap(true, Ordering::SeqCst) {
+        return Err(WaCustomError::LockError(
+            "Cannot compact the prop file while indexing is in progress".to_string(),
+        ));
+    }
+
+    let result = all_node_props(dense_index)
+        .and_then(|nodes| rewrite_prop_file(dense_index, prop_file_path, &nodes));
+    dense_index.is_indexing.store(false, Ordering::SeqCst);
+    result
+}
+
+/// The props of all nodes of `dense_index`, with the location of each node in
+/// the index files. Nodes are found through their recorded locations, as
+/// walking the graph doesn't reach a node once its edges are pruned.
+pub(crate) fn all_node_props(
+    dense_index: &DenseIndex,
+) -> Result<Vec<(Option<FileIndex>, Arc<NodeProp>)>, WaCustomError> {
+    let mut stack = vec![dense_index.get_root_vec()];
+    stack.extend(
+        dense_index
+            .unlocated_nodes
+            .lock()
+            .map_err(|_| WaCustomError::LockError("Failed to lock unlocated nodes".to_string()))?
+            .iter()
+            .copied(),
+    );
+    for file_index in retrieve_node_locations(&dense_index.lmdb)? {
+        stack.push(dense_index.cache.get_object(file_index)?);
+    }
+    let branches = dense_index
+        .vcs
+        .list_branches()
+        .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
+    for branch in branches {
+        let branch_id = BranchId::new(branch.get_branch_name());
+        if let Some(file_index) = retrieve_branch_root(&dense_index.lmdb, branch_id)? {
+            stack.push(
+                dense_index
+                    .cache
+                    .get_lazy_object(file_index, 1, &mut HashSet::new())?,
+            );
         }
-        Ok::<_, WaCustomError>(())
-    };
+    }
 
-    match ctx.config.indexing.mode {
-        VectorsIndexingMode::Sequential => {
-            index(vecs)?;
+    let mut visited = HashSet::new();
+    let mut nodes = Vec::new();
+    while let Some(item) = stack.pop() {
+        if item.is_null() || !visited.insert(item as usize) {
+            continue;
         }
-        VectorsIndexingMode::Batch { batch_size } => {
-            vecs.into_par_iter()
-                .chunks(batch_size)
-                .map(index)
-                .collect::<Result<_, _>>()?;
+        let item = unsafe { &*item };
+        let node = item.try_get_data(&dense_index.cache)?;
+        stack.push(node.get_parent());
+        stack.push(node.get_child());
+        stack.extend(node.get_neighbors());
+        stack.extend((0..node.versions.len()).filter_map(|i| node.versions.get(i)));
+        nodes.push((item.get_file_index(), node.prop.clone()));
+    }
+    Ok(nodes)
+}
+
+fn rewrite_prop_file(
+    dense_index: &DenseIndex,
+    prop_file_path: &Path,
+    nodes: &[(Option<FileIndex>, Arc<NodeProp>)],
+) -> Result<u64, WaCustomError> {
+    let fs_error = |e: std::io::Error| WaCustomError::FsError(e.to_string());
+    // readers loading a node take this lock to read its prop, so none of
+    // them sees a location half way through the move
+    let mut prop_file = dense_index
+        .prop_file
+        .write()
+        .map_err(|_| WaCustomError::LockError("Failed to lock the prop file".to_string()))?;
+    let old_size = prop_file.metadata().map_err(fs_error)?.len();
+
+    // the nodes in memory still point into the old file, so a compaction
+    // that failed after its journal was written can only be finished on load
+    if prop_journal_path(prop_file_path).exists() {
+        return Err(WaCustomError::FsError(
+            "An earlier compaction of the prop file is finished when the collection is loaded"
+                .to_string(),
+        ));
+    }
+    let compacted_path = compacted_prop_file_path(prop_file_path);
+    // left behind by a compaction that didn't get that far
+    if let Err(e) = std::fs::remove_file(&compacted_path) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            return Err(fs_error(e));
+        }
+    }
+    let compacted = OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(&compacted_path)
+        .map_err(fs_error)?;
+
+    dense_index.cache.forget_props();
+    let mut new_locations = HashMap::new();
+    for (_, prop) in nodes {
+        let old_location = prop.location.get();
+        if !new_locations.contains_key(&old_location) {
+            let new_location =
+                write_prop_to_file(&prop.id, prop.value.clone(), prop.source, &compacted)?;
+            new_locations.insert(old_location, new_location);
+        }
+    }
+    compacted.sync_all().map_err(fs_error)?;
+
+    // nodes not written yet pick their new location up from memory
+    let relocations: Vec<_> = nodes
+        .iter()
+        .filter_map(|(file_index, prop)| match file_index {
+            Some(FileIndex::Valid {
+                offset: FileOffset(offset),
+                version_id,
+                ..
+            }) => {
+                let (FileOffset(prop_offset), BytesToRead(prop_length)) =
+                    new_locations[&prop.location.get()];
+                Some(PropRelocation {
+                    version_id: **version_id,
+                    node_offset: *offset,
+                    prop_offset,
+                    prop_length,
+                })
+            }
+            _ => None,
+        })
+        .collect();
+    // buffered node writes have to reach the index files before the journal
+    // patches them, or a later flush would overwrite the new locations
+    dense_index.index_manager.flush_all()?;
+    write_prop_journal(prop_file_path, &relocations)?;
+    finish_prop_compaction(prop_file_path, &dense_index.index_manager)?;
+
+    let props: Vec<_> = nodes.iter().map(|(_, prop)| prop.clone()).collect();
+    for prop in &props {
+        if let Some(&new_location) = new_locations.get(&prop.location.get()) {
+            prop.location.set(new_location);
         }
     }
+    let new_size = compacted.metadata().map_err(fs_error)?.len();
+    *prop_file = compacted;
+    dense_index.cache.props_moved(&props);
 
-    Ok(())
+    Ok(old_size.saturating_sub(new_size))
 }
 
-pub fn index_embedding(
+/// Indexes the embeddings uploaded since the last indexing run, if any, in
+/// batches of `batch_size`.
+///
+/// Only one run can be in progress per index, others fail with
+/// `WaCustomError::LockError` while `dense_index.is_indexing` is set.
+pub fn index_pending_embeddings(
     config: &Config,
-    dense_index: Arc<DenseIndex>,
-    parent: SharedNode,
-    vector_emb: QuantizedVectorEmbedding,
-    prop: Arc<NodeProp>,
-    cur_entry: SharedNode,
-    cur_level: HNSWLevel,
-    version: Hash,
-    version_number: u16,
-    serialization_table: Arc<TSHashTable<SharedNode, ()>>,
-    lazy_item_versions_table: Arc<TSHashTable<(VectorId, u16, u8), SharedNode>>,
-    hnsw_params: &HNSWHyperParams,
-    max_level: u8,
+    dense_index: &Arc<DenseIndex>,
+    batch_size: usize,
 ) -> Result<(), WaCustomError> {
-    let fvec = vector_emb.quantized_vec.clone();
-    let mut skipm = PerformantFixedSet::new(if cur_level.0 == 0 {
-        hnsw_params.level_0_neighbors_count
-    } else {
-        hnsw_params.neighbors_count
-    });
-    skipm.insert(vector_emb.hash_vec.0 as u32);
+    if dense_index.is_indexing.swap(true, Ordering::SeqCst) {
+        return Err(WaCustomError::LockError(
+            "Indexing is already in progress".to_string(),
+        ));
+    }
 
-    let cur_node = unsafe { &*ProbLazyItem::get_latest_version(cur_entry, &dense_index.cache)?.0 }
-        .try_get_data(&dense_index.cache)?;
+    let result = index_unindexed_embeddings(config, dense_index, batch_size);
+    dense_index.is_indexing.store(false, Ordering::SeqCst);
+    result
+}
 
-    let z = traverse_find_nearest(
+fn index_unindexed_embeddings(
+    config: &Config,
+    dense_index: &Arc<DenseIndex>,
+    batch_size: usize,
+) -> Result<(), WaCustomError> {
+    let (_, count_unindexed) = get_embedding_counts(dense_index)?;
+    if count_unindexed == 0 {
+        return Ok(());
+    }
+
+    let serialization_table = Arc::new(TSHashTable::new(16));
+    index_embeddings(
         config,
-        &dense_index,
-        cur_entry,
-        &fvec,
-        &mut 0,
-        &mut skipm,
-        cur_level,
-        true,
-        true,
-        hnsw_params.ef_search,
-        hnsw_params.ef_construction,
+        dense_index.clone(),
+        batch_size,
+        serialization_table.clone(),
+        Arc::new(TSHashTable::new(16)),
     )?;
 
-    let z = if z.is_empty() {
-        let dist = dense_index
-            .distance_metric
-            .calculate(&fvec, &cur_node.prop.value)?;
+    let list = Arc::into_inner(serialization_table).unwrap().to_list();
+    for (node, _) in list {
+        write_node_to_file(node, &dense_index.index_manager)?;
+    }
+    dense_index.flush_vec_raw()?;
+    dense_index.index_manager.flush_all()?;
+    dense_index.record_node_locations()?;
 
-        vec![(cur_entry, dist)]
-    } else {
-        z
+    Ok(())
+}
+
+/// Recounts the pending embeddings of `dense_index` after a restart, those
+/// stored in its raw embeddings file past `next_embedding_offset`, and
+/// indexes them right away. An embedding cut short at the end of the file
+/// was being written when the process stopped, it's cut off. Returns the
+/// number of embeddings found pending.
+pub fn recover_pending_embeddings(
+    config: &Config,
+    dense_index: &Arc<DenseIndex>,
+) -> Result<usize, WaCustomError> {
+    let env = dense_index.lmdb.env.clone();
+    let db = dense_index.lmdb.db.clone();
+    let mut txn = env
+        .begin_rw_txn()
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e)))?;
+    let next_offset = match txn.get(*db, &"next_embedding_offset") {
+        Ok(bytes) => EmbeddingOffset::deserialize(bytes)
+            .map_err(|e| WaCustomError::DeserializationError(e.to_string()))?,
+        Err(lmdb::Error::NotFound) => EmbeddingOffset {
+            version: dense_index.get_current_version(),
+            offset: 0,
+        },
+        Err(err) => return Err(WaCustomError::DatabaseError(err.to_string())),
     };
-    if cur_level.0 > max_level {
-        // Just traverse down without creating nodes
-        if cur_level.0 != 0 {
-            index_embedding(
-                config,
-                dense_index.clone(),
-                ptr::null_mut(),
-                vector_emb.clone(),
-                prop.clone(),
-                unsafe { &*z[0].0 }
-                    .try_get_data(&dense_index.cache)?
-                    .get_child(),
-                HNSWLevel(cur_level.0 - 1),
-                version,
-                version_number,
-                serialization_table.clone(),
-                lazy_item_versions_table.clone(),
-                hnsw_params,
-                max_level,
-            )?;
-        }
+
+    let bufman = dense_index.vec_raw_manager.get(next_offset.version)?;
+    let file_len = bufman.file_size()? as u32;
+    let (mut offsets, end) =
+        scan_embedding_offsets_limited(&bufman, next_offset.offset, file_len, usize::MAX)?;
+    let complete_len = if end > file_len {
+        offsets.pop().unwrap()
     } else {
-        // Create node and edges at max_level and below
-        let lazy_node = create_node(
-            version,
-            version_number,
-            cur_level,
-            prop.clone(),
-            parent,
-            ptr::null_mut(),
-            if cur_level.0 == 0 {
-                hnsw_params.level_0_neighbors_count
-            } else {
-                hnsw_params.neighbors_count
-            },
+        end
+    };
+    if complete_len < file_len {
+        log::warn!(
+            "an embedding of {} never fully reached the raw embeddings file and is dropped",
+            dense_index.database_name
         );
-
-        let node = unsafe { &*lazy_node }.get_lazy_data().unwrap();
-
-        if let Some(parent) = unsafe { parent.as_ref() } {
-            parent
-                .try_get_data(&dense_index.cache)
-                .unwrap()
-                .set_child(lazy_node.clone());
+        for bufman in dense_index.vec_raw_bufmans(next_offset.version)? {
+            bufman.truncate(complete_len as u64)?;
         }
+    }
+    let pending = offsets.len();
 
-        if cur_level.0 != 0 {
-            index_embedding(
-                config,
-                dense_index.clone(),
-                lazy_node,
-                vector_emb.clone(),
-                prop.clone(),
-                unsafe { &*z[0].0 }
-                    .try_get_data(&dense_index.cache)?
-                    .get_child(),
-                HNSWLevel(cur_level.0 - 1),
-                version,
-                version_number,
-                serialization_table.clone(),
-                lazy_item_versions_table.clone(),
-                hnsw_params,
-                max_level,
-            )?;
-        }
+    txn.put(
+        *db,
+        &"count_unindexed",
+        &(pending as u32).to_le_bytes(),
+        WriteFlags::empty(),
+    )
+    .map_err(|e| {
+        WaCustomError::DatabaseError(format!("Failed to update `count_unindexed`: {}", e))
+    })?;
+    txn.commit().map_err(|e| {
+        WaCustomError::DatabaseError(format!("Failed to commit transaction: {}", e))
+    })?;
 
-        create_node_edges(
-            dense_index.clone(),
-            lazy_node,
-            node,
-            z,
-            version,
-            version_number,
-            serialization_table,
-            lazy_item_versions_table,
-        )?;
+    if pending > 0 {
+        index_pending_embeddings(config, dense_index, config.upload_process_batch_size)?;
     }
 
+    Ok(pending)
+}
+
+/// Makes `version` the current version of `dense_index`, with all of its
+/// embeddings indexed, so that new uploads start from it.
+fn start_indexed_version(dense_index: &DenseIndex, version: Hash) -> Result<(), WaCustomError> {
+    let env = dense_index.lmdb.env.clone();
+    let db = dense_index.lmdb.db.clone();
+    let mut txn = env
+        .begin_rw_txn()
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e)))?;
+    let next_offset = EmbeddingOffset { version, offset: 0 };
+    txn.put(
+        *db,
+        &"next_embedding_offset",
+        &next_offset.serialize(),
+        WriteFlags::empty(),
+    )
+    .map_err(|e| WaCustomError::DatabaseError(format!("Failed to put data: {}", e)))?;
+    txn.put(
+        *db,
+        &"count_unindexed",
+        &0u32.to_le_bytes(),
+        WriteFlags::empty(),
+    )
+    .map_err(|e| WaCustomError::DatabaseError(format!("Failed to put data: {}", e)))?;
+    txn.commit().map_err(|e| {
+        WaCustomError::DatabaseError(format!("Failed to commit transaction: {}", e))
+    })?;
+
+    update_current_version(&dense_index.lmdb, version)?;
+    dense_index.set_current_version(version);
+
     Ok(())
 }
 
-fn create_node(
-    version_id: Hash,
-    version_number: u16,
-    hnsw_level: HNSWLevel,
-    prop: Arc<NodeProp>,
-    parent: SharedNode,
-    child: SharedNode,
-    neighbors_count: usize,
-) -> SharedNode {
-    let node = ProbNode::new(hnsw_level, prop, parent, child, neighbors_count);
-    ProbLazyItem::new(node, version_id, version_number)
+/// Persists `root` as the root of `branch`'s graph. It's also kept in the
+/// cache so that checking the branch out again reuses the loaded graph.
+fn remember_branch_root(
+    dense_index: &DenseIndex,
+    branch: BranchId,
+    root: SharedNode,
+) -> Result<(), WaCustomError> {
+    let Some(file_index) = unsafe { &*root }.get_file_index() else {
+        return Err(WaCustomError::NodeError(
+            "root node of the branch isn't persisted".to_string(),
+        ));
+    };
+    if let FileIndex::Valid {
+        offset, version_id, ..
+    } = file_index
+    {
+        dense_index
+            .cache
+            .insert_lazy_object(version_id, offset.0, root);
+    }
+    persist_branch_root(&dense_index.lmdb, branch, file_index)
 }
 
-fn get_or_create_version(
-    dense_index: Arc<DenseIndex>,
-    lazy_item_versions_table: Arc<TSHashTable<(VectorId, u16, u8), SharedNode>>,
-    lazy_item: SharedNode,
-    version: Hash,
-    version_number: u16,
-) -> Result<SharedNode, WaCustomError> {
-    let node = unsafe { &*lazy_item }.try_get_data(&dense_index.cache)?;
+/// Lists the ids of the embeddings stored on the current branch in key order, starting at the
+/// embedding with id `from` (or the first one). Along with at most `limit`
+/// ids, returns the id to continue from if there are more.
+pub fn list_vector_ids(
+    dense_index: &DenseIndex,
+    from: Option<VectorId>,
+    limit: usize,
+) -> Result<(Vec<VectorId>, Option<VectorId>), WaCustomError> {
+    let branch = dense_index.branch_of(dense_index.get_current_version())?;
+    let env = dense_index.lmdb.env.clone();
+    let db = dense_index.lmdb.db.clone();
 
-    let new_version = lazy_item_versions_table.get_or_create(
-        (node.get_id().clone(), version_number, node.hnsw_level.0),
-        || {
-            if let Some(version) =
-                ProbLazyItem::get_version(lazy_item, version_number, &dense_index.cache)
-                    .expect("Deserialization failed")
-            {
-                return version;
-            }
+    let txn = env
+        .begin_ro_txn()
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e)))?;
+    let mut cursor = txn
+        .open_ro_cursor(*db)
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to open cursor: {}", e)))?;
 
-            let new_node = ProbNode::new_with_neighbors(
-                node.hnsw_level,
-                node.prop.clone(),
-                node.clone_neighbors(),
-                node.get_parent(),
-                node.get_child(),
-            );
+    let start_key = key!(e:branch, from.unwrap_or(VectorId(0)));
+    let mut ids = Vec::with_capacity(limit);
+    let mut next = None;
 
-            let version = ProbLazyItem::new(new_node, version, version_number);
+    for (key, _) in cursor.iter_from(&start_key) {
+        // the keys of the branch's embeddings share the first 9 bytes
+        if key.len() != 17 || key[..9] != start_key[..9] {
+            break;
+        }
+        let id = VectorId(u64::from_le_bytes(key[9..].try_into().unwrap()));
+        if ids.len() == limit {
+            next = Some(id);
+            break;
+        }
+        ids.push(id);
+    }
 
-            ProbLazyItem::add_version(lazy_item, version, &dense_index.cache)
-                .expect("Failed to add version")
-                .map_err(|_| "Failed to add version")
-                .unwrap();
+    Ok((ids, next))
+}
 
-            version
-        },
-    );
+/// Retrieves the metadata stored for a vector, `None` if it has none.
+pub fn get_metadata_by_id(
+    dense_index: &DenseIndex,
+    vector_id: &VectorId,
+) -> Result<Option<serde_json::Value>, WaCustomError> {
+    let env = dense_index.lmdb.env.clone();
+    let db = dense_index.lmdb.db.clone();
 
-    Ok(new_version)
+    let txn = env
+        .begin_ro_txn()
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e)))?;
+
+    read_metadata(&txn, *db, vector_id)
 }
 
-fn create_node_edges(
-    dense_index: Arc<DenseIndex>,
-    lazy_node: SharedNode,
-    node: &ProbNode,
-    neighbors: Vec<(SharedNode, MetricResult)>,
-    version: Hash,
-    version_number: u16,
-    serialization_table: Arc<TSHashTable<SharedNode, ()>>,
-    lazy_item_versions_table: Arc<TSHashTable<(VectorId, u16, u8), SharedNode>>,
-) -> Result<(), WaCustomError> {
-    // Handle regular neighbors
-    for (neighbor, dist) in neighbors {
-        serialization_table.insert(neighbor.clone(), ());
+/// Vectors without metadata (including the root) never match a filter.
+fn matches_filter(
+    txn: &impl Transaction,
+    db: lmdb::Database,
+    vector_id: &VectorId,
+    filter: &Filter,
+) -> Result<bool, WaCustomError> {
+    Ok(read_metadata(txn, db, vector_id)?.map_or(false, |metadata| filter.matches(&metadata)))
+}
 
-        let new_lazy_neighbor = get_or_create_version(
-            dense_index.clone(),
-            lazy_item_versions_table.clone(),
-            neighbor,
-            version,
-            version_number,
-        )?;
-        let new_neighbor = unsafe { &*new_lazy_neighbor }.try_get_data(&dense_index.cache)?;
+/// Checks the vectors a search visits against its filter. The metadata is
+/// read in a single LMDB read transaction, and parsed once per vector, the
+/// outcome is remembered for the rest of the search.
+///
+/// A thread can only have one read transaction open, so nothing else may
+/// read LMDB on the thread while the matcher is alive.
+struct FilterMatcher<'a> {
+    filter: &'a Filter,
+    txn: lmdb::RoTransaction<'a>,
+    db: lmdb::Database,
+    matches: RefCell<HashMap<VectorId, bool>>,
+}
 
-        node.add_neighbor(
-            new_neighbor.get_id().0 as u32,
-            new_lazy_neighbor.clone(),
-            dist,
-        );
-        new_neighbor.add_neighbor(node.get_id().0 as u32, lazy_node.clone(), dist);
+impl<'a> FilterMatcher<'a> {
+    fn new(dense_index: &'a DenseIndex, filter: &'a Filter) -> Result<Self, WaCustomError> {
+        let txn = dense_index.lmdb.env.begin_ro_txn().map_err(|e| {
+            WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e))
+        })?;
+        Ok(Self {
+            filter,
+            txn,
+            db: *dense_index.lmdb.db,
+            matches: RefCell::new(HashMap::new()),
+        })
     }
 
-    serialization_table.insert(lazy_node, ());
-
-    Ok(())
+    fn matches(&self, vector_id: &VectorId) -> Result<bool, WaCustomError> {
+        if let Some(&matches) = self.matches.borrow().get(vector_id) {
+            return Ok(matches);
+        }
+        let matches = matches_filter(&self.txn, self.db, vector_id, self.filter)?;
+        self.matches.borrow_mut().insert(vector_id.clone(), matches);
+        Ok(matches)
+    }
 }
 
-fn traverse_find_nearest(
-    config: &Config,
+// fn auto_config_storage_type(dense_index: Arc<DenseIndex>, vectors: &[&[f32]]) {
+//     let threshold = 0.0;
+//     let iterations = 32;
+
+//     let vec = concat_vectors(vectors);
+
+//     // First iteration with k = 16
+//     let initial_centroids_16 = generate_initial_centroids(&vec, 16);
+//     let (_, counts_16) = kmeans(&vec, &initial_centroids_16, iterations);
+//     let storage_type = if should_continue(&counts_16, threshold, 8) {
+//         // Second iteration with k = 8
+//         let initial_centroids_8 = generate_initial_centroids(&vec, 8);
+//         let (_, counts_8) = kmeans(&vec, &initial_centroids_8, iterations);
+//         if should_continue(&counts_8, threshold, 4) {
+//             // Third iteration with k = 4
+//             let initial_centroids_4 = generate_initial_centroids(&vec, 4);
+//             let (_, counts_4) = kmeans(&vec, &initial_centroids_4, iterations);
+
+//             if should_continue(&counts_4, threshold, 2) {
+//                 StorageType::SubByte(1)
+//             } else {
+//                 StorageType::SubByte(2)
+//             }
+//         } else {
+//             // StorageType::SubByte(3)
+//             StorageType::UnsignedByte
+//         }
+//     } else {
+//         StorageType::UnsignedByte
+//     };
+
+//     dense_index.storage_type.update_shared(storage_type);
+// }
+
+pub fn insert_embedding(
+    bufman: Arc<BufferManager>,
+    dense_index: Arc<DenseIndex>,
+    emb: &RawVectorEmbedding,
+    current_version: Hash,
+) -> Result<(), WaCustomError> {
+    let branch = dense_index.branch_of(current_version)?;
+    let env = dense_index.lmdb.env.clone();
+    let db = dense_index.lmdb.db.clone();
+
+    let mut txn = env
+        .begin_rw_txn()
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e)))?;
+
+    let count_unindexed = match txn.get(*db, &"count_unindexed") {
+        Ok(bytes) => {
+            let bytes = bytes.try_into().map_err(|e: TryFromSliceError| {
+                WaCustomError::DeserializationError(e.to_string())
+            })?;
+            u32::from_le_bytes(bytes)
+        }
+        Err(lmdb::Error::NotFound) => 0,
+        Err(err) => return Err(WaCustomError::DatabaseError(err.to_string())),
+    };
+
+    let embedding_key = key!(e:branch, emb.hash_vec);
+
+    let previous = match txn.get(*db, &embedding_key) {
+        Ok(bytes) => Some(
+            EmbeddingOffset::deserialize(bytes)
+                .map_err(|e| WaCustomError::DeserializationError(e.to_string()))?,
+        ),
+        Err(lmdb::Error::NotFound) => None,
+        Err(err) => return Err(WaCustomError::DatabaseError(err.to_string())),
+    };
+
+    let mut bufmans = vec![bufman];
+    bufmans.extend(dense_index.vec_raw_replica_bufmans(current_version)?);
+    let offset = {
+        let write_lock = dense_index.vec_raw_write_lock(current_version);
+        let _guard = write_lock.lock().unwrap();
+        write_embedding_replicated(&bufmans, emb)?
+    };
+
+    let offset = EmbeddingOffset {
+        version: current_version,
+        offset,
+    };
+    let offset_serialized = offset.serialize();
+
+    // the id is being overwritten, the graph node of the previous embedding
+    // (if it was indexed already) must no longer be returned
+    if let Some(previous) = previous {
+        tombstone_previous_embedding(&mut txn, *db, &emb.hash_vec, &previous)?;
+    }
+
+    txn.put(*db, &embedding_key, &offset_serialized, WriteFlags::empty())
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to put data: {}", e)))?;
+    put_metadata(&mut txn, *db, &emb.hash_vec, emb.metadata.as_ref())?;
+
+    txn.put(
+        *db,
+        &"count_unindexed",
+        &(count_unindexed + 1).to_le_bytes(),
+        WriteFlags::empty(),
+    )
+    .map_err(|e| {
+        WaCustomError::DatabaseError(format!("Failed to update `count_unindexed`: {}", e))
+    })?;
+
+    txn.commit().map_err(|e| {
+        WaCustomError::DatabaseError(format!("Failed to commit transaction: {}", e))
+    })?;
+
+    Ok(())
+}
+
+/// Records where the embedding being replaced by an upload of `vector_id`
+/// is stored, under the `t:` key of the id. Graph nodes built from a
+/// tombstoned embedding are still traversed, but never returned from a
+/// search.
+fn tombstone_previous_embedding(
+    txn: &mut lmdb::RwTransaction,
+    db: lmdb::Database,
+    vector_id: &VectorId,
+    previous: &EmbeddingOffset,
+) -> Result<(), WaCustomError> {
+    let tombstone_key = key!(t:vector_id);
+    let mut tombstones: Vec<(Hash, u32)> = match txn.get(db, &tombstone_key) {
+        Ok(bytes) => serde_cbor::from_slice(bytes)
+            .map_err(|e| WaCustomError::DeserializationError(e.to_string()))?,
+        Err(lmdb::Error::NotFound) => Vec::new(),
+        Err(err) => return Err(WaCustomError::DatabaseError(err.to_string())),
+    };
+    let tombstone = (previous.version, previous.offset);
+    if tombstones.contains(&tombstone) {
+        return Ok(());
+    }
+    tombstones.push(tombstone);
+
+    let tombstones = serde_cbor::to_vec(&tombstones)
+        .map_err(|e| WaCustomError::SerializationError(e.to_string()))?;
+    txn.put(db, &tombstone_key, &tombstones, WriteFlags::empty())
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to put tombstones: {}", e)))
+}
+
+/// Whether `node` was built from an embedding that has since been overwritten.
+///
+/// A node indexed in a transaction doesn't know the offset of its embedding,
+/// it's tombstoned along with any embedding of its id stored under its
+/// version. Nodes that don't know their embedding at all are never
+/// tombstoned.
+fn is_tombstoned(
+    txn: &impl Transaction,
+    db: lmdb::Database,
+    node: &ProbNode,
+) -> Result<bool, WaCustomError> {
+    let Some(source) = node.prop.source else {
+        return Ok(false);
+    };
+    match txn.get(db, &key!(t:node.prop.id)) {
+        Ok(bytes) => {
+            let tombstones: Vec<(Hash, u32)> = serde_cbor::from_slice(bytes)
+                .map_err(|e| WaCustomError::DeserializationError(e.to_string()))?;
+            Ok(tombstones.iter().any(|&(version, offset)| {
+                version == source.version && source.offset.map_or(true, |o| o == offset)
+            }))
+        }
+        Err(lmdb::Error::NotFound) => Ok(false),
+        Err(err) => Err(WaCustomError::DatabaseError(err.to_string())),
+    }
+}
+
+/// Drops the embeddings of a batch read from `version`'s raw vectors file
+/// that were overwritten by a later upload of the same id on `branch`, so
+/// that only the latest embedding of an id gets a graph node. The rest are
+/// returned along with their offsets.
+fn drop_superseded_embeddings(
     dense_index: &DenseIndex,
-    vtm: SharedNode,
-    fvec: &Storage,
-    nodes_visited: &mut u32,
-    skipm: &mut PerformantFixedSet,
-    cur_level: HNSWLevel,
-    is_indexing: bool,
-    shortlist: bool,
-    ef_search: u32,
-    ef_construction: u32,
-) -> Result<Vec<(SharedNode, MetricResult)>, WaCustomError> {
-    *nodes_visited += 1;
-    let mut tasks: SmallVec<[Vec<(SharedNode, MetricResult)>; 32]> = SmallVec::new();
-    let ef = if is_indexing {
-        ef_construction
-    } else {
-        ef_search
+    branch: BranchId,
+    version: Hash,
+    offsets: &[u32],
+    embeddings: Vec<RawVectorEmbedding>,
+) -> Result<Vec<(u32, RawVectorEmbedding)>, WaCustomError> {
+    let env = dense_index.lmdb.env.clone();
+    let db = dense_index.lmdb.db.clone();
+
+    let txn = env
+        .begin_ro_txn()
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e)))?;
+
+    let mut latest = Vec::with_capacity(embeddings.len());
+    for (&offset, emb) in offsets.iter().zip(embeddings) {
+        let current = match txn.get(*db, &key!(e:branch, emb.hash_vec)) {
+            Ok(bytes) => EmbeddingOffset::deserialize(bytes)
+                .map_err(|e| WaCustomError::DeserializationError(e.to_string()))?,
+            Err(lmdb::Error::NotFound) => continue,
+            Err(err) => return Err(WaCustomError::DatabaseError(err.to_string())),
+        };
+        if current.version == version && current.offset == offset {
+            latest.push((offset, emb));
+        }
+    }
+
+    Ok(latest)
+}
+
+pub fn index_embeddings(
+    config: &Config,
+    dense_index: Arc<DenseIndex>,
+    upload_process_batch_size: usize,
+    serialization_table: Arc<TSHashTable<SharedNode, ()>>,
+    lazy_item_versions_table: Arc<TSHashTable<(VectorId, u16, u8), SharedNode>>,
+) -> Result<(), WaCustomError> {
+    let env = dense_index.lmdb.env.clone();
+    let db = dense_index.lmdb.db.clone();
+
+    let txn = env
+        .begin_ro_txn()
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e)))?;
+
+    let mut count_indexed = match txn.get(*db, &"count_indexed") {
+        Ok(bytes) => {
+            let bytes = bytes.try_into().map_err(|e: TryFromSliceError| {
+                WaCustomError::DeserializationError(e.to_string())
+            })?;
+            u32::from_le_bytes(bytes)
+        }
+        Err(lmdb::Error::NotFound) => 0,
+        Err(err) => return Err(WaCustomError::DatabaseError(err.to_string())),
+    };
+    let mut count_unindexed = match txn.get(*db, &"count_unindexed") {
+        Ok(bytes) => {
+            let bytes = bytes.try_into().map_err(|e: TryFromSliceError| {
+                WaCustomError::DeserializationError(e.to_string())
+            })?;
+            u32::from_le_bytes(bytes)
+        }
+        Err(lmdb::Error::NotFound) => 0,
+        Err(err) => return Err(WaCustomError::DatabaseError(err.to_string())),
+    };
+
+    // nothing was indexed in a new collection yet, it starts from the
+    // beginning of its initial version
+    let embedding_offset = match txn.get(*db, &"next_embedding_offset") {
+        Ok(bytes) => EmbeddingOffset::deserialize(bytes)
+            .map_err(|e| WaCustomError::DeserializationError(e.to_string()))?,
+        Err(lmdb::Error::NotFound) => EmbeddingOffset {
+            version: dense_index.get_current_version(),
+            offset: 0,
+        },
+        Err(err) => return Err(WaCustomError::DatabaseError(err.to_string())),
     };
+    let version = embedding_offset.version;
+    // a version that was never recorded is the initial one, of the main branch
+    let version_hash = dense_index
+        .vcs
+        .get_version_hash(&version, &txn)
+        .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
+    let version_number = version_hash
+        .as_ref()
+        .map_or(0, |version_hash| *version_hash.version as u16);
+    let branch =
+        version_hash.map_or_else(|| BranchId::new("main"), |version_hash| version_hash.branch);
+
+    txn.abort();
+
+    let hnsw_params = dense_index.hnsw_params.clone();
+    let hnsw_params_guard = hnsw_params.read().unwrap();
+
+    // `scanned` is the number of records read, superseded ones included
+    let mut index = |embeddings: Vec<(u32, RawVectorEmbedding)>,
+                     scanned: u32,
+                     next_offset: u32|
+     -> Result<(), WaCustomError> {
+        let mut quantization_arc = dense_index.quantization_metric.clone();
+        let quantization = quantization_arc.get();
+
+        // the whole batch is checked before any of it is indexed, so a
+        // failing embedding doesn't leave the batch half indexed
+        let prepared = embeddings
+            .into_iter()
+            .map(|(offset, raw_emb)| {
+                let current_level = max_insert_level(
+                    &dense_index,
+                    &raw_emb.hash_vec,
+                    hnsw_params_guard.num_layers,
+                )?;
+                let quantized_vec = quantization
+                    .quantize(
+                        &raw_emb.raw_vec,
+                        dense_index.storage_type.clone().get().clone(),
+                        *dense_index.values_range.read().unwrap(),
+                    )
+                    .map_err(|e| WaCustomError::QuantizationError(e.to_string()))?;
+                Ok((offset, raw_emb, current_level, Arc::new(quantized_vec)))
+            })
+            .collect::<Result<Vec<_>, WaCustomError>>()?;
+        let indexed = prepared.len() as u32;
+
+        for (offset, raw_emb, current_level, quantized_vec) in prepared {
+            let source = EmbeddingSource {
+                version,
+                offset: Some(offset),
+            };
+            let mut prop_file_guard = dense_index.prop_file.write().unwrap();
+            let location = write_prop_to_file(
+                &raw_emb.hash_vec,
+                quantized_vec.clone(),
+                Some(source),
+                &mut *prop_file_guard,
+            )
+            .expect("failed to write prop");
+            drop(prop_file_guard);
+            if let Err(err) = check_prop_file_size(location, config.prop_file.soft_size_limit) {
+                log::warn!("{}", err);
+            }
+            let prop = Arc::new(NodeProp {
+                id: raw_emb.hash_vec.clone(),
+                value: quantized_vec.clone(),
+                location: PropLocation::new(location),
+                source: Some(source),
+            });
+            let embedding = QuantizedVectorEmbedding {
+                quantized_vec,
+                hash_vec: raw_emb.hash_vec,
+            };
+
+            let mut current_entry = dense_index.get_root_vec();
+
+            loop {
+                let data = unsafe { &*current_entry }
+                    .try_get_data(&dense_index.cache)
+                    .expect("Unable to load data");
+                if data.hnsw_level.0 > current_level.0 {
+                    current_entry = data.get_child();
+                } else if data.hnsw_level == current_level {
+                    break;
+                } else {
+                    panic!("missing node");
+                }
+            }
+
+            index_embedding(
+                config,
+                dense_index.clone(),
+                ptr::null_mut(),
+                embedding,
+                prop,
+                current_entry,
+                current_level,
+                version,
+                version_number,
+                serialization_table.clone(),
+                lazy_item_versions_table.clone(),
+                &hnsw_params_guard,
+                HNSWLevel(2),
+            )
+            .expect("index_embedding failed");
+        }
+
+        count_indexed += indexed;
+        count_unindexed = count_unindexed.saturating_sub(scanned);
+
+        let mut txn = env.begin_rw_txn().map_err(|e| {
+            WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e))
+        })?;
+
+        let next_embedding_offset = EmbeddingOffset {
+            version,
+            offset: next_offset,
+        };
+        let next_embedding_offset_serialized = next_embedding_offset.serialize();
+
+        txn.put(
+            *db,
+            &"next_embedding_offset",
+            &next_embedding_offset_serialized,
+            WriteFlags::empty(),
+        )
+        .map_err(|e| {
+            WaCustomError::DatabaseError(format!("Failed to update `next_embedding_offset`: {}", e))
+        })?;
+
+        txn.put(
+            *db,
+            &"count_indexed",
+            &count_indexed.to_le_bytes(),
+            WriteFlags::empty(),
+        )
+        .map_err(|e| {
+            WaCustomError::DatabaseError(format!("Failed to update `count_indexed`: {}", e))
+        })?;
+
+        txn.put(
+            *db,
+            &"count_unindexed",
+            &count_unindexed.to_le_bytes(),
+            WriteFlags::empty(),
+        )
+        .map_err(|e| {
+            WaCustomError::DatabaseError(format!("Failed to update `count_unindexed`: {}", e))
+        })?;
+
+        txn.commit().map_err(|e| {
+            WaCustomError::DatabaseError(format!("Failed to commit transaction: {}", e))
+        })?;
+
+        Ok(())
+    };
+
+    let bufman = dense_index.vec_raw_manager.get(version)?;
+    let mut reader = EmbeddingReader::new(bufman, embedding_offset.offset)?;
+    let file_len = reader.end();
+    if embedding_offset.offset >= file_len {
+        return index(Vec::new(), 0, file_len);
+    }
+
+    let mut offset = embedding_offset.offset;
+    loop {
+        let mut offsets = Vec::with_capacity(upload_process_batch_size);
+        let mut embeddings = Vec::with_capacity(upload_process_batch_size);
+        for result in reader.by_ref().take(upload_process_batch_size) {
+            let (emb, next) = result?;
+            offsets.push(offset);
+            embeddings.push(emb);
+            offset = next;
+        }
+        if embeddings.is_empty() {
+            break;
+        }
+        let embeddings =
+            drop_superseded_embeddings(&dense_index, branch, version, &offsets, embeddings)?;
+        index(embeddings, offsets.len() as u32, offset)?;
+    }
+
+    Ok(())
+}
+
+pub fn index_embeddings_in_transaction(
+    ctx: Arc<AppContext>,
+    dense_index: Arc<DenseIndex>,
+    version: Hash,
+    version_number: u16,
+    transaction: &DenseIndexTransaction,
+    vecs: Vec<(u64, Vec<f32>, Option<serde_json::Value>)>,
+) -> Result<(), WaCustomError> {
+    let quantization = &*dense_index.quantization_metric;
+    let hnsw_params = dense_index.hnsw_params.clone();
+    let hnsw_params_guard = hnsw_params.read().unwrap();
+    let index = |vecs: Vec<(u64, Vec<f32>, Option<serde_json::Value>)>| {
+        for (id, values, metadata) in vecs {
+            let raw_emb = RawVectorEmbedding {
+                hash_vec: VectorId(id),
+                raw_vec: Arc::new(values),
+                metadata,
+            };
+            transaction.post_raw_embedding(raw_emb.clone());
+            let max_level = max_insert_level(
+                &dense_index,
+                &raw_emb.hash_vec,
+                hnsw_params_guard.num_layers,
+            )?;
+            let quantized_vec = Arc::new(
+                quantization
+                    .quantize(
+                        &raw_emb.raw_vec,
+                        dense_index.storage_type.clone().get().clone(),
+                        *dense_index.values_range.read().unwrap(),
+                    )
+                    .map_err(|e| WaCustomError::QuantizationError(e.to_string()))?,
+            );
+
+            // the embedding is written in the background, its offset isn't
+            // known yet
+            let source = EmbeddingSource {
+                version,
+                offset: None,
+            };
+            let mut prop_file_guard = dense_index.prop_file.write().unwrap();
+            let location = write_prop_to_file(
+                &raw_emb.hash_vec,
+                quantized_vec.clone(),
+                Some(source),
+                &mut *prop_file_guard,
+            )?;
+            drop(prop_file_guard);
+            if let Err(err) = check_prop_file_size(location, ctx.config.prop_file.soft_size_limit) {
+                log::warn!("{}", err);
+            }
+
+            let prop = Arc::new(NodeProp {
+                id: raw_emb.hash_vec.clone(),
+                value: quantized_vec.clone(),
+                location: PropLocation::new(location),
+                source: Some(source),
+            });
+
+            let embedding = QuantizedVectorEmbedding {
+                quantized_vec,
+                hash_vec: raw_emb.hash_vec,
+            };
+
+            // Start from root at highest level
+            let root_entry = dense_index.get_root_vec();
+            let highest_level = HNSWLevel(hnsw_params_guard.num_layers);
+
+            index_embedding(
+                &ctx.config,
+                dense_index.clone(),
+                ptr::null_mut(),
+                embedding,
+                prop,
+                root_entry,
+                highest_level,
+                version,
+                version_number,
+                transaction.serialization_table.clone(),
+                transaction.lazy_item_versions_table.clone(),
+                &*hnsw_params_guard,
+                max_level, // Pass max_level to let index_embedding control node creation
+            )?;
+        }
+        Ok::<_, WaCustomError>(())
+    };
+
+    match ctx.config.indexing.mode {
+        VectorsIndexingMode::Sequential => {
+            index(vecs)?;
+        }
+        VectorsIndexingMode::Batch { batch_size } => {
+            vecs.into_par_iter()
+                .chunks(batch_size)
+                .map(index)
+                .collect::<Result<_, _>>()?;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn index_embedding(
+    config: &Config,
+    dense_index: Arc<DenseIndex>,
+    parent: SharedNode,
+    vector_emb: QuantizedVectorEmbedding,
+    prop: Arc<NodeProp>,
+    cur_entry: SharedNode,
+    cur_level: HNSWLevel,
+    version: Hash,
+    version_number: u16,
+    serialization_table: Arc<TSHashTable<SharedNode, ()>>,
+    lazy_item_versions_table: Arc<TSHashTable<(VectorId, u16, u8), SharedNode>>,
+    hnsw_params: &HNSWHyperParams,
+    max_level: HNSWLevel,
+) -> Result<(), WaCustomError> {
+    let fvec = vector_emb.quantized_vec.clone();
+    let mut skipm = PerformantFixedSet::new(if cur_level.0 == 0 {
+        hnsw_params.level_0_neighbors_count
+    } else {
+        hnsw_params.neighbors_count
+    });
+    skipm.insert(vector_emb.hash_vec.0 as u32);
+
+    let cur_entry = resolve_node(&dense_index, cur_entry)?;
+    let cur_node = unsafe { &*ProbLazyItem::get_latest_version(cur_entry, &dense_index.cache)?.0 }
+        .try_get_data(&dense_index.cache)?;
+
+    let z = traverse_find_nearest(
+        config,
+        &dense_index,
+        cur_entry,
+        &fvec,
+        &mut 0,
+        &mut skipm,
+        cur_level,
+        true,
+        true,
+        hnsw_params.ef_search,
+        hnsw_params.ef_construction,
+        hnsw_params.ef_construction as usize,
+        None,
+        None,
+    )?;
+
+    let z = if z.is_empty() {
+        let dist = dense_index.distance(&fvec, &cur_node.prop.value)?;
+
+        vec![(cur_entry, dist)]
+    } else {
+        z
+    };
+    if cur_level.0 > max_level.0 {
+        // Just traverse down without creating nodes
+        if cur_level.0 != 0 {
+            index_embedding(
+                config,
+                dense_index.clone(),
+                ptr::null_mut(),
+                vector_emb.clone(),
+                prop.clone(),
+                unsafe { &*z[0].0 }
+                    .try_get_data(&dense_index.cache)?
+                    .get_child(),
+                HNSWLevel(cur_level.0 - 1),
+                version,
+                version_number,
+                serialization_table.clone(),
+                lazy_item_versions_table.clone(),
+                hnsw_params,
+                max_level,
+            )?;
+        }
+    } else {
+        let neighbors_count = if cur_level.0 == 0 {
+            hnsw_params.level_0_neighbors_count
+        } else {
+            hnsw_params.neighbors_count
+        };
+        // Create node and edges at max_level and below
+        let lazy_node = create_node(
+            version,
+            version_number,
+            cur_level,
+            prop.clone(),
+            parent,
+            ptr::null_mut(),
+            neighbors_count,
+        );
+        dense_index.unlocated_nodes.lock().unwrap().push(lazy_node);
+
+        let node = unsafe { &*lazy_node }.get_lazy_data().unwrap();
+
+        if let Some(parent) = unsafe { parent.as_ref() } {
+            parent
+                .try_get_data(&dense_index.cache)?
+                .set_child(lazy_node.clone());
+        }
+
+        if cur_level.0 != 0 {
+            index_embedding(
+                config,
+                dense_index.clone(),
+                lazy_node,
+                vector_emb.clone(),
+                prop.clone(),
+                unsafe { &*z[0].0 }
+                    .try_get_data(&dense_index.cache)?
+                    .get_child(),
+                HNSWLevel(cur_level.0 - 1),
+                version,
+                version_number,
+                serialization_table.clone(),
+                lazy_item_versions_table.clone(),
+                hnsw_params,
+                max_level,
+            )?;
+        }
+
+        // only as many candidates as fit become neighbors, the rest would be
+        // evicted from the neighbor list right away
+        let z = match hnsw_params.neighbor_selection {
+            NeighborSelection::Simple => {
+                let mut z = z;
+                z.truncate(neighbors_count);
+                z
+            }
+            NeighborSelection::Heuristic => {
+                select_neighbors_heuristic(&dense_index, z, neighbors_count)?
+            }
+        };
+        create_node_edges(
+            dense_index.clone(),
+            lazy_node,
+            node,
+            z,
+            version,
+            version_number,
+            serialization_table,
+            lazy_item_versions_table,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Picks at most `count` neighbors among `candidates`, sorted nearest first,
+/// with the HNSW neighbor selection heuristic: a candidate is kept only if
+/// it's nearer to the new node than to every neighbor kept before it. Slots
+/// left over are filled with the nearest candidates that were pruned, as
+/// with `keepPrunedConnections` in the HNSW paper.
+fn select_neighbors_heuristic(
+    dense_index: &DenseIndex,
+    candidates: Vec<(SharedNode, MetricResult)>,
+    count: usize,
+) -> Result<Vec<(SharedNode, MetricResult)>, WaCustomError> {
+    let mut selected: Vec<(SharedNode, MetricResult)> = Vec::with_capacity(count);
+    let mut pruned = Vec::new();
+    for (candidate, dist) in candidates {
+        if selected.len() == count {
+            break;
+        }
+        let value = &unsafe { &*candidate }
+            .try_get_data(&dense_index.cache)?
+            .prop
+            .value;
+        let mut diverse = true;
+        for (neighbor, _) in &selected {
+            let neighbor_value = &unsafe { &**neighbor }
+                .try_get_data(&dense_index.cache)?
+                .prop
+                .value;
+            if dense_index.distance(value, neighbor_value)?.score() >= dist.score() {
+                diverse = false;
+                break;
+            }
+        }
+        if diverse {
+            selected.push((candidate, dist));
+        } else {
+            pruned.push((candidate, dist));
+        }
+    }
+    let free_slots = count - selected.len();
+    selected.extend(pruned.into_iter().take(free_slots));
+    Ok(selected)
+}
+
+fn create_node(
+    version_id: Hash,
+    version_number: u16,
+    hnsw_level: HNSWLevel,
+    prop: Arc<NodeProp>,
+    parent: SharedNode,
+    child: SharedNode,
+    neighbors_count: usize,
+) -> SharedNode {
+    let node = ProbNode::new(hnsw_level, prop, parent, child, neighbors_count);
+    ProbLazyItem::new(node, version_id, version_number)
+}
+
+fn get_or_create_version(
+    dense_index: Arc<DenseIndex>,
+    lazy_item_versions_table: Arc<TSHashTable<(VectorId, u16, u8), SharedNode>>,
+    lazy_item: SharedNode,
+    version: Hash,
+    version_number: u16,
+) -> Result<SharedNode, WaCustomError> {
+    let node = unsafe { &*lazy_item }.try_get_data(&dense_index.cache)?;
+
+    let new_version = lazy_item_versions_table.get_or_create(
+        (node.get_id().clone(), version_number, node.hnsw_level.0),
+        || {
+            if let Some(version) =
+                ProbLazyItem::get_version(lazy_item, version_number, &dense_index.cache)
+                    .expect("Deserialization failed")
+            {
+                return version;
+            }
+
+            let new_node = ProbNode::new_with_neighbors(
+                node.hnsw_level,
+                node.prop.clone(),
+                node.clone_neighbors(),
+                node.get_parent(),
+                node.get_child(),
+            );
+
+            let version = ProbLazyItem::new(new_node, version, version_number);
+
+            ProbLazyItem::add_version(lazy_item, version, &dense_index.cache)
+                .expect("Failed to add version")
+                .map_err(|_| "Failed to add version")
+                .unwrap();
+
+            version
+        },
+    );
+
+    Ok(new_version)
+}
+
+fn create_node_edges(
+    dense_index: Arc<DenseIndex>,
+    lazy_node: SharedNode,
+    node: &ProbNode,
+    neighbors: Vec<(SharedNode, MetricResult)>,
+    version: Hash,
+    version_number: u16,
+    serialization_table: Arc<TSHashTable<SharedNode, ()>>,
+    lazy_item_versions_table: Arc<TSHashTable<(VectorId, u16, u8), SharedNode>>,
+) -> Result<(), WaCustomError> {
+    // a node found more than once among the candidates is linked only once,
+    // so it doesn't take up two slots of the neighbor budget
+    let mut linked = HashSet::new();
+    let node_id = node.get_id().0 as u32;
+    // Handle regular neighbors
+    for (neighbor, dist) in neighbors {
+        let neighbor_id = unsafe { &*neighbor }
+            .try_get_data(&dense_index.cache)?
+            .get_id()
+            .clone();
+        if !linked.insert(neighbor_id) {
+            continue;
+        }
+        serialization_table.insert(neighbor.clone(), ());
+
+        let new_lazy_neighbor = get_or_create_version(
+            dense_index.clone(),
+            lazy_item_versions_table.clone(),
+            neighbor,
+            version,
+            version_number,
+        )?;
+        let new_neighbor = unsafe { &*new_lazy_neighbor }.try_get_data(&dense_index.cache)?;
+        let new_neighbor_id = new_neighbor.get_id().0 as u32;
+
+        // a neighbor pushed out of a full list to make room loses its edge
+        // back too, so it doesn't keep linking to a node that dropped it
+        if let Some((_, dropped)) = node.add_neighbor(new_neighbor_id, new_lazy_neighbor, dist) {
+            if dropped == new_lazy_neighbor {
+                continue;
+            }
+            remove_back_edge(
+                &dense_index,
+                dropped,
+                lazy_node,
+                version,
+                version_number,
+                &serialization_table,
+                &lazy_item_versions_table,
+            )?;
+        }
+        // a neighbor whose list is full of closer nodes doesn't link back,
+        // the new node keeps its edge to it regardless, like in plain HNSW
+        if let Some((_, dropped)) = new_neighbor.add_neighbor(node_id, lazy_node, dist) {
+            if dropped == lazy_node {
+                continue;
+            }
+            remove_back_edge(
+                &dense_index,
+                dropped,
+                new_lazy_neighbor,
+                version,
+                version_number,
+                &serialization_table,
+                &lazy_item_versions_table,
+            )?;
+        }
+    }
+
+    serialization_table.insert(lazy_node, ());
+
+    Ok(())
+}
+
+/// Removes the edge from `lazy_node` to `neighbor`, after `neighbor` dropped
+/// its edge to `lazy_node` to make room for a closer neighbor. The edge is
+/// removed from the version of `lazy_node` being built.
+///
+/// The edge may point to another version of `neighbor`, so it's matched by
+/// the full id of the node it points to.
+fn remove_back_edge(
+    dense_index: &Arc<DenseIndex>,
+    lazy_node: SharedNode,
+    neighbor: SharedNode,
+    version: Hash,
+    version_number: u16,
+    serialization_table: &TSHashTable<SharedNode, ()>,
+    lazy_item_versions_table: &Arc<TSHashTable<(VectorId, u16, u8), SharedNode>>,
+) -> Result<(), WaCustomError> {
+    serialization_table.insert(lazy_node, ());
+    let new_version = get_or_create_version(
+        dense_index.clone(),
+        lazy_item_versions_table.clone(),
+        lazy_node,
+        version,
+        version_number,
+    )?;
+    let neighbor_id = unsafe { &*neighbor }
+        .try_get_data(&dense_index.cache)?
+        .get_id()
+        .clone();
+    unsafe { &*new_version }
+        .try_get_data(&dense_index.cache)?
+        .remove_neighbor(neighbor_id.0 as u32, |node| {
+            node == neighbor
+                || unsafe { &*node }
+                    .try_get_data(&dense_index.cache)
+                    .is_ok_and(|node| *node.get_id() == neighbor_id)
+        });
+    Ok(())
+}
+
+/// Node found by `search_level_0`, ordered by score so the best one sits at
+/// the top of a `BinaryHeap`.
+struct SearchCandidate {
+    node: SharedNode,
+    dist: MetricResult,
+}
+
+impl PartialEq for SearchCandidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == std::cmp::Ordering::Equal
+    }
+}
+
+impl Eq for SearchCandidate {}
+
+impl PartialOrd for SearchCandidate {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for SearchCandidate {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.dist.score().total_cmp(&other.dist.score())
+    }
+}
+
+/// Best first search of level 0 starting at `entry`, as in the HNSW paper.
+/// The closest unexpanded candidate is expanded until it can't improve on
+/// the `ef` closest nodes found, which are returned best first.
+///
+/// Nodes not matching `filter` are still expanded, but don't count towards
+/// the `ef` nodes found, so a selective filter makes the search go on until
+/// `ef` matching nodes are found. Past the deadline no more candidates are
+/// expanded, except for the entry, whose neighbors are always scored.
+fn search_level_0(
+    dense_index: &DenseIndex,
+    entry: SharedNode,
+    fvec: &Storage,
+    query_id: &VectorId,
+    ef: usize,
+    filter: Option<&FilterMatcher>,
+    deadline: Option<Instant>,
+) -> Result<Vec<(SharedNode, MetricResult)>, WaCustomError> {
+    let ef = ef.max(1);
+    // keyed by node rather than by id, a re-uploaded vector has a node for
+    // each of its values
+    let mut visited = HashSet::from([entry]);
+    let mut candidates = BinaryHeap::new();
+    // the `ef` closest matching nodes so far, the farthest one on top
+    let mut nearest = BinaryHeap::new();
+
+    let mut score = |node: SharedNode,
+                     candidates: &mut BinaryHeap<SearchCandidate>,
+                     nearest: &mut BinaryHeap<Reverse<SearchCandidate>>|
+     -> Result<(), WaCustomError> {
+        let data = unsafe { &*node }.try_get_data(&dense_index.cache)?;
+        let dist = dense_index.distance(fvec, &data.prop.value)?;
+        if nearest.len() >= ef
+            && nearest
+                .peek()
+                .is_some_and(|Reverse(farthest)| dist.score() <= farthest.dist.score())
+        {
+            return Ok(());
+        }
+
+        candidates.push(SearchCandidate { node, dist });
+
+        let id = data.get_id();
+        // the root node isn't a vector of the collection
+        let matches = id.0 != u64::MAX
+            && id != query_id
+            && match filter {
+                Some(filter) => filter.matches(id)?,
+                None => true,
+            };
+        if matches {
+            nearest.push(Reverse(SearchCandidate { node, dist }));
+            if nearest.len() > ef {
+                nearest.pop();
+            }
+        }
+        Ok(())
+    };
+
+    score(entry, &mut candidates, &mut nearest)?;
+
+    while let Some(SearchCandidate { node, dist }) = candidates.pop() {
+        if nearest.len() >= ef
+            && nearest
+                .peek()
+                .is_some_and(|Reverse(farthest)| dist.score() < farthest.dist.score())
+        {
+            break;
+        }
+
+        // latest version of the node, or the one as of the version the index
+        // was rolled back to
+        let visible = dense_index.get_visible_version(node)?;
+        let data = unsafe { &*visible }.try_get_data(&dense_index.cache)?;
+        for neighbor in data.get_neighbors_raw() {
+            let Some((_, neighbor, _)) = (unsafe { neighbor.load(Ordering::Relaxed).as_ref() })
+            else {
+                continue;
+            };
+            if !visited.insert(*neighbor) || !dense_index.is_visible(*neighbor)? {
+                continue;
+            }
+            score(*neighbor, &mut candidates, &mut nearest)?;
+        }
+
+        // checked after the expansion, an entry that can't be returned (the
+        // root, or a node failing the filter) still leads to candidates that can
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            break;
+        }
+    }
+
+    let mut results: Vec<_> = nearest
+        .into_iter()
+        .map(|Reverse(SearchCandidate { node, dist })| (node, dist))
+        .collect();
+    results.sort_unstable_by(|(_, a), (_, b)| b.score().total_cmp(&a.score()));
+    Ok(results)
+}
+
+/// Nodes rejected by `filter` are still traversed through, but never
+/// returned, so they don't take up the result budget.
+///
+/// At most `max_candidates` of the nearest nodes found are returned, callers
+/// derive it from `ef_search` or `ef_construction`.
+fn traverse_find_nearest(
+    config: &Config,
+    dense_index: &DenseIndex,
+    vtm: SharedNode,
+    fvec: &Storage,
+    nodes_visited: &mut u32,
+    skipm: &mut PerformantFixedSet,
+    cur_level: HNSWLevel,
+    is_indexing: bool,
+    shortlist: bool,
+    ef_search: u32,
+    ef_construction: u32,
+    max_candidates: usize,
+    filter: Option<&FilterMatcher>,
+    deadline: Option<Instant>,
+) -> Result<Vec<(SharedNode, MetricResult)>, WaCustomError> {
+    *nodes_visited += 1;
+    // past the deadline the neighbors are still scored, but not traversed
+    let expired = deadline.is_some_and(|deadline| Instant::now() >= deadline);
+    // one entry per neighbor, sized for the default level 0 neighbors count
+    let mut tasks: SmallVec<[Vec<(SharedNode, MetricResult)>; 64]> = SmallVec::new();
+    let ef = if is_indexing {
+        ef_construction
+    } else {
+        ef_search
+    };
+
+    // latest version of the node, or the one as of the version the index was
+    // rolled back to
+    let visible_lazy_node = dense_index.get_visible_version(vtm)?;
+
+    let node = unsafe { &*visible_lazy_node }.try_get_data(&dense_index.cache)?;
+    if shortlist {
+        let mut neighbors = Vec::new();
+
+        for neighbor in node.get_neighbors_raw() {
+            let (neighbor_id, neighbor_lazy_item) = unsafe {
+                if let Some((neighbor_id, neighbor, _)) = neighbor.load(Ordering::Relaxed).as_ref()
+                {
+                    (*neighbor_id, *neighbor)
+                } else {
+                    continue;
+                }
+            };
+
+            if skipm.is_member(neighbor_id) || !dense_index.is_visible(neighbor_lazy_item)? {
+                continue;
+            }
+            skipm.insert(neighbor_id);
+
+            let neighbor_node = unsafe { &*neighbor_lazy_item }.try_get_data(&dense_index.cache)?;
+            let dist = dense_index.distance(&fvec, &neighbor_node.prop.value)?;
+            let matches = match filter {
+                Some(filter) => filter.matches(&neighbor_node.prop.id)?,
+                None => true,
+            };
+
+            neighbors.push((neighbor_lazy_item, dist, matches));
+        }
+
+        neighbors.sort_unstable_by(|(_, a, _), (_, b, _)| {
+            b.score()
+                .partial_cmp(&a.score())
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+
+        for (neighbor_idx, (neighbor_node, dist, matches)) in neighbors.into_iter().enumerate() {
+            if !expired && *nodes_visited < ef && neighbor_idx < config.search.shortlist_size {
+                let mut z = traverse_find_nearest(
+                    config,
+                    dense_index,
+                    neighbor_node,
+                    fvec,
+                    nodes_visited,
+                    skipm,
+                    cur_level,
+                    is_indexing,
+                    shortlist,
+                    ef_search,
+                    ef_construction,
+                    max_candidates,
+                    filter,
+                    deadline,
+                )?;
+                if matches {
+                    z.push((neighbor_node, dist));
+                }
+                tasks.push(z);
+            } else if matches {
+                tasks.push(vec![(neighbor_node, dist)]);
+            }
+        }
+    } else {
+        for neighbor in node.get_neighbors_raw() {
+            let (neighbor_id, neighbor_lazy_item) = unsafe {
+                if let Some((neighbor_id, neighbor, _)) = neighbor.load(Ordering::Relaxed).as_ref()
+                {
+                    (*neighbor_id, *neighbor)
+                } else {
+                    continue;
+                }
+            };
+
+            if skipm.is_member(neighbor_id) || !dense_index.is_visible(neighbor_lazy_item)? {
+                continue;
+            }
+            skipm.insert(neighbor_id);
+
+            let neighbor = unsafe { &*neighbor_lazy_item }.try_get_data(&dense_index.cache)?;
+            let dist = dense_index.distance(&fvec, &neighbor.prop.value)?;
+            let matches = match filter {
+                Some(filter) => filter.matches(&neighbor.prop.id)?,
+                None => true,
+            };
+
+            if !expired && *nodes_visited < ef {
+                let mut z = traverse_find_nearest(
+                    config,
+                    dense_index,
+                    neighbor_lazy_item,
+                    fvec,
+                    nodes_visited,
+                    skipm,
+                    cur_level,
+                    is_indexing,
+                    shortlist,
+                    ef_search,
+                    ef_construction,
+                    max_candidates,
+                    filter,
+                    deadline,
+                )?;
+                if matches {
+                    z.push((neighbor_lazy_item, dist));
+                }
+                tasks.push(z);
+            } else if matches {
+                tasks.push(vec![(neighbor_lazy_item, dist)]);
+            }
+        }
+    }
+
+    let mut nn: Vec<_> = tasks.into_iter().flatten().collect();
+    nn.sort_unstable_by(|a, b| b.1.score().partial_cmp(&a.1.score()).unwrap());
+    nn.truncate(max_candidates);
+    Ok(nn)
+}
+
+// fn delete_node_update_neighbours(
+//     dense_index: Arc<DenseIndex>,
+//     item: LazyItem<MergedNode>,
+//     skipm: HashSet<VectorId>,
+//     version_id: Hash,
+//     version_number: u16,
+//     hnsw_level: HNSWLevel,
+// ) -> Result<(), WaCustomError> {
+//     let node = item.get_data(dense_index.cache.clone());
+//     for nbr in node.neighbors.iter() {
+//         let mut skipm = skipm.clone();
+//         let nbr_node = nbr.1.get_data(dense_index.cache.clone());
+//         let (nbr_id, nbr_vec) = match nbr_node.get_prop() {
+//             PropState::Ready(prop) => (prop.id.clone(), prop.value.clone()),
+//             PropState::Pending(_) => {
+//                 // TODO: load prop
+//                 return Err(WaCustomError::NodeError("PropState is Pending".to_string()));
+//             }
+//         };
+//         skipm.insert(nbr_id);
+//         let mut nbr_nbrs = traverse_find_nearest(
+//             dense_index.clone(),
+//             dense_index
+//                 .root_vec
+//                 .item
+//                 .clone()
+//                 .get()
+//                 .get_latest_version(dense_index.cache.clone())
+//                 .0,
+//             nbr_vec,
+//             0,
+//             &mut skipm,
+//             HNSWLevel(dense_index.hnsw_params.clone().get().num_layers),
+//             false,
+//         )?;
+
+//         nbr_nbrs.truncate(20);
+//         let nbr_nbrs_set = IdentitySet::from_iter(
+//             nbr_nbrs
+//                 .into_iter()
+//                 .map(|(node, dist)| EagerLazyItem(dist, node)),
+//         );
+
+//         if let Some(version) = nbr.1.get_version(dense_index.cache.clone(), version_number) {
+//             let nbr_current_version_data = version.get_data(dense_index.cache.clone());
+//             nbr_current_version_data
+//                 .neighbors
+//                 .items
+//                 .clone()
+//                 .update(nbr_nbrs_set);
+//         } else {
+//             let (new_version, mut new_neighbours) = create_node_extract_neighbors(
+//                 version_id,
+//                 version_number,
+//                 hnsw_level,
+//                 nbr_node.prop.clone(),
+//                 nbr_node.parent.clone(),
+//                 nbr_node.child.clone(),
+//             );
+//             new_neighbours.items.update(nbr_nbrs_set);
+//             nbr.1.add_version(dense_index.cache.clone(), new_version);
+//             let mut exec_queue = dense_index.exec_queue_nodes.clone();
+//             exec_queue
+//                 .transactional_update(|queue| {
+//                     let mut new_queue = queue.clone();
+//                     new_queue.push(ArcShift::new(nbr.1.clone()));
+//                     new_queue
+//                 })
+//                 .unwrap();
+//         }
+//     }
+//     Ok(())
+// }
+
+// pub fn delete_vector_by_id(
+//     dense_index: Arc<DenseIndex>,
+//     vector_id: VectorId,
+// ) -> Result<(), WaCustomError> {
+//     let (current_version, current_version_number) = dense_index
+//         .vcs
+//         .add_next_version("main")
+//         .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
+//     dense_index.set_current_version(current_version);
+//     update_current_version(&dense_index.lmdb, current_version)?;
+
+//     let vec_raw = get_embedding_by_id(dense_index.clone(), vector_id.clone())?;
+//     let quantized = Arc::new(dense_index.quantization_metric.clone().get().quantize(
+//         &vec_raw.raw_vec,
+//         dense_index.storage_type.clone().get().clone(),
+//     )?);
+//     let mut skipm = HashSet::new();
+//     skipm.insert(vec_raw.hash_vec);
+//     let items = traverse_find_nearest(
+//         dense_index.clone(),
+//         dense_index
+//             .root_vec
+//             .item
+//             .clone()
+//             .get()
+//             .get_latest_version(dense_index.cache.clone())
+//             .0,
+//         quantized,
+//         0,
+//         &mut skipm,
+//         HNSWLevel(dense_index.hnsw_params.clone().get().num_layers),
+//         false,
+//     )?;
+
+//     let mut maybe_item = None;
+
+//     for (item, _) in items {
+//         let data = item.get_data(dense_index.cache.clone());
+//         let prop = match data.get_prop() {
+//             PropState::Ready(prop) => prop.id.clone(),
+//             PropState::Pending(_) => {
+//                 // TODO: load prop
+//                 return Err(WaCustomError::NodeError("PropState is Pending".to_string()));
+//             }
+//         };
+
+//         if prop == vector_id {
+//             maybe_item = Some(item);
+//         }
+//     }
+
+//     let Some(mut item) = maybe_item else {
+//         return Err(WaCustomError::NodeError(
+//             "Node not found in graph".to_string(),
+//         ));
+//     };
+
+//     for level in 0..=dense_index.hnsw_params.clone().get().num_layers {
+//         delete_node_update_neighbours(
+//             dense_index.clone(),
+//             item.clone(),
+//             skipm.clone(),
+//             current_version,
+//             *current_version_number as u16,
+//             HNSWLevel(level),
+//         )?;
+
+//         item = item
+//             .get_data(dense_index.cache.clone())
+//             .parent
+//             .clone()
+//             .item
+//             .get()
+//             .clone();
+//     }
+
+//     auto_commit_transaction(dense_index)?;
+
+//     Ok(())
+// }
+
+// pub fn delete_vector_by_id_in_transaction(
+//     dense_index: Arc<DenseIndex>,
+//     vector_id: VectorId,
+//     transaction_id: Hash,
+// ) -> Result<(), WaCustomError> {
+//     let txn = dense_index
+//         .lmdb
+//         .env
+//         .begin_ro_txn()
+//         .map_err(|err| WaCustomError::DatabaseError(err.to_string()))?;
+//     let version_hash = dense_index
+//         .vcs
+//         .get_version_hash(&transaction_id, &txn)
+//         .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?
+//         .ok_or(WaCustomError::DatabaseError(
+//             "VersionHash not found for transaction".to_string(),
+//         ))?;
+//     txn.abort();
+//     let current_version_number = *version_hash.version as u16;
+
+//     let vec_raw = get_embedding_by_id(dense_index.clone(), vector_id.clone())?;
+//     let quantized = Arc::new(dense_index.quantization_metric.clone().get().quantize(
+//         &vec_raw.raw_vec,
+//         dense_index.storage_type.clone().get().clone(),
+//     )?);
+//     let mut skipm = HashSet::new();
+//     skipm.insert(vec_raw.hash_vec);
+//     let items = traverse_find_nearest(
+//         dense_index.clone(),
+//         dense_index
+//             .root_vec
+//             .item
+//             .clone()
+//             .get()
+//             .get_latest_version(dense_index.cache.clone())
+//             .0,
+//         quantized,
+//         0,
+//         &mut skipm,
+//         HNSWLevel(dense_index.hnsw_params.clone().get().num_layers),
+//         false,
+//     )?;
+
+//     let mut maybe_item = None;
+
+//     for (item, _) in items {
+//         let data = item.get_data(dense_index.cache.clone());
+//         let prop = match data.get_prop() {
+//             PropState::Ready(prop) => prop.id.clone(),
+//             PropState::Pending(_) => {
+//                 // TODO: load prop
+//                 return Err(WaCustomError::NodeError("PropState is Pending".to_string()));
+//             }
+//         };
+
+//         if prop == vector_id {
+//             maybe_item = Some(item);
+//         }
+//     }
+
+//     let Some(mut item) = maybe_item else {
+//         return Err(WaCustomError::NodeError(
+//             "Node not found in graph".to_string(),
+//         ));
+//     };
+
+//     for level in 0..=dense_index.hnsw_params.clone().get().num_layers {
+//         delete_node_update_neighbours(
+//             dense_index.clone(),
+//             item.clone(),
+//             skipm.clone(),
+//             transaction_id,
+//             current_version_number,
+//             HNSWLevel(level),
+//         )?;
+
+//         item = item
+//             .get_data(dense_index.cache.clone())
+//             .parent
+//             .clone()
+//             .item
+//             .get()
+//             .clone();
+//     }
+
+//     auto_commit_transaction(dense_index)?;
+
+//     Ok(())
+// }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::api::vectordb::collections::repo::create_collection;
+    use crate::api::vectordb::collections::repo::tests::create_collection_dto;
+    use crate::api_service::{
+        ann_vector_query, calculate_statistics, init_dense_index_for_collection, run_upload,
+    };
+    use crate::app_context::tests::test_app_context;
+    use crate::models::cache_loader::ProbCache;
+    use crate::models::meta_persist::migrate_shared_embedding_offsets;
+    use crate::models::versioning::VersionControl;
+    use crate::quantization::product::ProductQuantization;
+    use arcshift::ArcShift;
+    use lmdb::Environment;
+    use std::collections::BTreeSet;
+    use std::fs::OpenOptions;
+    use std::io::SeekFrom;
+    use std::sync::Mutex;
+    use tempfile::{tempdir, TempDir};
+
+    const DIM: usize = 4;
+
+    pub(crate) fn test_config() -> Config {
+        toml::from_str(include_str!("../config.toml")).unwrap()
+    }
+
+    pub(crate) fn setup_dense_index(hnsw_params: HNSWHyperParams) -> (Arc<DenseIndex>, TempDir) {
+        setup_seeded_dense_index(hnsw_params, None)
+    }
+
+    fn setup_seeded_dense_index(
+        hnsw_params: HNSWHyperParams,
+        root_vector_seed: Option<u64>,
+    ) -> (Arc<DenseIndex>, TempDir) {
+        setup_quantized_dense_index(hnsw_params, root_vector_seed, QuantizationMetric::Scalar)
+    }
+
+    fn setup_quantized_dense_index(
+        hnsw_params: HNSWHyperParams,
+        root_vector_seed: Option<u64>,
+        quantization_metric: QuantizationMetric,
+    ) -> (Arc<DenseIndex>, TempDir) {
+        let dir = tempdir().unwrap();
+        let env = Arc::new(
+            Environment::new()
+                .set_max_dbs(2)
+                .set_map_size(10485760) // 10MB
+                .open(dir.as_ref())
+                .unwrap(),
+        );
+        let lmdb = MetaDb::from_env(env.clone(), "test").unwrap();
+        let (vcs, hash) = VersionControl::new(env, lmdb.db.clone()).unwrap();
+        let prop_file = Arc::new(RwLock::new(
+            OpenOptions::new()
+                .create(true)
+                .read(true)
+                .append(true)
+                .open(dir.as_ref().join("prop.data"))
+                .unwrap(),
+        ));
+        let index_manager = Arc::new(BufferManagerFactory::new(
+            dir.as_ref().into(),
+            |root, ver: &Hash| root.join(format!("{}.index", **ver)),
+            1.0,
+        ));
+        let vec_raw_manager = vec_raw_manager(dir.as_ref().into(), 1.0);
+        let cache = Arc::new(ProbCache::new(
+            1000,
+            index_manager.clone(),
+            prop_file.clone(),
+        ));
+        let values_range = (-1.0, 1.0);
+        let root = create_root_node(
+            &quantization_metric,
+            StorageType::UnsignedByte,
+            DIM,
+            prop_file.clone(),
+            hash,
+            index_manager.clone(),
+            values_range,
+            &hnsw_params,
+            root_vector_seed,
+        )
+        .unwrap();
+        index_manager.flush_all().unwrap();
+        let lp = Arc::new(generate_tuples(10.0, hnsw_params.num_layers));
+
+        let dense_index = DenseIndex::new(
+            "test".to_string(),
+            root,
+            lp,
+            DIM,
+            prop_file,
+            lmdb,
+            ArcShift::new(hash),
+            ArcShift::new(quantization_metric),
+            ArcShift::new(DistanceMetric::Cosine),
+            ArcShift::new(StorageType::UnsignedByte),
+            Arc::new(vcs),
+            hnsw_params,
+            cache,
+            index_manager,
+            vec_raw_manager,
+            vec![],
+            values_range,
+            0,
+            true,
+            None,
+        );
+        (Arc::new(dense_index), dir)
+    }
+
+    #[test]
+    fn test_seeded_root_vector_is_reproducible() {
+        let root_value = |dense_index: &DenseIndex| {
+            let root = unsafe { &*dense_index.get_root_vec() };
+            root.get_lazy_data().unwrap().prop.value.clone()
+        };
+
+        let hnsw_params = HNSWHyperParams::default_from_config(&test_config());
+        let (first, _first_dir) = setup_seeded_dense_index(hnsw_params.clone(), Some(7));
+        let (second, _second_dir) = setup_seeded_dense_index(hnsw_params.clone(), Some(7));
+        assert_eq!(root_value(&first), root_value(&second));
+
+        let (other, _other_dir) = setup_seeded_dense_index(hnsw_params, Some(8));
+        assert_ne!(root_value(&first), root_value(&other));
+    }
+
+    #[test]
+    fn test_ann_search_loads_pending_entry_node() {
+        let config = test_config();
+        let hnsw_params = HNSWHyperParams::default_from_config(&config);
+        let (dense_index, _dir) = setup_dense_index(hnsw_params.clone());
+
+        // hand `ann_search` a stub that only knows where the root was persisted
+        let file_index = dense_index.root_vec_offset().unwrap();
+        assert!(matches!(file_index, FileIndex::Valid { .. }));
+        let entry = ProbLazyItem::new_pending(file_index);
+
+        let query = QuantizedVectorEmbedding {
+            quantized_vec: Arc::new(
+                QuantizationMetric::Scalar
+                    .quantize(
+                        &[0.1, 0.2, 0.3, 0.4],
+                        StorageType::UnsignedByte,
+                        (-1.0, 1.0),
+                    )
+                    .unwrap(),
+            ),
+            hash_vec: VectorId(1),
+        };
+
+        let cache = dense_index.cache.clone();
+        let results = ann_search(
+            &config,
+            dense_index,
+            query,
+            entry,
+            HNSWLevel(hnsw_params.num_layers),
+            &hnsw_params,
+            None,
+            None,
+        )
+        .unwrap();
+
+        // one result per level, all of them the root vector
+        assert_eq!(results.len(), hnsw_params.num_layers as usize + 1);
+        for (node, _) in results {
+            let node = unsafe { &*node }.try_get_data(&cache).unwrap();
+            assert_eq!(node.prop.id, VectorId(u64::MAX));
+        }
+    }
+
+    pub(crate) fn index_vectors(
+        config: &Config,
+        dense_index: &Arc<DenseIndex>,
+        vecs: &[(u64, Vec<f32>)],
+    ) {
+        let hnsw_params = dense_index.hnsw_params.read().unwrap().clone();
+        let version = dense_index.get_current_version();
+        let version_number = {
+            let txn = dense_index.lmdb.env.begin_ro_txn().unwrap();
+            let version_hash = dense_index.vcs.get_version_hash(&version, &txn).unwrap();
+            *version_hash.unwrap().version as u16
+        };
+        let bufman = dense_index.vec_raw_manager.get(version).unwrap();
+        let branch = dense_index.branch_of(version).unwrap();
+        let serialization_table = Arc::new(TSHashTable::new(16));
+        let lazy_item_versions_table = Arc::new(TSHashTable::new(16));
+
+        for (id, values) in vecs {
+            let raw_emb = RawVectorEmbedding {
+                hash_vec: VectorId(*id),
+                raw_vec: Arc::new(values.clone()),
+                metadata: None,
+            };
+            insert_embedding(bufman.clone(), dense_index.clone(), &raw_emb, version).unwrap();
+            let offset = {
+                let txn = dense_index.lmdb.env.begin_ro_txn().unwrap();
+                let bytes = txn
+                    .get(*dense_index.lmdb.db, &key!(e:branch, raw_emb.hash_vec))
+                    .unwrap();
+                EmbeddingOffset::deserialize(bytes).unwrap().offset
+            };
+            let source = EmbeddingSource {
+                version,
+                offset: Some(offset),
+            };
+
+            let quantized_vec = Arc::new(
+                dense_index
+                    .quantization_metric
+                    .quantize(values, StorageType::UnsignedByte, (-1.0, 1.0))
+                    .unwrap(),
+            );
+            let location = write_prop_to_file(
+                &raw_emb.hash_vec,
+                quantized_vec.clone(),
+                Some(source),
+                &*dense_index.prop_file.read().unwrap(),
+            )
+            .unwrap();
+            let prop = Arc::new(NodeProp {
+                id: raw_emb.hash_vec.clone(),
+                value: quantized_vec.clone(),
+                location: PropLocation::new(location),
+                source: Some(source),
+            });
+
+            index_embedding(
+                config,
+                dense_index.clone(),
+                ptr::null_mut(),
+                QuantizedVectorEmbedding {
+                    quantized_vec,
+                    hash_vec: raw_emb.hash_vec,
+                },
+                prop,
+                dense_index.get_root_vec(),
+                HNSWLevel(hnsw_params.num_layers),
+                version,
+                version_number,
+                serialization_table.clone(),
+                lazy_item_versions_table.clone(),
+                &hnsw_params,
+                HNSWLevel(0),
+            )
+            .unwrap();
+        }
+        bufman.flush().unwrap();
+
+        // the embeddings are indexed above, so they don't count as pending
+        let next = EmbeddingOffset {
+            version,
+            offset: bufman.file_size().unwrap() as u32,
+        };
+        let mut txn = dense_index.lmdb.env.begin_rw_txn().unwrap();
+        txn.put(
+            *dense_index.lmdb.db,
+            &"count_unindexed",
+            &0u32.to_le_bytes(),
+            WriteFlags::empty(),
+        )
+        .unwrap();
+        txn.put(
+            *dense_index.lmdb.db,
+            &"next_embedding_offset",
+            &next.serialize(),
+            WriteFlags::empty(),
+        )
+        .unwrap();
+        txn.commit().unwrap();
+    }
+
+    /// Stores `vecs` as embeddings of `version` waiting to be indexed, as
+    /// `run_upload` does, and returns the version's raw embeddings buffer.
+    fn insert_pending_vectors(
+        dense_index: &Arc<DenseIndex>,
+        version: Hash,
+        vecs: &[(u64, Vec<f32>)],
+    ) -> Arc<BufferManager> {
+        let bufman = dense_index.vec_raw_manager.get(version).unwrap();
+        for (id, values) in vecs {
+            let emb = RawVectorEmbedding {
+                raw_vec: Arc::new(values.clone()),
+                hash_vec: VectorId(*id),
+                metadata: None,
+            };
+            insert_embedding(bufman.clone(), dense_index.clone(), &emb, version).unwrap();
+        }
+        bufman.flush().unwrap();
+        bufman
+    }
+
+    /// `count` vectors spread along the first dimension, `id / scale`.
+    fn line_vectors(count: u64, scale: f32) -> Vec<(u64, Vec<f32>)> {
+        (0..count)
+            .map(|id| (id, vec![id as f32 / scale, 0.2, -0.3, 0.4]))
+            .collect()
+    }
+
+    /// Searches the graph of `dense_index` for the `k` nearest neighbors of
+    /// `query`, as the search API does.
+    fn search(
+        config: &Config,
+        dense_index: &Arc<DenseIndex>,
+        query: &[f32],
+        k: usize,
+    ) -> Vec<(VectorId, MetricResult)> {
+        search_with(config, dense_index, query, k, None, None)
+    }
+
+    /// Like `search`, keeping only the vectors matching `filter` and giving
+    /// the graph search up at `deadline`.
+    fn search_with(
+        config: &Config,
+        dense_index: &Arc<DenseIndex>,
+        query: &[f32],
+        k: usize,
+        filter: Option<&Filter>,
+        deadline: Option<Instant>,
+    ) -> Vec<(VectorId, MetricResult)> {
+        let hnsw_params = dense_index.hnsw_params.read().unwrap().clone();
+        let quantized_vec = Arc::new(
+            dense_index
+                .quantization_metric
+                .clone()
+                .get()
+                .quantize(query, StorageType::UnsignedByte, (-1.0, 1.0))
+                .unwrap(),
+        );
+        let results = ann_search(
+            config,
+            dense_index.clone(),
+            QuantizedVectorEmbedding {
+                quantized_vec,
+                hash_vec: VectorId(u64::MAX - 1),
+            },
+            dense_index.get_root_vec(),
+            HNSWLevel(hnsw_params.num_layers),
+            &hnsw_params,
+            filter,
+            deadline,
+        )
+        .unwrap();
+        finalize_ann_results(dense_index.clone(), results, query, Some(k), filter).unwrap()
+    }
+
+    #[test]
+    fn test_euclidean_collection_is_ranked_by_euclidean_distance() {
+        let config = test_config();
+        let hnsw_params = HNSWHyperParams::default_from_config(&config);
+        let (dense_index, _dir) = setup_dense_index(hnsw_params);
+        dense_index
+            .distance_metric
+            .clone()
+            .update(DistanceMetric::Euclidean);
+
+        // 0 points the same way as the query but lies far from it, 1 is the
+        // nearest by euclidean distance though not by cosine similarity
+        let vecs = vec![
+            (0, vec![0.9, 0.0, 0.0, 0.0]),
+            (1, vec![0.45, 0.1, 0.0, 0.0]),
+            (2, vec![0.0, 0.8, 0.1, 0.0]),
+            (3, vec![-0.5, 0.2, 0.3, 0.1]),
+            (4, vec![0.1, -0.6, 0.0, 0.4]),
+        ];
+        index_vectors(&config, &dense_index, &vecs);
+
+        let query = [0.5, 0.0, 0.0, 0.0];
+        let results = search(&config, &dense_index, &query, 3);
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].0, VectorId(1));
+        assert_eq!(results[1].0, VectorId(0));
+        let mut previous = 0.0;
+        for (id, result) in &results {
+            let MetricResult::EuclideanDistance(distance) = result else {
+                panic!("expected a euclidean distance, got {:?}", result);
+            };
+            let values = &vecs[id.0 as usize].1;
+            let expected = query
+                .iter()
+                .zip(values)
+                .map(|(a, b)| (a - b) * (a - b))
+                .sum::<f32>()
+                .sqrt();
+            assert!((distance.0 - expected).abs() < 1e-6);
+            assert!(distance.0 >= previous);
+            previous = distance.0;
+        }
+    }
+
+    #[test]
+    fn test_create_node_edges_skips_duplicate_candidates() {
+        let config = test_config();
+        let hnsw_params = HNSWHyperParams::default_from_config(&config);
+        let (dense_index, _dir) = setup_dense_index(hnsw_params);
+        let version = dense_index.get_current_version();
+
+        let new_node = |id: u64, values: &[f32]| {
+            let value = dense_index
+                .quantization_metric
+                .quantize(values, StorageType::UnsignedByte, (-1.0, 1.0))
+                .unwrap();
+            let prop = Arc::new(NodeProp {
+                id: VectorId(id),
+                value: Arc::new(value),
+                location: PropLocation::new((FileOffset(0), BytesToRead(0))),
+                source: None,
+            });
+            create_node(
+                version,
+                0,
+                HNSWLevel(0),
+                prop,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                8,
+            )
+        };
+        let neighbor = new_node(1, &[0.1, 0.2, 0.3, 0.4]);
+        let lazy_node = new_node(2, &[0.1, 0.2, 0.3, 0.5]);
+        let node = unsafe { &*lazy_node }.get_lazy_data().unwrap();
+        let dist = MetricResult::CosineSimilarity(CosineSimilarity(0.9));
+
+        // the neighbor is found twice
+        create_node_edges(
+            dense_index.clone(),
+            lazy_node,
+            node,
+            vec![(neighbor, dist), (neighbor, dist)],
+            version,
+            1,
+            Arc::new(TSHashTable::new(16)),
+            Arc::new(TSHashTable::new(16)),
+        )
+        .unwrap();
+
+        let neighbor_ids = |node: &ProbNode| -> Vec<u64> {
+            node.get_neighbors()
+                .into_iter()
+                .map(|neighbor| {
+                    unsafe { &*neighbor }
+                        .try_get_data(&dense_index.cache)
+                        .unwrap()
+                        .get_id()
+                        .0
+                })
+                .collect()
+        };
+        assert_eq!(neighbor_ids(node), vec![1]);
+        let neighbor_version = ProbLazyItem::get_version(neighbor, 1, &dense_index.cache)
+            .unwrap()
+            .unwrap();
+        let neighbor_node = unsafe { &*neighbor_version }
+            .try_get_data(&dense_index.cache)
+            .unwrap();
+        assert_eq!(neighbor_ids(neighbor_node), vec![2]);
+    }
+
+    #[test]
+    fn test_create_node_edges_prunes_back_edges() {
+        let config = test_config();
+        let hnsw_params = HNSWHyperParams::default_from_config(&config);
+        let (dense_index, _dir) = setup_dense_index(hnsw_params);
+        let version = dense_index.get_current_version();
+
+        // nodes with room for 2 neighbors, except `d` with room for 1
+        let new_node = |id: u64, neighbors_count: usize| {
+            let value = dense_index
+                .quantization_metric
+                .quantize(
+                    &[0.1, 0.2, 0.3, (id % 10) as f32 / 10.0],
+                    StorageType::UnsignedByte,
+                    (-1.0, 1.0),
+                )
+                .unwrap();
+            let prop = Arc::new(NodeProp {
+                id: VectorId(id),
+                value: Arc::new(value),
+                location: PropLocation::new((FileOffset(0), BytesToRead(0))),
+                source: None,
+            });
+            create_node(
+                version,
+                0,
+                HNSWLevel(0),
+                prop,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                neighbors_count,
+            )
+        };
+        let score = |score| MetricResult::CosineSimilarity(CosineSimilarity(score));
+        let serialization_table = Arc::new(TSHashTable::new(16));
+        let lazy_item_versions_table = Arc::new(TSHashTable::new(16));
+        let data = |lazy_node: SharedNode| {
+            unsafe { &*lazy_node }
+                .try_get_data(&dense_index.cache)
+                .unwrap()
+        };
+        let [a, b, c, e] = [1, 2, 3, 5].map(|id| new_node(id, 2));
+        let d = new_node(4, 1);
+        // `d` is already full with a closer neighbor
+        data(d).add_neighbor(5, e, score(0.99));
+        data(e).add_neighbor(4, d, score(0.99));
+
+        let lazy_node = new_node(6, 2);
+        create_node_edges(
+            dense_index.clone(),
+            lazy_node,
+            data(lazy_node),
+            vec![
+                (d, score(0.5)),
+                (a, score(0.9)),
+                (b, score(0.8)),
+                (c, score(0.95)),
+            ],
+            version,
+            1,
+            serialization_table.clone(),
+            lazy_item_versions_table.clone(),
+        )
+        .unwrap();
+
+        let current = |lazy_node| {
+            ProbLazyItem::get_version(lazy_node, 1, &dense_index.cache)
+                .unwrap()
+                .unwrap_or(lazy_node)
+        };
+        let neighbor_ids = |lazy_node| -> BTreeSet<u64> {
+            data(current(lazy_node))
+                .get_neighbors()
+                .into_iter()
+                .map(|neighbor| data(neighbor).get_id().0)
+                .collect()
+        };
+        // `d` had no room for the new node, `b` was pushed out by `c`
+        assert_eq!(neighbor_ids(lazy_node), BTreeSet::from([1, 3]));
+        assert_eq!(neighbor_ids(d), BTreeSet::from([5]));
+        assert!(neighbor_ids(b).is_empty());
+
+        // and every edge left goes both ways
+        for lazy_node in [a, b, c, d, e, lazy_node] {
+            let id = data(lazy_node).get_id().0;
+            for neighbor in data(current(lazy_node)).get_neighbors() {
+                let back_edges: Vec<_> = data(neighbor)
+                    .get_neighbors()
+                    .into_iter()
+                    .map(|back| data(back).get_id().0)
+                    .collect();
+                assert!(
+                    back_edges.contains(&id),
+                    "{} links to {} but not back",
+                    id,
+                    data(neighbor).get_id().0
+                );
+            }
+        }
+
+        // a new node whose only candidate is full keeps its edge to it,
+        // rather than ending up without any
+        let f = new_node(7, 2);
+        create_node_edges(
+            dense_index.clone(),
+            f,
+            data(f),
+            vec![(d, score(0.5))],
+            version,
+            1,
+            serialization_table.clone(),
+            lazy_item_versions_table.clone(),
+        )
+        .unwrap();
+        assert_eq!(neighbor_ids(f), BTreeSet::from([4]));
+        assert_eq!(neighbor_ids(d), BTreeSet::from([5]));
+
+        // ids sharing their low 32 bits are told apart when an edge is removed
+        let [x, p, q] = [9, 8, 8 + (1 << 32)].map(|id| new_node(id, 2));
+        data(x).add_neighbor(8, p, score(0.9));
+        data(x).add_neighbor(8, q, score(0.8));
+        remove_back_edge(
+            &dense_index,
+            x,
+            q,
+            version,
+            1,
+            &serialization_table,
+            &lazy_item_versions_table,
+        )
+        .unwrap();
+        assert_eq!(neighbor_ids(x), BTreeSet::from([8]));
+    }
+
+    #[test]
+    fn test_heuristic_neighbor_selection_spreads_neighbors() {
+        let config = test_config();
+        let hnsw_params = HNSWHyperParams::default_from_config(&config);
+        let (dense_index, _dir) = setup_dense_index(hnsw_params);
+        let version = dense_index.get_current_version();
+        let quantize = |values: &[f32]| {
+            Arc::new(
+                dense_index
+                    .quantization_metric
+                    .quantize(values, StorageType::UnsignedByte, (0.0, 1.0))
+                    .unwrap(),
+            )
+        };
+
+        // a tight cluster of 5 vectors, and one vector off to the side that
+        // is a little farther from the new node
+        let query = quantize(&[0.8, 0.6, 0.0, 0.0]);
+        let mut vecs: Vec<_> = (0..5u64)
+            .map(|i| (i, vec![1.0, 0.05 * i as f32, 0.0, 0.0]))
+            .collect();
+        vecs.push((5, vec![0.0, 1.0, 0.0, 0.0]));
+        let mut candidates: Vec<_> = vecs
+            .iter()
+            .map(|(id, values)| {
+                let value = quantize(values);
+                let dist = dense_index
+                    .distance_metric
+                    .calculate(&query, &value)
+                    .unwrap();
+                let prop = Arc::new(NodeProp {
+                    id: VectorId(*id),
+                    value,
+                    location: PropLocation::new((FileOffset(0), BytesToRead(0))),
+                    source: None,
+                });
+                let node = create_node(
+                    version,
+                    0,
+                    HNSWLevel(0),
+                    prop,
+                    ptr::null_mut(),
+                    ptr::null_mut(),
+                    8,
+                );
+                (node, dist)
+            })
+            .collect();
+        candidates.sort_unstable_by(|a, b| b.1.score().partial_cmp(&a.1.score()).unwrap());
+
+        let ids = |selected: &[(SharedNode, MetricResult)]| -> Vec<u64> {
+            selected
+                .iter()
+                .map(|(node, _)| {
+                    unsafe { &**node }
+                        .try_get_data(&dense_index.cache)
+                        .unwrap()
+                        .get_id()
+                        .0
+                })
+                .collect()
+        };
+        // average similarity between the picked neighbors, lower is more
+        // spread out
+        let clustering = |selected: &[(SharedNode, MetricResult)]| {
+            let values: Vec<_> = selected
+                .iter()
+                .map(|(node, _)| {
+                    unsafe { &**node }
+                        .try_get_data(&dense_index.cache)
+                        .unwrap()
+                        .prop
+                        .value
+                        .clone()
+                })
+                .collect();
+            let mut total = 0.0;
+            let mut pairs = 0;
+            for (i, x) in values.iter().enumerate() {
+                for y in &values[i + 1..] {
+                    total += dense_index.distance_metric.calculate(x, y).unwrap().score();
+                    pairs += 1;
+                }
+            }
+            total / pairs as f32
+        };
+
+        // the simple selection only picks from the cluster
+        let mut simple = candidates.clone();
+        simple.truncate(3);
+        assert!(ids(&simple).iter().all(|&id| id < 5));
+
+        // the heuristic picks one vector of the cluster and the one aside,
+        // then fills the last slot with the nearest vector it pruned
+        let heuristic = select_neighbors_heuristic(&dense_index, candidates, 3).unwrap();
+        let heuristic_ids = ids(&heuristic);
+        assert_eq!(heuristic_ids.len(), 3);
+        assert!(heuristic_ids[..2].contains(&5));
+        assert!(heuristic_ids[2] < 5);
+        assert!(clustering(&heuristic) < clustering(&simple));
+    }
+
+    #[test]
+    fn test_heuristic_selection_fills_neighbor_lists() {
+        let config = test_config();
+        // two clusters of vectors along a line, where the heuristic alone
+        // keeps about one neighbor on each side of a vector
+        let vecs: Vec<_> = (0..60u64)
+            .map(|i| {
+                let step = (i % 30) as f32 * 0.01;
+                if i < 30 {
+                    (i, vec![1.0, step, 0.0, 0.0])
+                } else {
+                    (i, vec![step, 1.0, 0.0, 0.0])
+                }
+            })
+            .collect();
+
+        // the root node may take a slot too, and isn't fetched, so the
+        // heuristic is held to as many neighbors as the simple selection
+        let neighbors_len = |neighbor_selection| {
+            let mut hnsw_params = HNSWHyperParams::default_from_config(&config);
+            hnsw_params.neighbor_selection = neighbor_selection;
+            hnsw_params.level_0_neighbors_count = 8;
+            hnsw_params.neighbors_count = 4;
+            hnsw_params.ef_construction = 32;
+            let (dense_index, _dir) = setup_dense_index(hnsw_params);
+            index_vectors(&config, &dense_index, &vecs);
+
+            let levels = vector_fetch(dense_index.clone(), VectorId(59)).unwrap();
+            levels[0].as_ref().unwrap().1.len()
+        };
+        let simple_len = neighbors_len(NeighborSelection::Simple);
+        assert!(simple_len > 2);
+        assert_eq!(neighbors_len(NeighborSelection::Heuristic), simple_len);
+    }
+
+    #[test]
+    fn test_search_past_deadline_returns_partial_results() {
+        let config = test_config();
+        let hnsw_params = HNSWHyperParams::default_from_config(&config);
+        let (dense_index, _dir) = setup_dense_index(hnsw_params);
+
+        let vecs: Vec<_> = (0..40u64)
+            .map(|i| {
+                let angle = i as f32 * 0.15;
+                (i, vec![angle.cos(), angle.sin(), 0.3, -0.2])
+            })
+            .collect();
+        index_vectors(&config, &dense_index, &vecs);
+
+        // already passed when the search starts
+        let deadline = Instant::now();
+        let results = search_with(&config, &dense_index, &vecs[12].1, 5, None, Some(deadline));
+        assert!(!results.is_empty());
+        for (id, _) in &results {
+            assert!(id.0 < 40);
+        }
+    }
+
+    #[test]
+    fn test_ann_search_recall_against_brute_force() {
+        let config = test_config();
+        let hnsw_params = HNSWHyperParams::default_from_config(&config);
+        let (dense_index, _dir) = setup_dense_index(hnsw_params.clone());
+
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut random_vec = || {
+            (0..DIM)
+                .map(|_| rng.gen_range(-1.0..1.0))
+                .collect::<Vec<f32>>()
+        };
+        let vecs: Vec<_> = (0..200u64).map(|i| (i, random_vec())).collect();
+        index_vectors(&config, &dense_index, &vecs);
+        let quantize = |values: &[f32]| {
+            dense_index
+                .quantization_metric
+                .quantize(values, StorageType::UnsignedByte, (-1.0, 1.0))
+                .unwrap()
+        };
+        let quantized: Vec<_> = vecs.iter().map(|(_, values)| quantize(values)).collect();
+
+        // well below the number of vectors, so the search can't just visit
+        // all of them
+        let search_params = HNSWHyperParams {
+            ef_search: 32,
+            ..hnsw_params.clone()
+        };
+        let k = 10;
+        let mut found = 0;
+        for _ in 0..20 {
+            let query = quantize(&random_vec());
+
+            // exact top k under the same metric the graph was built with
+            let mut exact: Vec<_> = quantized
+                .iter()
+                .enumerate()
+                .map(|(id, value)| {
+                    let dist = dense_index
+                        .distance_metric
+                        .calculate(&query, value)
+                        .unwrap();
+                    (id as u64, dist.score())
+                })
+                .collect();
+            exact.sort_unstable_by(|(_, a), (_, b)| b.total_cmp(a));
+            let exact: HashSet<_> = exact[..k].iter().map(|(id, _)| *id).collect();
+
+            let results = ann_search(
+                &config,
+                dense_index.clone(),
+                QuantizedVectorEmbedding {
+                    quantized_vec: Arc::new(query),
+                    hash_vec: VectorId(u64::MAX - 1),
+                },
+                dense_index.get_root_vec(),
+                HNSWLevel(hnsw_params.num_layers),
+                &search_params,
+                None,
+                None,
+            )
+            .unwrap();
+            let mut approx: Vec<_> = results
+                .iter()
+                .map(|(node, dist)| {
+                    let node = unsafe { &**node }.try_get_data(&dense_index.cache).unwrap();
+                    (node.get_id().0, dist.score())
+                })
+                .filter(|(id, _)| *id != u64::MAX)
+                .collect();
+            approx.sort_unstable_by(|(_, a), (_, b)| b.total_cmp(a));
+            // a node can be reached through more than one of its versions
+            let mut seen = HashSet::new();
+            approx.retain(|(id, _)| seen.insert(*id));
+            found += approx
+                .iter()
+                .take(k)
+                .filter(|(id, _)| exact.contains(id))
+                .count();
+        }
+
+        let recall = found as f32 / (20 * k) as f32;
+        assert!(recall >= 0.9, "recall@{} is {}", k, recall);
+    }
+
+    #[test]
+    fn test_exact_search_matches_manual_computation() {
+        let config = test_config();
+        let (dense_index, _dir) = setup_dense_index(HNSWHyperParams::default_from_config(&config));
+        let version = dense_index.get_current_version();
+        let vecs: Vec<_> = (0..10u64)
+            .map(|i| (i, vec![1.0, i as f32 * 0.3, -0.5, 0.2 * (i % 3) as f32]))
+            .collect();
+        insert_pending_vectors(&dense_index, version, &vecs);
+
+        let query = [0.8, 0.9, -0.4, 0.1];
+        let mut expected: Vec<_> = vecs
+            .iter()
+            .map(|(id, values)| {
+                let dot: f32 = query.iter().zip(values).map(|(a, b)| a * b).sum();
+                let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+                (*id, dot / (norm(&query) * norm(values)))
+            })
+            .collect();
+        expected.sort_unstable_by(|(_, a), (_, b)| b.total_cmp(a));
+
+        let results = exact_search(&dense_index, &query, 3, None, false).unwrap();
+        assert_eq!(results.len(), 3);
+        for ((id, dist), (expected_id, expected_score)) in results.iter().zip(&expected) {
+            assert_eq!(id.0, *expected_id);
+            assert!((dist.get_value() - expected_score).abs() < 1e-6);
+        }
+
+        let all = exact_search(&dense_index, &query, 100, None, false).unwrap();
+        assert_eq!(all.len(), vecs.len());
+        // none of them is indexed yet
+        assert!(exact_search(&dense_index, &query, 100, None, true)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_exact_search_uses_the_metric_and_skips_pending_and_overwritten() {
+        let config = test_config();
+        let hnsw_params = HNSWHyperParams::default_from_config(&config);
+        let (dense_index, _dir) = setup_dense_index(hnsw_params);
+        dense_index
+            .distance_metric
+            .clone()
+            .update(DistanceMetric::Euclidean);
+        let vecs: Vec<_> = (0..8u64)
+            .map(|i| (i, vec![i as f32 / 10.0, 0.1, -0.2, 0.3]))
+            .collect();
+        index_vectors(&config, &dense_index, &vecs);
+        // 2 is overwritten, the embedding it was indexed with no longer counts
+        index_vectors(&config, &dense_index, &[(2, vec![-0.9, 0.9, 0.9, -0.9])]);
+
+        // uploaded after indexing, still pending
+        let version = dense_index.get_current_version();
+        insert_pending_vectors(&dense_index, version, &[(100, vec![0.32, 0.1, -0.2, 0.3])]);
+
+        let query = [0.33, 0.1, -0.2, 0.3];
+        let indexed = exact_search(&dense_index, &query, 2, None, true).unwrap();
+        let ids: Vec<_> = indexed.iter().map(|(id, _)| id.0).collect();
+        assert_eq!(ids, vec![3, 4]);
+        let MetricResult::EuclideanDistance(distance) = indexed[1].1 else {
+            panic!("expected a euclidean distance, got {:?}", indexed[1].1);
+        };
+        assert!((distance.0 - 0.07).abs() < 1e-5);
+
+        let all = exact_search(&dense_index, &query, 3, None, false).unwrap();
+        assert_eq!(all[0].0, VectorId(100));
+        let every = exact_search(&dense_index, &query, usize::MAX, None, false).unwrap();
+        assert_eq!(every.len(), 9);
+        let twos: Vec<_> = every.iter().filter(|(id, _)| id.0 == 2).collect();
+        assert_eq!(twos.len(), 1);
+        assert!(twos[0].1.distance() > 1.0);
+    }
+
+    #[test]
+    fn test_search_resolves_pending_root() {
+        let config = test_config();
+        let hnsw_params = HNSWHyperParams::default_from_config(&config);
+        let (dense_index, _dir) = setup_dense_index(hnsw_params.clone());
+
+        let vecs: Vec<_> = (0..20u64)
+            .map(|i| {
+                let angle = i as f32 * 0.3;
+                (i, vec![angle.cos(), angle.sin(), 0.2, -0.1])
+            })
+            .collect();
+        index_vectors(&config, &dense_index, &vecs);
+
+        // a freshly loaded index only knows where its root was persisted, the
+        // node itself is served by the cache
+        let root = dense_index.get_root_vec();
+        let file_index = dense_index.root_vec_offset().unwrap();
+        let FileIndex::Valid {
+            offset, version_id, ..
+        } = file_index.clone()
+        else {
+            panic!("root node isn't persisted");
+        };
+        dense_index
+            .cache
+            .insert_lazy_object(version_id, offset.0, root);
+        dense_index.set_root_vec(ProbLazyItem::new_pending(file_index));
+
+        let query = &vecs[7].1;
+        let search = || {
+            let quantized_vec = Arc::new(
+                dense_index
+                    .quantization_metric
+                    .quantize(query, StorageType::UnsignedByte, (-1.0, 1.0))
+                    .unwrap(),
+            );
+            ann_search(
+                &config,
+                dense_index.clone(),
+                QuantizedVectorEmbedding {
+                    quantized_vec,
+                    hash_vec: VectorId(u64::MAX - 1),
+                },
+                dense_index.get_root_vec(),
+                HNSWLevel(hnsw_params.num_layers),
+                &hnsw_params,
+                None,
+                None,
+            )
+        };
 
-    let (latest_version_lazy_node, _latest_version) =
-        ProbLazyItem::get_latest_version(vtm, &dense_index.cache)?;
+        let results = search().unwrap();
+        let results =
+            finalize_ann_results(dense_index.clone(), results, query, Some(5), None).unwrap();
+        assert_eq!(results[0].0, VectorId(7));
 
-    let node = unsafe { &*latest_version_lazy_node }.try_get_data(&dense_index.cache)?;
-    if shortlist {
-        let mut neighbors = Vec::new();
+        // a pending root without a location can't be resolved
+        dense_index.set_root_vec(ProbLazyItem::new_pending(FileIndex::Invalid));
+        assert!(search().is_err());
+    }
 
-        for neighbor in node.get_neighbors_raw() {
-            let (neighbor_id, neighbor_lazy_item) = unsafe {
-                if let Some((neighbor_id, neighbor, _)) = neighbor.load(Ordering::Relaxed).as_ref()
-                {
-                    (*neighbor_id, *neighbor)
-                } else {
-                    continue;
-                }
-            };
+    #[test]
+    fn test_ann_search_with_metadata_filter() {
+        let config = test_config();
+        let hnsw_params = HNSWHyperParams::default_from_config(&config);
+        let (dense_index, _dir) = setup_dense_index(hnsw_params);
 
-            if skipm.is_member(neighbor_id) {
-                continue;
+        let vecs: Vec<_> = (0..20u64)
+            .map(|i| (i, vec![i as f32 / 25.0, 0.5, -0.3, 0.1]))
+            .collect();
+        index_vectors(&config, &dense_index, &vecs);
+        let mut txn = dense_index.lmdb.env.begin_rw_txn().unwrap();
+        for i in 0..20u64 {
+            let metadata = serde_json::json!({ "group": i % 4 });
+            put_metadata(
+                &mut txn,
+                *dense_index.lmdb.db,
+                &VectorId(i),
+                Some(&metadata),
+            )
+            .unwrap();
+        }
+        txn.commit().unwrap();
+        assert_eq!(
+            get_metadata_by_id(&dense_index, &VectorId(5)).unwrap(),
+            Some(serde_json::json!({ "group": 1 }))
+        );
+        assert_eq!(
+            get_metadata_by_id(&dense_index, &VectorId(u64::MAX)).unwrap(),
+            None
+        );
+
+        let query = [0.4, 0.5, -0.3, 0.1];
+        let search =
+            |filter: &Filter| search_with(&config, &dense_index, &query, 10, Some(filter), None);
+
+        let filter: Filter =
+            serde_json::from_value(serde_json::json!({ "group": { "$eq": 1 } })).unwrap();
+        let results = search(&filter);
+        assert!(!results.is_empty());
+        for (id, _) in &results {
+            assert_eq!(id.0 % 4, 1);
+        }
+
+        let filter: Filter =
+            serde_json::from_value(serde_json::json!({ "group": { "$gt": 10 } })).unwrap();
+        assert!(search(&filter).is_empty());
+    }
+
+    #[test]
+    fn test_filter_matcher_parses_metadata_once() {
+        let config = test_config();
+        let hnsw_params = HNSWHyperParams::default_from_config(&config);
+        let (dense_index, _dir) = setup_dense_index(hnsw_params);
+
+        let mut txn = dense_index.lmdb.env.begin_rw_txn().unwrap();
+        for i in 0..4u64 {
+            let metadata = serde_json::json!({ "group": i % 2 });
+            put_metadata(
+                &mut txn,
+                *dense_index.lmdb.db,
+                &VectorId(i),
+                Some(&metadata),
+            )
+            .unwrap();
+        }
+        txn.commit().unwrap();
+
+        let filter: Filter =
+            serde_json::from_value(serde_json::json!({ "group": { "$eq": 1 } })).unwrap();
+        let matcher = FilterMatcher::new(&dense_index, &filter).unwrap();
+        for _ in 0..2 {
+            let matching: Vec<_> = (0..4u64)
+                .filter(|&i| matcher.matches(&VectorId(i)).unwrap())
+                .collect();
+            assert_eq!(matching, vec![1, 3]);
+        }
+        // the root has no metadata
+        assert!(!matcher.matches(&VectorId(u64::MAX)).unwrap());
+        assert_eq!(matcher.matches.borrow().len(), 5);
+    }
+
+    #[test]
+    fn test_selective_filter_still_finds_k_matches() {
+        let config = test_config();
+        let mut hnsw_params = HNSWHyperParams::default_from_config(&config);
+        hnsw_params.ef_search = 8;
+        let (dense_index, _dir) = setup_seeded_dense_index(hnsw_params, Some(7));
+        let mut dense_index = (*dense_index).clone();
+        dense_index.level_seed = Some(7);
+        let dense_index = Arc::new(dense_index);
+
+        let vecs: Vec<_> = (0..300u64)
+            .map(|i| {
+                let i = i as f32;
+                (
+                    i as u64,
+                    vec![
+                        (i * 0.37).cos(),
+                        (i * 0.37).sin(),
+                        (i * 0.11).cos() * 0.5,
+                        (i * 0.23).sin() * 0.5,
+                    ],
+                )
+            })
+            .collect();
+        index_vectors(&config, &dense_index, &vecs);
+        // 12 of the 300 vectors are in group 3
+        let mut txn = dense_index.lmdb.env.begin_rw_txn().unwrap();
+        for (id, _) in &vecs {
+            let metadata = serde_json::json!({ "group": id % 25 });
+            put_metadata(
+                &mut txn,
+                *dense_index.lmdb.db,
+                &VectorId(*id),
+                Some(&metadata),
+            )
+            .unwrap();
+        }
+        txn.commit().unwrap();
+
+        let filter: Filter =
+            serde_json::from_value(serde_json::json!({ "group": { "$eq": 3 } })).unwrap();
+        let query = vec![0.6, 0.8, 0.2, -0.1];
+        let results = search_with(&config, &dense_index, &query, 5, Some(&filter), None);
+        let expected = exact_search(&dense_index, &query, 5, Some(&filter), true).unwrap();
+
+        // with only 1 in 25 nodes matching, counting the others towards
+        // `ef_search` would end the search with a match or two
+        assert_eq!(results.len(), 5);
+        let found = results
+            .iter()
+            .filter(|(id, _)| expected.iter().any(|(expected_id, _)| expected_id == id))
+            .count();
+        assert!(found >= 4, "recall {}/5", found);
+    }
+
+    #[test]
+    fn test_metadata_follows_the_latest_embedding() {
+        let config = test_config();
+        let (dense_index, _dir) = setup_dense_index(HNSWHyperParams::default_from_config(&config));
+        let metadata = serde_json::json!({ "tag": "a" });
+        let embedding = |id: u64, metadata: Option<serde_json::Value>| RawVectorEmbedding {
+            raw_vec: Arc::new(vec![0.1, 0.2, -0.3, 0.4]),
+            hash_vec: VectorId(id),
+            metadata,
+        };
+
+        let version = dense_index.get_current_version();
+        let bufman = dense_index.vec_raw_manager.get(version).unwrap();
+        insert_embedding(
+            bufman.clone(),
+            dense_index.clone(),
+            &embedding(1, Some(metadata.clone())),
+            version,
+        )
+        .unwrap();
+        assert_eq!(
+            get_metadata_by_id(&dense_index, &VectorId(1)).unwrap(),
+            Some(metadata.clone())
+        );
+
+        // updating the vector without metadata drops the old metadata
+        insert_embedding(bufman, dense_index.clone(), &embedding(1, None), version).unwrap();
+        assert_eq!(
+            get_metadata_by_id(&dense_index, &VectorId(1)).unwrap(),
+            None
+        );
+
+        // nothing of an aborted transaction is recorded
+        let transaction =
+            DenseIndexTransaction::new(dense_index.clone(), config.commit_flush_batch_size)
+                .unwrap();
+        transaction.post_raw_embedding(embedding(2, Some(metadata.clone())));
+        transaction.abort().unwrap();
+        assert_eq!(
+            get_metadata_by_id(&dense_index, &VectorId(2)).unwrap(),
+            None
+        );
+        assert!(!vector_exists(&dense_index, &VectorId(2)).unwrap());
+
+        // a committed one records the metadata with the embedding
+        let transaction =
+            DenseIndexTransaction::new(dense_index.clone(), config.commit_flush_batch_size)
+                .unwrap();
+        transaction.post_raw_embedding(embedding(2, Some(metadata.clone())));
+        transaction.pre_commit().unwrap();
+        assert_eq!(
+            get_metadata_by_id(&dense_index, &VectorId(2)).unwrap(),
+            Some(metadata)
+        );
+        assert!(vector_exists(&dense_index, &VectorId(2)).unwrap());
+    }
+
+    #[test]
+    fn test_reupload_replaces_vector() {
+        let config = test_config();
+        let hnsw_params = HNSWHyperParams::default_from_config(&config);
+        let (dense_index, _dir) = setup_dense_index(hnsw_params);
+
+        let old = vec![0.9, 0.1, 0.2, 0.3];
+        let new = vec![-0.9, -0.1, -0.2, -0.3];
+        let mut vecs: Vec<_> = (0..20u64)
+            .map(|i| (i, vec![i as f32 / 25.0, 0.5, -0.3, 0.1]))
+            .collect();
+        vecs[5].1 = old.clone();
+        index_vectors(&config, &dense_index, &vecs);
+        index_vectors(&config, &dense_index, &[(5, new.clone())]);
+
+        assert_eq!(
+            *get_embedding_by_id(dense_index.clone(), &VectorId(5))
+                .unwrap()
+                .unwrap()
+                .raw_vec,
+            new
+        );
+
+        // the latest vector is found, once
+        let results = search(&config, &dense_index, &new, 20);
+        assert_eq!(results[0].0, VectorId(5));
+        assert!(results[0].1.get_value() > 0.99);
+        assert_eq!(results.iter().filter(|(id, _)| id.0 == 5).count(), 1);
+
+        // the node of the old vector is tombstoned, so the id can't be found
+        // through it anymore
+        let results = search(&config, &dense_index, &old, 20);
+        assert_ne!(results[0].0, VectorId(5));
+        for (id, score) in &results {
+            if id.0 == 5 {
+                assert!(score.get_value() < 0.0);
             }
-            skipm.insert(neighbor_id);
+        }
+    }
 
-            let neighbor_node = unsafe { &*neighbor_lazy_item }.try_get_data(&dense_index.cache)?;
-            let dist = dense_index
-                .distance_metric
-                .calculate(&fvec, &neighbor_node.prop.value)?;
+    #[test]
+    fn test_reupload_after_values_range_change_hides_old_node() {
+        let config = test_config();
+        let hnsw_params = HNSWHyperParams::default_from_config(&config);
+        let (dense_index, _dir) = setup_dense_index(hnsw_params);
 
-            neighbors.push((neighbor_lazy_item, dist));
+        let old = vec![0.9, 0.1, 0.2, 0.3];
+        let new = vec![-0.9, -0.1, -0.2, -0.3];
+        let mut vecs: Vec<_> = (0..20u64)
+            .map(|i| (i, vec![i as f32 / 25.0, 0.5, -0.3, 0.1]))
+            .collect();
+        vecs[5].1 = old.clone();
+        index_vectors(&config, &dense_index, &vecs);
+
+        // the old node's value no longer matches what its embedding quantizes
+        // to, it must be told apart by the embedding it was built from
+        *dense_index.values_range.write().unwrap() = (-2.0, 2.0);
+        index_vectors(&config, &dense_index, &[(5, new.clone())]);
+
+        let results = search(&config, &dense_index, &old, 20);
+        assert_ne!(results[0].0, VectorId(5));
+        for (id, score) in &results {
+            if id.0 == 5 {
+                assert!(score.get_value() < 0.0);
+            }
         }
+        let results = search(&config, &dense_index, &new, 20);
+        assert_eq!(results[0].0, VectorId(5));
+        assert_eq!(results.iter().filter(|(id, _)| id.0 == 5).count(), 1);
+    }
 
-        neighbors.sort_unstable_by(|(_, a), (_, b)| {
-            b.get_value()
-                .partial_cmp(&a.get_value())
-                .unwrap_or(std::cmp::Ordering::Equal)
-        });
+    #[test]
+    fn test_larger_candidate_budget_returns_more() {
+        let config = test_config();
+        let hnsw_params = HNSWHyperParams::default_from_config(&config);
+        let (dense_index, _dir) = setup_dense_index(hnsw_params.clone());
 
-        for (neighbor_idx, (neighbor_node, dist)) in neighbors.into_iter().enumerate() {
-            if *nodes_visited < ef && neighbor_idx < config.search.shortlist_size {
-                let mut z = traverse_find_nearest(
-                    config,
-                    dense_index,
-                    neighbor_node,
-                    fvec,
-                    nodes_visited,
-                    skipm,
-                    cur_level,
-                    is_indexing,
-                    shortlist,
-                    ef_search,
-                    ef_construction,
-                )?;
-                z.push((neighbor_node, dist));
-                tasks.push(z);
-            } else {
-                tasks.push(vec![(neighbor_node, dist)]);
+        let vecs: Vec<_> = (0..100u64)
+            .map(|i| {
+                let x = i as f32 / 100.0;
+                (i, vec![x, 1.0 - x, 0.5 - x / 2.0, 0.3])
+            })
+            .collect();
+        index_vectors(&config, &dense_index, &vecs);
+
+        let mut entry = dense_index.get_root_vec();
+        loop {
+            let node = unsafe { &*entry }.try_get_data(&dense_index.cache).unwrap();
+            if node.hnsw_level.0 == 0 {
+                break;
             }
+            entry = node.get_child();
         }
-    } else {
-        for neighbor in node.get_neighbors_raw() {
-            let (neighbor_id, neighbor_lazy_item) = unsafe {
-                if let Some((neighbor_id, neighbor, _)) = neighbor.load(Ordering::Relaxed).as_ref()
-                {
-                    (*neighbor_id, *neighbor)
-                } else {
-                    continue;
-                }
-            };
 
-            if skipm.is_member(neighbor_id) {
-                continue;
+        let query = dense_index
+            .quantization_metric
+            .quantize(
+                &[0.5, 0.5, 0.25, 0.3],
+                StorageType::UnsignedByte,
+                (-1.0, 1.0),
+            )
+            .unwrap();
+        let traverse = |max_candidates| {
+            let mut skipm = PerformantFixedSet::new(hnsw_params.level_0_neighbors_count);
+            traverse_find_nearest(
+                &config,
+                &dense_index,
+                entry,
+                &query,
+                &mut 0,
+                &mut skipm,
+                HNSWLevel(0),
+                false,
+                true,
+                hnsw_params.ef_search,
+                hnsw_params.ef_construction,
+                max_candidates,
+                None,
+                None,
+            )
+            .unwrap()
+        };
+
+        let small = traverse(5);
+        let large = traverse(50);
+        assert_eq!(small.len(), 5);
+        assert!(large.len() > small.len());
+        assert!(large.len() <= 50);
+    }
+
+    #[test]
+    fn test_list_vector_ids_pagination() {
+        let config = test_config();
+        let hnsw_params = HNSWHyperParams::default_from_config(&config);
+        let (dense_index, _dir) = setup_dense_index(hnsw_params);
+
+        let version = dense_index.get_current_version();
+        let vecs: Vec<_> = (0..100u64)
+            .map(|id| (id, vec![0.1, 0.2, 0.3, 0.4]))
+            .collect();
+        insert_pending_vectors(&dense_index, version, &vecs);
+
+        let mut seen = Vec::new();
+        let mut cursor = None;
+        let mut pages = 0;
+        loop {
+            let (ids, next) = list_vector_ids(&dense_index, cursor, 25).unwrap();
+            pages += 1;
+            assert!(ids.len() <= 25);
+            seen.extend(ids.into_iter().map(|id| id.0));
+            match next {
+                Some(next) => cursor = Some(next),
+                None => break,
             }
-            skipm.insert(neighbor_id);
+        }
 
-            let neighbor = unsafe { &*neighbor_lazy_item }.try_get_data(&dense_index.cache)?;
-            let dist = dense_index
-                .distance_metric
-                .calculate(&fvec, &neighbor.prop.value)?;
+        assert_eq!(pages, 4);
+        seen.sort_unstable();
+        assert_eq!(seen, (0..100).collect::<Vec<_>>());
+    }
 
-            if *nodes_visited < ef {
-                let mut z = traverse_find_nearest(
-                    config,
-                    dense_index,
-                    neighbor_lazy_item,
-                    fvec,
-                    nodes_visited,
-                    skipm,
-                    cur_level,
-                    is_indexing,
-                    shortlist,
-                    ef_search,
-                    ef_construction,
-                )?;
-                z.push((neighbor_lazy_item, dist));
-                tasks.push(z);
-            } else {
-                tasks.push(vec![(neighbor_lazy_item, dist)]);
+    #[test]
+    fn test_vector_exists() {
+        let config = test_config();
+        let hnsw_params = HNSWHyperParams::default_from_config(&config);
+        let (dense_index, _dir) = setup_dense_index(hnsw_params);
+
+        let version = dense_index.get_current_version();
+        let bufman = dense_index.vec_raw_manager.get(version).unwrap();
+        let emb = RawVectorEmbedding {
+            raw_vec: Arc::new(vec![0.1, 0.2, 0.3, 0.4]),
+            hash_vec: VectorId(42),
+            metadata: None,
+        };
+        insert_embedding(bufman, dense_index.clone(), &emb, version).unwrap();
+
+        assert!(vector_exists(&dense_index, &VectorId(42)).unwrap());
+        assert!(!vector_exists(&dense_index, &VectorId(43)).unwrap());
+    }
+
+    #[test]
+    fn test_sync_writes_buffered_data_to_disk() {
+        let config = test_config();
+        let hnsw_params = HNSWHyperParams::default_from_config(&config);
+        let (dense_index, dir) = setup_dense_index(hnsw_params);
+
+        let version = dense_index.get_current_version();
+        let bufman = dense_index.vec_raw_manager.get(version).unwrap();
+        let emb = RawVectorEmbedding {
+            raw_vec: Arc::new(vec![0.1, 0.2, 0.3, 0.4]),
+            hash_vec: VectorId(42),
+            metadata: None,
+        };
+        insert_embedding(bufman, dense_index.clone(), &emb, version).unwrap();
+
+        // without a sync the embedding only lives in the buffered region
+        let vec_raw_path = dir.as_ref().join(format!("{}.vec_raw", *version));
+        assert_eq!(std::fs::metadata(&vec_raw_path).unwrap().len(), 0);
+
+        dense_index.sync().unwrap();
+        assert!(std::fs::metadata(&vec_raw_path).unwrap().len() > 0);
+        assert!(vector_exists(&dense_index, &VectorId(42)).unwrap());
+    }
+
+    #[test]
+    fn test_calculate_statistics() {
+        let config = test_config();
+        let hnsw_params = HNSWHyperParams::default_from_config(&config);
+        let (dense_index, _dir) = setup_dense_index(hnsw_params.clone());
+
+        let empty = calculate_statistics(&dense_index).unwrap();
+        assert_eq!(empty.count, 0);
+
+        let vecs: Vec<_> = (0..30u64)
+            .map(|i| (i, vec![i as f32 / 40.0, -0.2, 0.3, 0.6]))
+            .collect();
+        index_vectors(&config, &dense_index, &vecs);
+
+        let stats = calculate_statistics(&dense_index).unwrap();
+        assert_eq!(stats.count, 30);
+        assert_eq!(
+            stats.level_node_counts.len(),
+            hnsw_params.num_layers as usize + 1
+        );
+        // vectors are only inserted at level 0
+        assert_eq!(stats.level_node_counts[0], 30);
+        assert!(stats.level_node_counts[1..].iter().all(|&c| c == 0));
+        assert!(stats.avg_neighbor_degree > 0.0);
+        assert!(stats.quantized_mean > 0.0);
+        assert!(stats.quantized_variance >= 0.0);
+
+        // a node no other node links to is still counted, and an overwritten
+        // vector is counted once
+        unlink_vector(&dense_index, VectorId(7));
+        index_vectors(&config, &dense_index, &[(12, vec![-0.5, 0.2, 0.1, 0.4])]);
+        let stats = calculate_statistics(&dense_index).unwrap();
+        assert_eq!(stats.count, 30);
+        assert_eq!(stats.level_node_counts[0], 30);
+    }
+
+    #[test]
+    fn test_reindex_keeps_neighbors() {
+        let config = test_config();
+        let hnsw_params = HNSWHyperParams::default_from_config(&config);
+        let (dense_index, _dir) = setup_dense_index(hnsw_params);
+
+        let vecs: Vec<_> = (0..30u64)
+            .map(|i| (i, vec![i as f32 / 40.0, 0.4, -0.2, 0.3]))
+            .collect();
+        index_vectors(&config, &dense_index, &vecs);
+
+        let nearest = |query: &[f32]| search(&config, &dense_index, query, 5);
+
+        let query = &vecs[12].1;
+        let before = nearest(query);
+        assert_eq!(before[0].0, VectorId(12));
+
+        let old_root = dense_index.get_root_vec();
+        let old_version = dense_index.get_current_version();
+        reindex(&config, dense_index.clone()).unwrap();
+        assert_ne!(dense_index.get_root_vec(), old_root);
+        assert_ne!(dense_index.get_current_version(), old_version);
+
+        let after = nearest(query);
+        assert_eq!(after[0].0, VectorId(12));
+        assert_eq!(
+            calculate_statistics(&dense_index).unwrap().count,
+            vecs.len() as u64
+        );
+    }
+
+    #[test]
+    fn test_rollback_to_earlier_version() {
+        let config = test_config();
+        let hnsw_params = HNSWHyperParams::default_from_config(&config);
+        let (dense_index, _dir) = setup_dense_index(hnsw_params);
+
+        let vecs: Vec<_> = (0..20u64)
+            .map(|i| (i, vec![i as f32 / 25.0, 0.5, -0.3, 0.1]))
+            .collect();
+        let first_version = dense_index.get_current_version();
+        index_vectors(&config, &dense_index, &vecs[..10]);
+
+        let (second_version, _) = dense_index.vcs.add_next_version("main").unwrap();
+        dense_index.set_current_version(second_version);
+        index_vectors(&config, &dense_index, &vecs[10..]);
+
+        let versions = dense_index.vcs.list_versions().unwrap();
+        assert_eq!(versions.len(), 2);
+        assert_eq!(versions[0].0, first_version);
+        assert_eq!(*versions[0].1.version, 0);
+        assert_eq!(versions[1].0, second_version);
+        assert_eq!(*versions[1].1.version, 1);
+
+        let top_10 = || search(&config, &dense_index, &[0.6, 0.5, -0.3, 0.1], 10);
+
+        assert!(top_10().iter().any(|(id, _)| id.0 >= 10));
+
+        dense_index.rollback_to(first_version).unwrap();
+        assert_eq!(dense_index.get_current_version(), first_version);
+        let results = top_10();
+        assert!(!results.is_empty());
+        for (id, _) in &results {
+            assert!(id.0 < 10);
+        }
+
+        // can't move forward again, nor write on top of a rolled back version
+        assert!(dense_index.rollback_to(second_version).is_err());
+        assert!(dense_index.ensure_writable().is_err());
+
+        // the next write branches off the rolled back version instead
+        branch_from_rollback(&config, &dense_index).unwrap();
+        dense_index.ensure_writable().unwrap();
+        assert_eq!(
+            dense_index.current_branch().unwrap().get_branch_name(),
+            "main@0"
+        );
+        let more: Vec<_> = (20..25u64)
+            .map(|i| (i, vec![0.6, 0.5, -0.3, i as f32 / 250.0]))
+            .collect();
+        index_vectors(&config, &dense_index, &more);
+        let results = top_10();
+        assert!(results.iter().any(|(id, _)| id.0 >= 20));
+        for (id, _) in &results {
+            assert!(id.0 < 10 || id.0 >= 20);
+        }
+        // main keeps the versions rolled back from
+        assert_eq!(
+            dense_index.vcs.get_branch_head("main").unwrap(),
+            Some(second_version)
+        );
+        // and doing it again is a no-op
+        branch_from_rollback(&config, &dense_index).unwrap();
+        assert_eq!(
+            dense_index.current_branch().unwrap().get_branch_name(),
+            "main@0"
+        );
+    }
+
+    #[test]
+    fn test_branch_inserts_leave_main_unaffected() {
+        let config = test_config();
+        let hnsw_params = HNSWHyperParams::default_from_config(&config);
+        let (dense_index, _dir) = setup_dense_index(hnsw_params);
+
+        let vecs: Vec<_> = (0..20u64)
+            .map(|i| (i, vec![i as f32 / 25.0, 0.5, -0.3, 0.1]))
+            .collect();
+        let main_version = dense_index.get_current_version();
+        index_vectors(&config, &dense_index, &vecs[..10]);
+
+        let branch_version =
+            create_branch(&config, dense_index.clone(), "experiment", main_version).unwrap();
+        assert!(create_branch(&config, dense_index.clone(), "experiment", main_version).is_err());
+        // creating a branch doesn't check it out
+        assert_eq!(dense_index.get_current_version(), main_version);
+
+        let branches = dense_index.vcs.list_branches().unwrap();
+        assert_eq!(branches.len(), 2);
+        let branch = branches
+            .iter()
+            .find(|branch| branch.get_branch_name() == "experiment")
+            .unwrap();
+        assert_eq!(branch.get_parent_branch(), BranchId::new("main"));
+        assert_eq!(*branch.get_parent_version(), 0);
+
+        checkout_branch(&config, dense_index.clone(), "experiment").unwrap();
+        assert_eq!(dense_index.get_current_version(), branch_version);
+        assert_eq!(
+            dense_index.current_branch().unwrap().get_branch_name(),
+            "experiment"
+        );
+        index_vectors(&config, &dense_index, &vecs[10..]);
+
+        // as many as there are vectors, the 10 nearest to the query are all
+        // inserted on the branch
+        let top_20 = || search(&config, &dense_index, &[0.6, 0.5, -0.3, 0.1], 20);
+
+        // the branch has main's vectors as of the branch point and its own
+        let results = top_20();
+        assert!(results.iter().any(|(id, _)| id.0 < 10));
+        assert!(results.iter().any(|(id, _)| id.0 >= 10));
+
+        checkout_branch(&config, dense_index.clone(), "main").unwrap();
+        assert_eq!(dense_index.get_current_version(), main_version);
+        let results = top_20();
+        assert!(!results.is_empty());
+        for (id, _) in &results {
+            assert!(id.0 < 10);
+        }
+
+        assert!(checkout_branch(&config, dense_index.clone(), "missing").is_err());
+
+        // an upsert on the branch leaves main's embedding of the id as it was
+        checkout_branch(&config, dense_index.clone(), "experiment").unwrap();
+        index_vectors(&config, &dense_index, &[(3, vec![-0.9, 0.1, 0.2, 0.3])]);
+        let upserted = get_embedding_by_id(dense_index.clone(), &VectorId(3))
+            .unwrap()
+            .unwrap();
+        assert_eq!(*upserted.raw_vec, vec![-0.9, 0.1, 0.2, 0.3]);
+        assert_eq!(
+            list_vector_ids(&dense_index, None, 100).unwrap().0.len(),
+            20
+        );
+
+        checkout_branch(&config, dense_index.clone(), "main").unwrap();
+        let original = get_embedding_by_id(dense_index.clone(), &VectorId(3))
+            .unwrap()
+            .unwrap();
+        assert_eq!(*original.raw_vec, vecs[3].1);
+        assert_eq!(
+            list_vector_ids(&dense_index, None, 100).unwrap().0.len(),
+            10
+        );
+    }
+
+    #[test]
+    fn test_shared_embedding_offsets_are_migrated_to_each_branch() {
+        let config = test_config();
+        let hnsw_params = HNSWHyperParams::default_from_config(&config);
+        let (dense_index, _dir) = setup_dense_index(hnsw_params);
+        let main_version = dense_index.get_current_version();
+        create_branch(&config, dense_index.clone(), "experiment", main_version).unwrap();
+
+        // offsets stored before they were keyed by branch
+        let offset = EmbeddingOffset {
+            version: main_version,
+            offset: 0,
+        };
+        let mut txn = dense_index.lmdb.env.begin_rw_txn().unwrap();
+        for id in [4u64, 9] {
+            let mut key = vec![1];
+            key.extend_from_slice(&id.to_le_bytes());
+            txn.put(
+                *dense_index.lmdb.db,
+                &key,
+                &offset.serialize(),
+                WriteFlags::empty(),
+            )
+            .unwrap();
+        }
+        txn.commit().unwrap();
+
+        let branches = [BranchId::new("main"), BranchId::new("experiment")];
+        assert_eq!(
+            migrate_shared_embedding_offsets(&dense_index.lmdb, &branches).unwrap(),
+            2
+        );
+        assert_eq!(
+            migrate_shared_embedding_offsets(&dense_index.lmdb, &branches).unwrap(),
+            0
+        );
+        let txn = dense_index.lmdb.env.begin_ro_txn().unwrap();
+        for branch in branches {
+            for id in [4u64, 9] {
+                let bytes = txn
+                    .get(*dense_index.lmdb.db, &key!(e:branch, VectorId(id)))
+                    .unwrap();
+                assert_eq!(EmbeddingOffset::deserialize(bytes).unwrap(), offset);
             }
         }
     }
 
-    let mut nn: Vec<_> = tasks.into_iter().flatten().collect();
-    nn.sort_unstable_by(|a, b| b.1.get_value().partial_cmp(&a.1.get_value()).unwrap());
+    #[test]
+    fn test_unreadable_version_record_is_an_error() {
+        let config = test_config();
+        let hnsw_params = HNSWHyperParams::default_from_config(&config);
+        let (dense_index, _dir) = setup_dense_index(hnsw_params);
+        let version = dense_index.get_current_version();
 
-    if is_indexing {
-        nn.truncate(5);
-    } else {
-        // ANN search
-        nn.truncate(100);
+        let mut txn = dense_index.lmdb.env.begin_rw_txn().unwrap();
+        txn.put(
+            *dense_index.lmdb.db,
+            &key!(v:version),
+            &[0xff; 3],
+            WriteFlags::empty(),
+        )
+        .unwrap();
+        txn.commit().unwrap();
+
+        assert!(dense_index.branch_of(version).is_err());
+        assert!(dense_index.vcs.get_version_branch(version).is_err());
+        assert!(dense_index.vcs.list_versions().is_err());
+        assert!(dense_index.rollback_to(version).is_err());
     }
-    Ok(nn)
-}
 
-// fn delete_node_update_neighbours(
-//     dense_index: Arc<DenseIndex>,
-//     item: LazyItem<MergedNode>,
-//     skipm: HashSet<VectorId>,
-//     version_id: Hash,
-//     version_number: u16,
-//     hnsw_level: HNSWLevel,
-// ) -> Result<(), WaCustomError> {
-//     let node = item.get_data(dense_index.cache.clone());
-//     for nbr in node.neighbors.iter() {
-//         let mut skipm = skipm.clone();
-//         let nbr_node = nbr.1.get_data(dense_index.cache.clone());
-//         let (nbr_id, nbr_vec) = match nbr_node.get_prop() {
-//             PropState::Ready(prop) => (prop.id.clone(), prop.value.clone()),
-//             PropState::Pending(_) => {
-//                 // TODO: load prop
-//                 return Err(WaCustomError::NodeError("PropState is Pending".to_string()));
-//             }
-//         };
-//         skipm.insert(nbr_id);
-//         let mut nbr_nbrs = traverse_find_nearest(
-//             dense_index.clone(),
-//             dense_index
-//                 .root_vec
-//                 .item
-//                 .clone()
-//                 .get()
-//                 .get_latest_version(dense_index.cache.clone())
-//                 .0,
-//             nbr_vec,
-//             0,
-//             &mut skipm,
-//             HNSWLevel(dense_index.hnsw_params.clone().get().num_layers),
-//             false,
-//         )?;
+    #[test]
+    fn test_index_pending_embeddings() {
+        let config = test_config();
+        let hnsw_params = HNSWHyperParams::default_from_config(&config);
+        let (dense_index, _dir) = setup_dense_index(hnsw_params);
+
+        // uploads start from the current version, as `run_upload` sets up
+        let version = dense_index.get_current_version();
+        start_indexed_version(&dense_index, version).unwrap();
+        insert_pending_vectors(&dense_index, version, &line_vectors(50, 60.0));
+        assert_eq!(get_embedding_counts(&dense_index).unwrap(), (0, 50));
+
+        // refused while another run holds the flag
+        dense_index.is_indexing.store(true, Ordering::SeqCst);
+        assert!(matches!(
+            index_pending_embeddings(&config, &dense_index, 16),
+            Err(WaCustomError::LockError(_))
+        ));
+        assert_eq!(get_embedding_counts(&dense_index).unwrap(), (0, 50));
+        dense_index.is_indexing.store(false, Ordering::SeqCst);
+
+        index_pending_embeddings(&config, &dense_index, 16).unwrap();
+        assert_eq!(get_embedding_counts(&dense_index).unwrap(), (50, 0));
+        assert!(!dense_index.is_indexing.load(Ordering::SeqCst));
+        assert_eq!(calculate_statistics(&dense_index).unwrap().count, 50);
+    }
+
+    #[test]
+    fn test_recover_pending_embeddings() {
+        let config = test_config();
+        let hnsw_params = HNSWHyperParams::default_from_config(&config);
+        let (dense_index, _dir) = setup_dense_index(hnsw_params);
+
+        let version = dense_index.get_current_version();
+        start_indexed_version(&dense_index, version).unwrap();
+        let bufman = insert_pending_vectors(&dense_index, version, &line_vectors(5, 10.0));
+        let complete_len = bufman.file_size().unwrap();
+
+        // the process stops before indexing: the counter it leaves behind
+        // can't be trusted and one more embedding only made it halfway into
+        // the raw embeddings file
+        let mut txn = dense_index.lmdb.env.begin_rw_txn().unwrap();
+        txn.put(
+            *dense_index.lmdb.db,
+            &"count_unindexed",
+            &0u32.to_le_bytes(),
+            WriteFlags::empty(),
+        )
+        .unwrap();
+        txn.commit().unwrap();
+        bufman
+            .with_cursor(|cursor| {
+                bufman.seek_with_cursor(cursor, SeekFrom::End(0))?;
+                bufman.write_with_cursor(cursor, &[64, 0, 0, 0, 1, 2])
+            })
+            .unwrap();
+
+        assert_eq!(
+            recover_pending_embeddings(&config, &dense_index).unwrap(),
+            5
+        );
+        assert_eq!(get_embedding_counts(&dense_index).unwrap(), (5, 0));
+        assert_eq!(calculate_statistics(&dense_index).unwrap().count, 5);
+        assert_eq!(bufman.file_size().unwrap(), complete_len);
+
+        // replaying again finds nothing left to do
+        assert_eq!(
+            recover_pending_embeddings(&config, &dense_index).unwrap(),
+            0
+        );
+        assert_eq!(get_embedding_counts(&dense_index).unwrap(), (5, 0));
+    }
+
+    #[test]
+    fn test_warm_cache() {
+        let config = test_config();
+        let hnsw_params = HNSWHyperParams::default_from_config(&config);
+        let (dense_index, _dir) = setup_dense_index(hnsw_params);
+
+        let version = dense_index.get_current_version();
+        start_indexed_version(&dense_index, version).unwrap();
+        insert_pending_vectors(&dense_index, version, &line_vectors(50, 60.0));
+        index_pending_embeddings(&config, &dense_index, 16).unwrap();
+
+        // what a restart leaves behind: an empty cache and a root that only
+        // knows where it was persisted
+        let root_index = dense_index.root_vec_offset().unwrap();
+        let cold = |capacity| {
+            let mut cold = (*dense_index).clone();
+            cold.cache = Arc::new(ProbCache::with_capacity(
+                1000,
+                capacity,
+                cold.index_manager.clone(),
+                cold.prop_file.clone(),
+            ));
+            cold.set_root_vec(ProbLazyItem::new_pending(root_index.clone()));
+            cold
+        };
+
+        let index = cold(1_000_000);
+        let top_level = warm_cache(&index, Some(1)).unwrap();
+        assert!(top_level > 0);
+        assert_eq!(index.cache.len(), top_level);
+        let rest = warm_cache(&index, None).unwrap();
+        assert!(rest > 0);
+        // everything is cached already
+        assert_eq!(warm_cache(&index, None).unwrap(), 0);
+
+        let before = index.cache.stats();
+        let root = index
+            .cache
+            .get_lazy_object(root_index.clone(), 1, &mut HashSet::new())
+            .unwrap();
+        assert!(!unsafe { &*root }.is_pending());
+        assert_eq!(index.cache.stats().hits, before.hits + 1);
+        assert_eq!(index.cache.stats().misses, before.misses);
+        assert_eq!(index.cache.len(), top_level + rest);
+
+        // stops once the cache is full
+        let small = cold(3);
+        assert_eq!(warm_cache(&small, None).unwrap(), 3);
+        assert_eq!(small.cache.len(), 3);
+    }
+
+    #[test]
+    fn test_compact_prop_file() {
+        let config = test_config();
+        let hnsw_params = HNSWHyperParams::default_from_config(&config);
+        let (dense_index, dir) = setup_dense_index(hnsw_params);
+
+        let version = dense_index.get_current_version();
+        start_indexed_version(&dense_index, version).unwrap();
+        insert_pending_vectors(&dense_index, version, &line_vectors(30, 40.0));
+        index_pending_embeddings(&config, &dense_index, 16).unwrap();
+
+        // props no node points at, like the ones a failed insert leaves behind
+        let root = unsafe { &*dense_index.get_root_vec() };
+        let value = root
+            .try_get_data(&dense_index.cache)
+            .unwrap()
+            .prop
+            .value
+            .clone();
+        {
+            let prop_file = dense_index.prop_file.read().unwrap();
+            for id in 1000..1100u64 {
+                write_prop_to_file(&VectorId(id), value.clone(), None, &prop_file).unwrap();
+            }
+        }
+
+        let prop_file_path = prop_file_path(dir.as_ref());
+        let size_before = std::fs::metadata(&prop_file_path).unwrap().len();
+        let reclaimed = compact_prop_file(&dense_index, &prop_file_path).unwrap();
+        assert!(reclaimed > 0);
+        assert_eq!(
+            std::fs::metadata(&prop_file_path).unwrap().len(),
+            size_before - reclaimed
+        );
+        // nothing left to reclaim
+        assert_eq!(compact_prop_file(&dense_index, &prop_file_path).unwrap(), 0);
+
+        // the nodes in memory point at their props in the new file
+        let nodes = all_node_props(&dense_index).unwrap();
+        assert!(nodes.len() > 30);
+        let mut file = File::open(&prop_file_path).unwrap();
+        let mut values = HashMap::new();
+        for (_, prop) in &nodes {
+            let stored = read_prop_from_file(prop.location.get(), &mut file).unwrap();
+            assert_eq!(stored.id, prop.id);
+            assert_eq!(stored.value, prop.value);
+            values.insert(prop.id.0, prop.value.clone());
+        }
+
+        // and so do the ones on disk, as loaded after a restart
+        let root_index = dense_index.root_vec_offset().unwrap();
+        let cold = cold_copy(&dense_index, dir.as_ref());
+        cold.set_root_vec(ProbLazyItem::new_pending(root_index));
+        let cold_nodes = all_node_props(&cold).unwrap();
+        assert!(cold_nodes.len() > 30);
+        for (_, prop) in &cold_nodes {
+            assert_eq!(prop.value, values[&prop.id.0]);
+        }
+    }
+
+    #[test]
+    fn test_compact_prop_file_keeps_unreachable_nodes() {
+        let config = test_config();
+        let hnsw_params = HNSWHyperParams::default_from_config(&config);
+        let (dense_index, dir) = setup_dense_index(hnsw_params);
+        let vecs: Vec<_> = (0..20u64)
+            .map(|i| {
+                let angle = i as f32 * 0.15;
+                (i, vec![angle.cos(), angle.sin(), 0.3, -0.2])
+            })
+            .collect();
+        index_vectors(&config, &dense_index, &vecs);
+        unlink_vector(&dense_index, VectorId(7));
+        write_unlocated_nodes(&dense_index);
+
+        let prop_file_path = prop_file_path(dir.as_ref());
+        let expected = vector_fetch(dense_index.clone(), VectorId(7)).unwrap();
+        compact_prop_file(&dense_index, &prop_file_path).unwrap();
+
+        let cold = Arc::new(cold_copy(&dense_index, dir.as_ref()));
+        let node = cold.find_node(&VectorId(7), HNSWLevel(0)).unwrap().unwrap();
+        let prop = unsafe { &*node }
+            .try_get_data(&cold.cache)
+            .unwrap()
+            .prop
+            .clone();
+        let mut file = File::open(&prop_file_path).unwrap();
+        let stored = read_prop_from_file(prop.location.get(), &mut file).unwrap();
+        assert_eq!(stored.id, VectorId(7));
+        assert_eq!(stored.value, prop.value);
+        assert_eq!(vector_fetch(cold, VectorId(7)).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_prop_compaction_is_finished_from_its_journal() {
+        let config = test_config();
+        let hnsw_params = HNSWHyperParams::default_from_config(&config);
+        let (dense_index, dir) = setup_dense_index(hnsw_params);
+        let vecs: Vec<_> = (0..20u64)
+            .map(|i| (i, vec![i as f32 / 20.0, 0.2, -0.3, 0.4]))
+            .collect();
+        index_vectors(&config, &dense_index, &vecs);
+        write_unlocated_nodes(&dense_index);
+        let prop_file_path = prop_file_path(dir.as_ref());
+
+        // a compacted file without a journal never got swapped in
+        std::fs::write(compacted_prop_file_path(&prop_file_path), b"partial").unwrap();
+        assert!(!finish_prop_compaction(&prop_file_path, &dense_index.index_manager).unwrap());
+        assert!(!compacted_prop_file_path(&prop_file_path).exists());
+
+        // a crash right after the journal was written, the props are stored
+        // in reverse order in the compacted file
+        let nodes = all_node_props(&dense_index).unwrap();
+        let compacted = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(compacted_prop_file_path(&prop_file_path))
+            .unwrap();
+        let mut relocations = Vec::new();
+        let mut values = HashMap::new();
+        for (file_index, prop) in nodes.iter().rev() {
+            let (FileOffset(prop_offset), BytesToRead(prop_length)) =
+                write_prop_to_file(&prop.id, prop.value.clone(), prop.source, &compacted).unwrap();
+            if let Some(FileIndex::Valid {
+                offset: FileOffset(offset),
+                version_id,
+                ..
+            }) = file_index
+            {
+                relocations.push(PropRelocation {
+                    version_id: **version_id,
+                    node_offset: *offset,
+                    prop_offset,
+                    prop_length,
+                });
+            }
+            values.insert(prop.id.0, prop.value.clone());
+        }
+        compacted.sync_all().unwrap();
+        assert!(relocations.len() >= vecs.len());
+        write_prop_journal(&prop_file_path, &relocations).unwrap();
+
+        assert!(finish_prop_compaction(&prop_file_path, &dense_index.index_manager).unwrap());
+        // done already
+        assert!(!finish_prop_compaction(&prop_file_path, &dense_index.index_manager).unwrap());
+
+        let root_index = dense_index.root_vec_offset().unwrap();
+        let cold = cold_copy(&dense_index, dir.as_ref());
+        cold.set_root_vec(ProbLazyItem::new_pending(root_index));
+        let mut file = File::open(&prop_file_path).unwrap();
+        for (_, prop) in all_node_props(&cold).unwrap() {
+            let stored = read_prop_from_file(prop.location.get(), &mut file).unwrap();
+            assert_eq!(stored.id, prop.id);
+            assert_eq!(prop.value, values[&prop.id.0]);
+        }
+    }
+
+    static CAPTURED_LOGS: std::sync::Mutex<Vec<(log::Level, String)>> =
+        std::sync::Mutex::new(Vec::new());
+
+    struct CapturingLogger;
+
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
+        }
 
-//         nbr_nbrs.truncate(20);
-//         let nbr_nbrs_set = IdentitySet::from_iter(
-//             nbr_nbrs
-//                 .into_iter()
-//                 .map(|(node, dist)| EagerLazyItem(dist, node)),
-//         );
+        fn log(&self, record: &log::Record) {
+            CAPTURED_LOGS
+                .lock()
+                .unwrap()
+                .push((record.level(), record.args().to_string()));
+        }
 
-//         if let Some(version) = nbr.1.get_version(dense_index.cache.clone(), version_number) {
-//             let nbr_current_version_data = version.get_data(dense_index.cache.clone());
-//             nbr_current_version_data
-//                 .neighbors
-//                 .items
-//                 .clone()
-//                 .update(nbr_nbrs_set);
-//         } else {
-//             let (new_version, mut new_neighbours) = create_node_extract_neighbors(
-//                 version_id,
-//                 version_number,
-//                 hnsw_level,
-//                 nbr_node.prop.clone(),
-//                 nbr_node.parent.clone(),
-//                 nbr_node.child.clone(),
-//             );
-//             new_neighbours.items.update(nbr_nbrs_set);
-//             nbr.1.add_version(dense_index.cache.clone(), new_version);
-//             let mut exec_queue = dense_index.exec_queue_nodes.clone();
-//             exec_queue
-//                 .transactional_update(|queue| {
-//                     let mut new_queue = queue.clone();
-//                     new_queue.push(ArcShift::new(nbr.1.clone()));
-//                     new_queue
-//                 })
-//                 .unwrap();
-//         }
-//     }
-//     Ok(())
-// }
+        fn flush(&self) {}
+    }
 
-// pub fn delete_vector_by_id(
-//     dense_index: Arc<DenseIndex>,
-//     vector_id: VectorId,
-// ) -> Result<(), WaCustomError> {
-//     let (current_version, current_version_number) = dense_index
-//         .vcs
-//         .add_next_version("main")
-//         .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
-//     dense_index.set_current_version(current_version);
-//     update_current_version(&dense_index.lmdb, current_version)?;
+    #[test]
+    fn test_node_load_failure_is_logged_as_error() {
+        // the logger is global, so it's installed once for the test binary,
+        // and can't be if some other logger got installed first
+        static LOGGER_INSTALLED: std::sync::OnceLock<bool> = std::sync::OnceLock::new();
+        let logger_installed = *LOGGER_INSTALLED.get_or_init(|| {
+            log::set_logger(&CapturingLogger)
+                .map(|()| log::set_max_level(log::LevelFilter::Error))
+                .is_ok()
+        });
 
-//     let vec_raw = get_embedding_by_id(dense_index.clone(), vector_id.clone())?;
-//     let quantized = Arc::new(dense_index.quantization_metric.clone().get().quantize(
-//         &vec_raw.raw_vec,
-//         dense_index.storage_type.clone().get().clone(),
-//     )?);
-//     let mut skipm = HashSet::new();
-//     skipm.insert(vec_raw.hash_vec);
-//     let items = traverse_find_nearest(
-//         dense_index.clone(),
-//         dense_index
-//             .root_vec
-//             .item
-//             .clone()
-//             .get()
-//             .get_latest_version(dense_index.cache.clone())
-//             .0,
-//         quantized,
-//         0,
-//         &mut skipm,
-//         HNSWLevel(dense_index.hnsw_params.clone().get().num_layers),
-//         false,
-//     )?;
+        let config = test_config();
+        let hnsw_params = HNSWHyperParams::default_from_config(&config);
+        let (dense_index, _dir) = setup_dense_index(hnsw_params);
 
-//     let mut maybe_item = None;
+        // a node without a location can't be loaded
+        let node = ProbLazyItem::new_pending(FileIndex::Invalid);
+        assert!(resolve_node(&dense_index, node).is_err());
 
-//     for (item, _) in items {
-//         let data = item.get_data(dense_index.cache.clone());
-//         let prop = match data.get_prop() {
-//             PropState::Ready(prop) => prop.id.clone(),
-//             PropState::Pending(_) => {
-//                 // TODO: load prop
-//                 return Err(WaCustomError::NodeError("PropState is Pending".to_string()));
-//             }
-//         };
+        if !logger_installed {
+            return;
+        }
+        let logs = CAPTURED_LOGS.lock().unwrap();
+        assert!(logs
+            .iter()
+            .any(|(level, message)| *level == log::Level::Error
+                && message.starts_with("Error loading node")));
+    }
 
-//         if prop == vector_id {
-//             maybe_item = Some(item);
-//         }
-//     }
+    #[test]
+    fn test_quantizer_is_trained_once_enough_vectors_are_sampled() {
+        let config = test_config();
+        let hnsw_params = HNSWHyperParams::default_from_config(&config);
+        let (dense_index, _dir) = setup_dense_index(hnsw_params);
+        let mut dense_index = (*dense_index).clone();
+        dense_index.quantization_metric =
+            ArcShift::new(QuantizationMetric::Product(ProductQuantization::new(2, 4)));
+        dense_index.training_sample = Arc::new(Mutex::new(ReservoirSampler::new(32)));
+
+        let vectors: Vec<Vec<f32>> = (0..100)
+            .map(|i| {
+                let x = (i % 10) as f32 / 10.0;
+                vec![x, -x, x / 2.0, 0.3]
+            })
+            .collect();
+        sample_for_training(&dense_index, vectors[..50].iter().map(|v| &v[..]));
+        sample_for_training(&dense_index, vectors[50..].iter().map(|v| &v[..]));
+        {
+            let sampler = dense_index.training_sample.lock().unwrap();
+            assert_eq!(sampler.seen(), 100);
+            assert_eq!(sampler.samples().len(), 32);
+        }
 
-//     let Some(mut item) = maybe_item else {
-//         return Err(WaCustomError::NodeError(
-//             "Node not found in graph".to_string(),
-//         ));
-//     };
+        assert!(dense_index
+            .quantization_metric
+            .clone()
+            .get()
+            .needs_training());
+        assert!(train_quantizer(&dense_index).unwrap());
+        assert!(!dense_index
+            .quantization_metric
+            .clone()
+            .get()
+            .needs_training());
+        // trained quantizers don't sample or train again
+        sample_for_training(&dense_index, vectors.iter().map(|v| &v[..]));
+        assert_eq!(dense_index.training_sample.lock().unwrap().seen(), 0);
+        assert!(!train_quantizer(&dense_index).unwrap());
+
+        let storage = dense_index
+            .quantization_metric
+            .clone()
+            .get()
+            .quantize(&vectors[3], StorageType::UnsignedByte, (-1.0, 1.0))
+            .unwrap();
+        // a code for each of the 2 subspaces
+        assert!(
+            matches!(storage, Storage::UnsignedByte { ref quant_vec, .. } if quant_vec.len() == 2)
+        );
+    }
 
-//     for level in 0..=dense_index.hnsw_params.clone().get().num_layers {
-//         delete_node_update_neighbours(
-//             dense_index.clone(),
-//             item.clone(),
-//             skipm.clone(),
-//             current_version,
-//             *current_version_number as u16,
-//             HNSWLevel(level),
-//         )?;
+    #[test]
+    fn test_queries_work_after_retraining_the_quantizer() {
+        let config = test_config();
+        let hnsw_params = HNSWHyperParams::default_from_config(&config);
+        let (dense_index, _dir) = setup_quantized_dense_index(
+            hnsw_params.clone(),
+            None,
+            QuantizationMetric::Product(ProductQuantization::new(2, 8)),
+        );
 
-//         item = item
-//             .get_data(dense_index.cache.clone())
-//             .parent
-//             .clone()
-//             .item
-//             .get()
-//             .clone();
-//     }
+        let vecs: Vec<_> = (0..40u64)
+            .map(|i| {
+                let angle = i as f32 * 0.15;
+                (i, vec![angle.cos(), angle.sin(), 0.3, -0.2])
+            })
+            .collect();
+        index_vectors(&config, &dense_index, &vecs);
+
+        let (sample_count, stats) = retrain_quantizer(&dense_index, 32).unwrap();
+        assert_eq!(sample_count, 32);
+        assert!(stats.iterations >= 1);
+        assert!(stats.distortion.is_finite());
+        assert!(!dense_index
+            .quantization_metric
+            .clone()
+            .get()
+            .needs_training());
+
+        let top_5 = |query: &[f32]| -> Vec<u64> {
+            search(&config, &dense_index, query, 5)
+                .into_iter()
+                .map(|(id, _)| id.0)
+                .collect()
+        };
+        assert!(top_5(&vecs[12].1).contains(&12));
+
+        // nodes quantized with the first centroids are still decoded with
+        // them once the quantizer is trained again
+        let more: Vec<_> = (40..60u64)
+            .map(|i| {
+                let angle = i as f32 * 0.15 + 0.07;
+                (i, vec![angle.cos(), angle.sin(), -0.3, 0.2])
+            })
+            .collect();
+        index_vectors(&config, &dense_index, &more);
+        retrain_quantizer(&dense_index, 32).unwrap();
+        let QuantizationMetric::Product(product) =
+            dense_index.quantization_metric.clone().get().clone()
+        else {
+            unreachable!();
+        };
+        assert_eq!(product.retired_codebooks.len(), 1);
+        assert!(top_5(&more[10].1).contains(&50));
 
-//     auto_commit_transaction(dense_index)?;
+        // scalar quantizers have nothing to train
+        let (scalar_index, _dir) = setup_dense_index(hnsw_params);
+        assert!(retrain_quantizer(&scalar_index, 32).is_err());
+    }
 
-//     Ok(())
-// }
+    #[test]
+    fn test_index_embeddings_into_new_collection() {
+        let config = test_config();
+        let hnsw_params = HNSWHyperParams::default_from_config(&config);
+        let (dense_index, _dir) = setup_dense_index(hnsw_params);
+
+        // a version the version control never recorded, with no
+        // `next_embedding_offset` either, its embeddings are written without
+        // going through the version control
+        let version = Hash::from(12345);
+        dense_index.set_current_version(version);
+        let bufman = dense_index.vec_raw_manager.get(version).unwrap();
+        let main = BranchId::new("main");
+        let mut txn = dense_index.lmdb.env.begin_rw_txn().unwrap();
+        for (id, values) in line_vectors(10, 10.0) {
+            let emb = RawVectorEmbedding {
+                raw_vec: Arc::new(values),
+                hash_vec: VectorId(id),
+                metadata: None,
+            };
+            let offset = write_embedding(bufman.clone(), &emb).unwrap();
+            txn.put(
+                *dense_index.lmdb.db,
+                &key!(e:main, emb.hash_vec),
+                &EmbeddingOffset { version, offset }.serialize(),
+                WriteFlags::empty(),
+            )
+            .unwrap();
+        }
+        txn.commit().unwrap();
+        bufman.flush().unwrap();
 
-// pub fn delete_vector_by_id_in_transaction(
-//     dense_index: Arc<DenseIndex>,
-//     vector_id: VectorId,
-//     transaction_id: Hash,
-// ) -> Result<(), WaCustomError> {
-//     let txn = dense_index
-//         .lmdb
-//         .env
-//         .begin_ro_txn()
-//         .map_err(|err| WaCustomError::DatabaseError(err.to_string()))?;
-//     let version_hash = dense_index
-//         .vcs
-//         .get_version_hash(&transaction_id, &txn)
-//         .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?
-//         .ok_or(WaCustomError::DatabaseError(
-//             "VersionHash not found for transaction".to_string(),
-//         ))?;
-//     txn.abort();
-//     let current_version_number = *version_hash.version as u16;
+        index_embeddings(
+            &config,
+            dense_index.clone(),
+            4,
+            Arc::new(TSHashTable::new(16)),
+            Arc::new(TSHashTable::new(16)),
+        )
+        .unwrap();
+        assert_eq!(get_embedding_counts(&dense_index).unwrap(), (10, 0));
+    }
 
-//     let vec_raw = get_embedding_by_id(dense_index.clone(), vector_id.clone())?;
-//     let quantized = Arc::new(dense_index.quantization_metric.clone().get().quantize(
-//         &vec_raw.raw_vec,
-//         dense_index.storage_type.clone().get().clone(),
-//     )?);
-//     let mut skipm = HashSet::new();
-//     skipm.insert(vec_raw.hash_vec);
-//     let items = traverse_find_nearest(
-//         dense_index.clone(),
-//         dense_index
-//             .root_vec
-//             .item
-//             .clone()
-//             .get()
-//             .get_latest_version(dense_index.cache.clone())
-//             .0,
-//         quantized,
-//         0,
-//         &mut skipm,
-//         HNSWLevel(dense_index.hnsw_params.clone().get().num_layers),
-//         false,
-//     )?;
+    #[test]
+    fn test_embeddings_are_indexed_from_the_collection_directory() {
+        let config = test_config();
+        let hnsw_params = HNSWHyperParams::default_from_config(&config);
+        let (dense_index, dir) = setup_dense_index(hnsw_params);
 
-//     let mut maybe_item = None;
+        let version = dense_index.get_current_version();
+        start_indexed_version(&dense_index, version).unwrap();
+        insert_pending_vectors(&dense_index, version, &line_vectors(10, 10.0));
 
-//     for (item, _) in items {
-//         let data = item.get_data(dense_index.cache.clone());
-//         let prop = match data.get_prop() {
-//             PropState::Ready(prop) => prop.id.clone(),
-//             PropState::Pending(_) => {
-//                 // TODO: load prop
-//                 return Err(WaCustomError::NodeError("PropState is Pending".to_string()));
-//             }
-//         };
+        // the raw embeddings live in the collection's directory, not the
+        // working directory
+        let file_name = format!("{}.vec_raw", *version);
+        assert!(dir.as_ref().join(&file_name).exists());
+        assert!(!std::path::Path::new(&file_name).exists());
 
-//         if prop == vector_id {
-//             maybe_item = Some(item);
-//         }
-//     }
+        index_pending_embeddings(&config, &dense_index, 4).unwrap();
+        assert_eq!(get_embedding_counts(&dense_index).unwrap(), (10, 0));
+    }
 
-//     let Some(mut item) = maybe_item else {
-//         return Err(WaCustomError::NodeError(
-//             "Node not found in graph".to_string(),
-//         ));
-//     };
+    #[test]
+    fn test_vectors_failing_quantization_fail_their_batch() {
+        let config = test_config();
+        let hnsw_params = HNSWHyperParams::default_from_config(&config);
+        let (dense_index, _dir) = setup_quantized_dense_index(
+            hnsw_params,
+            None,
+            QuantizationMetric::Product(ProductQuantization::new(2, 4)),
+        );
 
-//     for level in 0..=dense_index.hnsw_params.clone().get().num_layers {
-//         delete_node_update_neighbours(
-//             dense_index.clone(),
-//             item.clone(),
-//             skipm.clone(),
-//             transaction_id,
-//             current_version_number,
-//             HNSWLevel(level),
-//         )?;
+        let mut vecs = line_vectors(10, 10.0);
+        let mut product = ProductQuantization::new(2, 4);
+        let samples: Vec<&[f32]> = vecs.iter().map(|(_, v)| &v[..]).collect();
+        product.train(&samples, StorageType::UnsignedByte).unwrap();
+        dense_index
+            .quantization_metric
+            .clone()
+            .update(QuantizationMetric::Product(product));
+
+        let version = dense_index.get_current_version();
+        start_indexed_version(&dense_index, version).unwrap();
+        // 3 dimensions can't be split into the quantizer's 2 subspaces
+        vecs[4].1.truncate(3);
+        insert_pending_vectors(&dense_index, version, &vecs);
+
+        // the vector would be acknowledged but never searchable, so nothing
+        // of its batch is indexed and it's still pending
+        assert!(matches!(
+            index_pending_embeddings(&config, &dense_index, 16),
+            Err(WaCustomError::QuantizationError(_))
+        ));
+        assert_eq!(get_embedding_counts(&dense_index).unwrap(), (0, 10));
+        assert!(!dense_index.is_indexing.load(Ordering::SeqCst));
+    }
 
-//         item = item
-//             .get_data(dense_index.cache.clone())
-//             .parent
-//             .clone()
-//             .item
-//             .get()
-//             .clone();
-//     }
+    #[test]
+    fn test_vectors_on_levels_past_the_top_fail_their_batch() {
+        let config = test_config();
+        let hnsw_params = HNSWHyperParams::default_from_config(&config);
+        let num_layers = hnsw_params.num_layers;
+        let (dense_index, _dir) = setup_dense_index(hnsw_params);
+        let mut dense_index = (*dense_index).clone();
+        dense_index.levels_prob = Arc::new(vec![(0.0, num_layers as i32 + 1)]);
+        let dense_index = Arc::new(dense_index);
+
+        let version = dense_index.get_current_version();
+        start_indexed_version(&dense_index, version).unwrap();
+        insert_pending_vectors(&dense_index, version, &line_vectors(3, 10.0));
+
+        assert!(matches!(
+            index_pending_embeddings(&config, &dense_index, 16),
+            Err(WaCustomError::InvalidLevel(level, top)) if level == num_layers as u32 + 1 && top == num_layers
+        ));
+        assert_eq!(get_embedding_counts(&dense_index).unwrap(), (0, 3));
+    }
 
-//     auto_commit_transaction(dense_index)?;
+    #[test]
+    fn test_seeded_insert_levels_are_reproducible() {
+        let config = test_config();
+        let hnsw_params = HNSWHyperParams::default_from_config(&config);
+        let vecs = line_vectors(60, 70.0);
+
+        // ids of the nodes on each level, from the top one
+        let levels = |level_seed: Option<u64>| {
+            let (dense_index, _dir) = setup_seeded_dense_index(hnsw_params.clone(), Some(7));
+            let mut dense_index = (*dense_index).clone();
+            dense_index.level_seed = level_seed;
+            let dense_index = Arc::new(dense_index);
+
+            let version = dense_index.get_current_version();
+            start_indexed_version(&dense_index, version).unwrap();
+            insert_pending_vectors(&dense_index, version, &vecs);
+            index_pending_embeddings(&config, &dense_index, 16).unwrap();
+
+            let mut levels = Vec::new();
+            let mut level_root = dense_index.get_root_vec();
+            for level in (0..=hnsw_params.num_layers).rev() {
+                let mut ids = BTreeSet::new();
+                let mut queue = VecDeque::from([level_root]);
+                while let Some(item) = queue.pop_front() {
+                    let node = unsafe { &*item }.try_get_data(&dense_index.cache).unwrap();
+                    if ids.insert(node.prop.id.0) {
+                        queue.extend(node.get_neighbors());
+                    }
+                }
+                levels.push(ids);
+                if level > 0 {
+                    level_root = unsafe { &*level_root }
+                        .try_get_data(&dense_index.cache)
+                        .unwrap()
+                        .get_child();
+                }
+            }
+            levels
+        };
 
-//     Ok(())
-// }
+        let first = levels(Some(42));
+        assert_eq!(first.last().unwrap().len(), vecs.len() + 1);
+        assert_eq!(levels(Some(42)), first);
+        assert_ne!(levels(Some(43)), first);
+    }
+
+    #[test]
+    fn test_vector_fetch_returns_neighbors_per_level() {
+        let config = test_config();
+        let hnsw_params = HNSWHyperParams::default_from_config(&config);
+        let (dense_index, _dir) = setup_dense_index(hnsw_params.clone());
+        let vecs: Vec<_> = (0..20u64)
+            .map(|i| {
+                let angle = i as f32 * 0.15;
+                (i, vec![angle.cos(), angle.sin(), 0.3, -0.2])
+            })
+            .collect();
+        index_vectors(&config, &dense_index, &vecs);
+
+        let levels = vector_fetch(dense_index.clone(), VectorId(7)).unwrap();
+        assert_eq!(levels.len(), hnsw_params.num_layers as usize + 1);
+
+        let (id, neighbors) = levels[0].as_ref().unwrap();
+        assert_eq!(*id, VectorId(7));
+        assert!(!neighbors.is_empty());
+        assert!(neighbors
+            .iter()
+            .all(|(id, _)| id.0 < 20 && *id != VectorId(7)));
+        assert!(neighbors
+            .windows(2)
+            .all(|pair| pair[0].1.score() >= pair[1].1.score()));
+
+        // a vector on a level is on every level below it
+        let top = levels.iter().rposition(Option::is_some).unwrap();
+        assert!(levels[..=top].iter().all(Option::is_some));
+
+        let missing = vector_fetch(dense_index.clone(), VectorId(100)).unwrap();
+        assert!(missing.iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn test_vector_fetch_finds_unreachable_nodes() {
+        let config = test_config();
+        let hnsw_params = HNSWHyperParams::default_from_config(&config);
+        let (dense_index, dir) = setup_dense_index(hnsw_params);
+        let vecs: Vec<_> = (0..20u64)
+            .map(|i| {
+                let angle = i as f32 * 0.15;
+                (i, vec![angle.cos(), angle.sin(), 0.3, -0.2])
+            })
+            .collect();
+        index_vectors(&config, &dense_index, &vecs);
+
+        // no node links to vector 7 anymore, so it can't be reached from the
+        // root
+        unlink_vector(&dense_index, VectorId(7));
+        let levels = vector_fetch(dense_index.clone(), VectorId(7)).unwrap();
+        let (_, neighbors) = levels[0].as_ref().unwrap();
+        assert!(!neighbors.is_empty());
+
+        // once its nodes are written, it's found after a restart too
+        write_unlocated_nodes(&dense_index);
+        assert!(dense_index.unlocated_nodes.lock().unwrap().is_empty());
+        assert_eq!(
+            vector_fetch(Arc::new(cold_copy(&dense_index, dir.as_ref())), VectorId(7)).unwrap(),
+            levels
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_dense_index_is_queried_after_restart() {
+        let dir = tempdir().unwrap();
+        let ctx = test_app_context(dir.as_ref());
+        create_collection(
+            ctx.clone(),
+            create_collection_dto("reloaded", "cosine", "scalar"),
+        )
+        .await
+        .unwrap();
+        let collection = ctx
+            .ain_env
+            .collections_map
+            .get_collection("reloaded")
+            .unwrap();
+        let dense_index = init_dense_index_for_collection(
+            ctx.clone(),
+            &collection,
+            Some((-1.0, 1.0)),
+            HNSWHyperParams::default_from_config(&ctx.config),
+            QuantizationMetric::Scalar,
+            DistanceMetric::Cosine,
+            StorageType::UnsignedByte,
+            0,
+            true,
+            None,
+            None,
+            10.0,
+        )
+        .await
+        .unwrap();
+
+        let vecs: Vec<_> = (0..ctx.config.upload_threshold as u64)
+            .map(|id| {
+                let angle = id as f32 * 0.15;
+                (id, vec![angle.cos(), angle.sin(), 0.3, -0.2], None)
+            })
+            .collect();
+        let query = vecs[42].1.clone();
+        run_upload(ctx.clone(), dense_index.clone(), vecs, None).unwrap();
+        let ids = |results: Vec<(VectorId, MetricResult)>| -> Vec<_> {
+            results.into_iter().map(|(id, _)| id).collect()
+        };
+        let before = ids(ann_vector_query(
+            ctx.clone(),
+            dense_index,
+            query.clone(),
+            Some(5),
+            None,
+            None,
+            false,
+        )
+        .await
+        .unwrap());
+        assert_eq!(before[0], VectorId(42));
+
+        // a restart only keeps the records in lmdb and the files of the
+        // collection, the environment has to be closed to open it again
+        drop(collection);
+        drop(ctx);
+        let ctx = test_app_context(dir.as_ref());
+        // loaded along with the app environment
+        let reloaded = ctx.ain_env.collections_map.get("reloaded").unwrap();
+        assert_eq!(reloaded.dim, 4);
+
+        let after = ann_vector_query(ctx.clone(), reloaded, query, Some(5), None, None, false)
+            .await
+            .unwrap();
+        assert_eq!(ids(after), before);
+    }
+
+    /// Removes every edge to the nodes of `id`, from the nodes indexed so far
+    /// and the root nodes.
+    pub(crate) fn unlink_vector(dense_index: &DenseIndex, id: VectorId) {
+        let mut linking = dense_index.unlocated_nodes.lock().unwrap().clone();
+        let mut root = dense_index.get_root_vec();
+        while !root.is_null() {
+            linking.push(root);
+            root = unsafe { &*root }.get_lazy_data().unwrap().get_child();
+        }
+        for node in linking {
+            unsafe { &*node }
+                .get_lazy_data()
+                .unwrap()
+                .remove_neighbor(id.0 as u32, |neighbor| {
+                    unsafe { &*neighbor }
+                        .get_lazy_data()
+                        .is_some_and(|data| data.prop.id == id)
+                });
+        }
+    }
+
+    /// Writes the nodes indexed with `index_vectors` and records where they
+    /// are.
+    fn write_unlocated_nodes(dense_index: &DenseIndex) {
+        let nodes = dense_index.unlocated_nodes.lock().unwrap().clone();
+        for node in nodes {
+            write_node_to_file(node, &dense_index.index_manager).unwrap();
+        }
+        dense_index.index_manager.flush_all().unwrap();
+        dense_index.record_node_locations().unwrap();
+    }
+
+    /// A copy of `dense_index`, stored in `dir`, as loaded after a restart,
+    /// with nothing cached and the prop file opened again.
+    fn cold_copy(dense_index: &DenseIndex, dir: &Path) -> DenseIndex {
+        let mut cold = dense_index.clone();
+        cold.prop_file = Arc::new(RwLock::new(
+            OpenOptions::new()
+                .read(true)
+                .append(true)
+                .open(prop_file_path(dir))
+                .unwrap(),
+        ));
+        cold.cache = Arc::new(ProbCache::new(
+            1000,
+            cold.index_manager.clone(),
+            cold.prop_file.clone(),
+        ));
+        cold.unlocated_nodes = Arc::new(Mutex::new(Vec::new()));
+        cold
+    }
+}
@@ -1,10 +1,12 @@
 use crate::api;
 use crate::api::auth::{auth_module, authentication_middleware::AuthenticationMiddleware};
+use crate::api::health::health_module;
 use crate::api::vectordb::collections::collections_module;
 use crate::api::vectordb::transactions::transactions_module;
 use crate::api::vectordb::vectors::vectors_module;
 use crate::app_context::AppContext;
 use crate::config_loader::{load_config, ServerMode, Ssl};
+use crate::vector_store::recover_pending_embeddings;
 use actix_cors::Cors;
 use actix_web::web::Data;
 use actix_web::{middleware, web, App, HttpServer};
@@ -45,6 +47,22 @@ pub async fn run_actix_server() -> std::io::Result<()> {
     // env. Without app env, the HTTP server won't be able to
     // serve any incoming requests anyway.
     let ctx = AppContext::new(config.clone()).expect("Failed to initialize AppContext");
+    // index what was uploaded but not indexed before the last shutdown
+    for dense_index in ctx.ain_env.collections_map.iter() {
+        match recover_pending_embeddings(&ctx.config, dense_index.value()) {
+            Ok(0) => {}
+            Ok(count) => log::info!(
+                "Recovered {} pending embeddings of {}",
+                count,
+                dense_index.key()
+            ),
+            Err(err) => log::error!(
+                "Failed to recover the pending embeddings of {}: {}",
+                dense_index.key(),
+                err
+            ),
+        }
+    }
     let data = Data::new(ctx);
 
     let server = HttpServer::new(move || {
@@ -57,6 +75,8 @@ pub async fn run_actix_server() -> std::io::Result<()> {
             // register simple handler, handle all methods
             .app_data(web::JsonConfig::default().limit(8_388_608)) // 8 MB
             .service(auth_module())
+            // kept outside the authenticated scope so it can be probed
+            .service(health_module())
             .service(
                 web::scope("/vectordb")
                     .wrap(AuthenticationMiddleware)